@@ -1,7 +1,7 @@
 use std::borrow::Cow;
 
-
 #[derive(Debug)]
+#[cfg_attr(feature = "target-html+tar", allow(dead_code))]
 pub struct UnsupportedFeatureError {
     pub what_to_use: Cow<'static, str>,
     pub feature: Cow<'static, str>,
@@ -12,8 +12,7 @@ impl core::fmt::Display for UnsupportedFeatureError {
         write!(
             f,
             "Using {} requires the feature `{}` which was not enabled during compilation",
-            self.what_to_use,
-            self.feature,
+            self.what_to_use, self.feature,
         )
     }
 }
@@ -114,7 +114,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             // There answers are mostly bad, and confidently incorrect.
             let wasm = Base64Display::new(&wasm, &general_purpose::STANDARD);
             let data_uri = format!("data:application/octet-stream;base64,{wasm}");
-            let data_uri_constructor = format!("{data_uri}");
+            let data_uri_constructor = data_uri.to_string();
             let with_data = template.replace(
                 "__REPLACE_THIS_WITH_WASM_AS_A_DATA_URI__",
                 &data_uri_constructor,
@@ -195,6 +195,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 name: boot_file,
                 data: &binary_wasm,
                 attributes: Default::default(),
+                compression: None,
             }));
 
             if let Some(zip) = args.zip {
@@ -223,6 +224,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         name,
                         data: &data,
                         attributes: Default::default(),
+                        compression: None,
                     });
 
                     pushed_data.push(entry);
@@ -254,12 +256,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         continue;
                     }
 
-                    let data = std::fs::read(&full_path)?;
+                    let data = std::fs::read(full_path)?;
 
                     let entry = engine.escaped_base64(html_and_tar::Entry {
                         name,
                         data: &data,
                         attributes: Default::default(),
+                        compression: None,
                     });
 
                     pushed_data.push(entry);
@@ -273,7 +276,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 seq_of_bytes.push(data.data.as_slice());
             }
 
-            // FIXME: not sure if we should just do the open-end thing instead of EOF..
+            // Nothing appends to these documents, so end with a proper EOF for tar tools. See
+            // `TarEngine::escaped_open_end` for leaving the archive open instead.
 
             let eof = engine.escaped_eof();
             seq_of_bytes.push(eof.padding);
@@ -0,0 +1,94 @@
+//! The `amend` subcommand, a patch appended for a file changed since the build.
+
+use wasi_document::tar;
+
+use crate::extract::extract_matching;
+
+/// Append a patch that changes a packed file to `contents`, with only the bytes that differ.
+///
+/// Patches replace and extend the data of a file but can not truncate it, shorter contents are
+/// refused. Earlier patches are applied first, so a document can be amended again and again.
+pub fn amend_document(
+    document: &[u8],
+    name: &str,
+    contents: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let name = html_and_tar::HtmlAttributeSafeName::new(name)?;
+    let entries = html_and_tar::TarDecompiler::list(document)?;
+    let packed = extract_matching(document, &entries, &|path| path == name.0)?
+        .remove(name.0)
+        .ok_or_else(|| format!("The document has no file `{}` to amend", name.0))?;
+
+    if contents.len() < packed.len() {
+        return Err(format!(
+            "`{}` is shorter than the packed file, a patch can not truncate it",
+            name.0
+        )
+        .into());
+    }
+
+    let same = |(new, old): (&u8, &u8)| new == old;
+    let start = contents
+        .iter()
+        .zip(&packed)
+        .take_while(|&pair| same(pair))
+        .count();
+    // Data past the end of the packed file is always written, the same suffix only otherwise.
+    let end = if contents.len() > packed.len() {
+        contents.len()
+    } else {
+        let suffix = contents[start..]
+            .iter()
+            .rev()
+            .zip(packed[start..].iter().rev());
+        contents.len() - suffix.take_while(|&pair| same(pair)).count()
+    };
+
+    if start == end {
+        log::info!("`{}` is unchanged, the document is kept as is", name.0);
+        return Ok(document.to_vec());
+    }
+
+    tar::append(
+        document,
+        [tar::TarItem::Patch(html_and_tar::Patch {
+            name,
+            offset: start as u64,
+            data: &contents[start..end],
+        })],
+    )
+}
+
+#[test]
+fn amend_appends_only_the_changed_bytes() {
+    let document =
+        crate::pack::pack_fixture(&[("etc/motd", b"Hello, world!\n"), ("etc/issue", b"\\S\n")]);
+
+    let amended = amend_document(document.as_bytes(), "etc/motd", b"Hello, there!\n").unwrap();
+    let listed = html_and_tar::TarDecompiler::list(&amended).unwrap();
+    let patch = listed.last().unwrap();
+    assert_eq!(patch.header.parse_patch_offset(), Some(7));
+    assert_eq!(patch.size, b"there".len() as u64);
+
+    // Amended again, on top of the first patch and past the end of the file.
+    let amended = amend_document(&amended, "etc/motd", b"Hello, there! Bye.\n").unwrap();
+    let listed = html_and_tar::TarDecompiler::list(&amended).unwrap();
+    let files = extract_matching(&amended, &listed, &|_| true).unwrap();
+    assert_eq!(files["etc/motd"], b"Hello, there! Bye.\n");
+    assert_eq!(files["etc/issue"], b"\\S\n");
+
+    let amended = String::from_utf8(amended).unwrap();
+    let entries = wasi_document_dom::SourceDocument::new(&amended)
+        .split_tar_contents()
+        .unwrap();
+    let motd = entries[0].as_html_and_tar_entry().unwrap();
+    assert_eq!(motd.data, b"Hello, there! Bye.\n");
+
+    let unchanged = amend_document(document.as_bytes(), "etc/issue", b"\\S\n").unwrap();
+    assert_eq!(unchanged, document.as_bytes());
+
+    let err = amend_document(document.as_bytes(), "etc/motd", b"Hello\n").unwrap_err();
+    assert!(err.to_string().contains("can not truncate"), "{err}");
+    let err = amend_document(document.as_bytes(), "etc/hosts", b"").unwrap_err();
+    assert!(err.to_string().contains("no file `etc/hosts`"), "{err}");
+}
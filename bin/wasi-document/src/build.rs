@@ -2,14 +2,65 @@
 /// (load resources, make dependencies, instantiate templates, prepare filesystem).
 use crate::project::Build;
 
-use std::{path, process::Command};
+use std::{num::NonZeroUsize, path, process::Command};
+
+/// The errors of the work done on the threads of [`run_bounded`].
+type BuildError = Box<dyn std::error::Error + Send + Sync>;
 
 pub fn generate(
     configuration: &super::Configuration,
     build: &BuildEnv,
-) -> Result<super::Work, Box<dyn std::error::Error>> {
-    let stage2 = run_build(&configuration.machine.stage2)?;
-    let stage3 = run_build(&configuration.machine.stage3)?;
+) -> Result<crate::pack::Work, Box<dyn std::error::Error>> {
+    let jobs = build
+        .jobs
+        .or(configuration.jobs)
+        .or_else(|| std::thread::available_parallelism().ok())
+        .unwrap_or(NonZeroUsize::MIN);
+
+    // The stages are independent, build them side by side. Except for those built by cargo, which
+    // would only wait for each other on the lock of the target directory, so they take turns.
+    let machine = &configuration.machine;
+    let stages: Vec<_> = [&machine.stage2, &machine.stage3]
+        .into_iter()
+        .chain(machine.modules.values())
+        .collect();
+    let (cargo, others): (Vec<_>, Vec<_>) = (0..stages.len())
+        .partition(|&idx| matches!(stages[idx], Build::Rust { .. } | Build::Install(_)));
+    let lanes: Vec<_> = [cargo]
+        .into_iter()
+        .chain(others.into_iter().map(|idx| vec![idx]))
+        .collect();
+
+    let mut stages: Vec<_> = run_bounded(jobs, &lanes, |lane| {
+        lane.iter()
+            .map(|&idx| (idx, run_build(stages[idx], build)))
+            .collect::<Vec<_>>()
+    })
+    .into_iter()
+    .flatten()
+    .collect();
+    stages.sort_by_key(|&(idx, _)| idx);
+    let mut stages = stages
+        .into_iter()
+        .map(|(_, built)| built.map_err(|err| err as Box<dyn std::error::Error>));
+
+    let stage2 = stages.next().unwrap()?;
+    let stage3 = stages.next().unwrap()?;
+
+    let mut modules = vec![];
+    for (slot, built) in machine.modules.keys().zip(stages) {
+        modules.push((slot.clone(), built?.item));
+    }
+
+    for (stage, built) in [("stage2", &stage2.item), ("stage3", &stage3.item)]
+        .into_iter()
+        .chain(modules.iter().map(|(slot, item)| (slot.as_str(), item)))
+    {
+        crate::message::emit(crate::message::Event::StageBuilt {
+            stage,
+            size: built.len(),
+        });
+    }
 
     let mut root_fs = vec![];
     let mut resources = vec![];
@@ -50,84 +101,532 @@ pub fn generate(
         }
 
         root_fs.push(builder.path_while_alive().to_path_buf());
-        resources.push(Box::new(builder) as Box<dyn std::any::Any>);
+        resources.push(Box::new(builder) as Box<dyn std::any::Any + Send + Sync>);
     }
 
     let packers = configuration.web.to_roots(build);
 
-    Ok(super::Work {
+    let runtime_config = configuration
+        .document
+        .runtime_config
+        .as_ref()
+        .map(serde_json::to_vec)
+        .transpose()?;
+
+    let executable = configuration.document.executable_set()?;
+    let root_filter = configuration.document.root_filter()?;
+    let insertions = configuration.document.insertion_sets()?;
+
+    let remote = configuration.document.remote.as_deref().unwrap_or_default();
+    let remote = run_bounded(jobs, remote, fetch_remote)
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .map_err(|err| err as Box<dyn std::error::Error>)?;
+
+    let kernel_name = boot_name(&machine.kernel_name, crate::pack::BOOT_KERNEL_NAME)?;
+    let config_name = boot_name(&machine.config_name, crate::pack::BOOT_CONFIG_NAME)?;
+
+    Ok(crate::pack::Work {
         index_html: configuration.document.index_html.clone(),
         stage2: stage2.item,
         kernel: stage3.item,
+        modules,
+        runtime_config,
+        embedded_config: build
+            .embed_config
+            .then(|| configuration.contents.clone().into_bytes()),
+        kernel_name,
+        config_name,
         edit: false,
+        verbose: build.verbose,
+        emit_layout: build.emit_layout,
+        check_minify: build.check_minify,
+        check_browser_compat: build.check_browser_compat,
+        allow_invalid_wasm: build.allow_invalid_wasm,
+        doctype: configuration.document.doctype.clone(),
+        aliases: configuration.document.aliases.clone(),
+        line_width: NonZeroUsize::new(configuration.document.base64_line_width),
+        compression: (configuration.document.compress || build.compress)
+            .then_some(html_and_tar::Compression::Gzip),
+        js_target: configuration.document.js_target,
+        open_ended: configuration.document.open_ended,
+        csp: configuration.document.csp.clone(),
+        inline_assets: configuration.document.inline_assets.clone(),
+        devices: build.devices,
+        jobs,
         root_fs,
-        out: Some(build.cargo_workspace.target_directory.join("wasi.html")),
+        root_tar: configuration.document.root_tar.clone(),
+        remote,
+        executable,
+        root_filter,
+        insertions,
+        fixed_mtime: configuration.document.packed_mtime(),
+        out: Some(
+            build
+                .cargo_workspace
+                .target_directory
+                .join(output_name(configuration)?),
+        ),
         packers,
         resources,
     })
 }
 
+/// The output name with all tokens but `{hash}` substituted, the document is not packed yet.
+fn output_name(configuration: &super::Configuration) -> Result<String, Box<dyn std::error::Error>> {
+    let dir = path::absolute(&configuration.dir)?;
+    let name = dir
+        .file_name()
+        .map_or("wasi".into(), |name| name.to_string_lossy());
+    let date = utc_date(std::time::SystemTime::now());
+
+    crate::project::expand_output_name(
+        &configuration.document.output_name,
+        &[("name", &name), ("date", &date), ("hash", "{hash}")],
+    )
+}
+
+/// Format a time as `YYYY-MM-DD` in UTC.
+fn utc_date(time: std::time::SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    // The civil date of a day count, see <https://howardhinnant.github.io/date_algorithms.html>.
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn boot_name(
+    name: &Option<String>,
+    default: html_and_tar::HtmlAttributeSafeName,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match name {
+        None => Ok(default.0.to_owned()),
+        Some(name) => {
+            html_and_tar::HtmlAttributeSafeName::new(name)?;
+            Ok(name.clone())
+        }
+    }
+}
+
+/// How long a fetch may wait on the server, so an unresponsive one fails the build instead of
+/// stalling it. The body gets a total budget, ureq has no timeout for each read.
+const FETCH_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+const FETCH_RESPONSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+const FETCH_BODY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(600);
+
+fn fetch_remote(remote: &crate::project::Remote) -> Result<crate::pack::RemoteFile, BuildError> {
+    use sha2::Digest as _;
+
+    html_and_tar::HtmlAttributeSafeName::new(&remote.name)?;
+
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_connect(Some(FETCH_CONNECT_TIMEOUT))
+        .timeout_send_request(Some(FETCH_CONNECT_TIMEOUT))
+        .timeout_recv_response(Some(FETCH_RESPONSE_TIMEOUT))
+        .timeout_recv_body(Some(FETCH_BODY_TIMEOUT))
+        .build()
+        .into();
+
+    let fetch_error = |err: ureq::Error| format!("Failed to fetch `{}`: {err}", remote.url);
+    let mut response = agent.get(&remote.url).call().map_err(fetch_error)?;
+    let data = response
+        .body_mut()
+        .with_config()
+        .limit(u64::MAX)
+        .read_to_vec()
+        .map_err(fetch_error)?;
+
+    if let Some(expected) = &remote.sha256 {
+        let actual = format!("{:x}", sha2::Sha256::digest(&data));
+
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(format!(
+                "Hash mismatch for `{}`, expected sha256 {expected} but fetched {actual}",
+                remote.url
+            )
+            .into());
+        }
+    }
+
+    Ok(crate::pack::RemoteFile {
+        name: remote.name.clone(),
+        url: remote.url.clone(),
+        data,
+    })
+}
+
+/// Map items on at most `jobs` threads at once, keeping their order.
+pub(crate) fn run_bounded<'a, T: Sync, R: Send>(
+    jobs: NonZeroUsize,
+    items: &'a [T],
+    work: impl Fn(&'a T) -> R + Sync,
+) -> Vec<R> {
+    use std::sync::{Mutex, atomic::AtomicUsize, atomic::Ordering};
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.get().min(items.len()) {
+            scope.spawn(|| {
+                loop {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(idx) else {
+                        break;
+                    };
+
+                    let result = work(item);
+                    results.lock().unwrap()[idx] = Some(result);
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every item was processed"))
+        .collect()
+}
+
 struct BuiltResource {
     item: Vec<u8>,
 }
 
-fn run_build(build: &Build) -> Result<BuiltResource, Box<dyn std::error::Error>> {
+/// Build a stage, or reuse its last output when none of its inputs changed since.
+fn run_build(build: &Build, env: &BuildEnv) -> Result<BuiltResource, BuildError> {
+    let cached = CachedStage::new(build, env)?;
+
+    if !env.force
+        && let Some(item) = cached.as_ref().and_then(CachedStage::load)
+    {
+        log::info!("Reusing the last build of an unchanged stage, `--force` to rebuild it");
+        return Ok(BuiltResource { item });
+    }
+
+    let built = build_stage(build, env)?;
+
+    if let Some(cached) = &cached {
+        cached.store(&built.item)?;
+    }
+
+    Ok(built)
+}
+
+fn build_stage(build: &Build, env: &BuildEnv) -> Result<BuiltResource, BuildError> {
+    let (retries, verbose) = (env.build_retries, env.verbose);
     let item = match build {
-        Build::Rust { package, bin } => {
-            Command::new("cargo")
-                .arg("build")
-                .arg("-p")
-                .arg(&package)
-                .args(["--target", "wasm32-wasip1", "--release"])
-                .args(["--bin", bin])
-                .stdin(std::process::Stdio::null())
-                .status()
-                .inspect(|x| assert!(x.success()))?;
+        Build::Rust {
+            package,
+            bin,
+            default_features,
+            features,
+        } => {
+            run_with_retries(retries, verbose, || {
+                let mut command = Command::new("cargo");
+                command
+                    .arg("build")
+                    .arg("-p")
+                    .arg(package)
+                    .args(["--target", "wasm32-wasip1", "--release"])
+                    .args(["--bin", bin])
+                    .stdin(std::process::Stdio::null());
+
+                if !default_features {
+                    command.arg("--no-default-features");
+                }
+
+                if !features.is_empty() {
+                    command.arg("--features");
+                    command.arg(features.join(","));
+                }
+
+                Ok(command)
+            })?;
 
             let meta = metadata(path::Path::new("."))?;
             let path = format!("wasm32-wasip1/release/{bin}.wasm");
 
-            std::fs::read(meta.target_directory.join(path))?
+            read_artifact(
+                &meta.target_directory.join(path),
+                &format!("Does the package `{package}` have a binary target named `{bin}`?"),
+            )?
         }
         Build::Node { workdir, build } => {
-            Command::new("node")
-                .stdin(std::process::Stdio::null())
-                .current_dir(workdir)
-                .stdin(std::fs::File::open(workdir.join(build))?)
-                .status()
-                .inspect(|x| assert!(x.success()))?;
-
-            std::fs::read(workdir.join("out.js"))?
+            run_with_retries(retries, verbose, || {
+                let mut command = Command::new("node");
+                command
+                    .current_dir(workdir)
+                    .stdin(std::fs::File::open(workdir.join(build))?);
+                Ok(command)
+            })?;
+
+            read_artifact(
+                &workdir.join("out.js"),
+                &format!(
+                    "The script `{}` must write its bundle to `out.js` in its working directory.",
+                    build.display()
+                ),
+            )?
+        }
+        Build::Prebuilt { path } => std::fs::read(path)
+            .map_err(|err| format!("Can not read the prebuilt `{}`: {err}", path.display()))?,
+        Build::Install(install) => {
+            let target_dir = env.target_dir_for_wasm32_wasi().to_owned();
+            let builder = crate::cargo::BuildDir::new(Some(target_dir))?;
+            run_with_retries(retries, verbose, || Ok(builder.command(install)))?;
+
+            // Without a choice, cargo installs all binaries and we guess the one of the package.
+            let bin = install.bin.as_deref().unwrap_or(&install.package);
+            let path = format!("bin/{bin}.wasm");
+
+            read_artifact(
+                &builder.path_while_alive().join(path),
+                &format!(
+                    "Does the package `{}` have a binary target named `{bin}`? Set `bin` otherwise.",
+                    install.package
+                ),
+            )?
         }
     };
 
     Ok(BuiltResource { item })
 }
 
+/// The output of a stage's last build, stored in the target directory.
+///
+/// The key covers the flavor and parameters of the build, and the path, size and modification time
+/// of every file it may read. For a Rust stage those are the sources of its package and of the
+/// local packages it depends on, `Cargo.lock`, the compiler version and `RUSTFLAGS`. That is
+/// coarser than cargo's own fingerprint but spares us spawning the build tools at all. Installs
+/// and prebuilt stages are not cached: a git source can change remotely and a file is read as
+/// fast as the cache itself.
+struct CachedStage {
+    /// The output and its key, named by the parameters so each stage has one entry.
+    path: path::PathBuf,
+    key: String,
+}
+
+impl CachedStage {
+    const DIRECTORY: &str = "wasi-document-stages";
+
+    fn new(build: &Build, env: &BuildEnv) -> Result<Option<Self>, BuildError> {
+        use sha2::Digest as _;
+
+        let cache_dir = env.target_dir_for_wasm32_wasi();
+        let workspace = &env.cargo_workspace;
+
+        let parameters = format!("{build:?}");
+        let mut hasher = sha2::Sha256::new_with_prefix(&parameters);
+
+        // The outputs of the builds, which we must not count as their inputs. Compared by path, so
+        // resolve them the same way as the sources.
+        let resolve = |path: &path::Path| path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        let mut outputs: Vec<_> = [
+            cache_dir,
+            &workspace.target_directory,
+            &workspace.workspace_root.join("target"),
+        ]
+        .into_iter()
+        .map(resolve)
+        .collect();
+
+        let sources = match build {
+            Build::Rust { package, .. } => {
+                let Some(sources) = workspace.local_sources(package) else {
+                    return Ok(None);
+                };
+
+                let lock = workspace.workspace_root.join("Cargo.lock");
+                hasher.update(std::fs::read(lock).unwrap_or_default());
+                hasher.update(rustc_version());
+
+                for var in ["RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS"] {
+                    let flags = std::env::var_os(var).unwrap_or_default();
+                    hasher.update(flags.as_encoded_bytes());
+                    hasher.update([0]);
+                }
+
+                sources
+            }
+            Build::Node { workdir, .. } => {
+                outputs.push(resolve(&workdir.join("out.js")));
+                vec![workdir.canonicalize()?]
+            }
+            Build::Prebuilt { .. } | Build::Install(_) => return Ok(None),
+        };
+
+        for sources in sources {
+            let walk = walkdir::WalkDir::new(&sources)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_entry(|entry| {
+                    let path = entry.path();
+                    !outputs.iter().any(|output| path == output)
+                        && entry.file_name() != ".git"
+                        // The default target directory of a package outside the workspace.
+                        && path != sources.join("target")
+                });
+
+            for entry in walk {
+                let entry = entry?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+
+                let meta = entry.metadata()?;
+                let mtime = meta.modified()?.duration_since(std::time::UNIX_EPOCH)?;
+                hasher.update(entry.path().as_os_str().as_encoded_bytes());
+                hasher.update(meta.len().to_le_bytes());
+                hasher.update(mtime.as_nanos().to_le_bytes());
+            }
+        }
+
+        let name = crate::pack::short_hash(parameters.as_bytes());
+        Ok(Some(CachedStage {
+            path: cache_dir.join(Self::DIRECTORY).join(name),
+            key: format!("{:x}", hasher.finalize()),
+        }))
+    }
+
+    fn load(&self) -> Option<Vec<u8>> {
+        let key = std::fs::read_to_string(self.path.with_extension("key")).ok()?;
+        if key != self.key {
+            return None;
+        }
+
+        std::fs::read(self.path.with_extension("out")).ok()
+    }
+
+    /// Store the output before its key, so an interrupted store is a miss and not a stale hit.
+    fn store(&self, item: &[u8]) -> Result<(), BuildError> {
+        std::fs::create_dir_all(self.path.parent().unwrap())?;
+        let _ = std::fs::remove_file(self.path.with_extension("key"));
+        std::fs::write(self.path.with_extension("out"), item)?;
+        std::fs::write(self.path.with_extension("key"), &self.key)?;
+        Ok(())
+    }
+}
+
+/// Run a build command, again up to `retries` times while it fails.
+///
+/// Failed attempts are quiet. The output of the last attempt is shown when it fails, or with
+/// `verbose`, in one piece after it exited so that stages built side by side do not interleave.
+/// Missing output of a successful command is not retried, see [`read_artifact`].
+fn run_with_retries(
+    retries: u32,
+    verbose: bool,
+    command: impl Fn() -> std::io::Result<Command>,
+) -> Result<(), BuildError> {
+    for attempt in 1..=retries {
+        let mut command = command()?;
+        let output = command.output()?;
+
+        if output.status.success() {
+            return Ok(());
+        }
+
+        log::warn!(
+            "`{}` failed with {}, retrying ({attempt}/{retries})",
+            command.get_program().display(),
+            output.status
+        );
+    }
+
+    let mut command = command()?;
+    let output = command.output()?;
+
+    if verbose || !output.status.success() {
+        use std::io::Write as _;
+        std::io::stdout().lock().write_all(&output.stdout)?;
+        std::io::stderr().lock().write_all(&output.stderr)?;
+    }
+
+    if !output.status.success() {
+        let program = command.get_program().display();
+        return Err(format!("`{program}` failed with {}", output.status).into());
+    }
+
+    Ok(())
+}
+
+/// Read the output of a successful build, explaining its absence.
+fn read_artifact(path: &path::Path, hint: &str) -> Result<Vec<u8>, BuildError> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(data),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(format!(
+            "The build succeeded but did not produce `{}`. {hint}",
+            path.display()
+        )
+        .into()),
+        Err(err) => Err(err.into()),
+    }
+}
+
 pub struct BuildEnv {
     pub(crate) cargo_workspace: CargoMetadata,
     pub(crate) cargo_target_override: Option<path::PathBuf>,
+    pub(crate) verbose: bool,
+    pub(crate) jobs: Option<NonZeroUsize>,
+    pub(crate) embed_config: bool,
+    pub(crate) emit_layout: bool,
+    pub(crate) check_minify: bool,
+    pub(crate) check_browser_compat: bool,
+    pub(crate) build_retries: u32,
+    pub(crate) devices: bool,
+    pub(crate) compress: bool,
+    pub(crate) allow_invalid_wasm: bool,
+    pub(crate) force: bool,
 }
 
 impl BuildEnv {
-    pub fn new(args: &super::Args) -> Result<Self, Box<dyn std::error::Error>> {
-        let project = match args {
-            super::Args::Build { project, .. } | super::Args::Repack { project, .. } => project,
-        };
-
-        let path = match project {
-            None => path::Path::new(".").to_owned(),
-            Some(n) => n.canonicalize()?.parent().unwrap().to_owned(),
-        };
-
-        let cargo_target_override = match args {
-            super::Args::Build { target_dir, .. } => target_dir.clone(),
-            super::Args::Repack { .. } => None,
-        };
+    /// The environment of a build of the stages in the cargo workspace at `dir`.
+    pub fn new(
+        dir: &path::Path,
+        options: &super::BuildOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let super::BuildOptions {
+            target_dir,
+            verbose,
+            jobs,
+            embed_config,
+            emit_layout,
+            check_minify,
+            check_browser_compat,
+            build_retries,
+            devices,
+            compress,
+            allow_invalid_wasm,
+            force,
+        } = options;
 
         Ok(Self {
-            cargo_workspace: metadata(&path)?,
-            cargo_target_override,
+            cargo_workspace: metadata(dir).map_err(|err| err as Box<dyn std::error::Error>)?,
+            cargo_target_override: target_dir.clone(),
+            verbose: *verbose,
+            jobs: *jobs,
+            embed_config: *embed_config,
+            emit_layout: *emit_layout,
+            check_minify: *check_minify,
+            check_browser_compat: *check_browser_compat,
+            build_retries: *build_retries,
+            devices: *devices,
+            compress: *compress,
+            allow_invalid_wasm: *allow_invalid_wasm,
+            force: *force,
         })
     }
 
@@ -143,9 +642,85 @@ impl BuildEnv {
 #[derive(serde::Deserialize)]
 pub(crate) struct CargoMetadata {
     pub target_directory: path::PathBuf,
+    pub workspace_root: path::PathBuf,
+    #[serde(default)]
+    pub packages: Vec<CargoPackage>,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct CargoPackage {
+    pub name: String,
+    pub manifest_path: path::PathBuf,
+    /// Unset for packages on the local filesystem.
+    pub source: Option<String>,
+    pub dependencies: Vec<CargoDependency>,
 }
 
-fn metadata(build: &path::Path) -> Result<CargoMetadata, Box<dyn std::error::Error>> {
+#[derive(serde::Deserialize)]
+pub(crate) struct CargoDependency {
+    /// The directory of a path dependency.
+    pub path: Option<path::PathBuf>,
+}
+
+impl CargoMetadata {
+    /// The directories of a local package and the local packages it depends on, none nested in
+    /// another. `None` if there is no such local package.
+    fn local_sources(&self, package: &str) -> Option<Vec<path::PathBuf>> {
+        let local = |package: &&CargoPackage| package.source.is_none();
+        let root = self
+            .packages
+            .iter()
+            .filter(local)
+            .find(|candidate| candidate.name == package)?;
+
+        let mut dirs = std::collections::BTreeSet::new();
+        let mut pending = vec![root];
+
+        while let Some(package) = pending.pop() {
+            let dir = package.manifest_path.parent()?;
+            if !dirs.insert(dir.canonicalize().ok()?) {
+                continue;
+            }
+
+            for dir in package
+                .dependencies
+                .iter()
+                .filter_map(|dep| dep.path.as_deref())
+            {
+                let manifest = dir.join("Cargo.toml");
+                pending.extend(
+                    self.packages
+                        .iter()
+                        .filter(local)
+                        .filter(|package| package.manifest_path == manifest),
+                );
+            }
+        }
+
+        // Sorted, so a directory comes right before those within it.
+        let mut sources: Vec<path::PathBuf> = vec![];
+        for dir in dirs {
+            if !sources.last().is_some_and(|outer| dir.starts_with(outer)) {
+                sources.push(dir);
+            }
+        }
+
+        Some(sources)
+    }
+}
+
+/// The version of the compiler, which a cached Rust stage was built with.
+fn rustc_version() -> Vec<u8> {
+    let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    Command::new(rustc)
+        .arg("-V")
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map(|output| output.stdout)
+        .unwrap_or_default()
+}
+
+fn metadata(build: &path::Path) -> Result<CargoMetadata, BuildError> {
     let output = Command::new("cargo")
         .args(["metadata", "--format-version", "1"])
         .stdin(std::process::Stdio::null())
@@ -156,3 +731,299 @@ fn metadata(build: &path::Path) -> Result<CargoMetadata, Box<dyn std::error::Err
 
     Ok(serde_json::from_slice(&output.stdout)?)
 }
+
+#[test]
+fn bounded_keeps_order_and_limit() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let (running, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    let items: Vec<usize> = (0..16).collect();
+
+    let doubled = run_bounded(NonZeroUsize::new(3).unwrap(), &items, |&item| {
+        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+        peak.fetch_max(now, Ordering::SeqCst);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        running.fetch_sub(1, Ordering::SeqCst);
+        item * 2
+    });
+
+    assert_eq!(
+        doubled,
+        items.iter().map(|item| item * 2).collect::<Vec<_>>()
+    );
+    assert!(peak.load(Ordering::SeqCst) <= 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn files_encoded_ahead_pack_the_same() {
+    use wasi_document::tar;
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("usr")).unwrap();
+    std::fs::write(dir.path().join("etc-motd"), "Hello, world!\n").unwrap();
+    std::fs::write(dir.path().join("usr/empty"), "").unwrap();
+    std::fs::write(dir.path().join("usr/repeated"), [0x5a; 4096]).unwrap();
+    let binary: Vec<u8> = (0..=255).cycle().take(3000).collect();
+    std::fs::write(dir.path().join("usr/binary"), binary).unwrap();
+
+    let files: Vec<(String, Vec<u8>)> = walkdir::WalkDir::new(dir.path())
+        .sort_by_file_name()
+        .into_iter()
+        .map(Result::unwrap)
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| {
+            let name = entry.path().strip_prefix(dir.path()).unwrap();
+            let name = name.to_str().unwrap().to_owned();
+            (name, std::fs::read(entry.path()).unwrap())
+        })
+        .collect();
+
+    let options = tar::Options {
+        line_width: std::num::NonZeroUsize::new(76),
+        ..Default::default()
+    };
+    let compression = Some(html_and_tar::Compression::Gzip);
+
+    let pack = |ahead: bool| {
+        let jobs = std::num::NonZeroUsize::new(3).unwrap();
+        let encoded = run_bounded(jobs, &files, |(name, data)| {
+            let name = html_and_tar::HtmlAttributeSafeName::new(name).unwrap();
+            html_and_tar::EncodedEntry::new(
+                name,
+                Default::default(),
+                data,
+                compression,
+                options.line_width,
+            )
+        });
+
+        let mut source = wasi_document_dom::SourceDocument::new(include_str!("fixture.html"));
+        let elements = |push: &mut dyn FnMut(tar::TarItem<'_>)| {
+            if ahead {
+                encoded
+                    .into_iter()
+                    .for_each(|entry| push(tar::TarItem::Encoded(entry)));
+                return Ok::<_, Box<dyn std::error::Error>>(());
+            }
+
+            for (name, data) in &files {
+                push(tar::TarItem::Entry(html_and_tar::Entry {
+                    name: html_and_tar::HtmlAttributeSafeName::new(name)?,
+                    data,
+                    attributes: Default::default(),
+                    compression,
+                }));
+            }
+
+            Ok(())
+        };
+
+        tar::build_with_progress(
+            &mut source,
+            elements,
+            Some(b"console.log('stage0')"),
+            &options,
+            (),
+        )
+        .unwrap()
+        .0
+    };
+
+    let serial = pack(false);
+    assert_eq!(pack(true), serial);
+
+    let listed = html_and_tar::TarDecompiler::list(&serial).unwrap();
+    assert_eq!(listed.len(), files.len());
+}
+
+#[test]
+fn retries_until_the_command_succeeds() {
+    let dir = tempfile::tempdir().unwrap();
+    let marker = dir.path().join("failed-once");
+
+    let flaky = || {
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", "test -e \"$1\" || { touch \"$1\"; exit 1; }", "sh"])
+            .arg(&marker);
+        Ok(command)
+    };
+
+    run_with_retries(1, false, flaky).unwrap();
+
+    std::fs::remove_file(&marker).unwrap();
+    assert!(run_with_retries(0, false, flaky).is_err());
+
+    // A command that can not start keeps its error, not only its message.
+    let missing = || Ok(Command::new(dir.path().join("missing")));
+    let err = run_with_retries(0, false, missing).unwrap_err();
+    assert!(err.downcast_ref::<std::io::Error>().is_some(), "{err}");
+}
+
+#[test]
+fn utc_dates() {
+    let at = |secs| utc_date(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+
+    assert_eq!(at(0), "1970-01-01");
+    assert_eq!(at(951_782_400), "2000-02-29");
+    assert_eq!(at(1_709_251_199), "2024-02-29");
+}
+
+#[test]
+fn cached_stage_follows_its_inputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let workdir = dir.path().join("stage2");
+    std::fs::create_dir(&workdir).unwrap();
+    std::fs::write(workdir.join("build.mjs"), "// build").unwrap();
+
+    let env = BuildEnv {
+        cargo_workspace: CargoMetadata {
+            target_directory: dir.path().join("target"),
+            workspace_root: dir.path().to_owned(),
+            packages: vec![],
+        },
+        cargo_target_override: None,
+        verbose: false,
+        jobs: None,
+        embed_config: false,
+        emit_layout: false,
+        check_minify: false,
+        check_browser_compat: false,
+        build_retries: 0,
+        devices: false,
+        compress: false,
+        allow_invalid_wasm: false,
+        force: false,
+    };
+
+    let build = Build::Node {
+        workdir: workdir.clone(),
+        build: "build.mjs".into(),
+    };
+
+    let cached = CachedStage::new(&build, &env).unwrap().unwrap();
+    assert!(cached.load().is_none());
+    cached.store(b"bundle").unwrap();
+
+    // Writing the bundle is not a change of the inputs.
+    std::fs::write(workdir.join("out.js"), "bundle").unwrap();
+    let cached = CachedStage::new(&build, &env).unwrap().unwrap();
+    assert_eq!(cached.load().unwrap(), b"bundle");
+
+    std::fs::write(workdir.join("build.mjs"), "// changed build").unwrap();
+    let cached = CachedStage::new(&build, &env).unwrap().unwrap();
+    assert!(cached.load().is_none());
+
+    let prebuilt = Build::Prebuilt {
+        path: workdir.join("out.js"),
+    };
+    assert!(CachedStage::new(&prebuilt, &env).unwrap().is_none());
+}
+
+#[test]
+fn cached_rust_stage_reads_its_packages() {
+    let dir = tempfile::tempdir().unwrap();
+    for path in ["stage", "stage/target", "lib", "other", "target"] {
+        std::fs::create_dir(dir.path().join(path)).unwrap();
+        std::fs::write(dir.path().join(path).join("lib.rs"), "").unwrap();
+    }
+
+    let package = |name: &str, dependencies: &[&str]| CargoPackage {
+        name: name.to_owned(),
+        manifest_path: dir.path().join(name).join("Cargo.toml"),
+        source: None,
+        dependencies: dependencies
+            .iter()
+            .map(|name| CargoDependency {
+                path: Some(dir.path().join(name)),
+            })
+            .collect(),
+    };
+
+    let env = BuildEnv {
+        cargo_workspace: CargoMetadata {
+            target_directory: dir.path().join("target"),
+            workspace_root: dir.path().to_owned(),
+            packages: vec![
+                package("stage", &["lib"]),
+                package("lib", &[]),
+                package("other", &[]),
+            ],
+        },
+        cargo_target_override: Some(dir.path().join("custom-target")),
+        verbose: false,
+        jobs: None,
+        embed_config: false,
+        emit_layout: false,
+        check_minify: false,
+        check_browser_compat: false,
+        build_retries: 0,
+        devices: false,
+        compress: false,
+        allow_invalid_wasm: false,
+        force: false,
+    };
+
+    let build = Build::Rust {
+        package: "stage".into(),
+        bin: "stage".into(),
+        default_features: true,
+        features: vec![],
+    };
+
+    let cached = CachedStage::new(&build, &env).unwrap().unwrap();
+    cached.store(b"stage").unwrap();
+
+    // Neither other packages nor the default target directory are sources of the stage.
+    std::fs::write(dir.path().join("other/lib.rs"), "// changed").unwrap();
+    std::fs::write(dir.path().join("target/lib.rs"), "// changed").unwrap();
+    std::fs::write(dir.path().join("stage/target/lib.rs"), "// changed").unwrap();
+    let cached = CachedStage::new(&build, &env).unwrap().unwrap();
+    assert_eq!(cached.load().unwrap(), b"stage");
+
+    std::fs::write(dir.path().join("lib/lib.rs"), "// changed").unwrap();
+    let cached = CachedStage::new(&build, &env).unwrap().unwrap();
+    assert!(cached.load().is_none());
+    cached.store(b"stage").unwrap();
+
+    std::fs::write(dir.path().join("Cargo.lock"), "# changed").unwrap();
+    let cached = CachedStage::new(&build, &env).unwrap().unwrap();
+    assert!(cached.load().is_none());
+}
+
+#[test]
+fn remote_files_must_match_their_digest() {
+    use std::io::{Read as _, Write as _};
+
+    // Serves the same file to every request.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/motd", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            let response =
+                "HTTP/1.1 200 OK\r\nContent-Length: 6\r\nConnection: close\r\n\r\nHello\n";
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    let remote = |sha256: &str| crate::project::Remote {
+        name: "etc/motd".into(),
+        url: url.clone(),
+        sha256: Some(sha256.into()),
+    };
+
+    let hello = "66a045b452102c59d840ec097d59d9467e13a3f34f6494e539ffd32c1bb35f18";
+    let fetched = fetch_remote(&remote(&hello.to_uppercase())).unwrap();
+    assert_eq!(fetched.data, b"Hello\n");
+
+    let err = fetch_remote(&remote(&"0".repeat(64))).err().unwrap();
+    let err = err.to_string();
+    assert!(
+        err.contains("Hash mismatch") && err.contains(hello),
+        "{err}"
+    );
+}
@@ -4,7 +4,7 @@ use crate::project::{Install, InstallSource, RuntimeTarget};
 /// ```bash
 /// CARGO_PROFILE_RELEASE_OPT_LEVEL=s CARGO_PROFILE_RELEASE_STRIP=true CARGO_PROFILE_RELEASE_DEBUG=none cargo install --git https://github.com/mkeeter/fidget fidget-cli --target wasm32-wasip1  --no-default-features  --root .
 /// ```
-use std::{error, path, process};
+use std::{io, path, process};
 use tempfile::TempDir;
 
 pub struct BuildDir {
@@ -16,7 +16,7 @@ pub struct BuildDir {
 impl BuildDir {
     /// Note: we always supply `Some` from `generate` but this interface does not enforce it. Idk.
     /// May be worth exploring if you want to pipe through an environment flag.
-    pub fn new(target_dir: Option<path::PathBuf>) -> Result<Self, Box<dyn error::Error>> {
+    pub fn new(target_dir: Option<path::PathBuf>) -> io::Result<Self> {
         Ok(Self {
             dir: TempDir::new()?,
             wasm_bindgen_origin_dir: TempDir::new()?,
@@ -60,7 +60,7 @@ impl BuildDir {
                 cmd.arg("--path");
                 cmd.arg(path);
             }
-            InstallSource::CratesIo => {}
+            InstallSource::CratesIo {} => {}
         }
 
         cmd.arg(&install.package);
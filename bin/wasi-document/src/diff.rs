@@ -0,0 +1,76 @@
+//! The `diff` subcommand, the files added, removed and changed between two documents.
+use std::{io::Write, path::Path};
+
+use wasi_document_dom as dom;
+
+use crate::pack::short_hash;
+
+/// The data of the files extracted from a document, by name.
+pub fn document_files(
+    entries: &[dom::TarEntryOwned],
+) -> std::collections::BTreeMap<String, Vec<u8>> {
+    entries
+        .iter()
+        .filter_map(|entry| entry.as_html_and_tar_entry())
+        .map(|entry| (entry.name.0.to_owned(), entry.data.to_vec()))
+        .collect()
+}
+
+pub fn diff_documents(old: &Path, new: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let files = |path: &Path| -> Result<_, Box<dyn std::error::Error>> {
+        let document = std::fs::read_to_string(path)?;
+        let mut source = dom::SourceDocument::new(&document);
+        Ok(document_files(&source.split_tar_contents()?))
+    };
+
+    let (old, new) = (files(old)?, files(new)?);
+    let mut stdout = std::io::stdout().lock();
+
+    for line in file_changes(&old, &new) {
+        writeln!(stdout, "{line}")?;
+    }
+
+    Ok(())
+}
+
+/// The added, removed and changed files between two sets, with their content hashes.
+pub fn file_changes(
+    old: &std::collections::BTreeMap<String, Vec<u8>>,
+    new: &std::collections::BTreeMap<String, Vec<u8>>,
+) -> Vec<String> {
+    let names: std::collections::BTreeSet<_> = old.keys().chain(new.keys()).collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| match (old.get(name), new.get(name)) {
+            (Some(a), Some(b)) if a == b => None,
+            (Some(a), Some(b)) => Some(format!(
+                "changed {} -> {} {name}",
+                short_hash(a),
+                short_hash(b)
+            )),
+            (Some(a), None) => Some(format!("removed {} {name}", short_hash(a))),
+            (None, Some(b)) => Some(format!("added   {} {name}", short_hash(b))),
+            (None, None) => unreachable!(),
+        })
+        .collect()
+}
+
+#[test]
+fn file_changes_by_hash() {
+    let files = |list: &[(&str, &[u8])]| {
+        list.iter()
+            .map(|&(name, data)| (name.to_string(), data.to_vec()))
+            .collect()
+    };
+
+    let old = files(&[("etc/motd", b"Hello"), ("etc/issue", b"\\S"), ("gone", b"")]);
+    let new = files(&[("etc/motd", b"Hello!"), ("etc/issue", b"\\S"), ("new", b"")]);
+
+    let changes = file_changes(&old, &new);
+    assert_eq!(changes.len(), 3);
+    assert!(changes[0].starts_with("changed 185f8db32271fe25 -> "));
+    assert!(changes[0].ends_with(" etc/motd"));
+    assert_eq!(changes[1], "removed e3b0c44298fc1c14 gone");
+    assert_eq!(changes[2], "added   e3b0c44298fc1c14 new");
+}
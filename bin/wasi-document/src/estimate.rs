@@ -0,0 +1,103 @@
+//! The `estimate` subcommand, the inline and outlined cost of the files of a project.
+use std::path::Path;
+
+use crate::project::{self, Configuration};
+
+/// The cost of one file of the project, in bytes of the document.
+struct FileCost {
+    name: String,
+    inline: u64,
+    outlined: u64,
+}
+
+pub fn estimate_outlining(
+    configuration: &Configuration,
+    target: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let template = std::fs::metadata(&configuration.document.index_html)?.len();
+    let filter = configuration.document.root_filter()?;
+    let mut files = vec![];
+
+    if let Some(root) = &configuration.document.root {
+        for entry in walkdir::WalkDir::new(root)
+            .same_file_system(true)
+            .sort_by_file_name()
+        {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+
+            if !meta.is_file() {
+                continue;
+            }
+
+            let Ok(path) = entry.path().strip_prefix(root) else {
+                continue;
+            };
+
+            if path == Path::new(project::MetadataSidecar::FILE_NAME)
+                || path.to_str().is_some_and(|path| !filter.is_packed(path))
+            {
+                continue;
+            }
+
+            files.push(FileCost {
+                name: path.display().to_string(),
+                inline: html_and_tar::TarEngine::base64_entry_size(meta.len()),
+                outlined: html_and_tar::TarEngine::external_entry_size(),
+            });
+        }
+    }
+
+    if let Some(archive) = &configuration.document.root_tar {
+        let reader = std::io::BufReader::new(std::fs::File::open(archive)?);
+
+        for entry in html_and_tar::read_tar_stream(reader) {
+            let entry = entry?;
+
+            if !matches!(entry.header.typeflag, b'0' | b'\0' | b'7')
+                || !filter.is_packed(&entry.name)
+            {
+                continue;
+            }
+
+            files.push(FileCost {
+                inline: html_and_tar::TarEngine::base64_entry_size(entry.data.len() as u64),
+                outlined: html_and_tar::TarEngine::external_entry_size(),
+                name: entry.name,
+            });
+        }
+    }
+
+    // The files saving the most come first, these are the ones worth outlining.
+    files.sort_by_key(|file| std::cmp::Reverse(file.inline - file.outlined));
+
+    let mut total = template + files.iter().map(|file| file.inline).sum::<u64>();
+    eprintln!("Template: {template:>12} bytes");
+    eprintln!("Inline total: {total:>8} bytes, without the boot module and remote files");
+
+    for file in &files {
+        let outline = target.is_some_and(|target| total > target);
+
+        if outline {
+            total -= file.inline - file.outlined;
+        }
+
+        eprintln!(
+            "{:>12} inline {:>12} outlined{} {}",
+            file.inline,
+            file.outlined,
+            if outline { "  outline" } else { "" },
+            file.name,
+        );
+    }
+
+    if let Some(target) = target {
+        eprintln!("Recommended total: {total} bytes");
+
+        if total > target {
+            return Err(format!("Outlining all files does not reach {target} bytes").into());
+        }
+    }
+
+    Ok(())
+}
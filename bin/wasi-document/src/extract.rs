@@ -0,0 +1,156 @@
+//! The `extract` subcommand, the embedded files of a document written out.
+use std::{io::Write, path::Path};
+
+use wasi_document_dom as dom;
+
+pub fn extract_entries(
+    file: &Path,
+    pattern: &str,
+    out: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let data = std::fs::read(file)?;
+    let matcher = globset::GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher();
+
+    let entries = html_and_tar::TarDecompiler::list(&data)?;
+    let files = extract_matching(&data, &entries, &|name| matcher.is_match(name))?;
+
+    for (name, contents) in &files {
+        let path = Path::new(name);
+
+        if !path
+            .components()
+            .all(|component| matches!(component, std::path::Component::Normal(_)))
+        {
+            log::warn!("`{name}` is not a plain relative path, skipped");
+            continue;
+        }
+
+        let path = out.join(path);
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::write(&path, contents)?;
+        eprintln!("{:>10} {name}", contents.len());
+    }
+
+    Ok(())
+}
+
+/// Write the matching files of a document as a standard tar archive.
+///
+/// Unlike [`extract_entries`] this goes through the DOM, so it also reads documents whose tar
+/// structure a browser broke by saving them.
+pub fn extract_tar(
+    file: &Path,
+    pattern: &str,
+    tar: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let document = std::fs::read_to_string(file)?;
+    let matcher = globset::GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher();
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(tar)?);
+    dom::SourceDocument::new(&document).extract_tar(&mut out, &|name| matcher.is_match(name))?;
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Decode the files with matching names, only their entries are decoded.
+pub fn extract_matching(
+    data: &[u8],
+    entries: &[html_and_tar::ListedEntry],
+    is_match: &dyn Fn(&str) -> bool,
+) -> Result<std::collections::BTreeMap<String, Vec<u8>>, Box<dyn std::error::Error>> {
+    let mut files = std::collections::BTreeMap::<String, Vec<u8>>::new();
+
+    for entry in entries {
+        let header = &entry.header;
+
+        let Some(name) = entry.path.clone().filter(|name| is_match(name)) else {
+            continue;
+        };
+
+        let decoded =
+            match html_and_tar::TarDecompiler::file_data(header, &data[entry.data.clone()])? {
+                html_and_tar::ParsedFileData::Data(decoded) => decoded,
+                html_and_tar::ParsedFileData::External { .. } => {
+                    log::warn!("`{name}` is outlined from the document, skipped");
+                    continue;
+                }
+                html_and_tar::ParsedFileData::Device { .. } => {
+                    log::warn!("`{name}` is a device node, skipped");
+                    continue;
+                }
+                html_and_tar::ParsedFileData::Symlink { .. } => {
+                    log::warn!("`{name}` is a symbolic link, skipped");
+                    continue;
+                }
+                // Created along with the files in them.
+                html_and_tar::ParsedFileData::Directory => continue,
+                html_and_tar::ParsedFileData::Nothing => continue,
+            };
+
+        if let Some(offset) = header.parse_part_offset() {
+            let Some(file) = files
+                .get_mut(&name)
+                .filter(|file| file.len() as u64 == offset)
+            else {
+                return Err(format!("Part of `{name}` does not continue its file").into());
+            };
+
+            file.extend_from_slice(&decoded);
+        } else if let Some(offset) = header.parse_patch_offset() {
+            let Some(file) = files.get_mut(&name) else {
+                return Err(format!("Patch of `{name}` has no file to apply to").into());
+            };
+
+            html_and_tar::apply_patch(file, offset, &decoded)?;
+        } else if let Some(target) = header.parse_hard_link() {
+            // The target need not match, then we decode it on its own.
+            let linked = match files.get(target.0) {
+                Some(file) => file.clone(),
+                None => extract_matching(data, entries, &|name| name == target.0)?
+                    .remove(target.0)
+                    .ok_or_else(|| format!("Link `{name}` has no file `{}`", target.0))?,
+            };
+
+            files.insert(name, linked);
+        } else {
+            files.insert(name, decoded);
+        }
+    }
+
+    Ok(files)
+}
+
+#[test]
+fn extract_decodes_only_matching_files() {
+    let files: &[(&str, &[u8])] = &[
+        ("boot/wah-init.wasm", b"\0asm\x01\0\0\0"),
+        ("boot/config.json", b"{}"),
+        ("etc/motd", b"Hello, world!\n"),
+    ];
+
+    let packed = crate::pack::pack_fixture(files);
+    let entries = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+
+    let matcher = globset::GlobBuilder::new("boot/*")
+        .literal_separator(true)
+        .build()
+        .unwrap()
+        .compile_matcher();
+    let extracted =
+        extract_matching(packed.as_bytes(), &entries, &|name| matcher.is_match(name)).unwrap();
+
+    let names: Vec<_> = extracted.keys().map(String::as_str).collect();
+    assert_eq!(names, ["boot/config.json", "boot/wah-init.wasm"]);
+    assert_eq!(extracted["boot/config.json"], b"{}");
+}
@@ -0,0 +1,41 @@
+//! The `info` subcommand, what a built document was built for.
+use std::{io::Write, path::Path};
+
+use wasi_document_dom as dom;
+
+use crate::{
+    pack::{MODE_EDIT, MODE_LOCKED, SECTION_MODE},
+    sections::find_boot_module,
+};
+
+pub fn describe_document(file: &Path, kernel_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let document = std::fs::read_to_string(file)?;
+    let mut source = dom::SourceDocument::new(&document);
+    let entries = source.split_tar_contents()?;
+
+    let kernel = &entries[find_boot_module(&entries, kernel_name)?];
+    let wasm = kernel.as_html_and_tar_entry().unwrap().data;
+
+    let mut mode = None;
+    for payload in wasmparser::Parser::default().parse_all(wasm) {
+        if let wasmparser::Payload::CustomSection(reader) = payload?
+            && reader.name() == SECTION_MODE
+        {
+            mode = Some(reader.data());
+        }
+    }
+
+    let mode = match mode {
+        Some(MODE_EDIT) => "edit",
+        Some(MODE_LOCKED) => "locked",
+        Some(_) => "unknown",
+        None => "unknown, built before modes were recorded",
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "entries: {}", entries.len())?;
+    writeln!(stdout, "boot module: {kernel_name}, {} bytes", wasm.len())?;
+    writeln!(stdout, "mode: {mode}")?;
+
+    Ok(())
+}
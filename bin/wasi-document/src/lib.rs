@@ -0,0 +1,2 @@
+//! The document builder of `wasi-document`, for programs that pack documents themselves.
+pub mod tar;
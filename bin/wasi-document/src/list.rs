@@ -0,0 +1,15 @@
+//! The `list` subcommand, the embedded files of a document.
+use std::{io::Write, path::Path};
+
+pub fn list_entries(file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let data = std::fs::read(file)?;
+    let entries = html_and_tar::TarDecompiler::list(&data)?;
+
+    let mut stdout = std::io::stdout().lock();
+    for entry in &entries {
+        let name = entry.path.as_deref().unwrap_or("<invalid>");
+        writeln!(stdout, "{:>10} {name}", entry.size)?;
+    }
+
+    Ok(())
+}
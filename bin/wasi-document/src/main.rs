@@ -1,21 +1,104 @@
+mod amend;
 mod build;
 mod cargo;
+mod diff;
+mod estimate;
+mod extract;
+mod info;
+mod list;
+mod message;
+mod pack;
+mod patch;
 mod project;
-mod tar;
+mod repack;
+mod repair;
+mod sections;
+mod selftest;
+mod verify;
 mod webpack;
 
-use std::{io::Write as _, path::PathBuf};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
 
-use clap::Parser;
-use html_and_tar::HtmlAttributeSafeName;
-use wasi_document_dom as dom;
+use clap::{Parser, Subcommand};
 
 use project::Configuration;
 
 // FIXME: Rethink this as a project setup, i.e. like a `Cargo.toml` file where we can also describe
 // the nature of the machine so that this chooses the stage1, stage2, and other parameters for us.
 #[derive(Parser)]
+struct Cli {
+    /// Only report errors, not the warnings about the document.
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// How to report progress, `json` writes one event per line to stdout.
+    #[arg(long, global = true, value_enum, default_value = "human")]
+    message_format: message::MessageFormat,
+
+    #[command(subcommand)]
+    command: Args,
+}
+
+// The options of `build` that shape the stage builds and the packing. A doc comment here would
+// become the help text of the subcommand.
+#[derive(clap::Args, Default)]
+struct BuildOptions {
+    /// The cargo target directory of the stage builds, default to the one of the workspace.
+    #[arg(long)]
+    target_dir: Option<PathBuf>,
+
+    /// Describe the produced artifacts, such as the sections of the boot module.
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// How many stage builds, downloads and file encodings may run at once, default to the
+    /// number of CPUs.
+    #[arg(short, long)]
+    jobs: Option<std::num::NonZeroUsize>,
+
+    /// Pack the project configuration into the document, for `verify`.
+    #[arg(long)]
+    embed_config: bool,
+
+    /// Print where the headers, data, and HTML of the document are to stderr.
+    #[arg(long)]
+    emit_layout: bool,
+
+    /// Fail if a minified script refers to globals its source does not, slower.
+    #[arg(long)]
+    check_minify: bool,
+
+    /// Warn about parts of the template that a browser's Save As is known to break.
+    #[arg(long)]
+    check_browser_compat: bool,
+
+    /// Retry a failed stage build this many times, for flaky networks in CI.
+    #[arg(long, default_value_t = 0)]
+    build_retries: u32,
+
+    /// Pack character and block devices of the filesystem root, such as a `/dev`.
+    #[arg(long)]
+    devices: bool,
+
+    /// Gzip the packed files that get smaller by it, like `Document.compress`.
+    #[arg(long)]
+    compress: bool,
+
+    /// Pack boot modules that do not validate or import more than WASI, for experiments.
+    #[arg(long)]
+    allow_invalid_wasm: bool,
+
+    /// Rebuild the stages even if none of their inputs changed since the last build.
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Subcommand)]
 enum Args {
+    /// Build the stages of a project and pack them with its files into a document.
     Build {
         // Options.
         /// The path of the configuration file.
@@ -26,8 +109,31 @@ enum Args {
         #[arg(short, long)]
         out: Option<PathBuf>,
 
+        #[command(flatten)]
+        options: BuildOptions,
+    },
+    /// Compare the inline and outlined cost of the files of a project, without building.
+    Estimate {
+        /// The path of the configuration file.
+        #[arg(long)]
+        project: Option<PathBuf>,
+
+        /// The document size in bytes to reach, recommends files to outline for it.
         #[arg(long)]
-        target_dir: Option<PathBuf>,
+        target: Option<u64>,
+    },
+    /// Rebuild a document from its embedded configuration and compare the files.
+    Verify {
+        #[arg()]
+        file: PathBuf,
+
+        /// The directory against which the paths of the configuration are resolved.
+        #[arg(long, default_value = ".")]
+        root: PathBuf,
+
+        /// Only check that every file of the document still decodes, such as after a Save As.
+        #[arg(long)]
+        recoverable: bool,
     },
     /// Repack a tar structure from an HTML document that was modified as a DOM.
     Repack {
@@ -36,250 +142,292 @@ enum Args {
 
         #[arg()]
         file: PathBuf,
+
+        /// A stage0 script, inserted as-is, for documents without one of their own.
+        #[arg(long)]
+        stage0: Option<PathBuf>,
     },
-}
+    /// Check a project configuration, reporting every problem without building.
+    Check {
+        #[arg(long)]
+        project: Option<PathBuf>,
+    },
+    /// List the embedded files of a document, from its tar structure alone.
+    List {
+        #[arg()]
+        file: PathBuf,
+    },
+    /// Write the embedded files of a document whose names match a glob, from its tar structure.
+    #[command(allow_missing_positional = true)]
+    Extract {
+        /// A glob of names, where `*` does not cross a `/`.
+        #[arg(default_value = "**")]
+        pattern: String,
 
-struct Work {
-    index_html: PathBuf,
-    stage2: Vec<u8>,
-    kernel: Vec<u8>,
-    edit: bool,
-    root_fs: Vec<PathBuf>,
-    out: Option<PathBuf>,
+        #[arg()]
+        file: PathBuf,
+
+        /// The directory to write the files to.
+        #[arg(short, long, default_value = ".")]
+        out: PathBuf,
+
+        /// Write the files into a standard tar archive instead, recovered from the HTML.
+        #[arg(long, conflicts_with = "out")]
+        tar: Option<PathBuf>,
+    },
+    /// Describe a built document, such as whether it was built for editing.
+    Info {
+        #[arg()]
+        file: PathBuf,
+
+        /// The name of the boot module within the document.
+        #[arg(long, default_value = pack::BOOT_KERNEL_NAME.0)]
+        kernel_name: String,
+    },
+    /// List the sections of the boot module embedded in a document.
+    Sections {
+        #[arg()]
+        file: PathBuf,
+
+        /// The name of the boot module within the document.
+        #[arg(long, default_value = pack::BOOT_KERNEL_NAME.0)]
+        kernel_name: String,
+
+        /// Write the contents of this custom section to stdout instead.
+        #[arg(long)]
+        dump: Option<String>,
+    },
+    /// Replace the loader scripts of a built document, without its sources.
+    Patch {
+        #[arg()]
+        file: PathBuf,
+
+        /// A new stage2 script, for the custom section of the boot module.
+        #[arg(long)]
+        stage2: Option<PathBuf>,
+
+        /// A new stage0 script, inserted into the document as-is.
+        #[arg(long)]
+        stage0: Option<PathBuf>,
 
-    packers: Vec<project::ConfiguredPackRoot>,
+        /// The name of the boot module within the document.
+        #[arg(long, default_value = pack::BOOT_KERNEL_NAME.0)]
+        kernel_name: String,
+
+        /// A file to write the patched document to, default to stdout.
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Append a patch for a file changed since the document was built, instead of repacking it.
+    Amend {
+        #[arg()]
+        file: PathBuf,
 
-    /// Objects that guard a resource required for the others (i.e. tempdirs).
-    #[allow(dead_code)]
-    resources: Vec<Box<dyn std::any::Any>>,
+        /// The name of the changed file within the document.
+        #[arg()]
+        name: String,
+
+        /// The new contents of the file, they must not be shorter than the packed ones.
+        #[arg()]
+        contents: PathBuf,
+
+        /// A file to write the amended document to, default to stdout.
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Pack a built-in fixture in memory and check that it extracts byte for byte.
+    Selftest,
+    /// Compare the files of two documents, listing those added, removed and changed.
+    Diff {
+        #[arg()]
+        old: PathBuf,
+
+        #[arg()]
+        new: PathBuf,
+    },
+    /// Pack the files of a document saved by a browser again, undoing what the browser changed.
+    Repair {
+        #[arg()]
+        file: PathBuf,
+
+        /// The file to write the repaired document to.
+        #[arg()]
+        out: PathBuf,
+    },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    let project = project::Configuration::load(&args)?;
-    let build = build::BuildEnv::new(&args)?;
+    let Cli {
+        quiet,
+        message_format,
+        command: args,
+    } = Cli::parse();
+    message::init(message_format);
+    init_logger(quiet);
+
+    match &args {
+        Args::Build {
+            project: path,
+            out,
+            options,
+        } => {
+            if message::is_json() && out.is_none() {
+                return Err("JSON messages go to stdout, write the document with `--out`".into());
+            }
+
+            let project = project::Configuration::load(path.as_deref())?;
+            let build = build::BuildEnv::new(&project_dir(path.as_deref())?, options)?;
+
+            message::emit(message::Event::BuildStarted {
+                project: path
+                    .as_deref()
+                    .unwrap_or(Path::new(project::DEFAULT_PROJECT)),
+            });
 
-    match args {
-        Args::Build { .. } => {
             let project = build::generate(&project, &build)?;
-            merge_wasm(&project)
+            pack::merge_wasm(&project)
         }
-        Args::Repack { file, .. } => {
+        Args::Repack {
+            project: path,
+            file,
+            stage0,
+        } => {
+            let project = project::Configuration::load(path.as_deref())?;
+            let build = build::BuildEnv::new(&project_dir(path.as_deref())?, &Default::default())?;
             let project = build::generate(&project, &build)?;
-            rebuild_wasm(&project, file)
+            let stage0 = stage0.as_deref().map(std::fs::read).transpose()?;
+            repack::rebuild_wasm(&project, file, stage0.as_deref())
         }
-    }
-}
-
-const BOOT_KERNEL_NAME: HtmlAttributeSafeName =
-    match HtmlAttributeSafeName::new("boot/wah-init.wasm") {
-        Ok(name) => name,
-        Err(_) => panic!("Invalid attribute name, should be hardcoded and valid"),
-    };
-
-fn merge_wasm(project: &Work) -> Result<(), Box<dyn std::error::Error>> {
-    let source = std::fs::read_to_string(&project.index_html)?;
-    let bootable = finalize_kernel_wasm(&project.kernel, &project.stage2, project)?;
-    let roots: Vec<_> = project.packers.iter().map(|pck| pck.as_root()).collect();
-
-    let mut source = dom::SourceDocument::new(&source);
-    let source_script = minify_js(include_bytes!("stage0-html_plus_tar.js"));
-    let packer = crate::webpack::Packer::from_root(&roots);
-
-    let wasm = tar::build(
-        &mut source,
-        |push| {
-            // We can not externalize the 'kernel' entry since it contains the boot stage 1 file as
-            // well (in a custom section). That seems odd?
-            push(tar::TarItem::Entry(html_and_tar::Entry {
-                name: BOOT_KERNEL_NAME,
-                data: &bootable,
-                attributes: Default::default(),
-            }));
-
-            // Note: maybe we want to tag them as by their minor device number?
-            for root in &project.root_fs {
-                let iter = walkdir::WalkDir::new(root).same_file_system(true);
-
-                for entry in iter {
-                    let entry = entry?;
-
-                    let full_path = entry.path();
-                    let meta = entry.metadata()?;
-
-                    let Ok(path) = full_path.strip_prefix(&root) else {
-                        continue;
-                    };
-
-                    let Some(name) = path.to_str() else {
-                        continue;
-                    };
-
-                    let Ok(name) = HtmlAttributeSafeName::new(name) else {
-                        // FIXME: warn or transparently encode? URL-safe sounds nice.
-                        continue;
-                    };
-
-                    if !meta.is_file() {
-                        continue;
+        Args::Check { project } => {
+            let path = project
+                .as_deref()
+                .unwrap_or(Path::new(project::DEFAULT_PROJECT));
+
+            match Configuration::validate(path) {
+                Ok(()) => Ok(()),
+                Err(errors) => {
+                    for err in &errors {
+                        eprintln!("error: {err}");
                     }
 
-                    // FIXME: should be able to represent the file without reading it into memory.
-                    // We need the size for that, i.e. `html_and_tar` does not want to do the
-                    // metadata read itself to support file descriptors backed not be a filesytem
-                    // with metadata.
-                    let data = std::fs::read(&full_path)?;
-
-                    let mut entry = dom::TarEntryOwned::from_entry(html_and_tar::Entry {
-                        name,
-                        data: &data,
-                        attributes: Default::default(),
-                    });
-
-                    packer.process(&mut entry)?;
-
-                    if let Some(entry) = entry.as_html_and_tar_entry() {
-                        push(tar::TarItem::Entry(entry));
-                    } else if let Some(external) = entry.as_html_and_tar_external() {
-                        push(tar::TarItem::External(external));
-                    } else {
-                        todo!()
-                    };
+                    Err(format!("{} problems in {}", errors.len(), path.display()).into())
                 }
             }
-
-            Ok::<_, Box<dyn std::error::Error>>(())
+        }
+        Args::Estimate { project, target } => {
+            let path = project
+                .as_deref()
+                .unwrap_or(Path::new(project::DEFAULT_PROJECT));
+            let configuration = Configuration::from_toml(
+                &std::fs::read_to_string(path)?,
+                path.parent().unwrap_or(Path::new(".")),
+            )?;
+
+            estimate::estimate_outlining(&configuration, *target)
+        }
+        // Inspecting a document does not need a project.
+        Args::List { file } => list::list_entries(file),
+        Args::Extract {
+            pattern,
+            file,
+            out,
+            tar,
+        } => match tar {
+            Some(tar) => extract::extract_tar(file, pattern, tar),
+            None => extract::extract_entries(file, pattern, out),
         },
-        Some(&source_script),
-    )?;
-
-    match &project.out {
-        None => {
-            let mut stdout = std::io::stdout();
-            stdout.write_all(&wasm)?;
+        Args::Info { file, kernel_name } => info::describe_document(file, kernel_name),
+        Args::Sections {
+            file,
+            kernel_name,
+            dump,
+        } => sections::list_sections(file, kernel_name, dump.as_deref()),
+        Args::Patch {
+            file,
+            stage2,
+            stage0,
+            kernel_name,
+            out,
+        } => {
+            let document = std::fs::read_to_string(file)?;
+            let stage2 = stage2.as_ref().map(std::fs::read).transpose()?;
+            let stage0 = stage0.as_ref().map(std::fs::read).transpose()?;
+
+            let patched =
+                patch::patch_loader(&document, kernel_name, stage2.as_deref(), stage0.as_deref())?;
+
+            match out {
+                None => Ok(std::io::stdout().write_all(&patched)?),
+                Some(path) => Ok(pack::replace_file(path, &patched)?),
+            }
         }
-        Some(path) => {
-            std::fs::write(path, &wasm)?;
+        Args::Amend {
+            file,
+            name,
+            contents,
+            out,
+        } => {
+            let document = std::fs::read(file)?;
+            let contents = std::fs::read(contents)?;
+            let amended = amend::amend_document(&document, name, &contents)?;
+
+            match out {
+                None => Ok(std::io::stdout().write_all(&amended)?),
+                Some(path) => Ok(pack::replace_file(path, &amended)?),
+            }
         }
-    }
-
-    Ok(())
-}
-
-fn rebuild_wasm(project: &Work, file: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-    let source = std::fs::read_to_string(file)?;
-    let mut source = dom::SourceDocument::new(&source);
-    let mut entries = source.split_tar_contents()?;
-
-    let packer = crate::webpack::Packer::from_root(&[]);
-
-    for item in &mut entries {
-        packer.process(item)?;
-    }
-
-    let files = entries.iter().flat_map(|entry| {
-        if let Some(entry) = entry.as_html_and_tar_entry() {
-            Some(tar::TarItem::Entry(entry))
-        } else if let Some(external) = entry.as_html_and_tar_external() {
-            Some(tar::TarItem::External(external))
-        } else {
-            None
+        Args::Selftest => selftest::self_test(),
+        Args::Diff { old, new } => diff::diff_documents(old, new),
+        Args::Repair { file, out } => {
+            let document = std::fs::read_to_string(file)?;
+            let repaired = repair::repair_document(&document)?;
+            Ok(pack::replace_file(out, &repaired)?)
         }
-    });
-
-    let wasm = tar::build(
-        &mut source,
-        move |push| {
-            files.into_iter().for_each(push);
-            Ok::<_, Box<dyn std::error::Error>>(())
-        },
-        None,
-    )?;
+        Args::Verify {
+            file,
+            root,
+            recoverable,
+        } => {
+            if *recoverable {
+                return verify::verify_recoverable(file);
+            }
 
-    match &project.out {
-        None => {
-            let mut stdout = std::io::stdout();
-            stdout.write_all(&wasm)?;
-        }
-        Some(path) => {
-            std::fs::write(path, &wasm)?;
+            let build = build::BuildEnv::new(root, &Default::default())?;
+            verify::verify_document(file, root, &build)
         }
     }
-
-    Ok(())
 }
 
-/// The kernel is also the bootloader module. (Maybe not a good idea?).
-///
-/// Anyways it must contain custom sections with all the customization options from stage1's target
-/// onwards. (stage0 gets the boot module's bytes from the file list, stage1 interprets it).
-fn finalize_kernel_wasm(
-    wasm: &[u8],
-    stage2: &[u8],
-    args: &Work,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let parser = wasmparser::Parser::default();
-
-    let mut encoder = wasm_encoder::Module::new();
-
-    let custom_stage1;
-    // The actual (document) loader that prepares inputs and control for stage 2.
-    encoder.section(&wasm_encoder::CustomSection {
-        name: "wah_polyglot_stage1",
-        data: {
-            custom_stage1 = if args.edit {
-                assert!(std::env::var_os("WAH_POLYGLOT_EXPERIMENTAL").is_some());
-                minify_js(include_bytes!("stage1-edit.js"))
-            } else {
-                minify_js(include_bytes!("stage1.js"))
-            };
-
-            &custom_stage1
-        },
-    });
+/// The directory of the project file, the cargo workspace of its stages is found from there.
+fn project_dir(project: Option<&Path>) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(match project {
+        None => Path::new(".").to_owned(),
+        Some(path) => path.canonicalize()?.parent().unwrap().to_owned(),
+    })
+}
 
-    // FIXME: hm, a replacement section may be harmful. We expect that the loader up to stage2 can
-    // somehow revert the embedding, including normalizing any remote data into the document, so
-    // that we can rely on repacking the finalized document if it was modified or offered as a
-    // download standalone. If we switch to an arbitrary other document we need to ensure it is not
-    // destructive to that capability. Hence, not supported yet.
+fn init_logger(quiet: bool) {
+    let env = env_logger::Env::default().default_filter_or("warn");
+    let mut logger = env_logger::Builder::from_env(env);
 
-    /*
-        if let Some(index) = &args.index_html {
-            let index_html = std::fs::read(index)?;
+    if quiet {
+        logger.filter_level(log::LevelFilter::Error);
+    }
 
-            encoder.section(&wasm_encoder::CustomSection {
-                name: "wah_polyglot_stage1_html",
-                data: &index_html,
+    if message::is_json() {
+        logger
+            .target(env_logger::Target::Stdout)
+            .format(|buf, record| {
+                let message = record.args().to_string();
+                serde_json::to_writer(&mut *buf, &message::Event::Warning { message })?;
+                writeln!(buf)
             });
-        }
-    */
-
-    encoder.section(&wasm_encoder::CustomSection {
-        name: "wah_polyglot_stage2",
-        data: stage2,
-    });
-
-    for section in parser.parse_all(&wasm) {
-        if let Some((id, data_range)) = section?.as_section() {
-            encoder.section(&wasm_encoder::RawSection {
-                id,
-                data: &wasm[data_range],
-            });
-        }
+    } else {
+        logger.format(|buf, record| {
+            let level = record.level().as_str().to_lowercase();
+            writeln!(buf, "{level}: {}", record.args())
+        });
     }
 
-    Ok(encoder.finish())
-}
-
-fn minify_js(bytes: &[u8]) -> Vec<u8> {
-    let minified = wasi_document_minify_js::minify_js(bytes);
-
-    eprintln!(
-        "Minified size: {} bytes from {}",
-        minified.len(),
-        bytes.len()
-    );
-
-    minified
+    logger.init();
 }
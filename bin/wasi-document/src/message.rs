@@ -0,0 +1,66 @@
+//! Machine readable progress, one JSON object per line on stdout like `cargo --message-format`.
+use std::{io::Write as _, path::Path, sync::OnceLock};
+
+use serde::Serialize;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MessageFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+static FORMAT: OnceLock<MessageFormat> = OnceLock::new();
+
+#[derive(Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    BuildStarted { project: &'a Path },
+    StageBuilt { stage: &'a str, size: usize },
+    FilePacked { name: &'a str, size: u64 },
+    DocumentWritten { path: Option<&'a Path>, size: usize },
+    Warning { message: String },
+}
+
+/// Choose the format for the rest of the process, before any event.
+pub fn init(format: MessageFormat) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn is_json() -> bool {
+    FORMAT.get() == Some(&MessageFormat::Json)
+}
+
+/// Write an event, nothing in the human format which has its own messages.
+pub fn emit(event: Event) {
+    if !is_json() {
+        return;
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    // A reader that went away does not fail the build.
+    let _ = serde_json::to_writer(&mut stdout, &event);
+    let _ = writeln!(stdout);
+}
+
+/// Emits a [`Event::FilePacked`] for every file the builder packs.
+pub struct PackEvents;
+
+impl wasi_document::tar::PackProgress for PackEvents {
+    fn on_file(&mut self, name: &str, size: u64) {
+        emit(Event::FilePacked { name, size });
+    }
+}
+
+#[test]
+fn events_are_tagged_like_cargo() {
+    let event = Event::FilePacked {
+        name: "etc/motd",
+        size: 14,
+    };
+
+    assert_eq!(
+        serde_json::to_string(&event).unwrap(),
+        r#"{"reason":"file-packed","name":"etc/motd","size":14}"#
+    );
+}
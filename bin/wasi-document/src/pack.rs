@@ -0,0 +1,1899 @@
+//! Packing the built stages and the files of a project into a document, for `build`.
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use html_and_tar::HtmlAttributeSafeName;
+use wasi_document::tar;
+use wasi_document_dom as dom;
+
+use crate::{build, message, project, webpack};
+
+pub struct Work {
+    pub index_html: PathBuf,
+    pub stage2: Vec<u8>,
+    pub kernel: Vec<u8>,
+    /// More boot modules by their slot, see `Machine.modules`.
+    pub modules: Vec<(String, Vec<u8>)>,
+    pub runtime_config: Option<Vec<u8>>,
+    /// The project configuration, packed for verification.
+    pub embedded_config: Option<Vec<u8>>,
+    /// Packed names of the boot module and the runtime configuration.
+    pub kernel_name: String,
+    pub config_name: String,
+    pub edit: bool,
+    pub verbose: bool,
+    pub emit_layout: bool,
+    pub check_minify: bool,
+    pub check_browser_compat: bool,
+    /// Skip [`validate_boot_module`] for the kernel and the other boot modules.
+    pub allow_invalid_wasm: bool,
+    pub doctype: Option<String>,
+    pub line_width: Option<std::num::NonZeroUsize>,
+    /// Compress the packed files, see `Document.compress`.
+    pub compression: Option<html_and_tar::Compression>,
+    /// The ECMAScript version of the loader scripts, see `Document.js-target`.
+    pub js_target: wasi_document_minify_js::ESTarget,
+    /// Leave the archive without an EOF, for appending to it later.
+    pub open_ended: bool,
+    /// Put into the head of the document, see [`dom::SourceDocument::insert_csp`].
+    pub csp: Option<String>,
+    /// References in the template to inline as `data:` URIs, see `Document.inline-assets`.
+    pub inline_assets: Vec<String>,
+    /// Pack device nodes found in the roots, instead of skipping them.
+    pub devices: bool,
+    /// How many files of the roots are read and encoded at once.
+    pub jobs: std::num::NonZeroUsize,
+    /// Extra names of packed files, to their target.
+    pub aliases: std::collections::BTreeMap<String, String>,
+    pub root_fs: Vec<PathBuf>,
+    /// An archive packed like a filesystem root, see `Document.filesystem-root-tar`.
+    pub root_tar: Option<PathBuf>,
+    /// Files fetched for the `Remote` entries of the document.
+    pub remote: Vec<RemoteFile>,
+    /// Packed paths which are forced to be executable.
+    pub executable: globset::GlobSet,
+    /// Paths of the filesystem roots to pack, see `Document.include`.
+    pub root_filter: project::RootFilter,
+    /// Globs of root files packed at later insertion points, see `Document.Insertion`.
+    pub insertions: Vec<(String, globset::GlobSet)>,
+    /// The modification time of packed files instead of theirs on disk, see `Document.fixed-mtime`.
+    pub fixed_mtime: Option<std::time::SystemTime>,
+    pub out: Option<PathBuf>,
+
+    pub packers: Vec<project::ConfiguredPackRoot>,
+
+    /// Objects that guard a resource required for the others (i.e. tempdirs).
+    #[allow(dead_code)]
+    pub resources: Vec<Box<dyn std::any::Any + Send + Sync>>,
+}
+
+pub struct RemoteFile {
+    pub name: String,
+    pub url: String,
+    pub data: Vec<u8>,
+}
+
+pub const BOOT_KERNEL_NAME: HtmlAttributeSafeName =
+    match HtmlAttributeSafeName::new("boot/wah-init.wasm") {
+        Ok(name) => name,
+        Err(_) => panic!("Invalid attribute name, should be hardcoded and valid"),
+    };
+
+pub const BOOT_CONFIG_NAME: HtmlAttributeSafeName =
+    match HtmlAttributeSafeName::new("boot/config.json") {
+        Ok(name) => name,
+        Err(_) => panic!("Invalid attribute name, should be hardcoded and valid"),
+    };
+
+/// The boot modules of the slots and the one booted without a choice, as JSON.
+fn modules_manifest(kernel_name: HtmlAttributeSafeName, modules: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let slots: serde_json::Map<_, _> = modules
+        .iter()
+        .map(|(slot, _)| (slot.clone(), project::Machine::module_name(slot).into()))
+        .collect();
+
+    let manifest = serde_json::json!({
+        "default": kernel_name.0,
+        "modules": slots,
+    });
+
+    serde_json::to_vec(&manifest).expect("JSON values serialize")
+}
+
+/// The stage0 script, looking for the boot module under the given name.
+pub fn stage0_script(kernel_name: HtmlAttributeSafeName) -> String {
+    const SOURCE: &str = include_str!("stage0-html_plus_tar.js");
+    let default = format!("const BOOT = '{}';", BOOT_KERNEL_NAME.0);
+    assert!(SOURCE.contains(&default), "stage0 declares the boot name");
+
+    // A JSON string is a valid literal, we only need to keep it from closing the script tag.
+    let literal = serde_json::to_string(kernel_name.0)
+        .expect("strings serialize")
+        .replace('<', "\\u003c");
+    SOURCE.replacen(&default, &format!("const BOOT = {literal};"), 1)
+}
+
+/// The stage2 script, looking for the runtime configuration under the given name.
+///
+/// The bundled loader names the configuration once as a string literal. Its build output may have
+/// changed the quotes, so we replace either spelling.
+fn stage2_script(stage2: &[u8], config_name: HtmlAttributeSafeName) -> Vec<u8> {
+    if config_name == BOOT_CONFIG_NAME {
+        return stage2.to_vec();
+    }
+
+    let literal = serde_json::to_string(config_name.0).expect("strings serialize");
+    let mut script = String::from_utf8_lossy(stage2).into_owned();
+    let mut found = false;
+
+    for quote in ['\'', '"'] {
+        let default = format!("{quote}{}{quote}", BOOT_CONFIG_NAME.0);
+        found |= script.contains(&default);
+        script = script.replace(&default, &literal);
+    }
+
+    if !found {
+        log::warn!(
+            "The stage2 loader does not name `{}`, it may not find the configuration under `{}`",
+            BOOT_CONFIG_NAME.0,
+            config_name.0
+        );
+        return stage2.to_vec();
+    }
+
+    script.into_bytes()
+}
+
+/// Lists the boot modules of the slots, for the loader to pick from.
+const MODULES_MANIFEST_NAME: HtmlAttributeSafeName =
+    match HtmlAttributeSafeName::new("boot/modules.json") {
+        Ok(name) => name,
+        Err(_) => panic!("Invalid attribute name, should be hardcoded and valid"),
+    };
+
+pub const EMBEDDED_CONFIG_NAME: HtmlAttributeSafeName =
+    match HtmlAttributeSafeName::new("boot/.wasi-document.toml") {
+        Ok(name) => name,
+        Err(_) => panic!("Invalid attribute name, should be hardcoded and valid"),
+    };
+
+pub fn merge_wasm(project: &Work) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(path) = &project.out else {
+        let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+        let size = pack_document_into(project, &mut stdout, None)?;
+        message::emit(message::Event::DocumentWritten { path: None, size });
+        return Ok(());
+    };
+
+    // Streamed next to the output and moved into place once complete. A failed build keeps the
+    // previous document, and the name may depend on the hash of all of the data.
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = partial_path(path);
+
+    let mut writer = HashingWriter {
+        inner: std::io::BufWriter::new(std::fs::File::create(&partial)?),
+        hasher: Default::default(),
+    };
+
+    // Flushed explicitly, dropping the buffer would ignore an error such as a full disk. Synced
+    // before the rename, or a crash could leave a truncated document under the final name.
+    let packed = pack_document_into(project, &mut writer, Some(&partial)).and_then(|size| {
+        writer.flush()?;
+        writer.inner.get_ref().sync_all()?;
+        Ok(size)
+    });
+
+    let size = match packed {
+        Ok(size) => size,
+        Err(err) => {
+            let _ = std::fs::remove_file(&partial);
+            return Err(err);
+        }
+    };
+
+    let path = path.with_file_name(name.replace("{hash}", &short_digest(writer.hasher)));
+    std::fs::rename(&partial, &path)?;
+
+    message::emit(message::Event::DocumentWritten {
+        path: Some(&path),
+        size,
+    });
+
+    Ok(())
+}
+
+/// The sibling a file is written to before it replaces the one at `path`.
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.partial"))
+}
+
+/// Replace a file by renaming a complete one over it, an interrupted write keeps the previous file.
+///
+/// The data is synced before the rename, so a crash can not leave the new name on a truncated file.
+pub fn replace_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let partial = partial_path(path);
+    let written = std::fs::File::create(&partial)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&partial, path));
+
+    if written.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+
+    written
+}
+
+/// Hashes all data on its way to the inner writer.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: sha2::Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        use sha2::Digest as _;
+
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encode an asset as a `data:` URI, with the MIME type its extension suggests.
+fn data_uri(path: &Path, data: &[u8]) -> String {
+    use base64::Engine as _;
+
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    let mime = match extension.to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        _ => "application/octet-stream",
+    };
+
+    let encoded = base64::engine::general_purpose::STANDARD.encode(data);
+    format!("data:{mime};base64,{encoded}")
+}
+
+/// Write the document to its output, with the `{hash}` of its name substituted, or to stdout.
+pub fn write_document(
+    project: &Work,
+    document: &[u8],
+) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    let Some(path) = &project.out else {
+        std::io::stdout().write_all(document)?;
+        return Ok(None);
+    };
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let path = path.with_file_name(name.replace("{hash}", &short_hash(document)));
+    replace_file(&path, document)?;
+
+    Ok(Some(path))
+}
+
+/// The start of the SHA-256 of some data, enough to tell versions of a file apart.
+pub fn short_hash(data: &[u8]) -> String {
+    use sha2::Digest as _;
+
+    short_digest(sha2::Sha256::new_with_prefix(data))
+}
+
+fn short_digest(hasher: sha2::Sha256) -> String {
+    use sha2::Digest as _;
+
+    let digest = format!("{:x}", hasher.finalize());
+    digest[..16].to_owned()
+}
+
+pub fn pack_document(project: &Work) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut document = vec![];
+    pack_document_into(project, &mut document, None)?;
+    Ok(document)
+}
+
+/// Build the document into `out` as it is packed, returning its size.
+///
+/// A file being `written` to is skipped when found in a root, like the output itself.
+fn pack_document_into(
+    project: &Work,
+    out: &mut dyn Write,
+    written: Option<&Path>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let source = std::fs::read_to_string(&project.index_html)?;
+    let bootable = finalize_kernel_wasm(&project.kernel, &project.stage2, project)?;
+    let modules = project
+        .modules
+        .iter()
+        .map(|(slot, kernel)| {
+            let bootable = finalize_kernel_wasm(kernel, &project.stage2, project)?;
+            Ok((project::Machine::module_name(slot), bootable))
+        })
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+    let roots: Vec<_> = project.packers.iter().map(|pck| pck.as_root()).collect();
+
+    let options = tar::Options {
+        doctype: project.doctype.as_deref(),
+        line_width: project.line_width,
+        open_ended: project.open_ended,
+    };
+
+    if project.check_browser_compat {
+        for lint in tar::lint_browser_compat(&source, &options)? {
+            log::warn!("{lint}");
+        }
+    }
+
+    let mut source = dom::SourceDocument::new(&source);
+    if let Some(csp) = &project.csp {
+        source.insert_csp(csp)?;
+    }
+
+    if !project.inline_assets.is_empty() {
+        let template_dir = project.index_html.parent().unwrap_or(Path::new("."));
+        let uris = project
+            .inline_assets
+            .iter()
+            .map(|asset| {
+                let data = std::fs::read(template_dir.join(asset))?;
+                Ok((asset.clone(), data_uri(Path::new(asset), &data)))
+            })
+            .collect::<Result<_, std::io::Error>>()?;
+
+        let found = source.inline_references(&uris)?;
+        for asset in uris.keys().filter(|asset| !found.contains(*asset)) {
+            log::warn!("no `src` or `href` of the template refers to the inline asset `{asset}`");
+        }
+    }
+
+    let kernel_name = HtmlAttributeSafeName::new(&project.kernel_name)?;
+    let config_name = HtmlAttributeSafeName::new(&project.config_name)?;
+    let source_script = minify_js(
+        stage0_script(kernel_name).as_bytes(),
+        project.js_target,
+        project.check_minify,
+    )?;
+    let packer = webpack::Packer::from_root(&roots);
+
+    let outputs: Vec<_> = project
+        .out
+        .as_deref()
+        .into_iter()
+        .chain(written)
+        .filter_map(|out| out.canonicalize().ok())
+        .collect();
+    let is_output = |path: &Path| {
+        outputs.iter().any(|out| {
+            path.file_name() == out.file_name() && path.canonicalize().ok().as_ref() == Some(out)
+        })
+    };
+
+    let layout = tar::build_to_writer(
+        &mut source,
+        |push| {
+            let mut packed = PackedNames::default();
+
+            // We can not externalize the 'kernel' entry since it contains the boot stage 1 file as
+            // well (in a custom section). That seems odd?
+            packed.claim(kernel_name.0, || "the boot kernel".into())?;
+            push(tar::TarItem::Entry(html_and_tar::Entry {
+                name: kernel_name,
+                data: &bootable,
+                attributes: Default::default(),
+                compression: project.compression,
+            }));
+
+            let manifest;
+            if !modules.is_empty() {
+                for (name, bootable) in &modules {
+                    let name = HtmlAttributeSafeName::new(name)?;
+                    packed.claim(name.0, || "a boot module".into())?;
+                    push(tar::TarItem::Entry(html_and_tar::Entry {
+                        name,
+                        data: bootable,
+                        attributes: Default::default(),
+                        compression: project.compression,
+                    }));
+                }
+
+                manifest = modules_manifest(kernel_name, &project.modules);
+                packed.claim(MODULES_MANIFEST_NAME.0, || "the module manifest".into())?;
+                push(tar::TarItem::Entry(html_and_tar::Entry {
+                    name: MODULES_MANIFEST_NAME,
+                    data: &manifest,
+                    attributes: Default::default(),
+                    compression: project.compression,
+                }));
+            }
+
+            if let Some(config) = &project.runtime_config {
+                packed.claim(config_name.0, || "the runtime configuration".into())?;
+                push(tar::TarItem::Entry(html_and_tar::Entry {
+                    name: config_name,
+                    data: config,
+                    attributes: Default::default(),
+                    compression: project.compression,
+                }));
+            }
+
+            if let Some(config) = &project.embedded_config {
+                packed.claim(EMBEDDED_CONFIG_NAME.0, || {
+                    "the project configuration".into()
+                })?;
+                push(tar::TarItem::Entry(html_and_tar::Entry {
+                    name: EMBEDDED_CONFIG_NAME,
+                    data: config,
+                    attributes: Default::default(),
+                    compression: project.compression,
+                }));
+            }
+
+            for remote in &project.remote {
+                let name = HtmlAttributeSafeName::new(&remote.name)?;
+                packed.claim(name.0, || remote.url.clone())?;
+                push(tar::TarItem::Entry(html_and_tar::Entry {
+                    name,
+                    data: &remote.data,
+                    attributes: Default::default(),
+                    compression: project.compression,
+                }));
+            }
+
+            // Files for later insertion points are packed after all others, by their point.
+            let sidecars = project
+                .root_fs
+                .iter()
+                .map(|root| project::MetadataSidecar::load(root))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut deferred: Vec<Vec<Deferred>> =
+                project.insertions.iter().map(|_| vec![]).collect();
+            let insertion_of = |name: &str| {
+                project
+                    .insertions
+                    .iter()
+                    .position(|(_, files)| files.is_match(name))
+            };
+
+            // Entries wait here for the files before them to be encoded, a few at a time.
+            let mut queue = vec![];
+            let mut queued_files = 0;
+
+            // Note: maybe we want to tag them as by their minor device number?
+            for (root, sidecar) in project.root_fs.iter().zip(&sidecars) {
+                // Sorting siblings by name yields entries ordered by their relative path, as
+                // `Path` compares component-wise. The filesystem order is not stable across
+                // platforms and we want byte-stable documents from the same inputs.
+                let iter = walkdir::WalkDir::new(root)
+                    .same_file_system(true)
+                    .sort_by_file_name()
+                    .into_iter()
+                    // Not even walking excluded directories, a `node_modules` can be large.
+                    .filter_entry(|entry| {
+                        let path = entry.path().strip_prefix(root).ok().and_then(Path::to_str);
+                        path.is_none_or(|path| !project.root_filter.is_excluded(path))
+                    });
+
+                for entry in iter {
+                    let entry = entry?;
+
+                    let full_path = entry.path();
+                    let meta = entry.metadata()?;
+
+                    let Ok(path) = full_path.strip_prefix(root) else {
+                        continue;
+                    };
+
+                    let Some(name) = path.to_str() else {
+                        continue;
+                    };
+
+                    if path == Path::new(project::MetadataSidecar::FILE_NAME)
+                        || !project.root_filter.is_packed(name)
+                    {
+                        continue;
+                    }
+
+                    let Ok(name) = HtmlAttributeSafeName::new(name) else {
+                        // FIXME: warn or transparently encode? URL-safe sounds nice.
+                        continue;
+                    };
+
+                    // Links are packed as they are and never followed, so one pointing to a
+                    // directory above it can not make the walk loop.
+                    if entry.path_is_symlink() && !name.0.is_empty() {
+                        let target = std::fs::read_link(full_path)?;
+                        let Some(target) = target.to_str().and_then(symlink_target) else {
+                            log::warn!(
+                                "{} links to a path that can not be packed, skipped",
+                                full_path.display()
+                            );
+                            continue;
+                        };
+
+                        let mut attributes = html_and_tar::EntryAttributes {
+                            mode: file_mode(&meta),
+                            mtime: file_mtime(&meta, project.fixed_mtime),
+                            ..Default::default()
+                        };
+
+                        sidecar.apply(name.0, &mut attributes);
+                        packed.claim(name.0, || full_path.display().to_string())?;
+                        queue.push(Walked::Symlink {
+                            name: name.0.to_owned(),
+                            target: target.0.to_owned(),
+                            attributes,
+                        });
+
+                        continue;
+                    }
+
+                    if project.devices
+                        && let Some(device) = device_node(&meta)
+                    {
+                        let (kind, major, minor) = device?;
+                        let mut attributes = html_and_tar::EntryAttributes {
+                            mode: file_mode(&meta),
+                            mtime: file_mtime(&meta, project.fixed_mtime),
+                            devmajor: major,
+                            devminor: minor,
+                            ..Default::default()
+                        };
+
+                        sidecar.apply(name.0, &mut attributes);
+                        packed.claim(name.0, || full_path.display().to_string())?;
+                        queue.push(Walked::Device {
+                            name: name.0.to_owned(),
+                            kind,
+                            attributes,
+                        });
+
+                        continue;
+                    }
+
+                    // Empty directories must exist as well, such as a `tmp` for the guest.
+                    if meta.is_dir() {
+                        if name.0.is_empty() {
+                            continue;
+                        }
+
+                        let mut attributes = html_and_tar::EntryAttributes {
+                            mode: file_mode(&meta),
+                            mtime: file_mtime(&meta, project.fixed_mtime),
+                            ..Default::default()
+                        };
+
+                        sidecar.apply(name.0, &mut attributes);
+                        packed.claim(name.0, || full_path.display().to_string())?;
+                        queue.push(Walked::Directory {
+                            name: name.0.to_owned(),
+                            attributes,
+                        });
+
+                        continue;
+                    }
+
+                    if !meta.is_file() {
+                        continue;
+                    }
+
+                    // A root containing the output would otherwise pack the previous build.
+                    if is_output(full_path) {
+                        continue;
+                    }
+
+                    packed.claim(name.0, || full_path.display().to_string())?;
+
+                    if let Some(idx) = insertion_of(name.0) {
+                        deferred[idx].push(Deferred::File {
+                            sidecar,
+                            name: name.0.to_owned(),
+                            path: full_path.to_owned(),
+                            meta: Box::new(meta),
+                        });
+                        continue;
+                    }
+
+                    queue.push(Walked::File {
+                        sidecar,
+                        name: name.0.to_owned(),
+                        path: full_path.to_owned(),
+                        meta: Box::new(meta),
+                    });
+
+                    queued_files += 1;
+                    if queued_files == project.jobs.get() {
+                        push_walked(push, &packer, project, &queue)?;
+                        (queue, queued_files) = (vec![], 0);
+                    }
+                }
+            }
+
+            push_walked(push, &packer, project, &queue)?;
+
+            if let Some(archive) = &project.root_tar {
+                let reader = std::io::BufReader::new(std::fs::File::open(archive)?);
+
+                for entry in html_and_tar::read_tar_stream(reader) {
+                    let entry =
+                        entry.map_err(|err| format!("reading {}: {err}", archive.display()))?;
+                    let source = || format!("`{}` in {}", entry.name, archive.display());
+
+                    if !project.root_filter.is_packed(&entry.name) {
+                        continue;
+                    }
+
+                    let Ok(name) = HtmlAttributeSafeName::new(&entry.name) else {
+                        log::warn!("{} can not be packed, skipped", source());
+                        continue;
+                    };
+
+                    if matches!(entry.header.typeflag, b'0' | b'\0' | b'7')
+                        && let Some(idx) = insertion_of(name.0)
+                    {
+                        packed.claim(name.0, source)?;
+                        deferred[idx].push(Deferred::Tar(Box::new(entry)));
+                        continue;
+                    }
+
+                    let mut attributes = entry.attributes();
+                    if let Some(mtime) = project.fixed_mtime {
+                        attributes.mtime = Some(mtime);
+                    }
+
+                    match entry.header.typeflag {
+                        b'0' | b'\0' | b'7' => {
+                            packed.claim(name.0, source)?;
+                            push_tar_root_file(push, &packer, project, &entry)?;
+                        }
+                        b'1' => {
+                            let target = entry
+                                .link_target()
+                                .and_then(|target| HtmlAttributeSafeName::new(target).ok())
+                                .filter(|target| packed.sources.contains_key(target.0))
+                                .ok_or_else(|| {
+                                    format!("{} links to a file that is not packed", source())
+                                })?;
+
+                            if deferred
+                                .iter()
+                                .flatten()
+                                .any(|file| file.name() == target.0)
+                            {
+                                return Err(format!(
+                                    "{} links to a file packed at a later insertion point",
+                                    source()
+                                )
+                                .into());
+                            }
+
+                            packed.claim(name.0, source)?;
+                            push(tar::TarItem::Link(html_and_tar::Link {
+                                name,
+                                target,
+                                attributes,
+                            }));
+                        }
+                        b'2' => {
+                            let Some(target) = entry.symlink_target().and_then(symlink_target)
+                            else {
+                                log::warn!(
+                                    "{} links to a path that can not be packed, skipped",
+                                    source()
+                                );
+                                continue;
+                            };
+
+                            packed.claim(name.0, source)?;
+                            push(tar::TarItem::Symlink(html_and_tar::Symlink {
+                                name,
+                                target,
+                                attributes,
+                            }));
+                        }
+                        b'5' => {
+                            packed.claim(name.0, source)?;
+                            push(tar::TarItem::Directory(html_and_tar::Directory {
+                                name,
+                                attributes,
+                            }));
+                        }
+                        typeflag => match html_and_tar::DeviceKind::from_typeflag(typeflag) {
+                            Some(kind) if project.devices => {
+                                packed.claim(name.0, source)?;
+                                push(tar::TarItem::Device(html_and_tar::Device {
+                                    name,
+                                    kind,
+                                    attributes,
+                                }));
+                            }
+                            Some(_) => {}
+                            // Sparse files and other kinds without a counterpart. PAX and GNU long
+                            // name records were already applied by `read_tar_stream`.
+                            None => {}
+                        },
+                    }
+                }
+            }
+
+            for ((point, _), files) in project.insertions.iter().zip(&deferred) {
+                if files.is_empty() {
+                    continue;
+                }
+
+                push(tar::TarItem::Insertion(point));
+
+                for file in files {
+                    match file {
+                        Deferred::File {
+                            sidecar,
+                            name,
+                            path,
+                            meta,
+                        } => {
+                            let name = HtmlAttributeSafeName::new(name)?;
+                            let file =
+                                prepare_root_file(&packer, project, sidecar, name, path, meta)?;
+                            push_prepared(push, file);
+                        }
+                        Deferred::Tar(entry) => {
+                            push_tar_root_file(push, &packer, project, entry)?;
+                        }
+                    }
+                }
+            }
+
+            // After all files, since a link can only refer to data before it.
+            for (alias, target) in &project.aliases {
+                let name = HtmlAttributeSafeName::new(alias)?;
+                let target = HtmlAttributeSafeName::new(target)?;
+
+                if target.0.len() > html_and_tar::TarEngine::MAX_LINK_LEN {
+                    return Err(format!("Alias `{alias}` has a target that is too long").into());
+                }
+
+                if !packed.sources.contains_key(target.0) {
+                    return Err(
+                        format!("Alias `{alias}` of `{}` which is not packed", target.0).into(),
+                    );
+                }
+
+                packed.claim(name.0, || format!("an alias of `{}`", target.0))?;
+                push(tar::TarItem::Link(html_and_tar::Link {
+                    name,
+                    target,
+                    attributes: Default::default(),
+                }));
+            }
+
+            Ok::<_, Box<dyn std::error::Error>>(())
+        },
+        Some(&source_script),
+        &options,
+        &mut message::PackEvents,
+        out,
+    )?;
+
+    if project.emit_layout {
+        eprint!("{layout}");
+    }
+
+    Ok(layout.regions.last().map_or(0, |(_, range)| range.end))
+}
+
+/// The kind and numbers of a device node, if the file is one.
+#[cfg(unix)]
+fn device_node(
+    meta: &std::fs::Metadata,
+) -> Option<Result<(html_and_tar::DeviceKind, u16, u16), String>> {
+    use std::os::unix::fs::{FileTypeExt as _, MetadataExt as _};
+
+    let kind = if meta.file_type().is_char_device() {
+        html_and_tar::DeviceKind::Character
+    } else if meta.file_type().is_block_device() {
+        html_and_tar::DeviceKind::Block
+    } else {
+        return None;
+    };
+
+    // The encoding of glibc, which is also what Linux reports.
+    let rdev = meta.rdev();
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+
+    let numbers = u16::try_from(major)
+        .ok()
+        .zip(u16::try_from(minor).ok())
+        .map(|(major, minor)| (kind, major, minor))
+        .ok_or_else(|| format!("Device numbers {major}:{minor} do not fit the header"));
+
+    Some(numbers)
+}
+
+#[cfg(not(unix))]
+fn device_node(
+    _: &std::fs::Metadata,
+) -> Option<Result<(html_and_tar::DeviceKind, u16, u16), String>> {
+    None
+}
+
+/// The permission bits of a file, to keep them in the document.
+#[cfg(unix)]
+fn file_mode(meta: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// The modification time to pack, the `fixed` one for reproducible builds or else the one on disk.
+///
+/// One on disk too late for the header is left out, like one the filesystem can not tell.
+fn file_mtime(
+    meta: &std::fs::Metadata,
+    fixed: Option<std::time::SystemTime>,
+) -> Option<std::time::SystemTime> {
+    fixed.or_else(|| {
+        let mtime = meta.modified().ok()?;
+        let attributes = html_and_tar::EntryAttributes {
+            mtime: Some(mtime),
+            ..Default::default()
+        };
+
+        attributes.check().is_ok().then_some(mtime)
+    })
+}
+
+/// The target of a symbolic link as packed, if it is safe in HTML and fits the header.
+fn symlink_target(target: &str) -> Option<HtmlAttributeSafeName<'_>> {
+    HtmlAttributeSafeName::new(target)
+        .ok()
+        .filter(|target| target.0.len() <= html_and_tar::TarEngine::MAX_LINK_LEN)
+}
+
+/// The sources of all names packed so far. Extraction of duplicate names depends on the order of
+/// entries, so we refuse to produce such an archive.
+#[derive(Default)]
+struct PackedNames {
+    sources: std::collections::BTreeMap<String, String>,
+    /// The first name claimed for every lowercase spelling.
+    folded: std::collections::BTreeMap<String, String>,
+}
+
+impl PackedNames {
+    fn claim(
+        &mut self,
+        name: &str,
+        source: impl FnOnce() -> String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use std::collections::btree_map::Entry;
+
+        match self.sources.entry(name.to_owned()) {
+            Entry::Vacant(vacant) => {
+                vacant.insert(source());
+
+                if let Some(other) = self.case_collision(name) {
+                    log::warn!(
+                        "`{other}` and `{name}` differ only in case, they may collide after a \
+                        browser round-trip or on a case-insensitive filesystem. Consider renaming \
+                        one of them."
+                    );
+                }
+
+                Ok(())
+            }
+            Entry::Occupied(occupied) => Err(format!(
+                "Duplicate entry `{name}`, from {} and from {}",
+                occupied.get(),
+                source()
+            )
+            .into()),
+        }
+    }
+
+    /// An earlier name which is the same as this one, ignoring case.
+    fn case_collision(&mut self, name: &str) -> Option<&str> {
+        use std::collections::btree_map::Entry;
+
+        match self.folded.entry(name.to_lowercase()) {
+            Entry::Vacant(vacant) => {
+                vacant.insert(name.to_owned());
+                None
+            }
+            Entry::Occupied(occupied) => Some(occupied.into_mut()),
+        }
+    }
+}
+
+/// A regular file of a filesystem root for a later insertion point, packed after all others.
+enum Deferred<'s> {
+    File {
+        sidecar: &'s project::MetadataSidecar,
+        name: String,
+        path: PathBuf,
+        meta: Box<std::fs::Metadata>,
+    },
+    Tar(Box<html_and_tar::StandardEntry>),
+}
+
+impl Deferred<'_> {
+    fn name(&self) -> &str {
+        match self {
+            Deferred::File { name, .. } => name,
+            Deferred::Tar(entry) => &entry.name,
+        }
+    }
+}
+
+/// An entry of a directory root in walk order, queued so that its files are encoded in parallel.
+enum Walked<'s> {
+    File {
+        sidecar: &'s project::MetadataSidecar,
+        name: String,
+        path: PathBuf,
+        meta: Box<std::fs::Metadata>,
+    },
+    Directory {
+        name: String,
+        attributes: html_and_tar::EntryAttributes<'s>,
+    },
+    Symlink {
+        name: String,
+        target: String,
+        attributes: html_and_tar::EntryAttributes<'s>,
+    },
+    Device {
+        name: String,
+        kind: html_and_tar::DeviceKind,
+        attributes: html_and_tar::EntryAttributes<'s>,
+    },
+}
+
+/// Push queued entries in order, after reading and encoding their files on `project.jobs` threads.
+///
+/// The encoding does not depend on the entries before it, only placing the data does. So the
+/// document is the same as if each file was encoded when pushed.
+fn push_walked(
+    push: &mut dyn FnMut(tar::TarItem<'_>),
+    packer: &webpack::Packer,
+    project: &Work,
+    queue: &[Walked<'_>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let prepared = build::run_bounded(project.jobs, queue, |walked| {
+        let Walked::File {
+            sidecar,
+            name,
+            path,
+            meta,
+        } = walked
+        else {
+            return Ok(None);
+        };
+
+        let name = HtmlAttributeSafeName::new(name).map_err(|err| err.to_string())?;
+        prepare_root_file(packer, project, sidecar, name, path, meta)
+            .map(Some)
+            .map_err(|err| format!("packing {}: {err}", path.display()))
+    });
+
+    for (walked, prepared) in queue.iter().zip(prepared) {
+        match walked {
+            Walked::File { .. } => {
+                push_prepared(push, prepared?.expect("every file was prepared"));
+            }
+            Walked::Directory { name, attributes } => {
+                push(tar::TarItem::Directory(html_and_tar::Directory {
+                    name: HtmlAttributeSafeName::new(name)?,
+                    attributes: *attributes,
+                }));
+            }
+            Walked::Symlink {
+                name,
+                target,
+                attributes,
+            } => {
+                push(tar::TarItem::Symlink(html_and_tar::Symlink {
+                    name: HtmlAttributeSafeName::new(name)?,
+                    target: HtmlAttributeSafeName::new(target)?,
+                    attributes: *attributes,
+                }));
+            }
+            Walked::Device {
+                name,
+                kind,
+                attributes,
+            } => {
+                push(tar::TarItem::Device(html_and_tar::Device {
+                    name: HtmlAttributeSafeName::new(name)?,
+                    kind: *kind,
+                    attributes: *attributes,
+                }));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A regular file ready to be pushed, see [`prepare_root_file`].
+enum Prepared<'a> {
+    Encoded(html_and_tar::EncodedEntry<'a>),
+    /// Outlined by a packer, or large enough to be split into parts which are encoded in turn.
+    Owned(Box<dom::TarEntryOwned>),
+}
+
+fn push_prepared(push: &mut dyn FnMut(tar::TarItem<'_>), prepared: Prepared<'_>) {
+    match prepared {
+        Prepared::Encoded(entry) => push(tar::TarItem::Encoded(entry)),
+        Prepared::Owned(entry) => push_owned(push, &entry),
+    }
+}
+
+/// Read and encode a regular file of a directory root, with the attributes it has on disk.
+fn prepare_root_file<'a>(
+    packer: &webpack::Packer,
+    project: &Work,
+    sidecar: &project::MetadataSidecar,
+    name: HtmlAttributeSafeName<'a>,
+    path: &Path,
+    meta: &std::fs::Metadata,
+) -> Result<Prepared<'a>, Box<dyn std::error::Error>> {
+    // FIXME: should be able to represent the file without reading it into memory.
+    // We need the size for that, i.e. `html_and_tar` does not want to do the
+    // metadata read itself to support file descriptors backed not be a filesytem
+    // with metadata.
+    let data = std::fs::read(path)?;
+
+    let mut attributes = html_and_tar::EntryAttributes {
+        mode: file_mode(meta),
+        mtime: file_mtime(meta, project.fixed_mtime),
+        ..Default::default()
+    };
+
+    // The sidecar names single files, so it gets the final say.
+    if project.executable.is_match(name.0) {
+        attributes.mode = Some(0o755);
+    }
+
+    sidecar.apply(name.0, &mut attributes);
+
+    // The packer looks at the data we read, without another copy of it for each file.
+    let mut owned = dom::TarEntryOwned::from_parts(name, &attributes, project.compression, data);
+    packer.process(&mut owned)?;
+
+    let Some(entry) = owned
+        .as_html_and_tar_entry()
+        .filter(|entry| entry.data.len() <= tar::MAX_PART_SIZE)
+    else {
+        return Ok(Prepared::Owned(Box::new(owned)));
+    };
+
+    Ok(Prepared::Encoded(html_and_tar::EncodedEntry::new(
+        name,
+        attributes,
+        entry.data,
+        project.compression,
+        project.line_width,
+    )))
+}
+
+/// Push a regular file of the root archive, with the attributes of its header.
+fn push_tar_root_file(
+    push: &mut dyn FnMut(tar::TarItem<'_>),
+    packer: &webpack::Packer,
+    project: &Work,
+    entry: &html_and_tar::StandardEntry,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let name = HtmlAttributeSafeName::new(&entry.name)?;
+
+    let mut attributes = entry.attributes();
+    if let Some(mtime) = project.fixed_mtime {
+        attributes.mtime = Some(mtime);
+    }
+
+    if project.executable.is_match(name.0) {
+        attributes.mode = Some(0o755);
+    }
+
+    push_file(
+        push,
+        packer,
+        html_and_tar::Entry {
+            name,
+            data: &entry.data,
+            attributes,
+            compression: project.compression,
+        },
+    )
+}
+
+/// Push a regular file, or its reference if a packer outlines it.
+fn push_file(
+    push: &mut dyn FnMut(tar::TarItem<'_>),
+    packer: &webpack::Packer,
+    entry: html_and_tar::Entry<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut entry = dom::TarEntryOwned::from_entry(entry);
+    packer.process(&mut entry)?;
+    push_owned(push, &entry);
+
+    Ok(())
+}
+
+/// Push a file as its data or its reference, after a packer processed it.
+fn push_owned(push: &mut dyn FnMut(tar::TarItem<'_>), entry: &dom::TarEntryOwned) {
+    if let Some(entry) = entry.as_html_and_tar_entry() {
+        push(tar::TarItem::Entry(entry));
+    } else if let Some(external) = entry.as_html_and_tar_external() {
+        push(tar::TarItem::External(external));
+    } else {
+        todo!()
+    };
+}
+
+/// The kernel is also the bootloader module. (Maybe not a good idea?).
+///
+/// Anyways it must contain custom sections with all the customization options from stage1's target
+/// onwards. (stage0 gets the boot module's bytes from the file list, stage1 interprets it).
+fn finalize_kernel_wasm(
+    wasm: &[u8],
+    stage2: &[u8],
+    args: &Work,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if !args.allow_invalid_wasm {
+        validate_boot_module(wasm)?;
+    }
+
+    let parser = wasmparser::Parser::default();
+
+    let mut encoder = wasm_encoder::Module::new();
+
+    let custom_stage1;
+    // The actual (document) loader that prepares inputs and control for stage 2.
+    encoder.section(&wasm_encoder::CustomSection {
+        name: SECTION_STAGE1,
+        data: {
+            custom_stage1 = if args.edit {
+                assert!(std::env::var_os("WAH_POLYGLOT_EXPERIMENTAL").is_some());
+                minify_js(
+                    include_bytes!("stage1-edit.js"),
+                    args.js_target,
+                    args.check_minify,
+                )?
+            } else {
+                minify_js(
+                    include_bytes!("stage1.js"),
+                    args.js_target,
+                    args.check_minify,
+                )?
+            };
+
+            &custom_stage1
+        },
+    });
+
+    // FIXME: hm, a replacement section may be harmful. We expect that the loader up to stage2 can
+    // somehow revert the embedding, including normalizing any remote data into the document, so
+    // that we can rely on repacking the finalized document if it was modified or offered as a
+    // download standalone. If we switch to an arbitrary other document we need to ensure it is not
+    // destructive to that capability. Hence, not supported yet.
+
+    /*
+        if let Some(index) = &args.index_html {
+            let index_html = std::fs::read(index)?;
+
+            encoder.section(&wasm_encoder::CustomSection {
+                name: "wah_polyglot_stage1_html",
+                data: &index_html,
+            });
+        }
+    */
+
+    let stage2 = stage2_script(stage2, HtmlAttributeSafeName::new(&args.config_name)?);
+    encoder.section(&wasm_encoder::CustomSection {
+        name: SECTION_STAGE2,
+        data: &stage2,
+    });
+
+    // Not read by the loader, this lets tools tell editable documents apart.
+    encoder.section(&wasm_encoder::CustomSection {
+        name: SECTION_MODE,
+        data: if args.edit { MODE_EDIT } else { MODE_LOCKED },
+    });
+
+    for section in parser.parse_all(wasm) {
+        if let Some((id, data_range)) = section?.as_section() {
+            encoder.section(&wasm_encoder::RawSection {
+                id,
+                data: &wasm[data_range],
+            });
+        }
+    }
+
+    let finalized = encoder.finish();
+    summarize_sections(&finalized, args.verbose)?;
+
+    Ok(finalized)
+}
+
+/// Check that a boot module can be instantiated by the loader, before we pack it.
+///
+/// A truncated module or one built for the wrong target would otherwise only fail in the browser.
+/// The loader provides nothing but the WASI preview 1 functions, so no other import resolves.
+fn validate_boot_module(wasm: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    wasmparser::Validator::new()
+        .validate_all(wasm)
+        .map_err(|err| format!("Boot module is not a valid WebAssembly module: {err}"))?;
+
+    for payload in wasmparser::Parser::default().parse_all(wasm) {
+        let wasmparser::Payload::ImportSection(reader) = payload? else {
+            continue;
+        };
+
+        for import in reader {
+            let import = import?;
+
+            if import.module != WASI_IMPORT_MODULE {
+                return Err(format!(
+                    "Boot module imports `{}` from `{}`, the loader only provides `{WASI_IMPORT_MODULE}`. \
+                    Was it built for `wasm32-wasip1`?",
+                    import.name, import.module,
+                )
+                .into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The only import module the loader provides to boot modules.
+const WASI_IMPORT_MODULE: &str = "wasi_snapshot_preview1";
+
+pub const SECTION_STAGE1: &str = "wah_polyglot_stage1";
+pub const SECTION_STAGE2: &str = "wah_polyglot_stage2";
+pub const SECTION_MODE: &str = "wah_polyglot_mode";
+
+pub const MODE_EDIT: &[u8] = b"edit";
+pub const MODE_LOCKED: &[u8] = b"locked";
+
+/// Walk the sections of a finalized boot module, optionally listing them.
+///
+/// The loader picks the first custom section of a name so we insist that each of ours exists
+/// exactly once. An input module that was already finalized would otherwise shadow the new stages.
+pub fn summarize_sections(wasm: &[u8], verbose: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ours = std::collections::BTreeMap::<&str, usize>::new();
+    let mut count = 0;
+
+    for payload in wasmparser::Parser::default().parse_all(wasm) {
+        let payload = payload?;
+
+        let Some((id, range)) = payload.as_section() else {
+            continue;
+        };
+
+        let name = match &payload {
+            wasmparser::Payload::CustomSection(reader) => reader.name(),
+            _ => "",
+        };
+
+        if verbose {
+            eprintln!("Section {id:>2}: {:>10} bytes {name}", range.len());
+        }
+
+        if name.starts_with("wah_polyglot_") {
+            *ours.entry(name).or_default() += 1;
+        }
+
+        count += 1;
+    }
+
+    if verbose {
+        eprintln!("Boot module: {count} sections, {} bytes", wasm.len());
+    }
+
+    for expected in [SECTION_STAGE1, SECTION_STAGE2] {
+        match ours.get(expected).copied().unwrap_or(0) {
+            1 => {}
+            n => {
+                return Err(
+                    format!("Boot module has {n} `{expected}` sections, expected one").into(),
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Minify one of our scripts, or keep it as is if that fails. A larger document beats none, but
+/// not one the target browsers can not run.
+fn minify_js(
+    bytes: &[u8],
+    target: wasi_document_minify_js::ESTarget,
+    check: bool,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    use wasi_document_minify_js::{MinifyError, MinifyOptions};
+
+    let options = MinifyOptions {
+        target,
+        ..MinifyOptions::default()
+    };
+
+    let minified = match wasi_document_minify_js::minify_js_with(bytes, &options) {
+        Ok(minified) => minified,
+        Err(err @ MinifyError::Unsupported { .. }) => return Err(err.into()),
+        Err(err) => {
+            log::warn!("packing a script unminified, {err}");
+            return Ok(bytes.to_vec());
+        }
+    };
+
+    log::info!(
+        "Minified size: {} bytes from {}",
+        minified.len(),
+        bytes.len()
+    );
+
+    if check {
+        wasi_document_minify_js::check_minified_js(bytes, &minified)?;
+    }
+
+    Ok(minified)
+}
+
+#[test]
+fn case_collisions_are_found() {
+    let mut packed = PackedNames::default();
+    assert_eq!(packed.case_collision("README"), None);
+    assert_eq!(packed.case_collision("etc/motd"), None);
+    assert_eq!(packed.case_collision("readme"), Some("README"));
+    assert_eq!(packed.case_collision("Etc/MOTD"), Some("etc/motd"));
+}
+
+#[test]
+fn duplicate_names_are_refused() {
+    let mut packed = PackedNames::default();
+    packed
+        .claim(BOOT_KERNEL_NAME.0, || "the boot kernel".into())
+        .unwrap();
+    packed.claim("bin/sh", || "root/bin/sh".into()).unwrap();
+
+    let err = packed
+        .claim(BOOT_KERNEL_NAME.0, || "root/boot/wah-init.wasm".into())
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("the boot kernel"), "{err}");
+    assert!(err.contains("root/boot/wah-init.wasm"), "{err}");
+}
+
+#[test]
+fn stage0_uses_configured_kernel_name() {
+    let name = HtmlAttributeSafeName::new("sbin/it's</script>.wasm").unwrap();
+    let script = stage0_script(name);
+    assert!(script.contains(r#"const BOOT = "sbin/it's\u003c/script>.wasm";"#));
+    assert!(!script.contains(BOOT_KERNEL_NAME.0));
+}
+
+#[test]
+fn stage2_uses_configured_config_name() {
+    let template = tempfile::tempdir().unwrap();
+    let index_html = template.path().join("index.html");
+    std::fs::write(&index_html, include_str!("fixture.html")).unwrap();
+    let document: project::Document =
+        toml::from_str(&format!("index-html = {index_html:?}\n")).unwrap();
+
+    let root = tempfile::tempdir().unwrap();
+    let work = Work {
+        // The bundled loader before its build, and after with the quotes changed.
+        stage2: [
+            include_str!("../../../stage2-loader/stage2-wasi.js"),
+            "const CONFIG = \"boot/config.json\";",
+        ]
+        .concat()
+        .into_bytes(),
+        runtime_config: Some(br#"{"args":["init"]}"#.to_vec()),
+        config_name: "etc/loader.json".to_owned(),
+        ..root_work(root.path(), &document)
+    };
+    let packed = String::from_utf8(pack_document(&work).unwrap()).unwrap();
+
+    let mut doc = dom::SourceDocument::new(&packed);
+    let entries = doc.split_tar_contents().unwrap();
+    let entry = |name: &str| {
+        entries
+            .iter()
+            .filter_map(|entry| entry.as_html_and_tar_entry())
+            .find(|entry| entry.name.0 == name)
+    };
+    assert_eq!(
+        entry("etc/loader.json").unwrap().data,
+        br#"{"args":["init"]}"#
+    );
+    assert!(entry(BOOT_CONFIG_NAME.0).is_none());
+
+    let kernel = entry(BOOT_KERNEL_NAME.0).unwrap();
+    let stage2 = wasmparser::Parser::default()
+        .parse_all(kernel.data)
+        .find_map(|payload| match payload.unwrap() {
+            wasmparser::Payload::CustomSection(reader) if reader.name() == SECTION_STAGE2 => {
+                Some(String::from_utf8(reader.data().to_vec()).unwrap())
+            }
+            _ => None,
+        })
+        .unwrap();
+    assert!(stage2.contains(r#"const CONFIG = "etc/loader.json";"#));
+    assert!(stage2.contains("item.header.name === CONFIG"));
+    assert!(!stage2.contains(BOOT_CONFIG_NAME.0));
+}
+
+#[test]
+fn stage0_sees_patches_through_links() {
+    // Just enough of a browser for stage0 to read the files of a document.
+    const HARNESS: &str = r#"
+        const fs = require('node:fs');
+        const vm = require('node:vm');
+        const html = fs.readFileSync(process.argv[2], 'utf8');
+        const elements = [...html.matchAll(/<noscript ([^>]*)>([^<]*)/g)].map(([, attrs, text]) => {
+            const values = Object.fromEntries([...attrs.matchAll(/([\w-]+)="([^"]*)"/g)].map(m => m.slice(1)));
+            return { textContent: text, getAttribute: name => values[name] ?? null };
+        });
+
+        let load;
+        globalThis.window = { addEventListener: (_, listener) => { load = listener; } };
+        globalThis.document = { getElementsByClassName: () => elements };
+        globalThis.location = { hash: '' };
+        console.debug = () => {};
+
+        vm.runInThisContext(fs.readFileSync(process.argv[3], 'utf8') + ';globalThis.stage0 = __wah_stage0_global;');
+        load().then(() => {
+            const files = Object.entries(stage0.file_data)
+                .map(([name, data]) => [name, new TextDecoder().decode(data)]);
+            process.stdout.write(JSON.stringify(Object.fromEntries(files)));
+        });
+    "#;
+
+    if std::process::Command::new("node")
+        .arg("--version")
+        .output()
+        .is_err()
+    {
+        eprintln!("skipped, running stage0 needs node");
+        return;
+    }
+
+    let motd = HtmlAttributeSafeName::new("etc/motd").unwrap();
+    let mut source = dom::SourceDocument::new(include_str!("fixture.html"));
+    let packed = tar::build(
+        &mut source,
+        |push| {
+            push(tar::TarItem::Entry(html_and_tar::Entry {
+                name: motd,
+                data: b"Hello, world\n",
+                attributes: Default::default(),
+                compression: None,
+            }));
+            push(tar::TarItem::Link(html_and_tar::Link {
+                name: HtmlAttributeSafeName::new("etc/issue")?,
+                target: motd,
+                attributes: Default::default(),
+            }));
+            push(tar::TarItem::Patch(html_and_tar::Patch {
+                name: motd,
+                offset: 7,
+                data: b"there!\n",
+            }));
+
+            Ok::<_, html_and_tar::TarError>(())
+        },
+        Some(b"console.log('stage0')"),
+    )
+    .unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let [harness, document, stage0] =
+        ["harness.js", "document.html", "stage0.js"].map(|name| dir.path().join(name));
+    std::fs::write(&harness, HARNESS).unwrap();
+    std::fs::write(&document, packed).unwrap();
+    std::fs::write(&stage0, stage0_script(BOOT_KERNEL_NAME)).unwrap();
+
+    let output = std::process::Command::new("node")
+        .args([&harness, &document, &stage0])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let files: std::collections::BTreeMap<String, String> =
+        serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(files["etc/motd"], "Hello, there!\n");
+    assert_eq!(files["etc/issue"], "Hello, there!\n");
+}
+
+#[test]
+fn boot_module_imports_only_wasi() {
+    let module = |import_module: &str| {
+        let mut types = wasm_encoder::TypeSection::new();
+        types.function([], []);
+        let mut imports = wasm_encoder::ImportSection::new();
+        imports.import(
+            import_module,
+            "proc_exit",
+            wasm_encoder::EntityType::Function(0),
+        );
+
+        let mut module = wasm_encoder::Module::new();
+        module.section(&types).section(&imports);
+        module.finish()
+    };
+
+    validate_boot_module(b"\0asm\x01\0\0\0").unwrap();
+    validate_boot_module(&module(WASI_IMPORT_MODULE)).unwrap();
+    assert!(validate_boot_module(b"\0asm\x01\0").is_err());
+
+    let err = validate_boot_module(&module("env")).unwrap_err();
+    assert!(err.to_string().contains("`env`"));
+}
+
+#[test]
+fn loader_scripts_pass_the_minify_check() {
+    let stage0 = stage0_script(BOOT_KERNEL_NAME);
+
+    for script in [
+        stage0.as_bytes(),
+        include_bytes!("stage1.js"),
+        include_bytes!("stage1-edit.js"),
+    ] {
+        // Our own scripts must not take the unminified fallback.
+        wasi_document_minify_js::minify_js(script).unwrap();
+        // The oldest `Document.js-target` they support.
+        minify_js(script, wasi_document_minify_js::ESTarget::ES2020, true).unwrap();
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn dev_null_is_a_device_node() {
+    let meta = std::fs::metadata("/dev/null").unwrap();
+    let (kind, major, minor) = device_node(&meta).unwrap().unwrap();
+    assert_eq!(kind, html_and_tar::DeviceKind::Character);
+    assert_eq!((major, minor), (1, 3));
+
+    let meta = std::fs::metadata(env!("CARGO_MANIFEST_DIR")).unwrap();
+    assert!(device_node(&meta).is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn executable_mode_survives_packing() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::tempdir().unwrap();
+    let script = dir.path().join("run.sh");
+    std::fs::write(&script, "#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mode = file_mode(&std::fs::metadata(&script).unwrap());
+    assert_eq!(mode, Some(0o755));
+
+    let mut source = dom::SourceDocument::new(include_str!("fixture.html"));
+    let packed = tar::build(
+        &mut source,
+        |push| {
+            push(tar::TarItem::Entry(html_and_tar::Entry {
+                name: HtmlAttributeSafeName::new("bin/run.sh")?,
+                data: b"#!/bin/sh\n",
+                attributes: html_and_tar::EntryAttributes {
+                    mode,
+                    ..Default::default()
+                },
+                compression: None,
+            }));
+
+            Ok::<_, Box<dyn std::error::Error>>(())
+        },
+        Some(stage0_script(BOOT_KERNEL_NAME).as_bytes()),
+    )
+    .unwrap();
+
+    let packed = String::from_utf8(packed).unwrap();
+    let entries = dom::SourceDocument::new(&packed)
+        .split_tar_contents()
+        .unwrap();
+    assert_eq!(entries[0].attributes().mode, Some(0o755));
+}
+
+#[test]
+fn replaced_file_is_complete_or_previous() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("wasi.html");
+    std::fs::write(&path, "previous").unwrap();
+
+    replace_file(&path, b"complete").unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"complete");
+    assert!(!partial_path(&path).exists());
+
+    // The rename fails onto a directory, after the data was written.
+    let taken = dir.path().join("taken");
+    std::fs::create_dir(&taken).unwrap();
+    std::fs::write(taken.join("inner"), "kept").unwrap();
+    assert!(replace_file(&taken, b"lost").is_err());
+    assert!(!partial_path(&taken).exists());
+    assert_eq!(std::fs::read(taken.join("inner")).unwrap(), b"kept");
+}
+
+#[test]
+fn mtime_survives_packing() {
+    let dir = tempfile::tempdir().unwrap();
+    let motd = dir.path().join("motd");
+    let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+    std::fs::write(&motd, "Hello, world!\n").unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&motd)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+
+    let meta = std::fs::metadata(&motd).unwrap();
+    let mtime = file_mtime(&meta, None);
+    assert_eq!(mtime, Some(modified));
+
+    let fixed = std::time::UNIX_EPOCH + std::time::Duration::from_secs(315_532_800);
+    assert_eq!(file_mtime(&meta, Some(fixed)), Some(fixed));
+
+    let mut source = dom::SourceDocument::new(include_str!("fixture.html"));
+    let packed = tar::build(
+        &mut source,
+        |push| {
+            push(tar::TarItem::Entry(html_and_tar::Entry {
+                name: HtmlAttributeSafeName::new("etc/motd")?,
+                data: b"Hello, world!\n",
+                attributes: html_and_tar::EntryAttributes {
+                    mtime,
+                    ..Default::default()
+                },
+                compression: None,
+            }));
+
+            Ok::<_, Box<dyn std::error::Error>>(())
+        },
+        Some(stage0_script(BOOT_KERNEL_NAME).as_bytes()),
+    )
+    .unwrap();
+
+    let listed = html_and_tar::TarDecompiler::list(&packed).unwrap();
+    let attributes = html_and_tar::EntryAttributes::from_header(&listed[0].header);
+    assert_eq!(attributes.mtime, Some(modified));
+}
+
+/// Pack files into `fixture.html`, with a stand-in for the loader.
+#[cfg(test)]
+pub fn pack_fixture(files: &[(&str, &[u8])]) -> String {
+    let mut source = dom::SourceDocument::new(include_str!("fixture.html"));
+    let packed = tar::build(
+        &mut source,
+        |push| {
+            for &(name, data) in files {
+                push(tar::TarItem::Entry(html_and_tar::Entry {
+                    name: HtmlAttributeSafeName::new(name)?,
+                    data,
+                    attributes: Default::default(),
+                    compression: None,
+                }));
+            }
+
+            Ok::<_, html_and_tar::TarError>(())
+        },
+        Some(b"console.log('stage0')"),
+    )
+    .unwrap();
+
+    String::from_utf8(packed).unwrap()
+}
+
+/// Packing a directory root like a build of the `document`, with stand-in stages.
+#[cfg(test)]
+fn root_work(root: &Path, document: &project::Document) -> Work {
+    Work {
+        index_html: document.index_html.clone(),
+        stage2: b"stage2".to_vec(),
+        kernel: b"\0asm\x01\0\0\0".to_vec(),
+        modules: vec![],
+        runtime_config: None,
+        embedded_config: None,
+        kernel_name: BOOT_KERNEL_NAME.0.to_owned(),
+        config_name: BOOT_CONFIG_NAME.0.to_owned(),
+        edit: false,
+        verbose: false,
+        emit_layout: false,
+        check_minify: false,
+        check_browser_compat: false,
+        allow_invalid_wasm: false,
+        doctype: None,
+        line_width: std::num::NonZeroUsize::new(76),
+        compression: document.compress.then_some(html_and_tar::Compression::Gzip),
+        js_target: document.js_target,
+        open_ended: false,
+        csp: None,
+        inline_assets: vec![],
+        devices: false,
+        jobs: std::num::NonZeroUsize::new(2).unwrap(),
+        aliases: Default::default(),
+        root_fs: vec![root.to_owned()],
+        root_tar: None,
+        remote: vec![],
+        executable: document.executable_set().unwrap(),
+        root_filter: document.root_filter().unwrap(),
+        insertions: vec![],
+        fixed_mtime: document.packed_mtime(),
+        out: None,
+        packers: vec![],
+        resources: vec![],
+    }
+}
+
+#[test]
+fn reproducible_builds_hash_the_same() {
+    let template = tempfile::tempdir().unwrap();
+    let index_html = template.path().join("index.html");
+    std::fs::write(&index_html, include_str!("fixture.html")).unwrap();
+
+    let document: project::Document = toml::from_str(&format!(
+        "index-html = {index_html:?}\nreproducible = true\ncompress = true\n"
+    ))
+    .unwrap();
+
+    let files: [(&str, &[u8]); 3] = [
+        ("etc/motd", b"Hello, world!\n"),
+        ("usr/bin/run.sh", b"#!/bin/sh\n"),
+        ("usr/share/zeros", &[0; 4096]),
+    ];
+
+    // The same files, written in another order at another time on each checkout.
+    let build = |reversed: bool, secs: u64| {
+        let root = tempfile::tempdir().unwrap();
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        let mut order: Vec<_> = files.iter().collect();
+        if reversed {
+            order.reverse();
+        }
+
+        for (name, data) in order {
+            let path = root.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, data).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+
+        pack_document(&root_work(root.path(), &document)).unwrap()
+    };
+
+    let first = build(false, 1_700_000_000);
+    let second = build(true, 1_800_000_000);
+    assert_eq!(short_hash(&first), short_hash(&second));
+    assert!(first == second);
+
+    let listed = html_and_tar::TarDecompiler::list(&first).unwrap();
+    let motd = listed
+        .iter()
+        .find(|entry| entry.path.as_deref() == Some("etc/motd"))
+        .unwrap();
+    let attributes = html_and_tar::EntryAttributes::from_header(&motd.header);
+    let epoch =
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(html_and_tar::TarHeader::EPOCH);
+    assert_eq!(attributes.mtime, Some(epoch));
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_in_roots_are_packed_as_links() {
+    let template = tempfile::tempdir().unwrap();
+    let index_html = template.path().join("index.html");
+    std::fs::write(&index_html, include_str!("fixture.html")).unwrap();
+    let document: project::Document =
+        toml::from_str(&format!("index-html = {index_html:?}\n")).unwrap();
+
+    let root = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(root.path().join("bin")).unwrap();
+    std::fs::write(root.path().join("bin/busybox"), b"\0asm").unwrap();
+    std::os::unix::fs::symlink("busybox", root.path().join("bin/sh")).unwrap();
+    // Following these would walk the root again and again, or into the host.
+    std::os::unix::fs::symlink("..", root.path().join("bin/loop")).unwrap();
+    std::os::unix::fs::symlink("/etc", root.path().join("host")).unwrap();
+
+    let packed = pack_document(&root_work(root.path(), &document)).unwrap();
+    let listed = html_and_tar::TarDecompiler::list(&packed).unwrap();
+    let names: Vec<_> = listed
+        .iter()
+        .filter_map(|entry| entry.path.as_deref())
+        .collect();
+    assert!(names.contains(&"bin/busybox"));
+    assert!(!names.iter().any(|name| name.starts_with("bin/loop/")));
+    assert!(!names.iter().any(|name| name.starts_with("host/")));
+
+    let packed = String::from_utf8(packed).unwrap();
+    let entries = dom::SourceDocument::new(&packed)
+        .split_tar_contents()
+        .unwrap();
+
+    let links: Vec<_> = entries
+        .iter()
+        .filter_map(|entry| entry.as_html_and_tar_symlink())
+        .map(|link| (link.name.0, link.target.0))
+        .collect();
+    assert_eq!(
+        links,
+        [("bin/loop", ".."), ("bin/sh", "busybox"), ("host", "/etc")]
+    );
+}
+
+#[test]
+fn modules_manifest_lists_slots() {
+    let modules = [
+        ("tools".to_string(), vec![]),
+        ("viewer".to_string(), vec![]),
+    ];
+    let manifest = modules_manifest(BOOT_KERNEL_NAME, &modules);
+    let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+
+    assert_eq!(manifest["default"], "boot/wah-init.wasm");
+    assert_eq!(manifest["modules"]["tools"], "boot/tools/wah-init.wasm");
+    assert_eq!(manifest["modules"]["viewer"], "boot/viewer/wah-init.wasm");
+}
+
+#[test]
+fn streamed_hash_matches_the_data() {
+    let mut writer = HashingWriter {
+        inner: vec![],
+        hasher: Default::default(),
+    };
+
+    writer.write_all(b"Hello, ").unwrap();
+    writer.write_all(b"world!").unwrap();
+    assert_eq!(writer.inner, b"Hello, world!");
+    assert_eq!(short_digest(writer.hasher), short_hash(b"Hello, world!"));
+}
+
+#[test]
+fn data_uris_carry_the_mime_type() {
+    assert_eq!(
+        data_uri(Path::new("img/logo.PNG"), b"\x89PNG"),
+        "data:image/png;base64,iVBORw=="
+    );
+    assert_eq!(
+        data_uri(Path::new("LICENSE"), b""),
+        "data:application/octet-stream;base64,"
+    );
+}
@@ -0,0 +1,118 @@
+//! The `patch` subcommand, new loader scripts for a built document.
+
+use wasi_document_dom as dom;
+
+use crate::{
+    pack::{SECTION_STAGE2, summarize_sections},
+    repack::repack,
+    sections::find_boot_module,
+};
+
+/// Replace the loader of a built document, the stage0 script and the stage2 section.
+///
+/// The boot module keeps all its other sections, so this works without the original sources.
+pub fn patch_loader(
+    document: &str,
+    kernel_name: &str,
+    stage2: Option<&[u8]>,
+    stage0: Option<&[u8]>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut source = dom::SourceDocument::new(document);
+    let mut entries = source.split_tar_contents()?;
+
+    if let Some(stage2) = stage2 {
+        let index = find_boot_module(&entries, kernel_name)?;
+        let kernel = &mut entries[index];
+
+        let entry = kernel.as_html_and_tar_entry().unwrap();
+        let patched = replace_custom_section(entry.data, SECTION_STAGE2, stage2)?;
+        summarize_sections(&patched, false)?;
+
+        *kernel = dom::TarEntryOwned::from_entry(html_and_tar::Entry {
+            data: &patched,
+            ..entry
+        });
+    }
+
+    repack(&mut source, &entries, stage0)
+}
+
+/// Re-encode a module with the contents of its custom sections called `name` replaced.
+fn replace_custom_section(
+    wasm: &[u8],
+    name: &str,
+    data: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut encoder = wasm_encoder::Module::new();
+    let mut replaced = 0;
+
+    for payload in wasmparser::Parser::default().parse_all(wasm) {
+        let payload = payload?;
+
+        let Some((id, range)) = payload.as_section() else {
+            continue;
+        };
+
+        match &payload {
+            wasmparser::Payload::CustomSection(reader) if reader.name() == name => {
+                encoder.section(&wasm_encoder::CustomSection { name, data });
+                replaced += 1;
+            }
+            _ => {
+                encoder.section(&wasm_encoder::RawSection {
+                    id,
+                    data: &wasm[range],
+                });
+            }
+        }
+    }
+
+    if replaced == 0 {
+        return Err(format!("Boot module has no `{name}` section to replace").into());
+    }
+
+    Ok(encoder.finish())
+}
+
+#[test]
+fn patch_replaces_loader() {
+    let module = |stage2: &[u8]| {
+        let mut module = wasm_encoder::Module::new();
+        module.section(&wasm_encoder::CustomSection {
+            name: crate::pack::SECTION_STAGE1,
+            data: b"stage1",
+        });
+        module.section(&wasm_encoder::CustomSection {
+            name: SECTION_STAGE2,
+            data: stage2,
+        });
+        module.finish()
+    };
+
+    let document = crate::pack::pack_fixture(&[
+        (crate::pack::BOOT_KERNEL_NAME.0, &module(b"old stage2")),
+        ("etc/motd", b"Hello, world!\n"),
+    ]);
+
+    let patched = patch_loader(
+        &document,
+        crate::pack::BOOT_KERNEL_NAME.0,
+        Some(b"new stage2"),
+        Some(b"console.log('patched')"),
+    )
+    .unwrap();
+
+    let patched = String::from_utf8(patched).unwrap();
+    assert!(patched.contains("console.log('patched')"));
+    assert!(!patched.contains("console.log('stage0')"));
+
+    let mut doc = dom::SourceDocument::new(&patched);
+    let entries = doc.split_tar_contents().unwrap();
+    let kernel = entries[0].as_html_and_tar_entry().unwrap();
+    assert_eq!(kernel.name.0, crate::pack::BOOT_KERNEL_NAME.0);
+    assert_eq!(kernel.data, module(b"new stage2"));
+    assert_eq!(
+        entries[1].as_html_and_tar_entry().unwrap().data,
+        b"Hello, world!\n"
+    );
+}
@@ -1,51 +1,220 @@
-use std::{io, path::Path, path::PathBuf};
+use std::{collections::BTreeMap, io, num::NonZeroUsize, path::Path, path::PathBuf};
 
+use html_and_tar::{EntryAttributes, HtmlAttributeSafeName};
 use serde::Deserialize;
+use wasi_document_minify_js::ESTarget;
 
 use crate::{build::BuildEnv, webpack::PackRoot};
 
+pub const DEFAULT_PROJECT: &str = "./WasiDocument.toml";
+
 /// The merged tool input configuration.
 pub struct Configuration {
     pub document: Document,
     pub machine: Machine,
     pub web: WebPack,
+    pub jobs: Option<NonZeroUsize>,
+    /// The text of the project file.
+    pub contents: String,
+    /// The directory of the project file, relative paths of the project start here.
+    pub dir: PathBuf,
 }
 
 impl Configuration {
-    pub fn load(args: &super::Args) -> Result<Self, Box<dyn std::error::Error>> {
-        let default_cfg = || PathBuf::from("./WasiDocument.toml");
+    pub fn load(project: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let base = project.unwrap_or(Path::new(DEFAULT_PROJECT));
 
-        let base = match args {
-            super::Args::Build { project, .. } | super::Args::Repack { project, .. } => {
-                project.clone().unwrap_or_else(default_cfg)
-            }
-        };
+        let contents = std::fs::read_to_string(base)?;
+        let dir = base
+            .parent()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+        Self::from_toml(&contents, dir)
+    }
 
+    /// Parse a configuration whose relative paths start at `dir`.
+    pub fn from_toml(contents: &str, dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
         let Project {
             mut document,
             mut machine,
             web_pack: mut web,
-        } = {
-            let contents = std::fs::read_to_string(&base)?;
-            toml::from_str(&contents)?
-        };
+            jobs,
+        } = toml::from_str(contents)?;
 
-        let dir = base
-            .parent()
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        if let Err(err) = document.exclusive_roots() {
+            return Err(err.into());
+        }
 
-        document.absolute_paths(&dir);
-        machine.absolute_paths(&dir);
-        web.absolute_paths(&dir);
+        let max_mtime = html_and_tar::TarHeader::MAX_MTIME;
+        if let Some(mtime) = document.fixed_mtime.filter(|&mtime| mtime > max_mtime) {
+            return Err(
+                format!("Document.fixed-mtime {mtime} is after the latest {max_mtime}").into(),
+            );
+        }
+
+        document.absolute_paths(dir);
+        machine.absolute_paths(dir);
+        web.absolute_paths(dir);
 
         Ok(Configuration {
             document,
             machine,
             web,
+            jobs,
+            contents: contents.to_owned(),
+            dir: dir.to_owned(),
         })
     }
 }
 
+/// A problem with a project configuration, by the key it concerns.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(key: impl Into<String>, message: impl std::fmt::Display) -> Self {
+        ConfigError {
+            key: key.into(),
+            message: message.to_string().trim_end().to_owned(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.key, self.message)
+        }
+    }
+}
+
+impl Configuration {
+    /// Check a project file without building anything.
+    ///
+    /// Unlike [`Configuration::load`] this does not stop at the first problem. Each section is
+    /// parsed on its own, then the paths it names are checked for existence.
+    pub fn validate(path: &Path) -> Result<(), Vec<ConfigError>> {
+        let table: toml::Table = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::new("", err))
+            .and_then(|contents| toml::from_str(&contents).map_err(|err| ConfigError::new("", err)))
+            .map_err(|err| vec![err])?;
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let mut errors = vec![];
+
+        for key in table.keys() {
+            if !["Document", "Machine", "WebPack", "Jobs"].contains(&key.as_str()) {
+                errors.push(ConfigError::new(key, "unknown key"));
+            }
+        }
+
+        if let Some(mut document) = section::<Document>(&table, "Document", &mut errors) {
+            document.absolute_paths(dir);
+            document.validate(dir, &mut errors);
+        }
+
+        if let Some(machine) = table.get("Machine") {
+            let before = errors.len();
+
+            // Parse the stages separately, so a mistake in one does not hide the other.
+            for (stage, check) in [
+                ("stage2", try_stage::<BuildStage2> as fn(_) -> _),
+                ("stage3", try_stage::<BuildStage3>),
+            ] {
+                match machine.get(stage) {
+                    None => errors.push(ConfigError::new(format!("Machine.{stage}"), "missing")),
+                    Some(value) => {
+                        if let Err(err) = check(value.clone()) {
+                            errors.push(ConfigError::new(format!("Machine.{stage}"), err));
+                        }
+                    }
+                }
+            }
+
+            match machine.clone().try_into::<Machine>() {
+                Ok(machine) => machine.validate(dir, &mut errors),
+                // Already reported by its stage.
+                Err(_) if errors.len() > before => {}
+                Err(err) => errors.push(ConfigError::new("Machine", err)),
+            }
+
+            // The names do not depend on the stages, check them even when those do not parse.
+            for key in ["kernel-name", "config-name"] {
+                if let Some(name) = machine.get(key).and_then(toml::Value::as_str) {
+                    expect_name(&format!("Machine.{key}"), name, &mut errors);
+                }
+            }
+        } else {
+            errors.push(ConfigError::new("Machine", "missing"));
+        }
+
+        if table.contains_key("WebPack") {
+            section::<WebPack>(&table, "WebPack", &mut errors);
+        }
+
+        if table.contains_key("Jobs") {
+            section::<NonZeroUsize>(&table, "Jobs", &mut errors);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn section<T: serde::de::DeserializeOwned>(
+    table: &toml::Table,
+    key: &str,
+    errors: &mut Vec<ConfigError>,
+) -> Option<T> {
+    let Some(value) = table.get(key) else {
+        errors.push(ConfigError::new(key, "missing"));
+        return None;
+    };
+
+    value
+        .clone()
+        .try_into()
+        .map_err(|err| errors.push(ConfigError::new(key, err)))
+        .ok()
+}
+
+fn try_stage<T: serde::de::DeserializeOwned>(value: toml::Value) -> Result<(), toml::de::Error> {
+    value.try_into::<T>().map(drop)
+}
+
+fn expect_path(key: &str, path: &Path, is_dir: bool, errors: &mut Vec<ConfigError>) {
+    let found = if is_dir {
+        path.is_dir()
+    } else {
+        path.is_file()
+    };
+
+    if !found {
+        let kind = if is_dir { "directory" } else { "file" };
+        errors.push(ConfigError::new(
+            key,
+            format!("no {kind} at {}", path.display()),
+        ));
+    }
+}
+
+fn expect_name(key: &str, name: &str, errors: &mut Vec<ConfigError>) {
+    if let Err(err) = html_and_tar::HtmlAttributeSafeName::new(name) {
+        errors.push(ConfigError::new(
+            key,
+            format!("`{name}` can not be packed, {err}"),
+        ));
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "PascalCase", deny_unknown_fields)]
 pub struct Project {
@@ -53,6 +222,9 @@ pub struct Project {
     pub machine: Machine,
     #[serde(default)]
     pub web_pack: WebPack,
+    /// Limit on concurrent stage builds, downloads and file encodings, the command line takes
+    /// precedence.
+    pub jobs: Option<NonZeroUsize>,
 }
 
 #[derive(Deserialize)]
@@ -61,41 +233,430 @@ pub struct Document {
     pub index_html: PathBuf,
     #[serde(rename = "filesystem-root")]
     pub root: Option<PathBuf>,
+    /// A tar archive to pack as the filesystem root instead of a directory, such as the output of
+    /// `docker export`. Symbolic links in it are packed as links, their targets are not checked.
+    #[serde(rename = "filesystem-root-tar")]
+    pub root_tar: Option<PathBuf>,
     #[serde(rename = "Install")]
     pub install: Option<Vec<Install>>,
+    #[serde(rename = "Remote")]
+    pub remote: Option<Vec<Remote>>,
+    /// Files of the filesystem root packed at later insertion points of the template, instead of
+    /// the first one. Listed in the order of the points in the document.
+    #[serde(rename = "Insertion", default)]
+    pub insertions: Vec<Insertion>,
+    /// Structured configuration for the loader, packed as JSON to `boot/config.json` or the
+    /// `Machine.config-name`. The bundled stage2 loader takes `args`, a list replacing the arguments of the kernel, and `env`, a
+    /// table of variables added to its environment.
+    pub runtime_config: Option<toml::Table>,
+    /// Globs of packed paths that get mode `0755`, whatever the bits on the source filesystem.
+    #[serde(default)]
+    pub executable: Vec<String>,
+    /// Globs of paths in the filesystem root to pack, all of them if empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs of paths in the filesystem root to leave out, even if included. Excluding a
+    /// directory leaves out everything in it, such as `**/.git`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Seconds since the Unix epoch, the modification time of every file of the filesystem root
+    /// instead of the one on disk. For reproducible builds, as a checkout sets its own times.
+    pub fixed_mtime: Option<u64>,
+    /// Pack every file with the same modification time, the `fixed-mtime` or else the one of
+    /// entries without a time of their own. The document is then byte-identical for the same
+    /// template, stages and files.
+    #[serde(default)]
+    pub reproducible: bool,
+    /// The doctype put in front of a template without one, `<!DOCTYPE html>` by default.
+    pub doctype: Option<String>,
+    /// Additional names for packed files, stored as hard links to the data packed once.
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// Wrap the base64 data at this many characters, e.g. `76` like MIME. The default `0` writes
+    /// it in one line.
+    #[serde(default)]
+    pub base64_line_width: usize,
+    /// Gzip the data of packed files before the base64 encoding. Files that would not get smaller
+    /// by it, like images, stay as they are. The loader inflates them again.
+    #[serde(default)]
+    pub compress: bool,
+    /// The ECMAScript version the loader scripts are minified for, like `es2020`. The build fails
+    /// if they use newer syntax, which they do before `es2020`. `esnext` by default.
+    #[serde(default, deserialize_with = "deserialize_js_target")]
+    pub js_target: ESTarget,
+    /// Leave out the tar EOF so entries can be appended later. Plain tar tools will then complain
+    /// about the HTML after our data.
+    #[serde(default)]
+    pub open_ended: bool,
+    /// A Content-Security-Policy for the document, put into its `<head>` as a `<meta>` tag.
+    pub csp: Option<String>,
+    /// The file name of the document in the target directory. See [`OUTPUT_NAME_TOKENS`] for the
+    /// values substituted into it.
+    #[serde(default = "Document::default_output_name")]
+    pub output_name: String,
+    /// Assets that `src` or `href` attributes of the template refer to, inlined there as `data:`
+    /// URIs. Written as in the attributes, relative to the template.
+    #[serde(default)]
+    pub inline_assets: Vec<String>,
+}
+
+fn glob_set(patterns: &[String]) -> Result<globset::GlobSet, globset::Error> {
+    let mut set = globset::GlobSetBuilder::new();
+
+    for pattern in patterns {
+        let glob = globset::GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()?;
+        set.add(glob);
+    }
+
+    set.build()
+}
+
+/// Which paths of a filesystem root to pack, see `Document.include` and `Document.exclude`.
+pub struct RootFilter {
+    include: globset::GlobSet,
+    exclude: globset::GlobSet,
+}
+
+impl RootFilter {
+    /// Whether to pack the entry at a path relative to the root.
+    pub fn is_packed(&self, path: &str) -> bool {
+        !self.is_excluded(path) && (self.include.is_empty() || self.include.is_match(path))
+    }
+
+    /// Whether the path or a directory containing it is excluded.
+    pub fn is_excluded(&self, path: &str) -> bool {
+        Path::new(path)
+            .ancestors()
+            .filter(|path| !path.as_os_str().is_empty())
+            .any(|path| self.exclude.is_match(path))
+    }
+}
+
+/// Tokens of `Document.output-name`: the directory name of the project, the UTC date of the build
+/// as `YYYY-MM-DD` and the start of the SHA-256 of the document.
+pub const OUTPUT_NAME_TOKENS: [&str; 3] = ["name", "date", "hash"];
+
+/// Substitute the `{token}`s of an output name, failing on unknown tokens.
+pub fn expand_output_name(
+    template: &str,
+    tokens: &[(&str, &str)],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut expanded = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+
+        let (key, after) = rest[start + 1..]
+            .split_once('}')
+            .ok_or("unclosed `{` in the output name")?;
+        let (_, value) = tokens
+            .iter()
+            .find(|(token, _)| *token == key)
+            .ok_or_else(|| {
+                format!("unknown token `{{{key}}}`, expected one of {OUTPUT_NAME_TOKENS:?}")
+            })?;
+
+        expanded.push_str(value);
+        rest = after;
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
 }
 
 #[derive(Deserialize)]
-#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Machine {
     #[serde(deserialize_with = "BuildStage2::deserialize")]
     pub stage2: Build,
     #[serde(deserialize_with = "BuildStage3::deserialize")]
     pub stage3: Build,
+    /// The packed name of the boot module, for loaders expecting it elsewhere.
+    pub kernel_name: Option<String>,
+    /// The packed name of the runtime configuration.
+    pub config_name: Option<String>,
+    /// More boot modules by the name of their slot, built like `stage3`. The loader boots one of
+    /// them instead of the kernel when the URL fragment is `#module=<slot>`.
+    #[serde(default, deserialize_with = "BuildStage3::deserialize_map")]
+    pub modules: BTreeMap<String, Build>,
 }
 
 impl Document {
+    fn default_output_name() -> String {
+        "wasi.html".to_owned()
+    }
+
+    /// The modification time of all packed files, if they do not keep their own.
+    pub fn packed_mtime(&self) -> Option<std::time::SystemTime> {
+        let epoch = self.reproducible.then_some(html_and_tar::TarHeader::EPOCH);
+        let secs = self.fixed_mtime.or(epoch)?;
+        Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+    }
+
+    /// Compile the `executable` globs. A `*` does not cross a `/`, like in a shell.
+    pub fn executable_set(&self) -> Result<globset::GlobSet, globset::Error> {
+        glob_set(&self.executable)
+    }
+
+    /// Compile the `include` and `exclude` globs, like [`Document::executable_set`].
+    pub fn root_filter(&self) -> Result<RootFilter, globset::Error> {
+        Ok(RootFilter {
+            include: glob_set(&self.include)?,
+            exclude: glob_set(&self.exclude)?,
+        })
+    }
+
+    /// Compile the `files` of each insertion point, by its name.
+    pub fn insertion_sets(&self) -> Result<Vec<(String, globset::GlobSet)>, globset::Error> {
+        self.insertions
+            .iter()
+            .map(|insertion| Ok((insertion.name.clone(), glob_set(&insertion.files)?)))
+            .collect()
+    }
+
+    fn validate(&self, dir: &Path, errors: &mut Vec<ConfigError>) {
+        expect_path("Document.index-html", &self.index_html, false, errors);
+
+        let template_dir = self.index_html.parent().unwrap_or(dir);
+        for (idx, asset) in self.inline_assets.iter().enumerate() {
+            expect_path(
+                &format!("Document.inline-assets[{idx}]"),
+                &template_dir.join(asset),
+                false,
+                errors,
+            );
+        }
+
+        if let Some(root) = &self.root {
+            expect_path("Document.filesystem-root", root, true, errors);
+        }
+
+        if let Some(root) = &self.root_tar {
+            expect_path("Document.filesystem-root-tar", root, false, errors);
+        }
+
+        if let Err(err) = self.exclusive_roots() {
+            errors.push(ConfigError::new("Document.filesystem-root-tar", err));
+        }
+
+        for (key, patterns) in [
+            ("Document.executable", &self.executable),
+            ("Document.include", &self.include),
+            ("Document.exclude", &self.exclude),
+        ] {
+            if let Err(err) = glob_set(patterns) {
+                errors.push(ConfigError::new(key, err));
+            }
+        }
+
+        for (idx, insertion) in self.insertions.iter().enumerate() {
+            if let Err(err) = glob_set(&insertion.files) {
+                errors.push(ConfigError::new(
+                    format!("Document.Insertion[{idx}].files"),
+                    err,
+                ));
+            }
+
+            if self.insertions[..idx]
+                .iter()
+                .any(|other| other.name == insertion.name)
+            {
+                errors.push(ConfigError::new(
+                    format!("Document.Insertion[{idx}].name"),
+                    format!("`{}` is listed twice", insertion.name),
+                ));
+            }
+        }
+
+        for (alias, target) in &self.aliases {
+            for name in [alias, target] {
+                if let Err(err) = HtmlAttributeSafeName::new(name) {
+                    errors.push(ConfigError::new(format!("Document.aliases.{alias}"), err));
+                }
+            }
+        }
+
+        if let Some(doctype) = &self.doctype
+            && let Err(err) = html_and_tar::TarEngine::validate_doctype(doctype)
+        {
+            errors.push(ConfigError::new("Document.doctype", err));
+        }
+
+        if let Some(csp) = &self.csp
+            && let Err(err) = wasi_document_dom::SourceDocument::validate_csp(csp)
+        {
+            errors.push(ConfigError::new("Document.csp", err));
+        }
+
+        let placeholders = OUTPUT_NAME_TOKENS.map(|token| (token, "x"));
+        match expand_output_name(&self.output_name, &placeholders) {
+            Ok(name)
+                if Path::new(&name)
+                    .components()
+                    .eq([std::path::Component::Normal(name.as_ref())]) => {}
+            Ok(_) => errors.push(ConfigError::new(
+                "Document.output-name",
+                "expected a file name, without directories",
+            )),
+            Err(err) => errors.push(ConfigError::new("Document.output-name", err)),
+        }
+
+        for (idx, install) in self.install.iter().flatten().enumerate() {
+            if let InstallSource::Path { path } = &install.source {
+                expect_path(
+                    &format!("Document.Install[{idx}].path"),
+                    &dir.join(path),
+                    true,
+                    errors,
+                );
+            }
+        }
+
+        for (idx, remote) in self.remote.iter().flatten().enumerate() {
+            expect_name(
+                &format!("Document.Remote[{idx}].name"),
+                &remote.name,
+                errors,
+            );
+
+            let is_digest =
+                |hex: &str| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit());
+            if remote.sha256.as_deref().is_some_and(|hex| !is_digest(hex)) {
+                errors.push(ConfigError::new(
+                    format!("Document.Remote[{idx}].sha256"),
+                    "expected 64 hexadecimal digits",
+                ));
+            }
+        }
+    }
+
     pub fn absolute_paths(&mut self, base: &Path) {
         self.index_html = base.join(&self.index_html);
         if let Some(root) = &mut self.root {
             *root = base.join(&root);
         }
+        if let Some(root) = &mut self.root_tar {
+            *root = base.join(&root);
+        }
+        for install in self.install.iter_mut().flatten() {
+            install.absolute_paths(base);
+        }
+    }
+
+    /// The filesystem root is either a directory or an archive.
+    fn exclusive_roots(&self) -> Result<(), &'static str> {
+        if self.root.is_some() && self.root_tar.is_some() {
+            return Err("set either this or `filesystem-root`, not both");
+        }
+
+        Ok(())
     }
 }
 
 impl Machine {
+    fn validate(&self, dir: &Path, errors: &mut Vec<ConfigError>) {
+        for (stage, build) in [("stage2", &self.stage2), ("stage3", &self.stage3)] {
+            if let Build::Node { workdir, build } = build {
+                let workdir = dir.join(workdir);
+                expect_path(&format!("Machine.{stage}.workdir"), &workdir, true, errors);
+                // The build script is run from within its working directory.
+                let script = workdir.join(build);
+                expect_path(&format!("Machine.{stage}.build"), &script, false, errors);
+            }
+        }
+
+        let stages = [
+            ("stage2".to_owned(), &self.stage2),
+            ("stage3".to_owned(), &self.stage3),
+        ];
+        let modules = self
+            .modules
+            .iter()
+            .map(|(slot, build)| (format!("modules.{slot}"), build));
+
+        for (key, build) in stages.into_iter().chain(modules) {
+            match build {
+                Build::Prebuilt { path } => {
+                    expect_path(
+                        &format!("Machine.{key}.path"),
+                        &dir.join(path),
+                        false,
+                        errors,
+                    );
+                }
+                Build::Install(install) => {
+                    if let InstallSource::Path { path } = &install.source {
+                        expect_path(
+                            &format!("Machine.{key}.path"),
+                            &dir.join(path),
+                            true,
+                            errors,
+                        );
+                    }
+
+                    // The loader instantiates the module itself, there is no bindgen glue.
+                    if !matches!(install.target, RuntimeTarget::Wasm32Wasip1) {
+                        errors.push(ConfigError::new(
+                            format!("Machine.{key}.target"),
+                            "a boot module must target `wasm32-wasip1`",
+                        ));
+                    }
+
+                    if install.wasm_bindgen.is_some() {
+                        errors.push(ConfigError::new(
+                            format!("Machine.{key}.wasm-bindgen"),
+                            "not supported for a boot module",
+                        ));
+                    }
+                }
+                Build::Rust { .. } | Build::Node { .. } => {}
+            }
+        }
+
+        for slot in self.modules.keys() {
+            if slot.is_empty() || slot.contains('/') {
+                errors.push(ConfigError::new(
+                    format!("Machine.modules.{slot}"),
+                    "expected a name without `/`",
+                ));
+            } else {
+                expect_name(
+                    &format!("Machine.modules.{slot}"),
+                    &Self::module_name(slot),
+                    errors,
+                );
+            }
+        }
+    }
+
+    /// The packed name of the boot module in a slot.
+    pub fn module_name(slot: &str) -> String {
+        format!("boot/{slot}/wah-init.wasm")
+    }
+
     pub fn absolute_paths(&mut self, base: &Path) {
         Self::absolute_build(&mut self.stage2, base);
         Self::absolute_build(&mut self.stage3, base);
+
+        for build in self.modules.values_mut() {
+            Self::absolute_build(build, base);
+        }
     }
 
     fn absolute_build(build: &mut Build, base: &Path) {
         match build {
-            Build::Rust { package: _, bin: _ } => {}
+            Build::Rust { .. } => {}
             Build::Node { workdir, build } => {
                 *workdir = base.join(&workdir);
                 *build = base.join(&build);
             }
+            Build::Prebuilt { path } => {
+                *path = base.join(&path);
+            }
+            Build::Install(install) => install.absolute_paths(base),
         }
     }
 }
@@ -144,9 +705,108 @@ impl ConfiguredPackRoot {
     }
 }
 
+/// Per-file metadata overrides, read from a `.wasi-meta.toml` at the top of a filesystem root.
+///
+/// The keys are paths relative to that root. This restores metadata that the checkout could not
+/// preserve, e.g. the executable bit in a clone on Windows.
+#[derive(Deserialize, Default)]
+#[serde(transparent)]
+pub struct MetadataSidecar {
+    pub files: BTreeMap<String, MetadataOverride>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct MetadataOverride {
+    pub mode: Option<u32>,
+    /// Seconds since the Unix epoch.
+    pub mtime: Option<u64>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl MetadataOverride {
+    /// Whether the numbers fit a tar header, which has a few octal digits for each.
+    pub fn check(&self) -> Result<(), String> {
+        let max_id = html_and_tar::TarHeader::MAX_ID;
+        if let Some(id) = self.uid.into_iter().chain(self.gid).find(|&id| id > max_id) {
+            return Err(format!("has an owner id {id}, above the largest {max_id}"));
+        }
+
+        let max_mtime = html_and_tar::TarHeader::MAX_MTIME;
+        if let Some(mtime) = self.mtime.filter(|&mtime| mtime > max_mtime) {
+            return Err(format!(
+                "has an mtime {mtime}, after the latest {max_mtime}"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl MetadataSidecar {
+    pub const FILE_NAME: &str = ".wasi-meta.toml";
+
+    /// Load the sidecar of a root, no file means no overrides.
+    ///
+    /// The numbers must fit the fields of a tar header, see [`MetadataOverride::check`].
+    pub fn load(root: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = root.join(Self::FILE_NAME);
+        let sidecar: Self = match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err.into()),
+        };
+
+        for (name, meta) in &sidecar.files {
+            if let Err(err) = meta.check() {
+                return Err(format!("{}: `{name}` {err}", path.display()).into());
+            }
+        }
+
+        Ok(sidecar)
+    }
+
+    pub fn apply(&self, name: &str, attributes: &mut EntryAttributes) {
+        let Some(meta) = self.files.get(name) else {
+            return;
+        };
+
+        attributes.mode = meta.mode.or(attributes.mode);
+        attributes.uid = meta.uid.or(attributes.uid);
+        attributes.gid = meta.gid.or(attributes.gid);
+
+        if let Some(secs) = meta.mtime {
+            let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            attributes.mtime = Some(mtime);
+        }
+    }
+}
+
+/// A later insertion point of the template, the element with id
+/// `WAH_POLYGLOT_HTML_PLUS_TAR_CONTENT_<name>`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Insertion {
+    pub name: String,
+    /// Globs of regular files of the filesystem root packed there. The first point with a
+    /// matching glob gets the file.
+    pub files: Vec<String>,
+}
+
+/// A file downloaded at build time and packed under `name`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Remote {
+    pub name: String,
+    pub url: String,
+    /// The expected hex digest of the downloaded bytes, to pin a dependency.
+    pub sha256: Option<String>,
+}
+
 /// Options that you can control. Binaries are installed at the root of the packed directory, and
 /// the target is always `wasm32-wasip1`.
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Install {
     pub package: String,
@@ -166,7 +826,7 @@ pub struct Install {
     pub target: RuntimeTarget,
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub enum RuntimeTarget {
     #[default]
     #[serde(rename = "wasm32-wasip1")]
@@ -179,9 +839,16 @@ impl Install {
     fn r#true() -> bool {
         true
     }
+
+    /// Cargo runs in our working directory, not the one of the configuration.
+    fn absolute_paths(&mut self, base: &Path) {
+        if let InstallSource::Path { path } = &mut self.source {
+            *path = base.join(&path);
+        }
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case", untagged)]
 pub enum InstallSource {
     Git {
@@ -192,19 +859,35 @@ pub enum InstallSource {
     Path {
         path: PathBuf,
     },
-    CratesIo,
+    /// Neither of the others, an empty variant as the flattened fields are a map.
+    CratesIo {},
 }
 
 #[derive(Debug)]
 pub enum Build {
-    Rust { package: String, bin: String },
-    Node { workdir: PathBuf, build: PathBuf },
+    Rust {
+        package: String,
+        bin: String,
+        default_features: bool,
+        features: Vec<String>,
+    },
+    Node {
+        workdir: PathBuf,
+        build: PathBuf,
+    },
+    /// An artifact built elsewhere, packed as it is.
+    Prebuilt {
+        path: PathBuf,
+    },
+    /// A binary installed with `cargo install`, like the `Document.Install` entries.
+    Install(Install),
 }
 
 #[derive(Deserialize)]
 #[serde(tag = "flavor", rename_all = "kebab-case")]
 pub enum BuildStage2 {
     Node { workdir: PathBuf, build: PathBuf },
+    Prebuilt { path: PathBuf },
 }
 
 impl BuildStage2 {
@@ -217,6 +900,7 @@ impl From<BuildStage2> for Build {
     fn from(value: BuildStage2) -> Self {
         match value {
             BuildStage2::Node { workdir, build } => Build::Node { workdir, build },
+            BuildStage2::Prebuilt { path } => Build::Prebuilt { path },
         }
     }
 }
@@ -224,19 +908,55 @@ impl From<BuildStage2> for Build {
 #[derive(Deserialize)]
 #[serde(tag = "flavor", rename_all = "kebab-case")]
 pub enum BuildStage3 {
-    Rust { package: String, bin: String },
+    /// Features are selected like for an [`Install`].
+    #[serde(rename_all = "kebab-case")]
+    Rust {
+        package: String,
+        bin: String,
+        #[serde(default = "Install::r#true")]
+        default_features: bool,
+        #[serde(default)]
+        features: Vec<String>,
+    },
+    Prebuilt {
+        path: PathBuf,
+    },
+    /// From crates.io, a git repository or a path, see [`Install`].
+    Install(Install),
 }
 
 impl BuildStage3 {
     fn deserialize<'de, D: serde::de::Deserializer<'de>>(de: D) -> Result<Build, D::Error> {
         deserialize_into::<D, Build, Self>(de)
     }
+
+    fn deserialize_map<'de, D: serde::de::Deserializer<'de>>(
+        de: D,
+    ) -> Result<BTreeMap<String, Build>, D::Error> {
+        let map = BTreeMap::<String, Self>::deserialize(de)?;
+        Ok(map
+            .into_iter()
+            .map(|(slot, build)| (slot, build.into()))
+            .collect())
+    }
 }
 
 impl From<BuildStage3> for Build {
     fn from(value: BuildStage3) -> Self {
         match value {
-            BuildStage3::Rust { package, bin } => Build::Rust { package, bin },
+            BuildStage3::Rust {
+                package,
+                bin,
+                default_features,
+                features,
+            } => Build::Rust {
+                package,
+                bin,
+                default_features,
+                features,
+            },
+            BuildStage3::Prebuilt { path } => Build::Prebuilt { path },
+            BuildStage3::Install(install) => Build::Install(install),
         }
     }
 }
@@ -249,3 +969,319 @@ where
 {
     B::deserialize(de).map(Into::into)
 }
+
+fn deserialize_js_target<'de, D: serde::de::Deserializer<'de>>(
+    de: D,
+) -> Result<ESTarget, D::Error> {
+    String::deserialize(de)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+#[test]
+fn validate_reports_all_problems() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("WasiDocument.toml");
+
+    std::fs::write(
+        &path,
+        r#"
+[Document]
+index-html = "missing.html"
+
+[Machine]
+stage2 = { flavor = "rust", package = "loader", bin = "loader" }
+stage3 = { flavor = "node", workdir = ".", build = "build.mjs" }
+kernel-name = 'boot/"init"'
+"#,
+    )
+    .unwrap();
+
+    let errors = Configuration::validate(&path).unwrap_err();
+    let keys: Vec<_> = errors.iter().map(|err| err.key.as_str()).collect();
+    assert_eq!(
+        keys,
+        [
+            "Document.index-html",
+            "Machine.stage2",
+            "Machine.stage3",
+            "Machine.kernel-name"
+        ]
+    );
+    assert!(
+        errors[3].to_string().contains(r#"boot/"init""#),
+        "{}",
+        errors[3]
+    );
+}
+
+#[test]
+fn module_slots_are_validated() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("WasiDocument.toml");
+    std::fs::write(dir.path().join("index.html"), "").unwrap();
+
+    std::fs::write(
+        &path,
+        r#"
+[Document]
+index-html = "index.html"
+
+[Machine]
+stage2 = { flavor = "node", workdir = ".", build = "WasiDocument.toml" }
+stage3 = { flavor = "rust", package = "kernel", bin = "kernel" }
+
+[Machine.modules]
+tools = { flavor = "rust", package = "tools", bin = "tools" }
+"a/b" = { flavor = "rust", package = "nested", bin = "nested" }
+'"quoted"' = { flavor = "rust", package = "quoted", bin = "quoted" }
+"#,
+    )
+    .unwrap();
+
+    let errors = Configuration::validate(&path).unwrap_err();
+    let keys: Vec<_> = errors.iter().map(|err| err.key.as_str()).collect();
+    assert_eq!(keys, ["Machine.modules.\"quoted\"", "Machine.modules.a/b"]);
+    assert_eq!(Machine::module_name("tools"), "boot/tools/wah-init.wasm");
+}
+
+#[test]
+fn stage3_features() {
+    let machine: Machine = toml::from_str(
+        r#"
+stage2 = { flavor = "node", workdir = ".", build = "build.mjs" }
+stage3 = { flavor = "rust", package = "kernel", bin = "kernel", default-features = false, features = ["net", "fs"] }
+
+[modules]
+tools = { flavor = "rust", package = "tools", bin = "tools" }
+"#,
+    )
+    .unwrap();
+
+    let Build::Rust {
+        default_features,
+        features,
+        ..
+    } = &machine.stage3
+    else {
+        panic!("stage3 is a rust build");
+    };
+    assert!(!default_features);
+    assert_eq!(features, &["net", "fs"]);
+
+    let Build::Rust {
+        default_features,
+        features,
+        ..
+    } = &machine.modules["tools"]
+    else {
+        panic!("modules are rust builds");
+    };
+    assert!(default_features);
+    assert!(features.is_empty());
+}
+
+#[test]
+fn prebuilt_stages() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("WasiDocument.toml");
+    std::fs::write(dir.path().join("index.html"), "").unwrap();
+    std::fs::write(dir.path().join("stage2.js"), "").unwrap();
+
+    std::fs::write(
+        &path,
+        r#"
+[Document]
+index-html = "index.html"
+
+[Machine]
+stage2 = { flavor = "prebuilt", path = "stage2.js" }
+stage3 = { flavor = "prebuilt", path = "missing.wasm" }
+
+[Machine.modules]
+tools = { flavor = "prebuilt", path = "tools.wasm" }
+"#,
+    )
+    .unwrap();
+
+    let errors = Configuration::validate(&path).unwrap_err();
+    let keys: Vec<_> = errors.iter().map(|err| err.key.as_str()).collect();
+    assert_eq!(keys, ["Machine.stage3.path", "Machine.modules.tools.path"]);
+
+    let mut machine: Machine = toml::from_str(
+        r#"
+stage2 = { flavor = "prebuilt", path = "stage2.js" }
+stage3 = { flavor = "prebuilt", path = "stage3.wasm" }
+"#,
+    )
+    .unwrap();
+    machine.absolute_paths(dir.path());
+
+    let Build::Prebuilt { path } = &machine.stage3 else {
+        panic!("stage3 is prebuilt");
+    };
+    assert_eq!(path, &dir.path().join("stage3.wasm"));
+}
+
+#[test]
+fn installed_stages() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("WasiDocument.toml");
+    std::fs::write(dir.path().join("index.html"), "").unwrap();
+
+    std::fs::write(
+        &path,
+        r#"
+[Document]
+index-html = "index.html"
+
+[Machine]
+stage2 = { flavor = "prebuilt", path = "index.html" }
+stage3 = { flavor = "install", package = "fidget-cli", git = "https://github.com/mkeeter/fidget", default-features = false }
+
+[Machine.modules]
+local = { flavor = "install", package = "tools", path = "missing" }
+web = { flavor = "install", package = "web", target = "wasm32-unknown-unknown", wasm-bindgen = "web" }
+"#,
+    )
+    .unwrap();
+
+    let errors = Configuration::validate(&path).unwrap_err();
+    let keys: Vec<_> = errors.iter().map(|err| err.key.as_str()).collect();
+    assert_eq!(
+        keys,
+        [
+            "Machine.modules.local.path",
+            "Machine.modules.web.target",
+            "Machine.modules.web.wasm-bindgen",
+        ]
+    );
+
+    let mut machine: Machine = toml::from_str(
+        r#"
+stage2 = { flavor = "prebuilt", path = "stage2.js" }
+stage3 = { flavor = "install", package = "kernel", bin = "init", path = "kernel" }
+"#,
+    )
+    .unwrap();
+    machine.absolute_paths(dir.path());
+
+    let Build::Install(install) = &machine.stage3 else {
+        panic!("stage3 is installed");
+    };
+    assert_eq!(install.bin.as_deref(), Some("init"));
+    assert!(install.default_features);
+    let InstallSource::Path { path } = &install.source else {
+        panic!("stage3 is installed from a path");
+    };
+    assert_eq!(path, &dir.path().join("kernel"));
+}
+
+#[test]
+fn output_names_are_expanded() {
+    let tokens = [
+        ("name", "demo"),
+        ("date", "2024-02-29"),
+        ("hash", "0123abcd"),
+    ];
+
+    assert_eq!(
+        expand_output_name("{name}-{hash}.html", &tokens).unwrap(),
+        "demo-0123abcd.html"
+    );
+    assert_eq!(
+        expand_output_name("{name}-{date}.html", &tokens).unwrap(),
+        "demo-2024-02-29.html"
+    );
+    assert_eq!(
+        expand_output_name("wasi.html", &tokens).unwrap(),
+        "wasi.html"
+    );
+    assert!(expand_output_name("{version}.html", &tokens).is_err());
+    assert!(expand_output_name("{name.html", &tokens).is_err());
+}
+
+#[test]
+fn filesystem_roots_are_exclusive() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("WasiDocument.toml");
+    std::fs::write(dir.path().join("index.html"), "").unwrap();
+    std::fs::create_dir(dir.path().join("root")).unwrap();
+    std::fs::write(dir.path().join("root.tar"), [0; 1024]).unwrap();
+
+    let project = |document: &str| {
+        format!(
+            r#"
+[Document]
+index-html = "index.html"
+{document}
+
+[Machine]
+stage2 = {{ flavor = "node", workdir = ".", build = "WasiDocument.toml" }}
+stage3 = {{ flavor = "rust", package = "kernel", bin = "kernel" }}
+"#
+        )
+    };
+
+    let contents = project(r#"filesystem-root-tar = "root.tar""#);
+    let configuration = Configuration::from_toml(&contents, dir.path()).unwrap();
+    assert_eq!(
+        configuration.document.root_tar.as_deref(),
+        Some(dir.path().join("root.tar").as_path())
+    );
+
+    let contents = project("filesystem-root = \"root\"\nfilesystem-root-tar = \"root.tar\"");
+    assert!(Configuration::from_toml(&contents, dir.path()).is_err());
+
+    std::fs::write(&path, contents).unwrap();
+    let errors = Configuration::validate(&path).unwrap_err();
+    let keys: Vec<_> = errors.iter().map(|err| err.key.as_str()).collect();
+    assert_eq!(keys, ["Document.filesystem-root-tar"]);
+}
+
+#[test]
+fn root_filter_excludes_before_including() {
+    let document = |include: &[&str], exclude: &[&str]| {
+        let contents =
+            format!("index-html = \"index.html\"\ninclude = {include:?}\nexclude = {exclude:?}");
+        toml::from_str::<Document>(&contents)
+            .unwrap()
+            .root_filter()
+            .unwrap()
+    };
+
+    let filter = document(&[], &["**/*.log", "**/.git"]);
+    assert!(filter.is_packed("etc/motd"));
+    assert!(!filter.is_packed("build.log"));
+    assert!(!filter.is_packed("var/log/boot.log"));
+    assert!(!filter.is_packed(".git"));
+    assert!(!filter.is_packed("vendor/lib/.git/config"));
+    assert!(filter.is_packed("logs"));
+
+    let filter = document(&["www/**", "**/*.log"], &["**/*.log"]);
+    assert!(filter.is_packed("www/index.js"));
+    assert!(!filter.is_packed("www/access.log"));
+    assert!(!filter.is_packed("etc/motd"));
+}
+
+#[test]
+fn sidecar_values_fit_the_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let sidecar = dir.path().join(MetadataSidecar::FILE_NAME);
+
+    std::fs::write(&sidecar, "[\"bin/tool\"]\nuid = 1000\nmtime = 0\n").unwrap();
+    assert!(MetadataSidecar::load(dir.path()).is_ok());
+
+    // Above seven octal digits, the nul of the field then holds the eighth.
+    std::fs::write(&sidecar, "[\"bin/tool\"]\ngid = 2097152\n").unwrap();
+    assert!(MetadataSidecar::load(dir.path()).is_ok());
+
+    std::fs::write(&sidecar, "[\"bin/tool\"]\ngid = 16777216\n").unwrap();
+    let Err(err) = MetadataSidecar::load(dir.path()) else {
+        panic!("a gid above eight octal digits loads");
+    };
+    assert!(err.to_string().contains("`bin/tool`"), "{err}");
+
+    std::fs::write(&sidecar, "[\"bin/tool\"]\nmtime = 18446744073709551615\n").unwrap();
+    assert!(MetadataSidecar::load(dir.path()).is_err());
+}
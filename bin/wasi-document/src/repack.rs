@@ -0,0 +1,59 @@
+//! The `repack` subcommand, a new tar structure for a document that was modified as a DOM.
+use std::path::Path;
+
+use wasi_document::tar;
+use wasi_document_dom as dom;
+
+use crate::pack::{Work, write_document};
+
+pub fn rebuild_wasm(
+    project: &Work,
+    file: &Path,
+    stage0: Option<&[u8]>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let source = std::fs::read_to_string(file)?;
+    let mut source = dom::SourceDocument::new(&source);
+    let mut entries = source.split_tar_contents()?;
+
+    let packer = crate::webpack::Packer::from_root(&[]);
+
+    for item in &mut entries {
+        packer.process(item)?;
+    }
+
+    let wasm = repack(&mut source, &entries, stage0)?;
+    write_document(project, &wasm)?;
+    Ok(())
+}
+
+/// Pack extracted files into their stripped document again, with a fresh tar structure.
+///
+/// Without a new stage0 the document keeps its own.
+pub fn repack(
+    source: &mut dom::SourceDocument,
+    entries: &[dom::TarEntryOwned],
+    stage0: Option<&[u8]>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let files = entries.iter().flat_map(|entry| {
+        if let Some(entry) = entry.as_html_and_tar_entry() {
+            Some(tar::TarItem::Entry(entry))
+        } else if let Some(device) = entry.as_html_and_tar_device() {
+            Some(tar::TarItem::Device(device))
+        } else if let Some(directory) = entry.as_html_and_tar_directory() {
+            Some(tar::TarItem::Directory(directory))
+        } else if let Some(symlink) = entry.as_html_and_tar_symlink() {
+            Some(tar::TarItem::Symlink(symlink))
+        } else {
+            entry.as_html_and_tar_external().map(tar::TarItem::External)
+        }
+    });
+
+    tar::build(
+        source,
+        move |push| {
+            files.into_iter().for_each(push);
+            Ok::<_, Box<dyn std::error::Error>>(())
+        },
+        stage0,
+    )
+}
@@ -0,0 +1,41 @@
+//! The `repair` subcommand, a document saved by a browser packed again.
+
+use wasi_document_dom as dom;
+
+use crate::repack::repack;
+
+/// Extract all files of a document that may have been mangled by a browser and pack them again,
+/// so the next save starts from a canonical document.
+pub fn repair_document(document: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut source = dom::SourceDocument::new(document);
+    let entries = source.split_tar_contents()?;
+    repack(&mut source, &entries, None)
+}
+
+#[test]
+fn repair_undoes_browser_mangling() {
+    let files: &[(&str, &[u8])] = &[
+        ("boot/wah-init.wasm", b"\0asm\x01\0\0\0"),
+        ("etc/motd", b"Hello, world!\n"),
+        ("large", &[0x5a; 4097]),
+    ];
+
+    let packed = crate::pack::pack_fixture(files);
+    let expected: std::collections::BTreeMap<_, _> = files
+        .iter()
+        .map(|&(name, data)| (name.to_owned(), data.to_vec()))
+        .collect();
+
+    for (idx, mangle) in wasi_document::tar::CHROMIUM_MANGLINGS.iter().enumerate() {
+        let repaired = repair_document(&mangle(packed.clone())).unwrap();
+
+        // The tar structure is intact again, not only the elements.
+        let entries = html_and_tar::TarDecompiler::list(&repaired).unwrap();
+        let extracted = crate::extract::extract_matching(&repaired, &entries, &|_| true).unwrap();
+        assert_eq!(extracted, expected, "mangling {idx}");
+
+        // A canonical document comes out of a repair as it went in.
+        let again = repair_document(std::str::from_utf8(&repaired).unwrap()).unwrap();
+        assert!(again == repaired, "mangling {idx}");
+    }
+}
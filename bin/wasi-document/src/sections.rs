@@ -0,0 +1,65 @@
+//! The `sections` subcommand, the sections of the boot module embedded in a document.
+use std::{io::Write, path::Path};
+
+use wasi_document_dom as dom;
+
+pub fn list_sections(
+    file: &Path,
+    kernel_name: &str,
+    dump: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let document = std::fs::read_to_string(file)?;
+    let mut source = dom::SourceDocument::new(&document);
+    let entries = source.split_tar_contents()?;
+
+    let kernel = &entries[find_boot_module(&entries, kernel_name)?];
+    let wasm = kernel.as_html_and_tar_entry().unwrap().data;
+
+    let mut stdout = std::io::stdout().lock();
+    let mut dumped = false;
+
+    for payload in wasmparser::Parser::default().parse_all(wasm) {
+        let payload = payload?;
+
+        let Some((id, range)) = payload.as_section() else {
+            continue;
+        };
+
+        let wasmparser::Payload::CustomSection(reader) = &payload else {
+            if dump.is_none() {
+                writeln!(stdout, "{id:>2} {:>10}", range.len())?;
+            }
+
+            continue;
+        };
+
+        match dump {
+            None => writeln!(stdout, "{id:>2} {:>10} {}", range.len(), reader.name())?,
+            Some(name) if name == reader.name() => {
+                stdout.write_all(reader.data())?;
+                dumped = true;
+            }
+            Some(_) => {}
+        }
+    }
+
+    match dump {
+        Some(name) if !dumped => Err(format!("Boot module has no `{name}` section").into()),
+        _ => Ok(()),
+    }
+}
+
+/// The index of the boot module among the extracted entries of a document.
+pub fn find_boot_module(
+    entries: &[dom::TarEntryOwned],
+    kernel_name: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    entries
+        .iter()
+        .position(|entry| {
+            entry
+                .as_html_and_tar_entry()
+                .is_some_and(|entry| entry.name.0 == kernel_name)
+        })
+        .ok_or_else(|| format!("No boot module `{kernel_name}` in the document").into())
+}
@@ -0,0 +1,96 @@
+//! The `selftest` subcommand, a fixture packed in memory and extracted again.
+
+use html_and_tar::HtmlAttributeSafeName;
+use wasi_document::tar;
+use wasi_document_dom as dom;
+
+use crate::{
+    extract::extract_matching,
+    pack::{BOOT_KERNEL_NAME, stage0_script},
+};
+
+/// Round trip a fixture through the engine, to check that the tool works in this environment.
+pub fn self_test() -> Result<(), Box<dyn std::error::Error>> {
+    const TEMPLATE: &str = include_str!("fixture.html");
+
+    let binary: Vec<u8> = (0..=255).collect();
+    let files: &[(&str, &[u8])] = &[
+        ("etc/motd", b"Hello, world!\n"),
+        ("etc/empty", b""),
+        ("usr/lib/bytes", &binary),
+    ];
+
+    let stage0 = stage0_script(BOOT_KERNEL_NAME);
+    let mut source = dom::SourceDocument::new(TEMPLATE);
+    let document = tar::build(
+        &mut source,
+        |push| {
+            for &(name, data) in files {
+                push(tar::TarItem::Entry(html_and_tar::Entry {
+                    name: HtmlAttributeSafeName::new(name)?,
+                    data,
+                    attributes: Default::default(),
+                    compression: None,
+                }));
+            }
+
+            Ok::<_, Box<dyn std::error::Error>>(())
+        },
+        Some(stage0.as_bytes()),
+    )?;
+
+    let expected: std::collections::BTreeMap<String, Vec<u8>> = files
+        .iter()
+        .map(|&(name, data)| (name.to_string(), data.to_vec()))
+        .collect();
+
+    let by_tar = || -> Result<_, Box<dyn std::error::Error>> {
+        let entries = html_and_tar::TarDecompiler::list(&document)?;
+        extract_matching(&document, &entries, &|_| true)
+    };
+
+    let by_dom = || -> Result<_, Box<dyn std::error::Error>> {
+        let text = std::str::from_utf8(&document)?;
+        let mut doc = dom::SourceDocument::new(text);
+        let mut files = std::collections::BTreeMap::new();
+
+        for entry in doc.split_tar_contents()? {
+            let entry = entry
+                .as_html_and_tar_entry()
+                .ok_or("a packed file came back without data")?;
+            files.insert(entry.name.0.to_string(), entry.data.to_vec());
+        }
+
+        Ok(files)
+    };
+
+    let checks: [(&str, &dyn Fn() -> Result<_, _>); 2] =
+        [("tar structure", &by_tar), ("html dom", &by_dom)];
+    let mut failed = 0;
+
+    for (what, check) in checks {
+        match check() {
+            Ok(files) if files == expected => println!("{what}: ok"),
+            Ok(_) => {
+                println!("{what}: FAILED, the files differ");
+                failed += 1;
+            }
+            Err(err) => {
+                println!("{what}: FAILED, {err}");
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{failed} of {} checks failed", checks.len()).into());
+    }
+
+    println!("selftest: ok, {} bytes packed", document.len());
+    Ok(())
+}
+
+#[test]
+fn selftest_passes() {
+    self_test().unwrap();
+}
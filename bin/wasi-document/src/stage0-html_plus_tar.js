@@ -19,6 +19,7 @@
 // State object, introspectable for now.
 let __wah_stage0_global = {};
 const BOOT = 'boot/wah-init.wasm';
+const MODULES = 'boot/modules.json';
 
 function b64_decode(b64, options={}) {
   // Effectively a static, since calls share the default argument object.
@@ -70,12 +71,22 @@ window.addEventListener('load', async function() {
   global.file_objects = [];
   global.file_data = {};
 
+  function santize_bytes_until_nul(str) {
+    return str.replaceAll(String.fromCodePoint(0xfffd), '\0').replace(/\0.*$/, '');
+  }
+
+  // The full name announced by a GNU long name element for the next one.
+  let pendingLongName = null;
+  // Hard links by their index in `file_objects`, resolved once all patches
+  // are applied so that a later patch of the target shows through the link.
+  const links = [];
+
   for (let el of dataElements) {
-    const givenName = el.getAttribute('data-wahtml_id')
+    const fieldName = el.getAttribute('data-wahtml_id')
       ?.replaceAll(String.fromCodePoint(0xfffd), '')
       ?.replaceAll(String.fromCodePoint(0), '');
 
-    if (givenName === null) {
+    if (fieldName === null) {
       continue;
     }
 
@@ -98,8 +109,6 @@ window.addEventListener('load', async function() {
     let b64content = el.textContent.replace(/^[^0-9a-zA-Z+\/]*/, "");
     let trimBack = b64content.slice(-2048, b64content.length).replace(/^[0-9a-zA-Z+\/=]*/, "").length;
     b64content = b64content.slice(0, -trimBack);
-    const raw_content = b64_decode(b64content);
-    global.file_data[givenName] = raw_content;
 
     // The `TarHeader` contents except for the name (first field), so at an
     // offset 100 bytes into the header. Note: offsets are dependent on the
@@ -108,15 +117,106 @@ window.addEventListener('load', async function() {
     // offsets are the same.
     const file_header = el.getAttribute('data-b');
 
-    if (b64content.length != parseInt(file_header.slice(24, 36), 8)) {
+    // A GNU long name, typeflag 'L'. Its text is the full name of the next
+    // element, which only has a truncated one in its header. The text starts
+    // with the nul bytes at the end of the header.
+    if (file_header.charCodeAt(56) === 76) {
+      const text = el.textContent.replace(/^[\0\ufffd]*/, '');
+      pendingLongName = santize_bytes_until_nul(text);
+      continue;
+    }
+
+    // Names too long for their field continue at the start of the ustar
+    // `prefix` at offset 345, split at a `/`.
+    const prefix = santize_bytes_until_nul(file_header.slice(245, 269));
+    const givenName = pendingLongName ?? (prefix ? `${prefix}/${fieldName}` : fieldName);
+    pendingLongName = null;
+    const encoded_size = parseInt(file_header.slice(24, 36), 8);
+    // Data we wrapped ourselves has a line break after every `line_width`
+    // characters except on the last line. The size counts those.
+    const line_width = parseInt(file_header.slice(281, 289), 8) || 0;
+    const base64_size = line_width
+      ? encoded_size - Math.floor(encoded_size / (line_width + 1))
+      : encoded_size;
+
+    // The size counts the base64 bytes as written. If a browser wrapped the
+    // text while saving, or we did, we take the slow path and strip everything
+    // that is not base64, which never contains whitespace.
+    if (b64content.length != encoded_size || line_width) {
+      b64content = el.textContent.replace(/[^0-9a-zA-Z+\/=]/g, "");
+    }
+
+    if (b64content.length != base64_size) {
       console.log(givenName, el);
       throw 'Bad file';
     }
 
-    function santize_bytes_until_nul(str) {
-      return str.replaceAll(String.fromCodePoint(0xfffd), '\0').replace(/\0.*$/, '');
+    let raw_content = b64_decode(b64content);
+
+    // Data compressed before the encoding names its format at offset 389,
+    // such as `gzip`. Offsets of parts and patches are those of the inflated
+    // file, so it is inflated first.
+    const compression = santize_bytes_until_nul(file_header.slice(289, 297));
+
+    if (compression) {
+      const inflated = new Blob([raw_content]).stream()
+        .pipeThrough(new DecompressionStream(compression));
+      raw_content = new Uint8Array(await new Response(inflated).arrayBuffer());
     }
 
+    // A continuation part of a large file that was split over several
+    // elements, typeflag 'M'. Its data follows the previous part in order.
+    if (file_header.charCodeAt(56) === 77) {
+      const previous = global.file_objects[global.file_objects.length - 1];
+      const offset = parseInt(file_header.slice(269, 281), 8);
+
+      if (previous?.header.name !== givenName || previous.data.length !== offset) {
+        console.log(givenName, el);
+        throw 'Bad file part';
+      }
+
+      const joined = new Uint8Array(offset + raw_content.length);
+      joined.set(previous.data);
+      joined.set(raw_content, offset);
+      previous.data = joined;
+      global.file_data[givenName] = joined;
+      continue;
+    }
+
+    // A patch to a file from earlier in the document, typeflag 'P'. Its data
+    // is written over the file at the offset, extending it if necessary.
+    if (file_header.charCodeAt(56) === 80) {
+      const base = global.file_objects.findLast(obj => obj.header.name === givenName);
+      const offset = parseInt(file_header.slice(269, 281), 8);
+
+      if (base === undefined || offset > base.data.length) {
+        console.log(givenName, el);
+        throw 'Bad file patch';
+      }
+
+      const length = Math.max(base.data.length, offset + raw_content.length);
+      const patched = new Uint8Array(length);
+      patched.set(base.data);
+      patched.set(raw_content, offset);
+      base.data = patched;
+      global.file_data[givenName] = patched;
+      continue;
+    }
+
+    // A hard link to a file from earlier in the document, typeflag '1'. It
+    // has no data of its own, we present it as a regular file with the data
+    // of its target.
+    let typeflag = file_header?.charCodeAt(56) || 0;
+    const content = raw_content;
+
+    if (typeflag === 49) {
+      const target = santize_bytes_until_nul(file_header.slice(57, 157));
+      links.push({ index: global.file_objects.length, target, el });
+      typeflag = 48;
+    }
+
+    global.file_data[givenName] = content;
+
     // Note we do not attach the DOM element here. We want a clean, pure memory
     // representation of the file system tree here. (That we can send to a
     // worker).
@@ -132,17 +232,47 @@ window.addEventListener('load', async function() {
         gid: file_header ? parseInt(file_header.slice(16, 24), 8) : 0,
         size: file_header ? parseInt(file_header.slice(24, 36), 8) : 0,
         mtime: file_header ? parseInt(file_header.slice(36, 48), 8) : 0,
-        typeflag: file_header?.charCodeAt(56) || 0,
+        typeflag: typeflag,
         linkname: file_header ? santize_bytes_until_nul(file_header.slice(57, 157)) : '',
         uname: file_header ? santize_bytes_until_nul(file_header.slice(165, 197)) : '',
         gname: file_header ? santize_bytes_until_nul(file_header.slice(197, 229)) : '',
       },
-      data: raw_content,
+      data: content,
       element: el,
     });
   }
 
-  const boot_wasm_bytes = global.file_data[BOOT];
+  for (const { index, target, el } of links) {
+    const link = global.file_objects[index];
+    const base = global.file_objects.slice(0, index).findLast(obj => obj.header.name === target);
+
+    if (base === undefined) {
+      console.log(link.header.name, el);
+      throw 'Bad file link';
+    }
+
+    link.data = base.data;
+
+    if (global.file_objects.findLast(obj => obj.header.name === link.header.name) === link) {
+      global.file_data[link.header.name] = base.data;
+    }
+  }
+
+  // A document can carry more boot modules, listed in a manifest by their
+  // slot. The URL fragment `#module=<slot>` boots one of them instead.
+  let boot = BOOT;
+  const slot = new URLSearchParams(location.hash.slice(1)).get('module');
+
+  if (slot !== null && global.file_data[MODULES] !== undefined) {
+    const manifest = JSON.parse(new TextDecoder().decode(global.file_data[MODULES]));
+    boot = manifest.modules[slot] ?? boot;
+
+    if (boot === BOOT) {
+      console.warn('Wasm-As-HTML bootstrapping stage-0: no module', slot, manifest);
+    }
+  }
+
+  const boot_wasm_bytes = global.file_data[boot];
 
   if (boot_wasm_bytes === undefined) {
     console.debug('Wasm-As-HTML bootstrapping stage-0: no handoff to boot, done');
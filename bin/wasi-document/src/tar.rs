@@ -1,23 +1,497 @@
-use html_and_tar::{Entry, External, TarEngine};
+use std::{io::Write, ops::Range};
+
+use html_and_tar::{
+    Device, Directory, EncodedEntry, Entry, EscapedData, External, HtmlAttributeSafeName, Link,
+    Patch, Symlink, TarEngine,
+};
 use wasi_document_dom as dom;
 
 pub enum TarItem<'data> {
     Entry(Entry<'data>),
+    /// A file encoded by the caller, with the line width of [`Options`]. It is not split into
+    /// parts, only files up to [`MAX_PART_SIZE`] should be encoded ahead.
+    Encoded(EncodedEntry<'data>),
     External(External<'data>),
+    Link(Link<'data>),
+    Symlink(Symlink<'data>),
+    Device(Device<'data>),
+    Directory(Directory<'data>),
+    /// A change to a file packed earlier, usually appended to a document, see [`append`].
+    Patch(Patch<'data>),
+    /// Continue with the next items at a later insertion point of the template, by its name.
+    ///
+    /// The HTML up to it is skipped by the tar structure, see [`TarEngine::escaped_end`]. Points
+    /// must be used in document order, those passed over are dropped. At least one item must
+    /// come before, the skip is only possible from within escaped data.
+    Insertion(&'data str),
+}
+
+/// Names by which the loader and the extraction recognize our elements.
+const MARKERS: &[&str] = &[
+    "wah_polyglot_data",
+    "wah_polyglot_skip",
+    "WAH_POLYGLOT_HTML_PLUS_TAR",
+];
+
+/// The parts of the document outside our own tags which we copy unchanged.
+fn raw_regions(source: &dom::SourceDocument, structure: &dom::Structure) -> Vec<Range<usize>> {
+    let html = source.span(structure.html_tag);
+    let insert = source.span(structure.insertion_tag);
+    let stage0 = source.span(structure.stage0);
+    let continued = continued_insertions(source, structure);
+
+    let mut regions = html_between(insert.end..stage0.start, &continued);
+    regions.insert(0, html.start + structure.html_insertion_point..insert.start);
+    regions.extend(html_between(stage0.end..source[..].len(), &continued));
+    regions
+}
+
+/// The later insertion points of the template with their byte ranges.
+fn continued_insertions(
+    source: &dom::SourceDocument,
+    structure: &dom::Structure,
+) -> Vec<(String, Range<usize>)> {
+    structure
+        .continued_insertions
+        .iter()
+        .map(|(name, span)| (name.clone(), source.span(*span)))
+        .collect()
 }
 
+/// The HTML in a range, without the insertion points within it.
+fn html_between(range: Range<usize>, points: &[(String, Range<usize>)]) -> Vec<Range<usize>> {
+    let mut pieces = vec![];
+    let mut start = range.start;
+
+    for (_, point) in points
+        .iter()
+        .filter(|(_, point)| range.contains(&point.start))
+    {
+        pieces.push(start..point.start);
+        start = point.end;
+    }
+
+    pieces.push(start..range.end);
+    pieces.retain(|piece| !piece.is_empty());
+    pieces
+}
+
+/// Find mentions of our markers in the regions of the document we copy unchanged.
+fn marker_collisions(text: &str, regions: &[Range<usize>]) -> Vec<(usize, &'static str)> {
+    let mut found = vec![];
+
+    for region in regions {
+        let raw = &text[region.clone()];
+
+        for &marker in MARKERS {
+            found.extend(
+                raw.match_indices(marker)
+                    .map(|(idx, _)| (region.start + idx, marker)),
+            );
+        }
+    }
+
+    found.sort();
+    found
+}
+
+/// Whether a `<script>` element has no code.
+fn is_empty_script(element: &str) -> bool {
+    let body = element.split_once('>').map_or("", |(_, rest)| {
+        rest.rsplit_once("</").map_or(rest, |(body, _)| body)
+    });
+    body.trim().is_empty()
+}
+
+/// Check that a stage0 script stays within the element we wrap it in.
+///
+/// We insert it as the text of `<script id=WAH_POLYGLOT_HTML_PLUS_TAR_STAGE0>`, by which a later
+/// repack finds it again. A `</script` in the code would end the element early, and a `<!--` can
+/// make the parser skip our closing tag. A whole `<script>` element passed instead of its code
+/// ends up nested in ours, and fails the same way.
+fn validate_stage0(script: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let code = std::str::from_utf8(script).map_err(|err| format!("The stage0 script is {err}"))?;
+    let lower = code.to_ascii_lowercase();
+
+    for (needle, shown) in [("</script", "`</script`"), ("<!--", "`<!--`")] {
+        if let Some(offset) = lower.find(needle) {
+            return Err(format!(
+                "The stage0 script contains {shown} at byte {offset}, which breaks its element. \
+                Pass the code alone, without `<script>` tags, and escape it in strings as `<\\/`"
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the parts of a template that a browser's Save As is known to break, with advice.
+///
+/// Besides the lints of the DOM, this checks that the head fits into the first tar header with
+/// the doctype the builder would insert.
+pub fn lint_browser_compat(
+    template: &str,
+    options: &Options,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut lints = dom::SourceDocument::new(template).browser_compat_lints()?;
+
+    // The builder measures the head after synthesizing its own tags, so do the same.
+    let mut source = dom::SourceDocument::new(template);
+    let structure = source.prepare_tar_structure()?;
+    let html = source.span(structure.html_tag);
+    let head = &source[0..html.start + structure.html_insertion_point];
+
+    let mut engine = TarEngine::default();
+    if let Some(doctype) = options.doctype {
+        engine.set_doctype(doctype)?;
+    }
+
+    let len = engine.head_len(head.as_bytes());
+    if len > TarEngine::MAX_HEAD_LEN {
+        lints.push(format!(
+            "the document up to the end of its `<html>` tag is {len} bytes, only {} fit into the \
+            first tar header. Move attributes of `<html>` to `<body>` or drop them.",
+            TarEngine::MAX_HEAD_LEN
+        ));
+    }
+
+    Ok(lints)
+}
+
+/// The most data of a file we put into one element, larger files are split into parts.
+///
+/// Browsers cap the length of strings, around 512 MiB for V8. This leaves plenty of room for the
+/// base64 expansion of a third.
+pub const MAX_PART_SIZE: usize = 96 << 20;
+
 pub fn build<E>(
     source: &mut dom::SourceDocument,
     elements: impl FnOnce(&mut dyn FnMut(TarItem<'_>)) -> Result<(), E>,
     script: Option<&[u8]>,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+where
+    Box<dyn std::error::Error>: From<E>,
+{
+    let options = Options::default();
+    let (document, _) =
+        build_with_part_size(source, elements, script, &options, &mut (), MAX_PART_SIZE)?;
+    Ok(document)
+}
+
+/// How the engine writes the document, the defaults of `TarEngine` unless set.
+#[derive(Default)]
+pub struct Options<'a> {
+    /// Put in front of a template without one, see [`TarEngine::set_doctype`].
+    pub doctype: Option<&'a str>,
+    /// Wrap the base64 data, see [`TarEngine::set_line_width`].
+    pub line_width: Option<std::num::NonZeroUsize>,
+    /// Leave out the tar EOF, see [`TarEngine::escaped_open_end`].
+    pub open_ended: bool,
+}
+
+/// Like [`build`] but also return where each piece ended up in the document, and report each
+/// packed file and the end to `progress`. Pass `()` to ignore the progress.
+pub fn build_with_progress<E>(
+    source: &mut dom::SourceDocument,
+    elements: impl FnOnce(&mut dyn FnMut(TarItem<'_>)) -> Result<(), E>,
+    script: Option<&[u8]>,
+    options: &Options,
+    mut progress: impl PackProgress,
+) -> Result<(Vec<u8>, Layout), Box<dyn std::error::Error>>
+where
+    Box<dyn std::error::Error>: From<E>,
+{
+    build_with_part_size(
+        source,
+        elements,
+        script,
+        options,
+        &mut progress,
+        MAX_PART_SIZE,
+    )
+}
+
+/// Like [`build`] but write the document to `out` while it is built, instead of holding all of it
+/// in memory. Only the data of one file is kept at a time. Also returns where each piece ended up
+/// in the document, and reports each packed file and the end to `progress`.
+pub fn build_to_writer<E>(
+    source: &mut dom::SourceDocument,
+    elements: impl FnOnce(&mut dyn FnMut(TarItem<'_>)) -> Result<(), E>,
+    script: Option<&[u8]>,
+    options: &Options,
+    mut progress: impl PackProgress,
+    out: &mut dyn Write,
+) -> Result<Layout, Box<dyn std::error::Error>>
+where
+    Box<dyn std::error::Error>: From<E>,
+{
+    build_into(
+        source,
+        elements,
+        script,
+        options,
+        &mut progress,
+        MAX_PART_SIZE,
+        out,
+    )
+}
+
+/// Observes the builder, for example to drive a progress bar. Does nothing by default.
+pub trait PackProgress {
+    /// A file was packed, with the size of its data before encoding.
+    ///
+    /// Outlined files report the size of their external data, links and devices report zero.
+    fn on_file(&mut self, name: &str, bytes: u64) {
+        let _ = (name, bytes);
+    }
+
+    /// The document is complete, `total` bytes long.
+    fn on_finish(&mut self, total: u64) {
+        let _ = total;
+    }
+}
+
+impl PackProgress for () {}
+
+/// Report to a progress that the caller looks at again after the build.
+impl<P: PackProgress + ?Sized> PackProgress for &mut P {
+    fn on_file(&mut self, name: &str, bytes: u64) {
+        P::on_file(self, name, bytes);
+    }
+
+    fn on_finish(&mut self, total: u64) {
+        P::on_finish(self, total);
+    }
+}
+
+/// The byte ranges of the pieces of a built document, in order.
+#[derive(Default)]
+pub struct Layout {
+    pub regions: Vec<(String, Range<usize>)>,
+}
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (label, range) in &self.regions {
+            writeln!(
+                f,
+                "{:>10}..{:<10} {:>10} bytes  {label}",
+                range.start,
+                range.end,
+                range.len()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn build_with_part_size<E>(
+    source: &mut dom::SourceDocument,
+    elements: impl FnOnce(&mut dyn FnMut(TarItem<'_>)) -> Result<(), E>,
+    script: Option<&[u8]>,
+    options: &Options,
+    progress: &mut dyn PackProgress,
+    part_size: usize,
+) -> Result<(Vec<u8>, Layout), Box<dyn std::error::Error>>
+where
+    Box<dyn std::error::Error>: From<E>,
+{
+    let mut document = vec![];
+    let layout = build_into(
+        source,
+        elements,
+        script,
+        options,
+        progress,
+        part_size,
+        &mut document,
+    )?;
+
+    Ok((document, layout))
+}
+
+impl<'data> TarItem<'data> {
+    /// The name of the file and the size of its data before encoding, none for insertion points.
+    fn name_and_size(&self) -> Option<(HtmlAttributeSafeName<'data>, u64)> {
+        Some(match self {
+            TarItem::Entry(entry) => (entry.name, entry.data.len() as u64),
+            TarItem::Encoded(entry) => (entry.name, entry.realsize),
+            TarItem::External(external) => (external.name, external.realsize),
+            TarItem::Link(link) => (link.name, 0),
+            TarItem::Symlink(symlink) => (symlink.name, 0),
+            TarItem::Device(device) => (device.name, 0),
+            TarItem::Directory(directory) => (directory.name, 0),
+            TarItem::Patch(patch) => (patch.name, patch.data.len() as u64),
+            TarItem::Insertion(_) => return None,
+        })
+    }
+}
+
+/// Write the escaped entries of a file, after a long name entry if its name needs one.
+fn push_item(
+    engine: &mut TarEngine,
+    sink: &mut Sink,
+    name: HtmlAttributeSafeName,
+    item: TarItem<'_>,
+    part_size: usize,
+) {
+    // Entries announce a long name per part themselves.
+    if !matches!(item, TarItem::Entry(_))
+        && let Some(long_name) = engine.escaped_long_name(name)
+    {
+        sink.push_escaped(&long_name);
+    }
+
+    match item {
+        TarItem::Entry(entry) => {
+            for part in engine.escaped_base64_parts(entry, part_size) {
+                sink.push_escaped(&part);
+            }
+        }
+        TarItem::Encoded(entry) => {
+            sink.push_escaped(&engine.escaped_encoded(entry));
+        }
+        TarItem::External(external) => {
+            sink.push_escaped(&engine.escaped_external(external));
+        }
+        TarItem::Link(link) => {
+            sink.push_escaped(&engine.escaped_link(link));
+        }
+        TarItem::Symlink(symlink) => {
+            sink.push_escaped(&engine.escaped_symlink(symlink));
+        }
+        TarItem::Device(device) => {
+            sink.push_escaped(&engine.escaped_device(device));
+        }
+        TarItem::Directory(directory) => {
+            sink.push_escaped(&engine.escaped_directory(directory));
+        }
+        TarItem::Patch(patch) => {
+            sink.push_escaped(&engine.escaped_patch(patch));
+        }
+        TarItem::Insertion(_) => unreachable!("insertion points are continued by the builder"),
+    }
+}
+
+/// Append files to a document we built, in place of the end of its archive.
+///
+/// Everything else of the document stays as it is, an open-ended archive is left open again. The
+/// template is not known anymore, so insertion points can not be continued and the data is not
+/// wrapped into lines.
+pub fn append<'data>(
+    document: &[u8],
+    items: impl IntoIterator<Item = TarItem<'data>>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let end = html_and_tar::TarDecompiler::end_of_archive(document)?;
+    let mut engine = TarEngine::resume_from(document)?;
+    // An EOF is two empty blocks, an open end only the terminator of the escaped data.
+    let open_ended = end.len() < 2 * core::mem::size_of::<html_and_tar::TarHeader>();
+
+    let mut appended = vec![];
+    let mut sink = Sink {
+        out: &mut appended,
+        len: 0,
+        layout: Layout::default(),
+        error: None,
+    };
+
+    sink.push("html", &document[..end.start]);
+
+    for item in items {
+        let Some((name, _)) = item.name_and_size() else {
+            return Err("Insertion points can not be continued when appending".into());
+        };
+
+        push_item(&mut engine, &mut sink, name, item, MAX_PART_SIZE);
+    }
+
+    if open_ended {
+        let end = engine.escaped_open_end();
+        sink.push("padding", end.padding);
+        sink.push("open end", end.data);
+    } else {
+        sink.push_escaped(&engine.escaped_eof());
+    }
+
+    sink.push("html", &document[end.end..]);
+    debug_assert!(sink.error.is_none(), "writing to memory does not fail");
+
+    Ok(appended)
+}
+
+/// Writes the pieces of a document in order and records where each one ended up.
+///
+/// The first write error is kept and all later pieces are dropped, the builder reports it once it
+/// is done.
+struct Sink<'w> {
+    out: &'w mut dyn Write,
+    len: usize,
+    layout: Layout,
+    error: Option<std::io::Error>,
+}
+
+impl Sink<'_> {
+    fn push(&mut self, label: &str, bytes: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+
+        if let Err(err) = self.out.write_all(bytes) {
+            self.error = Some(err);
+            return;
+        }
+
+        let range = self.len..self.len + bytes.len();
+        self.len = range.end;
+
+        if range.is_empty() {
+            return;
+        }
+
+        // Adjacent pieces of the same kind read better as one region.
+        match self.layout.regions.last_mut() {
+            Some((last, last_range)) if *last == label => last_range.end = range.end,
+            _ => self.layout.regions.push((label.to_owned(), range)),
+        }
+    }
+
+    fn push_escaped(&mut self, entry: &EscapedData) {
+        let name = entry.file.parse_path().unwrap_or_default();
+        self.push("padding", entry.padding);
+        self.push(&format!("header {name}"), entry.header.as_bytes());
+        self.push(&format!("file header {name}"), entry.file.as_bytes());
+        self.push(&format!("data {name}"), entry.data.as_slice());
+    }
+}
+
+fn build_into<E>(
+    source: &mut dom::SourceDocument,
+    elements: impl FnOnce(&mut dyn FnMut(TarItem<'_>)) -> Result<(), E>,
+    script: Option<&[u8]>,
+    options: &Options,
+    progress: &mut dyn PackProgress,
+    part_size: usize,
+    out: &mut dyn Write,
+) -> Result<Layout, Box<dyn std::error::Error>>
 where
     Box<dyn std::error::Error>: From<E>,
 {
     let structure = source.prepare_tar_structure()?;
 
     let mut engine = TarEngine::default();
-    let mut seq_of_bytes: Vec<&[u8]> = vec![];
+    engine.set_line_width(options.line_width);
+
+    if let Some(doctype) = options.doctype {
+        engine.set_doctype(doctype)?;
+    }
+    let mut sink = Sink {
+        out,
+        len: 0,
+        layout: Layout::default(),
+        error: None,
+    };
 
     let mut head_span = source.span(structure.html_tag);
     head_span.end = head_span.start + structure.html_insertion_point;
@@ -29,51 +503,1494 @@ where
 
     assert!(where_to_insert.end < where_to_enter.start);
 
+    let continued = continued_insertions(source, &structure);
+    for (name, point) in &continued {
+        if point.start < where_to_insert.end
+            || (point.end > where_to_enter.start && point.start < where_to_enter.end)
+        {
+            return Err(format!(
+                "The insertion point `{name}` must come after the first one, outside of the \
+                stage0 script"
+            )
+            .into());
+        }
+    }
+
+    // Keeping the script of a document only works if it has one. A template without any gets an
+    // empty one from `prepare_tar_structure`, which would not bootstrap anything.
+    if script.is_none() && is_empty_script(&source[where_to_enter.clone()]) {
+        return Err("The document has no stage0 script to keep, pass one with `--stage0`".into());
+    }
+
+    if let Some(script) = script {
+        validate_stage0(script)?;
+    }
+
+    let raw_regions = raw_regions(source, &structure);
+
+    for (offset, marker) in marker_collisions(&source[..], &raw_regions) {
+        log::warn!(
+            "the document mentions `{marker}` at byte {offset}, outside our data. The \
+            loader or a later extraction may mistake it for packed data."
+        );
+    }
+
     let init = engine.start_of_file(head.as_bytes(), where_to_insert.start);
-    seq_of_bytes.push(init.header.as_bytes());
-    seq_of_bytes.push(init.extra.as_slice());
-    seq_of_bytes.push(source[init.consumed..where_to_insert.start].as_bytes());
+    sink.push("initial header", init.header.as_bytes());
+    sink.push("initial header data", init.extra.as_slice());
+    sink.push(
+        "html",
+        source[init.consumed..where_to_insert.start].as_bytes(),
+    );
 
-    let mut pushed_data = vec![];
+    let stage0: Vec<&[u8]> = match script {
+        Some(script) => vec![
+            b"<script id=WAH_POLYGLOT_HTML_PLUS_TAR_STAGE0>",
+            script,
+            b"</script>",
+        ],
+        // Insert the original script unchanged but this could be used to update it. This might be
+        // one created by `prepare_tar_structure`.
+        None => vec![source[where_to_enter.clone()].as_bytes()],
+    };
+
+    // The document between two offsets as written, without the insertion points passed over.
+    let stretch = |range: Range<usize>, passed: &[(String, Range<usize>)]| {
+        let mut pieces = vec![];
+
+        for piece in html_between(range, passed) {
+            if !piece.contains(&where_to_enter.start) {
+                pieces.push(("html", source[piece].as_bytes()));
+                continue;
+            }
+
+            pieces.push(("html", source[piece.start..where_to_enter.start].as_bytes()));
+            pieces.extend(stage0.iter().map(|&bytes| ("stage0", bytes)));
+            pieces.push(("html", source[where_to_enter.end..piece.end].as_bytes()));
+        }
+
+        pieces
+    };
+
+    // Every escaped entry is written right away, only one file is held in memory at a time.
+    let mut is_escaped = false;
+    // Where the HTML continues after the current insertion point, and the points still ahead.
+    let (mut resume_at, mut ahead) = (where_to_insert.end, &continued[..]);
+    let mut failed = None;
 
     (elements)(&mut |item| {
-        let entry = match item {
-            TarItem::Entry(entry) => engine.escaped_base64(entry),
-            TarItem::External(external) => engine.escaped_external(external),
+        if sink.error.is_some() || failed.is_some() {
+            return;
+        }
+
+        let (name, size) = match &item {
+            TarItem::Insertion(point) => {
+                let Some(idx) = ahead.iter().position(|(name, _)| name == point) else {
+                    failed = Some(format!(
+                        "The template has no insertion point `{point}` after the current one"
+                    ));
+                    return;
+                };
+
+                if !is_escaped {
+                    failed = Some(format!(
+                        "Nothing is packed before the insertion point `{point}`, the HTML up to \
+                        it can only be skipped after a file"
+                    ));
+                    return;
+                }
+
+                let target = ahead[idx].1.clone();
+                let pieces = stretch(resume_at..target.start, &ahead[..idx]);
+                let skip = pieces.iter().map(|(_, bytes)| bytes.len()).sum();
+
+                let sentinel = engine.escaped_end(skip);
+                sink.push("padding", sentinel.padding);
+                sink.push("skip header", sentinel.header.as_bytes());
+
+                for (label, bytes) in pieces {
+                    sink.push(label, bytes);
+                }
+
+                (resume_at, ahead) = (target.end, &ahead[idx + 1..]);
+                is_escaped = false;
+                return;
+            }
+            file => file
+                .name_and_size()
+                .expect("only insertion points have no file"),
         };
 
-        pushed_data.push(entry);
+        progress.on_file(name.0, size);
+        push_item(&mut engine, &mut sink, name, item, part_size);
+        is_escaped = true;
     })?;
 
-    for entry in &pushed_data {
-        seq_of_bytes.push(entry.padding);
-        seq_of_bytes.push(entry.header.as_bytes());
-        seq_of_bytes.push(entry.file.as_bytes());
-        seq_of_bytes.push(entry.data.as_slice());
+    if let Some(err) = failed {
+        return Err(err.into());
+    }
+
+    // Only escaped data can be left open. Without any the archive still gets its EOF, appending
+    // replaces that just the same.
+    if options.open_ended && is_escaped {
+        let end = engine.escaped_open_end();
+        sink.push("padding", end.padding);
+        sink.push("open end", end.data);
+    } else {
+        let eof = engine.escaped_eof();
+        sink.push("padding", eof.padding);
+        sink.push("eof", eof.header.as_bytes());
+        sink.push("eof", eof.file.as_bytes());
+        sink.push("eof data", eof.data.as_slice());
     }
 
-    let eof;
-    if !pushed_data.is_empty() {
-        eof = engine.escaped_eof();
-        seq_of_bytes.push(eof.padding);
-        seq_of_bytes.push(eof.header.as_bytes());
-        seq_of_bytes.push(eof.file.as_bytes());
-        seq_of_bytes.push(eof.data.as_slice());
+    for (label, bytes) in stretch(resume_at..source[..].len(), ahead) {
+        sink.push(label, bytes);
     }
 
-    seq_of_bytes.push(source[where_to_insert.end..where_to_enter.start].as_bytes());
+    if let Some(err) = sink.error {
+        return Err(err.into());
+    }
 
-    if let Some(source_script) = script {
-        seq_of_bytes.push(b"<script id=WAH_POLYGLOT_HTML_PLUS_TAR_STAGE0>");
-        seq_of_bytes.push(&source_script);
-        seq_of_bytes.push(b"</script>");
-    } else {
-        // Insert the original script unchanged but this could be used to update it. This might be
-        // one created by `prepare_tar_structure`.
-        seq_of_bytes.push(source[where_to_enter.start..where_to_enter.end].as_bytes());
+    sink.out.flush()?;
+    progress.on_finish(sink.len as u64);
+    Ok(sink.layout)
+}
+
+/// A stand-in for the loader, `fixture.html` has none of its own.
+#[cfg(test)]
+const STAGE0: &[u8] = b"console.log('stage0')";
+
+#[cfg(test)]
+fn pack_fixture(files: &[(&str, &[u8])]) -> String {
+    TestPack::default().files(files)
+}
+
+#[cfg(test)]
+fn pack_template(template: &str, files: &[(&str, &[u8])]) -> String {
+    TestPack {
+        template: Some(template),
+        ..TestPack::default()
+    }
+    .files(files)
+}
+
+/// How a test packs its items, into `fixture.html` with the default options unless set.
+#[cfg(test)]
+#[derive(Default)]
+struct TestPack<'a> {
+    template: Option<&'a str>,
+    options: Options<'a>,
+    part_size: Option<usize>,
+    /// Of the files packed by [`TestPack::files`].
+    compression: Option<html_and_tar::Compression>,
+}
+
+#[cfg(test)]
+impl TestPack<'_> {
+    fn files(&self, files: &[(&str, &[u8])]) -> String {
+        let compression = self.compression;
+        self.items(|push| {
+            for &(name, data) in files {
+                push(TarItem::Entry(Entry {
+                    compression,
+                    ..file(name, data)
+                }));
+            }
+        })
+        .0
+    }
+
+    fn items(&self, elements: impl FnOnce(&mut dyn FnMut(TarItem<'_>))) -> (String, Layout) {
+        self.try_items(elements).unwrap()
+    }
+
+    fn try_items(
+        &self,
+        elements: impl FnOnce(&mut dyn FnMut(TarItem<'_>)),
+    ) -> Result<(String, Layout), Box<dyn std::error::Error>> {
+        let template = self.template.unwrap_or(include_str!("fixture.html"));
+        let mut source = dom::SourceDocument::new(template);
+        let (packed, layout) = build_with_part_size(
+            &mut source,
+            |push| {
+                elements(push);
+                Ok::<_, Box<dyn std::error::Error>>(())
+            },
+            Some(STAGE0),
+            &self.options,
+            &mut (),
+            self.part_size.unwrap_or(MAX_PART_SIZE),
+        )?;
+
+        Ok((String::from_utf8(packed)?, layout))
+    }
+}
+
+/// A file with the default attributes.
+#[cfg(test)]
+fn file<'a>(name: &'a str, data: &'a [u8]) -> Entry<'a> {
+    Entry {
+        name: html_and_tar::HtmlAttributeSafeName::new(name).unwrap(),
+        data,
+        attributes: Default::default(),
+        compression: None,
+    }
+}
+
+/// The files of a document by name and data, and its text without them.
+#[cfg(test)]
+fn unpack(doc: &str) -> (Vec<(String, Vec<u8>)>, String) {
+    let mut doc = dom::SourceDocument::new(doc);
+    let files = doc
+        .split_tar_contents()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            let entry = entry.as_html_and_tar_entry().unwrap();
+            (entry.name.0.to_owned(), entry.data.to_vec())
+        })
+        .collect();
+
+    (files, doc[..].to_owned())
+}
+
+/// Files as [`unpack`] returns them.
+#[cfg(test)]
+fn owned(files: &[(&str, &[u8])]) -> Vec<(String, Vec<u8>)> {
+    files
+        .iter()
+        .map(|&(name, data)| (name.to_owned(), data.to_vec()))
+        .collect()
+}
+
+/// The ways in which Chromium's Save As has been observed to mangle a document, see the module
+/// documentation of `html_and_tar`. A document should survive each of them, or be repaired.
+pub const CHROMIUM_MANGLINGS: &[fn(String) -> String] = &[
+    |doc| doc.replace("<!DOCTYPE html>", "<!DOCTYPE HTML>"),
+    |doc| doc.replace('\0', "\u{fffd}"),
+    |doc| doc.replace('\0', "&#65533;"),
+    |doc| doc.replace("LANG=", "lang="),
+    |doc| {
+        doc.trim_matches(|ch: char| ch.is_whitespace() || ch == '\0')
+            .to_string()
+    },
+    |doc| {
+        // Line breaks in the text of our data nodes, also within the base64 payload.
+        let doc = doc.replace("</noscript>", "\n</noscript>");
+        let mut wrapped = String::with_capacity(doc.len());
+        let mut run = 0;
+
+        for ch in doc.chars() {
+            run = if ch.is_ascii_alphanumeric() {
+                run + 1
+            } else {
+                0
+            };
+            wrapped.push(ch);
+
+            if run == 76 {
+                wrapped.push('\n');
+                run = 0;
+            }
+        }
+
+        wrapped
+    },
+    |doc| {
+        let start = doc.find("<template>").unwrap() + "<template>".len();
+        let end = doc.find("</template>").unwrap();
+        format!("{}{}", &doc[..start], &doc[end..])
+    },
+];
+
+#[test]
+fn chromium_save_as_round_trip() {
+    let files: &[(&str, &[u8])] = &[
+        ("boot/wah-init.wasm", b"\0asm\x01\0\0\0"),
+        ("etc/motd", b"Hello, world!\n"),
+        ("large", &[0x5a; 4096]),
+    ];
+
+    let packed = pack_fixture(files);
+
+    let all = CHROMIUM_MANGLINGS
+        .iter()
+        .fold(packed.clone(), |doc, mangle| mangle(doc));
+
+    let individually = CHROMIUM_MANGLINGS
+        .iter()
+        .map(|mangle| mangle(packed.clone()));
+
+    for (idx, mangled) in individually.chain([all]).enumerate() {
+        let (unpacked, text) = unpack(&mangled);
+        assert_eq!(unpacked, owned(files), "mangling {idx}");
+
+        // The scheme does not use `<template>` whose content would be dropped.
+        assert!(!text.contains("wah_polyglot_data"), "mangling {idx}");
+        assert!(text.contains("<p>Hello</p>"), "mangling {idx}");
+    }
+}
+
+#[test]
+fn uppercase_attributes_round_trip() {
+    let files: &[(&str, &[u8])] = &[("etc/motd", b"Hello, world!\n"), ("large", &[0x5a; 4096])];
+
+    // Names of attributes are case-insensitive, another tool may spell them differently.
+    let packed = pack_fixture(files)
+        .replace(
+            " class=\"wah_polyglot_data\"",
+            " CLASS=\"wah_polyglot_data\"",
+        )
+        .replace(" data-wahtml_id=\"", " Data-Wahtml_Id=\"")
+        .replace(" data-b=\"", " DATA-B=\"")
+        .replace(" data-a=\"", " DATA-A=\"");
+    assert!(!packed.contains("data-b"));
+
+    let (unpacked, text) = unpack(&packed);
+    assert_eq!(unpacked, owned(files));
+    assert!(!text.contains("wah_polyglot_data"));
+    assert!(!text.contains("DATA-A"));
+    assert!(text.contains("<p>Hello</p>"));
+}
+
+#[test]
+fn list_entries_of_a_saved_document() {
+    let files: &[(&str, &[u8])] = &[
+        ("boot/wah-init.wasm", b"\0asm\x01\0\0\0"),
+        ("etc/motd", b"Hello, world!\n"),
+        ("large", &[0x5a; 4096]),
+    ];
+
+    let packed = pack_fixture(files);
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+
+    let saved = CHROMIUM_MANGLINGS
+        .iter()
+        .fold(packed.clone(), |doc, mangle| mangle(doc));
+
+    let manifest = dom::SourceDocument::new(&saved).list_entries().unwrap();
+    assert_eq!(manifest.len(), listed.len());
+
+    for (entry, listed) in manifest.iter().zip(&listed) {
+        assert_eq!(Some(&entry.name), listed.path.as_ref());
+        assert_eq!(entry.size, listed.header.parse_size().unwrap());
+        assert_eq!(entry.typeflag, listed.header.typeflag);
+        assert_eq!(entry.attributes().mode, Some(0o644));
+    }
+}
+
+#[test]
+fn saved_document_is_recoverable() {
+    let files: &[(&str, &[u8])] = &[
+        ("boot/wah-init.wasm", b"\0asm\x01\0\0\0"),
+        ("etc/motd", b"Hello, world!\n"),
+        (
+            "usr/share/a/name/longer/than/the/name/field/of/a/tar/header/is/split/into/the/prefix",
+            b"split",
+        ),
+        ("large", &[0x5a; 4096]),
+    ];
+
+    let packed = pack_fixture(files);
+    let saved = CHROMIUM_MANGLINGS
+        .iter()
+        .fold(packed.clone(), |doc, mangle| mangle(doc));
+
+    for doc in [&packed, &saved] {
+        let verified = dom::SourceDocument::new(doc).verify_file_elements();
+        assert_eq!(verified.unwrap(), files.len());
+    }
+
+    // A header byte edited by hand, the element still parses.
+    let at = packed.find("data-b=").unwrap();
+    let edited = packed[..at].to_owned() + &packed[at..].replacen("0000644", "0000755", 1);
+    let err = dom::SourceDocument::new(&edited).verify_file_elements();
+    let err = err.unwrap_err().to_string();
+    assert!(
+        err.contains("`boot/wah-init.wasm` has a broken header"),
+        "{err}"
+    );
+
+    // Data lost from an element, as by an editor truncating a line.
+    let truncated = packed.replacen("WlpaWlpa", "", 1);
+    let err = dom::SourceDocument::new(&truncated).verify_file_elements();
+    let err = err.unwrap_err().to_string();
+    assert!(err.contains("`large` has 5456 bytes of data"), "{err}");
+}
+
+/// Reverse the order of attributes in every opening tag of an element.
+#[cfg(test)]
+fn reverse_attributes(doc: &str, element: &str) -> String {
+    let opening = format!("<{element} ");
+    let mut out = String::with_capacity(doc.len());
+    let mut rest = doc;
+
+    while let Some(start) = rest.find(&opening) {
+        out.push_str(&rest[..start + opening.len()]);
+        rest = &rest[start + opening.len()..];
+
+        let (mut attributes, mut current, mut quoted) = (vec![], String::new(), false);
+        let mut chars = rest.char_indices();
+
+        let end = loop {
+            let (idx, ch) = chars.next().unwrap();
+            match ch {
+                '"' => quoted = !quoted,
+                '>' if !quoted => break idx,
+                ' ' if !quoted => {
+                    attributes.push(core::mem::take(&mut current));
+                    continue;
+                }
+                _ => {}
+            }
+
+            current.push(ch);
+        };
+
+        attributes.push(current);
+        attributes.retain(|attr| !attr.is_empty());
+        attributes.reverse();
+
+        out.push_str(&attributes.join(" "));
+        rest = &rest[end..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+#[test]
+fn attribute_reordering_round_trip() {
+    let files: &[(&str, &[u8])] = &[("etc/motd", b"Hello, world!\n"), ("large", &[0x5a; 4096])];
+
+    let packed = pack_fixture(files);
+    let reordered = reverse_attributes(&packed, "noscript");
+    let reordered = reverse_attributes(&reordered, "html");
+    assert!(reordered.contains(r#"<html data-a=""#));
+    assert!(reordered.contains(r#"<noscript data-b=""#));
+
+    let (unpacked, text) = unpack(&reordered);
+    assert_eq!(unpacked, owned(files));
+    assert!(!text.contains("data-a"));
+    assert!(text.contains("<p>Hello</p>"));
+
+    // The cleaned document still has its `<html>` tag reordered, repacking must find its end.
+    let (unpacked, _) = unpack(&pack_template(&text, files));
+    assert_eq!(unpacked, owned(files));
+}
+
+#[test]
+fn list_matches_dom_extraction() {
+    let files: &[(&str, &[u8])] = &[
+        ("boot/wah-init.wasm", b"\0asm\x01\0\0\0"),
+        ("etc/motd", b"Hello, world!\n"),
+        ("large", &[0x5a; 4097]),
+    ];
+
+    let packed = pack_fixture(files);
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+
+    let mut doc = dom::SourceDocument::new(&packed);
+    let entries = doc.split_tar_contents().unwrap();
+
+    assert_eq!(listed.len(), entries.len());
+    for (listed, entry) in listed.iter().zip(&entries) {
+        let entry = entry.as_html_and_tar_entry().unwrap();
+        assert_eq!(listed.header.parse_name(), Some(entry.name));
+        assert_eq!(listed.size, entry.data.len() as u64);
+    }
+}
+
+#[test]
+fn multi_line_html_tag_round_trip() {
+    // Has both tags of the tar structure, so it is used as-is and not reformatted by a reparse.
+    const TEMPLATE: &str = "<!DOCTYPE html>\n<html\n  lang=\"en\"\n  data-theme=\"dark > light\"\n  class=\"no-js\"\n>\n<head><title>Formatted</title>\n<template id=\"WAH_POLYGLOT_HTML_PLUS_TAR_CONTENT\"></template>\n</head>\n<body><p>Hello</p>\n<script id=\"WAH_POLYGLOT_HTML_PLUS_TAR_STAGE0\"></script>\n</body>\n</html>\n";
+
+    let mut source = dom::SourceDocument::new(TEMPLATE);
+    let structure = source.prepare_tar_structure().unwrap();
+    let html = source.span(structure.html_tag);
+    let tag = &source[html.start..html.start + structure.html_insertion_point];
+    assert!(
+        tag.starts_with("<html\n") && tag.ends_with("\n>"),
+        "{tag:?}"
+    );
+
+    let files: &[(&str, &[u8])] = &[("etc/motd", b"Hello, world!\n")];
+    let packed = pack_template(TEMPLATE, files);
+    assert!(packed.contains(r#"<html lang="en" data-theme="dark > light" class="no-js" data-a=""#));
+
+    let (unpacked, text) = unpack(&packed);
+    assert_eq!(unpacked, owned(files));
+    assert!(text.contains("<p>Hello</p>"));
+}
+
+#[test]
+fn extraction_recovers_ownership_and_mode() {
+    let attributes = html_and_tar::EntryAttributes {
+        mode: Some(0o755),
+        uid: Some(1000),
+        gid: Some(100),
+        ..Default::default()
+    };
+
+    let (packed, _) = TestPack::default().items(|push| {
+        push(TarItem::Entry(Entry {
+            attributes,
+            ..file("bin/run", b"#!/bin/sh\n")
+        }));
+        push(TarItem::Entry(file("etc/motd", b"Hello\n")));
+    });
+
+    let mut doc = dom::SourceDocument::new(&packed);
+    let entries = doc.split_tar_contents().unwrap();
+
+    let run = entries[0].attributes();
+    assert_eq!(
+        (run.mode, run.uid, run.gid),
+        (Some(0o755), Some(1000), Some(100))
+    );
+
+    // Entries without explicit attributes get the defaults of the encoding.
+    let motd = entries[1].attributes();
+    assert_eq!(motd.mode, Some(0o644));
+    assert_eq!((motd.uid, motd.gid), (Some(0o177776), Some(0o177776)));
+}
+
+#[test]
+fn split_files_are_joined() {
+    let large: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+    let files: &[(&str, &[u8])] = &[("etc/motd", b"Hello, world!\n"), ("large", &large)];
+
+    let packed = TestPack {
+        part_size: Some(4096),
+        ..TestPack::default()
+    }
+    .files(files);
+    assert_eq!(packed.matches("class=\"wah_polyglot_data\"").count(), 4);
+
+    let mut streamed = vec![];
+    dom::SourceDocument::new(&packed)
+        .for_each_file(|entry| {
+            let entry = entry.as_html_and_tar_entry().unwrap();
+            streamed.push((entry.name.0.to_owned(), entry.data.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+
+    assert_eq!(streamed, owned(files));
+    assert_eq!(unpack(&packed).0, owned(files));
+}
+
+#[test]
+fn markers_in_the_template_are_found() {
+    let template = include_str!("fixture.html").replace(
+        "<p>Hello</p>",
+        "<p class=wah_polyglot_data data-wahtml_id=fake>Hello</p>",
+    );
+
+    let mut source = dom::SourceDocument::new(&template);
+    let structure = source.prepare_tar_structure().unwrap();
+    let found = marker_collisions(&source[..], &raw_regions(&source, &structure));
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].1, "wah_polyglot_data");
+
+    // A clean template has none, our own tags are not part of the regions.
+    let mut source = dom::SourceDocument::new(include_str!("fixture.html"));
+    let structure = source.prepare_tar_structure().unwrap();
+    assert!(marker_collisions(&source[..], &raw_regions(&source, &structure)).is_empty());
+}
+
+#[test]
+fn patches_apply_on_extraction() {
+    let (packed, _) = TestPack::default().items(|push| {
+        push(TarItem::Entry(file("etc/motd", b"Hello, world\n")));
+        push(TarItem::Patch(Patch {
+            name: html_and_tar::HtmlAttributeSafeName::new("etc/motd").unwrap(),
+            offset: 7,
+            data: b"there!\n",
+        }));
+    });
+
+    let mut streamed = vec![];
+    dom::SourceDocument::new(&packed)
+        .for_each_file(|entry| {
+            streamed.push(entry.as_html_and_tar_entry().unwrap().data.to_vec());
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(streamed, [b"Hello, there!\n"]);
+
+    assert_eq!(
+        unpack(&packed).0,
+        owned(&[("etc/motd", b"Hello, there!\n")])
+    );
+}
+
+#[test]
+fn streamed_links_refer_back_past_other_files() {
+    let (packed, _) = TestPack::default().items(|push| {
+        let motd = html_and_tar::HtmlAttributeSafeName::new("etc/motd").unwrap();
+        push(TarItem::Entry(file("etc/motd", b"Hello, world\n")));
+        push(TarItem::Entry(file("usr/other", b"other")));
+        push(TarItem::Link(Link {
+            name: html_and_tar::HtmlAttributeSafeName::new("etc/issue").unwrap(),
+            target: motd,
+            attributes: Default::default(),
+        }));
+        push(TarItem::Patch(Patch {
+            name: motd,
+            offset: 7,
+            data: b"there!\n",
+        }));
+    });
+
+    let doc = dom::SourceDocument::new(&packed);
+
+    let mut streamed = vec![];
+    doc.for_each_file(|entry| {
+        let entry = entry.as_html_and_tar_entry().unwrap();
+        streamed.push((entry.name.0.to_owned(), entry.data.to_vec()));
+        Ok(())
+    })
+    .unwrap();
+
+    let patched: &[u8] = b"Hello, there!\n";
+    assert_eq!(
+        streamed,
+        owned(&[
+            ("usr/other", b"other"),
+            ("etc/motd", patched),
+            ("etc/issue", patched),
+        ])
+    );
+
+    let mut plain = vec![];
+    doc.extract_tar(&mut plain, &|_| true).unwrap();
+    let read: Vec<_> = html_and_tar::read_tar_stream(plain.as_slice())
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(read[1].name, "etc/motd");
+    assert_eq!(read[1].data, patched);
+    assert_eq!(read[2].link_target(), Some("etc/motd"));
+
+    assert_eq!(unpack(&packed).0[2].1, patched);
+}
+
+#[test]
+fn layout_covers_the_document() {
+    let (packed, layout) = TestPack::default().items(|push| {
+        push(TarItem::Entry(file("etc/motd", b"Hello, world!\n")));
+    });
+
+    let mut end = 0;
+    for (_, range) in &layout.regions {
+        assert_eq!(range.start, end);
+        end = range.end;
+    }
+
+    assert_eq!(end, packed.len());
+
+    let labels: Vec<_> = layout.regions.iter().map(|(label, _)| &label[..]).collect();
+    assert_eq!(labels[0], "initial header");
+    assert!(labels.contains(&"data etc/motd"));
+    assert!(labels.contains(&"eof"));
+}
+
+#[test]
+fn externals_keep_their_realsize() {
+    let (packed, _) = TestPack::default().items(|push| {
+        push(TarItem::External(External {
+            name: html_and_tar::HtmlAttributeSafeName::new("usr/lib/big.wasm").unwrap(),
+            realsize: 123_456,
+            reference: html_and_tar::HtmlAttributeSafeName::new("big.wasm").unwrap(),
+            attributes: Default::default(),
+        }));
+    });
+
+    let mut doc = dom::SourceDocument::new(&packed);
+    let entries = doc.split_tar_contents().unwrap();
+    assert_eq!(entries.len(), 1);
+
+    let external = entries[0].as_html_and_tar_external().unwrap();
+    assert_eq!(external.name.0, "usr/lib/big.wasm");
+    assert_eq!(external.reference.0, "big.wasm");
+    assert_eq!(external.realsize, 123_456);
+}
+
+#[test]
+fn custom_doctype_survives_the_build() {
+    const DOCTYPE: &str = "<!doctype html>";
+    let template = include_str!("fixture.html").replace("<!doctype html>\n", "");
+    let files: &[(&str, &[u8])] = &[("etc/motd", b"Hello, world!\n")];
+
+    let packed = TestPack {
+        template: Some(&template),
+        options: Options {
+            doctype: Some(DOCTYPE),
+            ..Options::default()
+        },
+        ..TestPack::default()
+    }
+    .files(files);
+
+    assert!(
+        packed.starts_with(&format!("\0{DOCTYPE}<html")),
+        "{}",
+        &packed[..80]
+    );
+    assert_eq!(unpack(&packed).0, owned(files));
+
+    assert!(html_and_tar::TarEngine::validate_doctype("<!DOCTYPE html>").is_ok());
+    assert!(html_and_tar::TarEngine::validate_doctype("DOCTYPE html").is_err());
+    assert!(html_and_tar::TarEngine::validate_doctype("<!a><b>").is_err());
+}
+
+#[test]
+fn aliases_share_the_data() {
+    const DATA: &[u8] = b"console.log('an asset with a rather distinctive body');";
+
+    let (packed, _) = TestPack::default().items(|push| {
+        push(TarItem::Entry(file("index.js", DATA)));
+        push(TarItem::Link(Link {
+            name: html_and_tar::HtmlAttributeSafeName::new("main.js").unwrap(),
+            target: html_and_tar::HtmlAttributeSafeName::new("index.js").unwrap(),
+            attributes: Default::default(),
+        }));
+    });
+
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+    let sizes: Vec<_> = listed.iter().map(|entry| entry.size).collect();
+    assert_eq!(sizes, [DATA.len() as u64, 0]);
+
+    assert_eq!(
+        unpack(&packed).0,
+        owned(&[("index.js", DATA), ("main.js", DATA)])
+    );
+}
+
+#[test]
+fn open_ended_round_trip() {
+    let files: &[(&str, &[u8])] = &[("etc/motd", b"Hello, world!\n"), ("etc/issue", b"\\S\n")];
+
+    let (packed, layout) = TestPack {
+        options: Options {
+            open_ended: true,
+            ..Options::default()
+        },
+        ..TestPack::default()
+    }
+    .items(|push| {
+        for &(name, data) in files {
+            push(TarItem::Entry(file(name, data)));
+        }
+    });
+
+    let labels: Vec<_> = layout
+        .regions
+        .iter()
+        .map(|(label, _)| label.as_str())
+        .collect();
+    assert!(labels.contains(&"open end"));
+    assert!(!labels.contains(&"eof"));
+
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+    let sizes: Vec<_> = listed.iter().map(|entry| entry.size).collect();
+    assert_eq!(sizes, [14, 3]);
+
+    assert_eq!(unpack(&packed).0, owned(files));
+}
+
+#[test]
+fn appending_keeps_the_end_of_the_archive() {
+    for open_ended in [false, true] {
+        let packed = TestPack {
+            options: Options {
+                open_ended,
+                ..Options::default()
+            },
+            ..TestPack::default()
+        }
+        .files(&[("etc/motd", b"Hello, world!\n")]);
+
+        let appended = append(
+            packed.as_bytes(),
+            [
+                TarItem::Entry(file("etc/issue", b"\\S\n")),
+                TarItem::Patch(Patch {
+                    name: HtmlAttributeSafeName::new("etc/motd").unwrap(),
+                    offset: 7,
+                    data: b"there!\n",
+                }),
+            ],
+        )
+        .unwrap();
+        let appended = String::from_utf8(appended).unwrap();
+
+        let (files, text) = unpack(&appended);
+        assert_eq!(
+            files,
+            owned(&[("etc/motd", b"Hello, there!\n"), ("etc/issue", b"\\S\n")])
+        );
+        assert_eq!(text, unpack(&packed).1);
+
+        // An EOF is written again, an open end stays open.
+        let before = html_and_tar::TarDecompiler::end_of_archive(packed.as_bytes()).unwrap();
+        let after = html_and_tar::TarDecompiler::end_of_archive(appended.as_bytes()).unwrap();
+        assert_eq!(after.len(), before.len());
+        assert_eq!(appended[after.end..], packed[before.end..]);
+    }
+
+    let packed = pack_fixture(&[("etc/motd", b"Hello, world!\n")]);
+    let err = append(packed.as_bytes(), [TarItem::Insertion("later")]).unwrap_err();
+    assert!(err.to_string().contains("Insertion points"), "{err}");
+}
+
+#[test]
+fn wrapped_data_round_trip() {
+    let files: &[(&str, &[u8])] = &[("etc/motd", b"Hello, world!\n"), ("large", &[0x5a; 4097])];
+
+    let packed = TestPack {
+        options: Options {
+            line_width: std::num::NonZeroUsize::new(76),
+            ..Options::default()
+        },
+        ..TestPack::default()
+    }
+    .files(files);
+
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+    let sizes: Vec<_> = listed.iter().map(|entry| entry.size).collect();
+    assert_eq!(sizes, [14, 4097]);
+
+    for (idx, mangle) in CHROMIUM_MANGLINGS.iter().enumerate() {
+        let (unpacked, _) = unpack(&mangle(packed.clone()));
+        assert_eq!(unpacked, owned(files), "mangling {idx}");
+    }
+}
+
+#[test]
+fn empty_stage0_is_refused_on_repack() {
+    let mut source = dom::SourceDocument::new(include_str!("fixture.html"));
+    let result = build(
+        &mut source,
+        |_| Ok::<_, Box<dyn std::error::Error>>(()),
+        None,
+    );
+    assert!(result.unwrap_err().to_string().contains("stage0"));
+
+    assert!(is_empty_script("<script id=X>\n  </script>"));
+    assert!(!is_empty_script("<script id=X>boot()</script>"));
+}
+
+#[test]
+fn trimmed_and_reindented_round_trip() {
+    let files: &[(&str, &[u8])] = &[("etc/motd", b"Hello, world!\n"), ("large", &[0x5a; 4097])];
+
+    let packed = pack_fixture(files);
+    let reindented: String = packed
+        .trim_matches(|ch: char| ch == '\0' || ch.is_whitespace())
+        .lines()
+        .map(|line| format!("    {line}\n"))
+        .collect();
+    let reindented = format!("\n\n{reindented}  \n");
+
+    assert_eq!(unpack(&reindented).0, owned(files));
+
+    // The tags are found again in the shifted text.
+    let mut doc = dom::SourceDocument::new(&reindented);
+    let structure = doc.prepare_tar_structure().unwrap();
+    let stage0 = doc.span(structure.stage0);
+    assert!(doc[stage0].starts_with("<script"));
+}
+
+#[test]
+fn device_nodes_round_trip() {
+    let (packed, _) = TestPack::default().items(|push| {
+        push(TarItem::Device(Device {
+            name: html_and_tar::HtmlAttributeSafeName::new("dev/null").unwrap(),
+            kind: html_and_tar::DeviceKind::Character,
+            attributes: html_and_tar::EntryAttributes {
+                devmajor: 1,
+                devminor: 3,
+                ..Default::default()
+            },
+        }));
+    });
+
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+    assert!(matches!(
+        html_and_tar::TarDecompiler::file_data(&listed[0].header, b"").unwrap(),
+        html_and_tar::ParsedFileData::Device {
+            kind: html_and_tar::DeviceKind::Character,
+            major: 1,
+            minor: 3,
+        }
+    ));
+
+    let mut doc = dom::SourceDocument::new(&packed);
+    let entries = doc.split_tar_contents().unwrap();
+
+    let device = entries[0].as_html_and_tar_device().unwrap();
+    assert_eq!(device.name.0, "dev/null");
+    assert_eq!(device.kind, html_and_tar::DeviceKind::Character);
+    assert_eq!(
+        (device.attributes.devmajor, device.attributes.devminor),
+        (1, 3)
+    );
+    assert!(entries[0].as_html_and_tar_entry().is_none());
+}
+
+#[test]
+fn compressed_files_round_trip() {
+    let text = "All work and no play makes Jack a dull boy.\n".repeat(300);
+    // Does not get smaller by compressing, it is stored as is.
+    let noise: Vec<u8> = std::iter::successors(Some(0x9e37_79b9_7f4a_7c15_u64), |x| {
+        let x = x ^ x << 13;
+        let x = x ^ x >> 7;
+        Some(x ^ x << 17)
+    })
+    .map(|x| (x >> 56) as u8)
+    .take(5000)
+    .collect();
+    let files: &[(&str, &[u8])] = &[("etc/text", text.as_bytes()), ("noise", &noise)];
+
+    let gzip = Some(html_and_tar::Compression::Gzip);
+    let packed = TestPack {
+        part_size: Some(4096),
+        compression: gzip,
+        ..TestPack::default()
+    }
+    .files(files);
+
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+    let compressions: Vec<_> = listed
+        .iter()
+        .map(|entry| entry.header.parse_compression())
+        .collect();
+    // Every part is compressed on its own, the noise in neither of its parts.
+    assert_eq!(compressions, [gzip, gzip, gzip, gzip, None, None]);
+
+    let sizes: Vec<_> = listed.iter().map(|entry| entry.size).collect();
+    assert_eq!(sizes, [4096, 4096, 4096, 912, 4096, 904]);
+
+    assert_eq!(unpack(&packed).0, owned(files));
+}
+
+#[test]
+fn documents_without_files_end_the_archive() {
+    for open_ended in [false, true] {
+        let (packed, _) = TestPack {
+            options: Options {
+                open_ended,
+                ..Options::default()
+            },
+            ..TestPack::default()
+        }
+        .items(|_| {});
+
+        let packed = packed.as_bytes();
+        assert!(
+            html_and_tar::TarDecompiler::list(packed)
+                .unwrap()
+                .is_empty()
+        );
+        let end = html_and_tar::TarDecompiler::end_of_archive(packed).unwrap();
+        assert!(packed[end].iter().all(|&b| b == 0));
+
+        let (unpacked, text) = unpack(std::str::from_utf8(packed).unwrap());
+        assert!(unpacked.is_empty());
+        assert!(text.contains("<p>Hello</p>"));
+    }
+}
+
+#[test]
+fn directories_round_trip() {
+    let (packed, _) = TestPack::default().items(|push| {
+        push(TarItem::Directory(Directory {
+            name: html_and_tar::HtmlAttributeSafeName::new("tmp").unwrap(),
+            attributes: Default::default(),
+        }));
+        push(TarItem::Directory(Directory {
+            name: html_and_tar::HtmlAttributeSafeName::new("var/cache").unwrap(),
+            attributes: html_and_tar::EntryAttributes {
+                mode: Some(0o700),
+                ..Default::default()
+            },
+        }));
+    });
+
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0].size, 0);
+    assert!(matches!(
+        html_and_tar::TarDecompiler::file_data(&listed[0].header, b"").unwrap(),
+        html_and_tar::ParsedFileData::Directory
+    ));
+
+    let mut doc = dom::SourceDocument::new(&packed);
+    let entries = doc.split_tar_contents().unwrap();
+
+    let tmp = entries[0].as_html_and_tar_directory().unwrap();
+    assert_eq!(tmp.name.0, "tmp");
+    assert_eq!(tmp.attributes.mode, Some(0o755));
+    let cache = entries[1].as_html_and_tar_directory().unwrap();
+    assert_eq!(cache.name.0, "var/cache");
+    assert_eq!(cache.attributes.mode, Some(0o700));
+    assert!(entries[0].as_html_and_tar_entry().is_none());
+}
+
+#[test]
+fn symlinks_round_trip() {
+    let (packed, _) = TestPack::default().items(|push| {
+        push(TarItem::Entry(file("bin/busybox", b"\0asm")));
+        push(TarItem::Symlink(Symlink {
+            name: html_and_tar::HtmlAttributeSafeName::new("bin/sh").unwrap(),
+            target: html_and_tar::HtmlAttributeSafeName::new("busybox").unwrap(),
+            attributes: Default::default(),
+        }));
+    });
+
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+    assert_eq!(listed[1].size, 0);
+    assert!(matches!(
+        html_and_tar::TarDecompiler::file_data(&listed[1].header, b"").unwrap(),
+        html_and_tar::ParsedFileData::Symlink { target } if target == "busybox"
+    ));
+
+    let mut doc = dom::SourceDocument::new(&packed);
+    let entries = doc.split_tar_contents().unwrap();
+
+    let sh = entries[1].as_html_and_tar_symlink().unwrap();
+    assert_eq!((sh.name.0, sh.target.0), ("bin/sh", "busybox"));
+    assert_eq!(sh.attributes.mode, Some(0o777));
+    assert!(entries[1].as_html_and_tar_entry().is_none());
+    assert!(entries[0].as_html_and_tar_symlink().is_none());
+
+    // Standard tools see the link as well.
+    let mut plain = vec![];
+    entries[1].write_plain_tar(&mut plain).unwrap();
+    let read: Vec<_> = html_and_tar::read_tar_stream(plain.as_slice())
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(read[0].name, "bin/sh");
+    assert_eq!(read[0].symlink_target(), Some("busybox"));
+}
+
+#[test]
+fn strip_payload_leaves_the_template() {
+    let packed = pack_fixture(&[("etc/motd", b"Hello, world!\n")]);
+
+    let mut source = dom::SourceDocument::new(&packed);
+    source.strip_payload().unwrap();
+
+    let text = &source[..];
+    assert!(text.contains("<p>Hello</p>"));
+    assert!(!text.contains("wah_polyglot_data"));
+    assert!(!text.contains("data-a"));
+    assert!(!text.contains("stage0"));
+
+    // The template can go through the builder again.
+    let repacked = pack_template(text, &[("etc/issue", b"\\S\n")]);
+    let mut doc = dom::SourceDocument::new(&repacked);
+    let entries = doc.split_tar_contents().unwrap();
+    assert_eq!(entries.len(), 1);
+}
+
+#[test]
+fn csp_goes_into_the_head_once() {
+    const POLICY: &str = "default-src 'self' blob: data:";
+
+    let mut source = dom::SourceDocument::new(include_str!("fixture.html"));
+    source.insert_csp(POLICY).unwrap();
+
+    let text = source[..].to_string();
+    let head = &text[text.find("<head>").unwrap()..text.find("</head>").unwrap()];
+    assert!(head.contains("http-equiv=\"Content-Security-Policy\""));
+    assert!(head.contains(&format!("content=\"{POLICY}\"")));
+
+    assert!(source.insert_csp(POLICY).is_err());
+    assert!(dom::SourceDocument::validate_csp("default-src \"self\"").is_err());
+
+    let packed = pack_template(&text, &[("etc/motd", b"Hello, world!\n")]);
+    assert!(packed.contains(POLICY));
+    let mut doc = dom::SourceDocument::new(&packed);
+    assert_eq!(doc.split_tar_contents().unwrap().len(), 1);
+}
+
+#[test]
+fn progress_sees_every_file() {
+    #[derive(Default)]
+    struct Recorded {
+        files: Vec<(String, u64)>,
+        total: Option<u64>,
+    }
+
+    impl PackProgress for Recorded {
+        fn on_file(&mut self, name: &str, bytes: u64) {
+            self.files.push((name.to_owned(), bytes));
+        }
+
+        fn on_finish(&mut self, total: u64) {
+            self.total = Some(total);
+        }
+    }
+
+    let mut recorded = Recorded::default();
+    let mut source = dom::SourceDocument::new(include_str!("fixture.html"));
+    let (packed, _) = build_with_progress(
+        &mut source,
+        |push| {
+            push(TarItem::Entry(file("etc/motd", b"Hello, world!\n")));
+            push(TarItem::Link(html_and_tar::Link {
+                name: html_and_tar::HtmlAttributeSafeName::new("etc/issue").unwrap(),
+                target: html_and_tar::HtmlAttributeSafeName::new("etc/motd").unwrap(),
+                attributes: Default::default(),
+            }));
+
+            Ok::<_, Box<dyn std::error::Error>>(())
+        },
+        Some(STAGE0),
+        &Options::default(),
+        &mut recorded,
+    )
+    .unwrap();
+
+    assert_eq!(
+        recorded.files,
+        [("etc/motd".to_owned(), 14), ("etc/issue".to_owned(), 0)]
+    );
+    assert_eq!(recorded.total, Some(packed.len() as u64));
+}
+
+#[test]
+fn stage0_must_stay_in_its_element() {
+    assert!(validate_stage0(STAGE0).is_ok());
+    assert!(validate_stage0(b"let tag = '<\\/script>';").is_ok());
+
+    for script in [
+        &b"<script>console.log('stage0')</script>"[..],
+        b"document.write('</SCRIPT>')",
+        b"<!-- console.log('stage0')",
+        b"\xff",
+    ] {
+        let mut source = dom::SourceDocument::new(include_str!("fixture.html"));
+        let result = build(
+            &mut source,
+            |_| Ok::<_, Box<dyn std::error::Error>>(()),
+            Some(script),
+        );
+        assert!(result.is_err(), "{}", String::from_utf8_lossy(script));
+    }
+}
+
+#[test]
+fn browser_compat_lints() {
+    let fixture = include_str!("fixture.html");
+    let lints = lint_browser_compat(fixture, &Options::default()).unwrap();
+    assert_eq!(lints.len(), 1, "{lints:?}");
+    assert!(lints[0].contains("`<template>`"));
+
+    // lithtml can not parse the quoted identifiers of a public doctype.
+    let legacy = fixture.replace(
+        "<!doctype html>",
+        "<!DOCTYPE html SYSTEM about:legacy-compat>",
+    );
+    let long = fixture.replace("LANG=\"en\"", &format!("class=\"{}\"", "x".repeat(80)));
+
+    for (template, needle) in [(legacy, "doctype"), (long, "bytes")] {
+        let lints = lint_browser_compat(&template, &Options::default()).unwrap();
+        assert!(lints.iter().any(|lint| lint.contains(needle)), "{lints:?}");
+    }
+}
+
+#[test]
+fn long_names_round_trip() {
+    let long = format!("share/doc/wasi-document/{}", "a".repeat(89));
+    let files: &[(&str, &[u8])] = &[(&long, b"Hello, world!\n"), ("etc/motd", b"Hi\n")];
+
+    let packed = pack_fixture(files);
+
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+    let paths: Vec<_> = listed
+        .iter()
+        .map(|entry| entry.path.as_deref().unwrap())
+        .collect();
+    assert_eq!(paths, [long.as_str(), "etc/motd"]);
+
+    let mut doc = dom::SourceDocument::new(&packed);
+    let entries = doc.split_tar_contents().unwrap();
+    let entry = entries[0].as_html_and_tar_entry().unwrap();
+    assert_eq!(entry.name.0, long);
+    assert_eq!(entry.data, b"Hello, world!\n");
+}
+
+#[test]
+fn streamed_build_matches_the_in_memory_one() {
+    /// Accepts a few bytes per call, as a pipe may.
+    struct Trickle(Vec<u8>);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(7);
+            self.0.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
     }
 
-    seq_of_bytes.push(source[where_to_enter.end..].as_bytes());
+    let files: &[(&str, &[u8])] = &[("etc/motd", b"Hello, world!\n"), ("large", &[0x5a; 4097])];
+    let packed = pack_fixture(files);
+
+    let mut source = dom::SourceDocument::new(include_str!("fixture.html"));
+    let mut streamed = Trickle(vec![]);
+    let layout = build_to_writer(
+        &mut source,
+        |push| {
+            for &(name, data) in files {
+                push(TarItem::Entry(file(name, data)));
+            }
+
+            Ok::<_, Box<dyn std::error::Error>>(())
+        },
+        Some(STAGE0),
+        &Options::default(),
+        &mut (),
+        &mut streamed,
+    )
+    .unwrap();
+
+    assert_eq!(streamed.0, packed.as_bytes());
+    assert_eq!(layout.regions.last().unwrap().1.end, packed.len());
+}
+
+#[test]
+fn bad_base64_is_an_error() {
+    let packed = pack_fixture(&[("etc/motd", b"Hello, world!\n")]);
+    let mangled = packed.replacen("SGVsbG8s", "SGVs!G8s", 1);
+    assert_ne!(mangled, packed);
+
+    let mut doc = dom::SourceDocument::new(&mangled);
+    let err = doc.split_tar_contents().err().unwrap();
+    assert!(err.to_string().contains("etc/motd"), "{err}");
+}
+
+#[test]
+fn gnu_long_names_round_trip() {
+    let long = format!("usr/share/{}motd", "a/".repeat(143));
+    assert_eq!(long.len(), 300);
+    let files: &[(&str, &[u8])] = &[("etc/issue", b"Hi\n"), (&long, b"Hello, world!\n")];
+
+    let packed = pack_fixture(files);
+
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+    let paths: Vec<_> = listed
+        .iter()
+        .map(|entry| entry.path.as_deref().unwrap())
+        .collect();
+    assert_eq!(paths, ["etc/issue", long.as_str()]);
+
+    let mut doc = dom::SourceDocument::new(&packed);
+    let entries = doc.split_tar_contents().unwrap();
+    assert_eq!(entries.len(), 2);
+    let entry = entries[1].as_html_and_tar_entry().unwrap();
+    assert_eq!(entry.name.0, long);
+    assert_eq!(entry.data, b"Hello, world!\n");
+}
+
+#[test]
+fn plain_tar_extraction() {
+    const DATA: &[u8] = b"console.log('extracted');";
+    let split = format!("share/{}/{}", "d".repeat(40), "e".repeat(90));
+    let long = format!("share/{}/{}", "b".repeat(150), "c".repeat(150));
+
+    let (packed, _) = TestPack::default().items(|push| {
+        push(TarItem::Entry(file("index.js", DATA)));
+        push(TarItem::Link(Link {
+            name: html_and_tar::HtmlAttributeSafeName::new("main.js").unwrap(),
+            target: html_and_tar::HtmlAttributeSafeName::new("index.js").unwrap(),
+            attributes: Default::default(),
+        }));
+
+        for name in [&split, &long] {
+            push(TarItem::Entry(file(name, b"Hello, world!\n")));
+        }
+    });
+
+    // Every entry as its typeflag, path, link target and data.
+    fn read_plain_tar(archive: &[u8]) -> Vec<(u8, String, String, Vec<u8>)> {
+        let c_str = |field: &[u8]| {
+            let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            String::from_utf8(field[..end].to_vec()).unwrap()
+        };
+
+        assert_eq!(archive.len() % 512, 0);
+        let mut blocks = archive.chunks_exact(512);
+        let mut entries = vec![];
+        let mut long_name = None;
+
+        loop {
+            let mut header = html_and_tar::TarHeader::EMPTY;
+            header.assign_from_bytes(blocks.next().unwrap().try_into().unwrap());
+
+            if header.is_empty() {
+                assert!(blocks.next().unwrap().iter().all(|&b| b == 0));
+                assert!(blocks.next().is_none());
+                return entries;
+            }
+
+            header.verify_checksum().unwrap();
+            assert_eq!(&header.magic, b"ustar\0");
+
+            let size = header.parse_size().unwrap() as usize;
+            let data: Vec<u8> = blocks
+                .by_ref()
+                .take(size.div_ceil(512))
+                .flatten()
+                .copied()
+                .take(size)
+                .collect();
+
+            if header.typeflag == b'L' {
+                long_name = Some(c_str(&data));
+                continue;
+            }
+
+            let path = long_name
+                .take()
+                .unwrap_or_else(|| match c_str(&header.prefix) {
+                    prefix if prefix.is_empty() => c_str(&header.name),
+                    prefix => format!("{prefix}/{}", c_str(&header.name)),
+                });
+
+            entries.push((header.typeflag, path, c_str(&header.linkname), data));
+        }
+    }
+
+    let doc = dom::SourceDocument::new(&packed);
+    let mut archive = vec![];
+    doc.extract_tar(&mut archive, &|_| true).unwrap();
+
+    let hello = b"Hello, world!\n".to_vec();
+    assert_eq!(
+        read_plain_tar(&archive),
+        [
+            (b'0', "index.js".into(), "".into(), DATA.to_vec()),
+            (b'1', "main.js".into(), "index.js".into(), vec![]),
+            (b'0', split.clone(), "".into(), hello.clone()),
+            (b'0', long.clone(), "".into(), hello),
+        ]
+    );
+
+    // Without its target, the link is written as a file of its own.
+    let mut archive = vec![];
+    doc.extract_tar(&mut archive, &|name| name == "main.js")
+        .unwrap();
+    assert_eq!(
+        read_plain_tar(&archive),
+        [(b'0', "main.js".into(), "".into(), DATA.to_vec())]
+    );
+}
+
+#[test]
+fn replace_entry_in_place() {
+    let files: &[(&str, &[u8])] = &[
+        ("boot/wah-init.wasm", b"\0asm\x01\0\0\0"),
+        ("etc/motd", b"Hello, world!\n"),
+        ("large", &[0x5a; 4096]),
+    ];
+
+    let packed = pack_fixture(files);
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+    let (wasm, motd) = (&listed[0].data, &listed[1].data);
+
+    // Grows by several blocks, the entries after it move along.
+    let grown = vec![0x61; 2000];
+    let mut doc = dom::SourceDocument::new(&packed);
+    doc.replace_entry("boot/wah-init.wasm", &grown).unwrap();
+    let replaced = &doc[..];
+
+    let header = wasm.start - 512;
+    let old_end = wasm.start + wasm.len().next_multiple_of(512);
+    assert_eq!(replaced[..header], packed[..header]);
+    assert!(replaced.ends_with(&packed[old_end..]));
+    assert_eq!(replaced.len() - packed.len(), 5 * 512);
+
+    let mut split = dom::SourceDocument::new(replaced);
+    let entries = split.split_tar_contents().unwrap();
+    let data: Vec<_> = entries
+        .iter()
+        .map(|entry| entry.as_html_and_tar_entry().unwrap().data)
+        .collect();
+    assert_eq!(data, [&grown[..], files[1].1, files[2].1]);
+
+    // Within the same blocks, only the header and the data change.
+    let mut doc = dom::SourceDocument::new(&packed);
+    doc.replace_entry("etc/motd", b"Bye!\n").unwrap();
+    let replaced = &doc[..];
+    assert_eq!(replaced.len(), packed.len());
+    assert_eq!(replaced[..motd.start - 512], packed[..motd.start - 512]);
+    assert_eq!(replaced[motd.start + 512..], packed[motd.start + 512..]);
+
+    let relisted = html_and_tar::TarDecompiler::list(replaced.as_bytes()).unwrap();
+    assert_eq!(relisted[1].size, 5);
+    relisted[1].header.verify_checksum().unwrap();
+
+    assert!(doc.replace_entry("etc/passwd", b"").is_err());
+}
+
+#[test]
+fn later_insertion_points() {
+    const TEMPLATE: &str = "<!DOCTYPE html><html lang=\"en\"><head><title>Regions</title></head>\
+        <body><p>Above the fold</p><div id=\"WAH_POLYGLOT_HTML_PLUS_TAR_CONTENT_unused\"></div>\
+        <p>Below the fold</p><div id=\"WAH_POLYGLOT_HTML_PLUS_TAR_CONTENT_below\"></div>\
+        <p>Footer</p></body></html>";
+
+    let files: &[(&str, &[u8])] = &[
+        ("boot/wah-init.wasm", b"\0asm\x01\0\0\0"),
+        ("large", &[0x5a; 4096]),
+    ];
+
+    let pack = |points: &[(usize, &str)]| {
+        let pack = TestPack {
+            template: Some(TEMPLATE),
+            ..TestPack::default()
+        };
+
+        pack.try_items(|push| {
+            for (idx, &(name, data)) in files.iter().enumerate() {
+                for &(_, point) in points.iter().filter(|&&(before, _)| before == idx) {
+                    push(TarItem::Insertion(point));
+                }
+
+                push(TarItem::Entry(file(name, data)));
+            }
+        })
+        .map(|(packed, _)| packed)
+    };
+
+    let packed = pack(&[(1, "below")]).unwrap();
+    assert!(!packed.contains("_unused"));
+    assert!(!packed.contains("_below"));
+
+    let below = packed.find("Below the fold").unwrap();
+    assert!(packed.find("WlpaWlpa").unwrap() > below);
+    assert!(packed.find("AGFzbQEAAAA=").unwrap() < below);
+
+    let listed = html_and_tar::TarDecompiler::list(packed.as_bytes()).unwrap();
+    let names: Vec<_> = listed.iter().map(|entry| entry.path.as_deref()).collect();
+    assert_eq!(names, [Some(files[0].0), Some(files[1].0)]);
+
+    let (unpacked, text) = unpack(&packed);
+    assert_eq!(unpacked, owned(files));
+    assert!(!text.contains("wah_polyglot_skip"));
+    assert!(text.contains("Below the fold"));
 
-    Ok(seq_of_bytes.join(&b""[..]))
+    // Points are only used in order, and only after a file.
+    assert!(pack(&[(1, "nowhere")]).is_err());
+    assert!(pack(&[(0, "below")]).is_err());
+    assert!(pack(&[(1, "below"), (1, "unused")]).is_err());
 }
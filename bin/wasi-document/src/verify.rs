@@ -0,0 +1,63 @@
+//! The `verify` subcommand, a rebuild of a document from its embedded configuration.
+use std::path::Path;
+
+use wasi_document_dom as dom;
+
+use crate::{
+    build,
+    diff::{document_files, file_changes},
+    pack::{EMBEDDED_CONFIG_NAME, pack_document},
+    project::Configuration,
+};
+
+pub fn verify_document(
+    file: &Path,
+    root: &Path,
+    build: &build::BuildEnv,
+) -> Result<(), Box<dyn std::error::Error>> {
+    verify_recoverable(file)?;
+
+    let document = std::fs::read_to_string(file)?;
+    let mut source = dom::SourceDocument::new(&document);
+    let entries = source.split_tar_contents()?;
+
+    let config = entries
+        .iter()
+        .filter_map(|entry| entry.as_html_and_tar_entry())
+        .find(|entry| entry.name == EMBEDDED_CONFIG_NAME)
+        .ok_or("The document has no embedded configuration, build it with `--embed-config`")?;
+
+    let contents = std::str::from_utf8(config.data)?;
+    let configuration = Configuration::from_toml(contents, root)?;
+    let mut work = build::generate(&configuration, build)?;
+    work.embedded_config = Some(config.data.to_vec());
+
+    let rebuilt = String::from_utf8(pack_document(&work)?)?;
+    let mut rebuilt = dom::SourceDocument::new(&rebuilt);
+    let rebuilt = rebuilt.split_tar_contents()?;
+
+    // A file only the original has went missing from the rebuild, one only the rebuild has is
+    // new in it. Both count as differences, like a changed file.
+    let (original, rebuilt) = (document_files(&entries), document_files(&rebuilt));
+    let differences = file_changes(&original, &rebuilt);
+
+    for line in &differences {
+        eprintln!("{line}");
+    }
+
+    if !differences.is_empty() {
+        let count = differences.len();
+        return Err(format!("{count} files differ from the rebuild").into());
+    }
+
+    eprintln!("{} files match the rebuild", original.len());
+    Ok(())
+}
+
+/// Check that all files of a document can still be extracted, naming the first that can not.
+pub fn verify_recoverable(file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let document = std::fs::read_to_string(file)?;
+    let count = dom::SourceDocument::new(&document).verify_file_elements()?;
+    eprintln!("{count} files are recoverable");
+    Ok(())
+}
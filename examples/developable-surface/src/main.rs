@@ -33,7 +33,7 @@ pub fn _create_renderer(
     pacer: AsyncIterator<wasm_bindgen::JsValue>,
 ) -> Result<RenderHandle, wasm_bindgen::JsValue> {
     std::panic::set_hook(Box::new(console_error_panic_hook::hook));
-    let _ = console_log::init_with_level(log::Level::Info).unwrap();
+    console_log::init_with_level(log::Level::Info).unwrap();
 
     let canvas = gl::util::get_canvas("canvas-name")
         .ok_or_else(|| "no such canvas `canvas-name`".to_string())?;
@@ -163,7 +163,7 @@ impl GlobalState {
         let tris: usize = models.iter().map(|m| m.mesh.indices.len() / 3).sum();
 
         for model in models {
-            let mesh = mk_mesh(&self.ctx, &model)?;
+            let mesh = mk_mesh(&self.ctx, model)?;
             meshes.push(mesh);
         }
 
@@ -5,7 +5,7 @@ use rand::SeedableRng;
 use rand_distr::{Distribution, Normal};
 use rand_xorshift::XorShiftRng;
 
-const OUT_FILE_NAME: &'static str = "normal-dist2.png";
+const OUT_FILE_NAME: &str = "normal-dist2.png";
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut output = String::new();
     render_svg(&mut output)?;
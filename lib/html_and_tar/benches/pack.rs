@@ -0,0 +1,54 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use html_and_tar::{Entry, HtmlAttributeSafeName, TarEngine};
+
+const HEAD: &[u8] = b"<!DOCTYPE html><html lang=\"en\">";
+
+/// Files from tiny to sizeable, roughly what a packed root looks like.
+fn files(count: usize) -> Vec<(String, Vec<u8>)> {
+    (0..count)
+        .map(|idx| {
+            let len = 16 << (idx % 13);
+            let data = (0..len).map(|byte| (byte * 31 + idx) as u8).collect();
+            (format!("root/file{idx}"), data)
+        })
+        .collect()
+}
+
+fn pack(files: &[(String, Vec<u8>)]) -> usize {
+    let mut engine = TarEngine::default();
+    let init = engine.start_of_file(HEAD, HEAD.len());
+    let mut total = init.header.as_bytes().len() + init.extra.len();
+
+    for (name, data) in files {
+        let entry = engine.escaped_base64(Entry {
+            name: HtmlAttributeSafeName::new(name).unwrap(),
+            data,
+            attributes: Default::default(),
+            compression: None,
+        });
+
+        total += entry.padding.len() + 2 * 512 + entry.data.len();
+    }
+
+    let eof = engine.escaped_eof();
+    total + eof.padding.len() + eof.data.len()
+}
+
+fn bench_pack(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pack");
+
+    for count in [16, 256] {
+        let files = files(count);
+        let bytes: usize = files.iter().map(|(_, data)| data.len()).sum();
+
+        group.throughput(Throughput::Bytes(bytes as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &files, |b, files| {
+            b.iter(|| pack(files))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_pack);
+criterion_main!(benches);
@@ -20,11 +20,11 @@ fn main() {
     let mut engine = TarEngine::default();
 
     {
-        let init = engine.start_of_file(HTML[..html].as_bytes(), where_to_insert);
+        let init = engine.start_of_file(&HTML.as_bytes()[..html], where_to_insert);
 
         seq_of_bytes.own(init.header.as_bytes());
         seq_of_bytes.own(init.extra.as_slice());
-        seq_of_bytes.push(HTML[init.consumed..where_to_insert].as_bytes());
+        seq_of_bytes.push(&HTML.as_bytes()[init.consumed..where_to_insert]);
     }
 
     {
@@ -32,6 +32,7 @@ fn main() {
             name: HtmlAttributeSafeName::new("example0").unwrap(),
             data: b"Hello, world!",
             attributes: Default::default(),
+            compression: None,
         });
 
         seq_of_bytes.push(data.padding);
@@ -59,6 +60,7 @@ fn main() {
             name: HtmlAttributeSafeName::new("Emporingen").unwrap(),
             data: b"Off with their heads",
             attributes: Default::default(),
+            compression: None,
         });
         seq_of_bytes.push(data.padding);
 
@@ -74,7 +76,7 @@ fn main() {
         seq_of_bytes.own(end.file.as_bytes());
         seq_of_bytes.own(end.data.as_slice());
 
-        seq_of_bytes.push(HTML[where_to_insert..].as_bytes());
+        seq_of_bytes.push(&HTML.as_bytes()[where_to_insert..]);
     }
 
     let stdout = std::io::stdout();
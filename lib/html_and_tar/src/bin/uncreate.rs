@@ -3,7 +3,7 @@ use std::{
     io::{Read as _, Write as _},
 };
 
-use html_and_tar::{ParsedEscape, TarDecompiler};
+use html_and_tar::{ParsedEscape, TarDecompiler, TarError};
 
 fn main() {
     let mut stdin = std::io::stdin();
@@ -17,28 +17,24 @@ fn uncompile(data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
     let initial = decompiler.start_of_file(data)?;
     let mut ranges = vec![initial.header, initial.continues];
 
-    let mut is_in_escape = false;
-    loop {
-        let parsed = if is_in_escape {
-            decompiler.continue_escape(data)
-        } else {
-            decompiler.next_escape(data)
-        }?;
-
-        match parsed {
-            ParsedEscape::Entry(file, _) => {
+    for parsed in decompiler.entries(data) {
+        match parsed? {
+            ParsedEscape::Entry(file, range, long_name) => {
                 let name = CStr::from_bytes_until_nul(&file.name).unwrap();
-                eprintln!("File: {}", name.to_string_lossy());
-                is_in_escape = true;
-            }
-            ParsedEscape::EndOfEscapes { html_data } => {
-                ranges.push(html_data);
-                is_in_escape = false;
-            }
-            ParsedEscape::Eof { end } => {
-                ranges.push(end..data.len());
-                break;
+                let name = long_name.unwrap_or_else(|| name.to_string_lossy().into_owned());
+
+                let decoded = data
+                    .get(range)
+                    .ok_or(TarError::NotEnoughData)
+                    .and_then(|encoded| TarDecompiler::file_data(&file, encoded));
+
+                match decoded {
+                    Ok(_) => eprintln!("File: {name}"),
+                    Err(err) => eprintln!("File: {name}, can not be decoded: {err}"),
+                }
             }
+            ParsedEscape::EndOfEscapes { html_data } => ranges.push(html_data),
+            ParsedEscape::Eof { end } => ranges.push(end..data.len()),
         }
     }
 
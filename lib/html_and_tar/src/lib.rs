@@ -21,6 +21,7 @@
 ///   using this document then you have scripts on and then everything is permissible.
 use core::ops::Range;
 use std::ffi::CStr;
+use std::io::{Read, Write};
 
 // See resilience, this text can be rewritten by the browser with line feeds and we can restore the
 // original contents just fine.
@@ -29,7 +30,7 @@ use base64::{engine::general_purpose::STANDARD, Engine as _};
 mod bytemuck {
     pub fn bytes_of(tar: &super::TarHeader) -> &[u8] {
         let len = core::mem::size_of_val(tar);
-        unsafe { &*core::slice::from_raw_parts(tar as *const _ as *const u8, len) }
+        unsafe { core::slice::from_raw_parts(tar as *const _ as *const u8, len) }
     }
 
     pub fn bytes_of_mut(tar: &mut super::TarHeader) -> &mut [u8] {
@@ -62,6 +63,16 @@ pub struct TarHeader {
 }
 
 impl TarHeader {
+    /// The modification time of entries without their own, in seconds since the Unix epoch. Well,
+    /// the project began here. A stable default so that the same files make the same archive.
+    pub const EPOCH: u64 = 0o14707041774;
+
+    /// The largest uid or gid, eight octal digits filling the field, see [`write_octal_wide`].
+    pub const MAX_ID: u32 = 0o77777777;
+
+    /// The latest modification time, twelve octal digits of seconds since the Unix epoch.
+    pub const MAX_MTIME: u64 = 0o777777777777;
+
     pub fn as_bytes(&self) -> &[u8] {
         bytemuck::bytes_of(self)
     }
@@ -75,8 +86,7 @@ impl TarHeader {
         // The usual id for nobody, 65534, in octal is 177776
         self.uid.copy_from_slice(b"0177776\0");
         self.gid.copy_from_slice(b"0177776\0");
-        // FIXME: well the project began here.
-        self.mtime.copy_from_slice(b"14707041774\0");
+        write_octal(&mut self.mtime, Self::EPOCH).expect("the epoch fits the header");
         // Use standard star header, this is _not_ an old style GNU header.
         self.magic = *b"ustar\0";
         self.version = *b"  ";
@@ -84,55 +94,89 @@ impl TarHeader {
         self.gname[..7].copy_from_slice(b"nobody\0");
     }
 
+    /// Write the attributes into their fields.
+    ///
+    /// # Panics
+    ///
+    /// If an id or the mtime does not fit the header, see [`EntryAttributes::check`].
     pub fn assign_attributes(&mut self, extras: &EntryAttributes) {
-        if let Some(mtime) = extras.mtime {
-            let mtime = mtime
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_else(|_| Default::default())
-                .as_secs();
-            let bytes = format!("{mtime:011o}\0");
-            self.mtime.copy_from_slice(bytes.as_bytes());
+        if let Some(mode) = extras.mode {
+            write_octal(&mut self.mode, (mode & 0o7777777).into()).unwrap();
+        }
+
+        if let Some(uid) = extras.uid {
+            write_octal_wide(&mut self.uid, uid.into()).expect("uid does not fit the header");
+        }
+
+        if let Some(gid) = extras.gid {
+            write_octal_wide(&mut self.gid, gid.into()).expect("gid does not fit the header");
+        }
+
+        if let Some(mtime) = extras.mtime_secs() {
+            write_octal_wide(&mut self.mtime, mtime).expect("mtime does not fit the header");
         }
 
         if let Some(HtmlAttributeSafeName(uname)) = extras.uname {
             let uname_bytes = uname.as_bytes();
-            assert!(uname_bytes.len() < self.uname.len() - 1);
+            assert!(uname_bytes.len() < self.uname.len());
             self.uname[..uname_bytes.len()].copy_from_slice(uname_bytes);
             self.uname[uname_bytes.len()] = b'\0';
         }
 
         if let Some(HtmlAttributeSafeName(gname)) = extras.gname {
             let gname_bytes = gname.as_bytes();
-            assert!(gname_bytes.len() < self.gname.len() - 1);
+            assert!(gname_bytes.len() < self.gname.len());
             self.gname[..gname_bytes.len()].copy_from_slice(gname_bytes);
             self.gname[gname_bytes.len()] = b'\0';
         }
 
-        let devmajor = format!("{:o}\0", extras.devmajor);
-        self.devmajor[..devmajor.len()].copy_from_slice(devmajor.as_bytes());
-        let devminor = format!("{:o}\0", extras.devminor);
-        self.devminor[..devminor.len()].copy_from_slice(devminor.as_bytes());
+        write_octal(&mut self.devmajor, extras.devmajor.into()).unwrap();
+        write_octal(&mut self.devminor, extras.devminor.into()).unwrap();
     }
 
     pub fn assign_checksum(&mut self) {
-        let mut acc = 0u32;
+        let acc = self.compute_checksum();
+        let bytes = format!("{acc:06o}\0 ");
+        self.chksum.copy_from_slice(bytes.as_bytes());
+    }
 
-        for by in &mut self.chksum {
-            *by = b' ';
-        }
+    /// The checksum of the header, the sum of its bytes with the `chksum` field read as spaces.
+    pub fn compute_checksum(&self) -> u32 {
+        let all: u32 = self.as_bytes().iter().map(|&by| u32::from(by)).sum();
+        let chksum: u32 = self.chksum.iter().map(|&by| u32::from(by)).sum();
+        all - chksum + u32::from(b' ') * self.chksum.len() as u32
+    }
 
-        for &by in self.as_bytes() {
-            acc += u32::from(by);
+    /// Check that the `chksum` field holds the checksum of the header.
+    ///
+    /// The digits may be followed by a nul, a space or both in either order, writers disagree.
+    pub fn verify_checksum(&self) -> Result<(), TarError> {
+        let found = parse_octal(&self.chksum).map_err(TarError::Num)?;
+        let expected = u64::from(self.compute_checksum());
+
+        if found != expected {
+            return Err(TarError::BadChecksum { expected, found });
         }
 
-        let bytes = format!("{acc:06o}\0 ");
-        self.chksum.copy_from_slice(bytes.as_bytes());
+        Ok(())
     }
 
-    fn assign_size(&mut self, size: usize) {
-        let bytes = format!("{size:011o}\0");
+    /// Like [`Self::verify_checksum`], for a header read back from the attributes of its element.
+    ///
+    /// The attribute values leave out the quotes and markup closing them, which the checksum of a
+    /// file header written by [`TarEngine`] still covers.
+    pub fn verify_element_checksum(&self) -> Result<(), TarError> {
+        let mut header = *self;
+        let name = header.name.len() - TarEngine::ID_END_CONT.len();
+        header.name[name..].copy_from_slice(TarEngine::ID_END_CONT);
+        let prefix = header.prefix.len() - TarEngine::DATA_START.len();
+        header.prefix[prefix..].copy_from_slice(TarEngine::DATA_START);
+        header.verify_checksum()
+    }
+
+    fn assign_size(&mut self, size: u64) {
         // Note: this is numeric, so can not contain a closing quote.
-        self.size.copy_from_slice(bytes.as_bytes());
+        write_octal(&mut self.size, size).expect("file too large for the header");
     }
 
     pub fn parse_size(&self) -> Result<u64, core::num::ParseIntError> {
@@ -140,12 +184,7 @@ impl TarHeader {
             return Ok(0);
         };
 
-        let size_str = CStr::from_bytes_until_nul(&self.size)
-            .ok()
-            .and_then(|cstr| cstr.to_str().ok())
-            .unwrap_or("");
-
-        u64::from_str_radix(size_str, 8)
+        parse_octal(&self.size)
     }
 
     pub fn parse_name(&self) -> Option<HtmlAttributeSafeName<'_>> {
@@ -153,11 +192,127 @@ impl TarHeader {
         Some(HtmlAttributeSafeName(cstr.to_str().ok()?))
     }
 
+    /// The full path of the entry, a ustar `prefix` joined to the name.
+    ///
+    /// We only use the start of `prefix`, see [`TarEngine::MAX_PREFIX_LEN`].
+    pub fn parse_path(&self) -> Option<String> {
+        let name = self.parse_name()?;
+        let prefix = CStr::from_bytes_until_nul(&self.prefix[Self::PATH_PREFIX]).ok()?;
+
+        Some(match prefix.to_str().ok()? {
+            "" => name.0.to_owned(),
+            prefix => format!("{prefix}/{}", name.0),
+        })
+    }
+
     pub fn parse_link(&self) -> Option<HtmlAttributeSafeName<'_>> {
         let cstr = CStr::from_bytes_until_nul(&self.linkname).ok()?;
         Some(HtmlAttributeSafeName(cstr.to_str().ok()?))
     }
 
+    /// For a continuation part of a split file, the offset of its data in the whole file.
+    ///
+    /// Like the GNU multi-volume continuation we use typeflag `M` and keep the offset in the same
+    /// place, at byte 369 of the header. See [`TarEngine::escaped_base64_parts`].
+    pub fn parse_part_offset(&self) -> Option<u64> {
+        if self.typeflag != b'M' {
+            return None;
+        }
+
+        parse_octal(&self.prefix[Self::OFFSET]).ok()
+    }
+
+    /// For a patch, the offset in its file at which the data of the patch is written.
+    ///
+    /// Patches use typeflag `P` and the same offset field as parts. See [`Patch`].
+    pub fn parse_patch_offset(&self) -> Option<u64> {
+        if self.typeflag != b'P' {
+            return None;
+        }
+
+        parse_octal(&self.prefix[Self::OFFSET]).ok()
+    }
+
+    /// Whether all fields are zero, as in the two blocks ending an archive.
+    ///
+    /// The padding after `prefix` is not part of any field. Some writers leave garbage there, so
+    /// it does not count.
+    pub fn is_empty(&self) -> bool {
+        let fields = &self.as_bytes()[..500];
+        fields.iter().all(|&b| b == 0)
+    }
+
+    /// The width of the lines the base64 data was wrapped at, if any.
+    ///
+    /// See [`TarEngine::set_line_width`].
+    pub fn parse_line_width(&self) -> Option<u64> {
+        parse_octal(&self.prefix[Self::LINE_WIDTH])
+            .ok()
+            .filter(|&width| width > 0)
+    }
+
+    /// The number of base64 characters in the data, the size without the line breaks.
+    pub fn parse_base64_len(&self) -> Result<u64, core::num::ParseIntError> {
+        let size = self.parse_size()?;
+
+        Ok(match self.parse_line_width() {
+            // Every full line is followed by a line break, the last line is not.
+            Some(width) => size - size / (width + 1),
+            None => size,
+        })
+    }
+
+    /// For a hard link, the name of the file whose data it shares.
+    pub fn parse_hard_link(&self) -> Option<HtmlAttributeSafeName<'_>> {
+        if self.typeflag != b'1' {
+            return None;
+        }
+
+        self.parse_link()
+    }
+
+    /// For a symbolic link, the path it points to. Relative to the directory of the link.
+    pub fn parse_symlink(&self) -> Option<HtmlAttributeSafeName<'_>> {
+        if self.typeflag != b'2' {
+            return None;
+        }
+
+        self.parse_link()
+    }
+
+    /// For an outlined file, the length of the data found at its reference. For a compressed
+    /// file, the length of its data once inflated.
+    ///
+    /// See [`TarEngine::escaped_external`] and [`Entry::compression`].
+    pub fn parse_realsize(&self) -> Option<u64> {
+        if self.typeflag != b'S' && self.parse_compression().is_none() {
+            return None;
+        }
+
+        parse_octal(&self.prefix[Self::REALSIZE]).ok()
+    }
+
+    /// How the data of a file was compressed before its base64 encoding, if at all.
+    pub fn parse_compression(&self) -> Option<Compression> {
+        let name = CStr::from_bytes_until_nul(&self.prefix[Self::COMPRESSION]).ok()?;
+        Compression::from_name(name.to_bytes())
+    }
+
+    /// Mark the data as compressed, see [`TarHeader::parse_compression`].
+    pub fn assign_compression(&mut self, compression: Compression) {
+        let name = compression.name();
+        let field = &mut self.prefix[Self::COMPRESSION];
+        field.fill(0);
+        field[..name.len()].copy_from_slice(name);
+    }
+
+    /// With its terminating nul, the rest of `prefix` holds our numeric fields.
+    const PATH_PREFIX: Range<usize> = 0..TarEngine::MAX_PREFIX_LEN + 1;
+    const OFFSET: Range<usize> = 369 - 345..381 - 345;
+    const LINE_WIDTH: Range<usize> = 381 - 345..389 - 345;
+    const COMPRESSION: Range<usize> = 389 - 345..397 - 345;
+    const REALSIZE: Range<usize> = 452 - 345..464 - 345;
+
     pub const EMPTY: Self = TarHeader {
         name: [0; 100],
         mode: [0; 8],
@@ -179,6 +334,67 @@ impl TarHeader {
     };
 }
 
+/// Parse a numeric tar field, octal digits optionally surrounded by spaces and ended by a nul.
+///
+/// Writers disagree on the exact padding, GNU tar for instance pads with leading spaces instead of
+/// zeroes in some fields. A field without any digits is an error.
+pub fn parse_octal(field: &[u8]) -> Result<u64, core::num::ParseIntError> {
+    let end = field
+        .iter()
+        .position(|&b| b == b'\0')
+        .unwrap_or(field.len());
+    // Non-ASCII bytes are not digits either, which the parse then reports.
+    let digits = String::from_utf8_lossy(&field[..end]);
+    u64::from_str_radix(digits.trim_matches(' '), 8)
+}
+
+/// Write a numeric tar field as zero-padded octal digits followed by a nul.
+///
+/// Fails if the value needs more digits than the field has room for. We do not use the base-256
+/// extension for such values, they would not be safe to place in an HTML attribute.
+pub fn write_octal(field: &mut [u8], value: u64) -> Result<(), TarError> {
+    let Some((nul, digits)) = field.split_last_mut() else {
+        return Err(TarError::NumTooLarge);
+    };
+
+    let bits = 3 * digits.len() as u32;
+    if value.checked_shr(bits).unwrap_or(0) != 0 {
+        return Err(TarError::NumTooLarge);
+    }
+
+    let mut rest = value;
+    for digit in digits.iter_mut().rev() {
+        *digit = b'0' + (rest & 0o7) as u8;
+        rest >>= 3;
+    }
+
+    *nul = b'\0';
+    Ok(())
+}
+
+/// Write a numeric tar field as octal digits, using all of its bytes if the value needs them.
+///
+/// The terminating nul then makes room for one more digit, which GNU tar writes for ids too large
+/// for seven digits and every reader we know of accepts.
+pub fn write_octal_wide(field: &mut [u8], value: u64) -> Result<(), TarError> {
+    if write_octal(field, value).is_ok() {
+        return Ok(());
+    }
+
+    let bits = 3 * field.len() as u32;
+    if value.checked_shr(bits).unwrap_or(0) != 0 {
+        return Err(TarError::NumTooLarge);
+    }
+
+    let mut rest = value;
+    for digit in field.iter_mut().rev() {
+        *digit = b'0' + (rest & 0o7) as u8;
+        rest >>= 3;
+    }
+
+    Ok(())
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct HtmlAttributeSafeName<'la>(pub &'la str);
 
@@ -206,8 +422,55 @@ impl<'la> HtmlAttributeSafeName<'la> {
             }
         };
 
+        // A name that does not fit the header is written as text of its own element instead, see
+        // [`TarEngine::escaped_long_name`].
+        if Self::split_point(name.as_bytes()).is_none() {
+            let bytes = name.as_bytes();
+            let mut i = 0;
+
+            while i < bytes.len() {
+                if bytes[i] == b'<' || bytes[i] == b'&' || bytes[i] == b'\0' {
+                    return Err(TarError::LongNameHasMarkup);
+                }
+
+                i += 1;
+            }
+        }
+
         Ok(HtmlAttributeSafeName(name))
     }
+
+    /// Where a name is split into `prefix` and `name` of a header, at a `/` like in ustar.
+    ///
+    /// Zero if the name fits by itself. Otherwise the longest prefix that fits, for the most room
+    /// in the name.
+    const fn split_point(name: &[u8]) -> Option<usize> {
+        if name.len() <= TarEngine::MAX_NAME_LEN {
+            return Some(0);
+        }
+
+        let mut i = TarEngine::MAX_PREFIX_LEN;
+
+        while i > 0 {
+            if name[i] == b'/' && name.len() - i - 1 <= TarEngine::MAX_NAME_LEN {
+                return Some(i);
+            }
+
+            i -= 1;
+        }
+
+        None
+    }
+
+    /// The parts of the name going into the `prefix` and `name` fields of a header.
+    ///
+    /// `None` if the name needs a long name entry.
+    fn split(&self) -> Option<(&'la str, &'la str)> {
+        Some(match Self::split_point(self.0.as_bytes())? {
+            0 => ("", self.0),
+            idx => (&self.0[..idx], &self.0[idx + 1..]),
+        })
+    }
 }
 
 pub struct Entry<'la> {
@@ -217,10 +480,111 @@ pub struct Entry<'la> {
     pub data: &'la [u8],
     /// The metadata for this file.
     pub attributes: EntryAttributes<'la>,
+    /// Compress the data before encoding it. Data that does not get smaller, such as images or
+    /// fonts which are compressed already, is stored as is.
+    pub compression: Option<Compression>,
 }
 
-#[derive(Clone, Copy)]
+/// A file whose data was compressed and encoded ahead of its headers, by [`EncodedEntry::new`].
+///
+/// Encoding is most of the work of inserting a file and does not depend on the entries before
+/// it, so it can run on other threads. [`TarEngine::escaped_encoded`] then places the entry.
+pub struct EncodedEntry<'la> {
+    pub name: HtmlAttributeSafeName<'la>,
+    pub attributes: EntryAttributes<'la>,
+    /// The size of the file before it was compressed and encoded.
+    pub realsize: u64,
+    compression: Option<Compression>,
+    line_width: Option<core::num::NonZeroUsize>,
+    encoded: Vec<u8>,
+}
+
+impl<'la> EncodedEntry<'la> {
+    /// Encode the data of a file with the line width of the engine that places it.
+    ///
+    /// The fields are those of an [`Entry`], but the data need not outlive the encoding.
+    pub fn new(
+        name: HtmlAttributeSafeName<'la>,
+        attributes: EntryAttributes<'la>,
+        data: &[u8],
+        compression: Option<Compression>,
+        line_width: Option<core::num::NonZeroUsize>,
+    ) -> Self {
+        let engine = TarEngine {
+            line_width,
+            ..TarEngine::default()
+        };
+
+        let (encoded, compression) = engine.encode_compressed(data, compression);
+
+        EncodedEntry {
+            name,
+            attributes,
+            realsize: data.len() as u64,
+            compression,
+            line_width,
+            encoded,
+        }
+    }
+}
+
+/// A compression of the data of an entry, recorded by name in its header.
+///
+/// Readers inflate the data before anything else, parts and patches refer to offsets in the
+/// inflated file. The stage0 loader uses the browser's `DecompressionStream`, only formats it
+/// supports can be added here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+}
+
+impl Compression {
+    fn name(self) -> &'static [u8] {
+        match self {
+            Compression::Gzip => b"gzip",
+        }
+    }
+
+    fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"gzip" => Some(Compression::Gzip),
+            _ => None,
+        }
+    }
+
+    /// The compressed data, if it is smaller.
+    fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+                encoder.write_all(data).expect("writing to memory");
+                encoder.finish().expect("writing to memory")
+            }
+        };
+
+        (compressed.len() < data.len()).then_some(compressed)
+    }
+
+    fn decompress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut inflated = vec![];
+
+        match self {
+            Compression::Gzip => flate2::read::GzDecoder::new(data).read_to_end(&mut inflated)?,
+        };
+
+        Ok(inflated)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
 pub struct EntryAttributes<'la> {
+    /// The permission bits, defaults to `0644` when packed.
+    pub mode: Option<u32>,
+    /// The numeric owner, defaults to nobody when packed.
+    pub uid: Option<u32>,
+    /// The numeric group, defaults to nobody when packed.
+    pub gid: Option<u32>,
     pub mtime: Option<std::time::SystemTime>,
     pub uname: Option<HtmlAttributeSafeName<'la>>,
     pub gname: Option<HtmlAttributeSafeName<'la>>,
@@ -229,12 +593,48 @@ pub struct EntryAttributes<'la> {
 }
 
 impl<'la> EntryAttributes<'la> {
+    /// Whether the ids and the mtime fit a header, which [`TarHeader::assign_attributes`] needs.
+    ///
+    /// Larger numbers would need the base-256 extension, whose bytes are not safe in the HTML
+    /// attribute holding the header.
+    pub fn check(&self) -> Result<(), TarError> {
+        if self
+            .uid
+            .into_iter()
+            .chain(self.gid)
+            .any(|id| id > TarHeader::MAX_ID)
+        {
+            return Err(TarError::NumTooLarge);
+        }
+
+        if self
+            .mtime_secs()
+            .is_some_and(|mtime| mtime > TarHeader::MAX_MTIME)
+        {
+            return Err(TarError::NumTooLarge);
+        }
+
+        Ok(())
+    }
+
+    /// The mtime as seconds since the Unix epoch, times before it are packed as the epoch.
+    fn mtime_secs(&self) -> Option<u64> {
+        let mtime = self.mtime?;
+        let since = mtime
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Some(since.as_secs())
+    }
+
     /// Extract the metadata from an existing tar header.
     pub fn from_header(header: &'la TarHeader) -> Self {
-        let mtime = CStr::from_bytes_until_nul(&header.mtime)
-            .ok()
-            .and_then(|cstr| cstr.to_str().ok())
-            .and_then(|mtime| u64::from_str_radix(mtime, 8).ok())
+        let octal = |field: &[u8]| parse_octal(field).ok();
+
+        let mode = octal(&header.mode).and_then(|num| num.try_into().ok());
+        let uid = octal(&header.uid).and_then(|num| num.try_into().ok());
+        let gid = octal(&header.gid).and_then(|num| num.try_into().ok());
+
+        let mtime = octal(&header.mtime)
             .map(|secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs));
 
         let uname = CStr::from_bytes_until_nul(&header.uname)
@@ -245,19 +645,18 @@ impl<'la> EntryAttributes<'la> {
             .ok()
             .and_then(|cstr| cstr.to_str().ok());
 
-        let devmajor = CStr::from_bytes_until_nul(&header.devmajor)
-            .ok()
-            .and_then(|cstr| cstr.to_str().ok())
-            .and_then(|dev| u16::from_str_radix(dev, 8).ok())
+        let devmajor = octal(&header.devmajor)
+            .and_then(|dev| dev.try_into().ok())
             .unwrap_or(0);
 
-        let devminor = CStr::from_bytes_until_nul(&header.devminor)
-            .ok()
-            .and_then(|cstr| cstr.to_str().ok())
-            .and_then(|dev| u16::from_str_radix(dev, 8).ok())
+        let devminor = octal(&header.devminor)
+            .and_then(|dev| dev.try_into().ok())
             .unwrap_or(0);
 
         EntryAttributes {
+            mode,
+            uid,
+            gid,
             mtime,
             uname: uname.map(HtmlAttributeSafeName),
             gname: gname.map(HtmlAttributeSafeName),
@@ -267,18 +666,94 @@ impl<'la> EntryAttributes<'la> {
     }
 }
 
-impl Default for EntryAttributes<'_> {
-    fn default() -> Self {
-        Self {
-            mtime: None,
-            uname: None,
-            gname: None,
-            devmajor: 0,
-            devminor: 0,
+/// The kind of a device node, by its tar typeflag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// Typeflag `3`.
+    Character,
+    /// Typeflag `4`.
+    Block,
+}
+
+impl DeviceKind {
+    const fn typeflag(self) -> u8 {
+        match self {
+            DeviceKind::Character => b'3',
+            DeviceKind::Block => b'4',
+        }
+    }
+
+    /// The kind of a device node by its typeflag, `None` for other entries.
+    pub fn from_typeflag(typeflag: u8) -> Option<Self> {
+        match typeflag {
+            b'3' => Some(DeviceKind::Character),
+            b'4' => Some(DeviceKind::Block),
+            _ => None,
         }
     }
 }
 
+/// A device node without data, its numbers are `devmajor` and `devminor` of the attributes.
+pub struct Device<'la> {
+    pub name: HtmlAttributeSafeName<'la>,
+    pub kind: DeviceKind,
+    pub attributes: EntryAttributes<'la>,
+}
+
+/// A directory, so that it exists even without files in it.
+pub struct Directory<'la> {
+    /// The name, without a trailing `/`.
+    pub name: HtmlAttributeSafeName<'la>,
+    /// The metadata, the mode defaults to `0755` when packed.
+    pub attributes: EntryAttributes<'la>,
+}
+
+/// A second name for a file packed earlier, stored as a hard link without data of its own.
+pub struct Link<'la> {
+    /// The additional name.
+    pub name: HtmlAttributeSafeName<'la>,
+    /// The name of the file whose data is shared.
+    pub target: HtmlAttributeSafeName<'la>,
+    /// The metadata for this name.
+    pub attributes: EntryAttributes<'la>,
+}
+
+/// A symbolic link, such as `bin/sh` to `busybox`. Its target is kept as is, it need not exist.
+pub struct Symlink<'la> {
+    /// The name of the link itself.
+    pub name: HtmlAttributeSafeName<'la>,
+    /// The path the link points to, relative to its directory unless it starts with a `/`.
+    pub target: HtmlAttributeSafeName<'la>,
+    /// The metadata, the mode defaults to `0777` when packed.
+    pub attributes: EntryAttributes<'la>,
+}
+
+/// A change to a file packed earlier in the same document.
+///
+/// Re-saving an edited document can append patches instead of rewriting whole files. The data
+/// replaces the bytes of the file starting at `offset`, and extends the file if it reaches past its
+/// end. The offset must not lie beyond the end of the file. See [`apply_patch`].
+pub struct Patch<'la> {
+    /// The name of the patched file.
+    pub name: HtmlAttributeSafeName<'la>,
+    pub offset: u64,
+    pub data: &'la [u8],
+}
+
+/// Write the data of a patch over a file.
+pub fn apply_patch(file: &mut Vec<u8>, offset: u64, data: &[u8]) -> Result<(), TarError> {
+    let start = usize::try_from(offset)
+        .ok()
+        .filter(|&start| start <= file.len())
+        .ok_or(TarError::PatchOutOfBounds)?;
+
+    let overlap = data.len().min(file.len() - start);
+    file[start..][..overlap].copy_from_slice(&data[..overlap]);
+    file.extend_from_slice(&data[overlap..]);
+
+    Ok(())
+}
+
 pub struct External<'la> {
     /// An ascii name for this file.
     pub name: HtmlAttributeSafeName<'la>,
@@ -319,29 +794,81 @@ pub struct EscapedSentinel {
     pub header: TarHeader,
 }
 
+/// Closes a sequence of escaped data, after the EOF or in place of it.
+const ESCAPE_TERMINATOR: &[u8] = b"</noscript>";
+
+/// Closes the escaped data of an open-ended archive, see [`TarEngine::escaped_open_end`].
+pub struct EscapedTerminator {
+    pub padding: &'static [u8],
+    pub data: &'static [u8],
+}
+
 pub struct ParsedInitial {
     pub header: Range<usize>,
     pub continues: Range<usize>,
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum ParsedEscape {
-    Entry(TarHeader, Range<usize>),
-    EndOfEscapes { html_data: Range<usize> },
-    Eof { end: usize },
+    /// The header of a file, the range of its data, and its full name if a long name entry
+    /// preceded it.
+    Entry(TarHeader, Range<usize>, Option<String>),
+    EndOfEscapes {
+        html_data: Range<usize>,
+    },
+    Eof {
+        end: usize,
+    },
 }
 
 pub enum ParsedFileData {
     Data(Vec<u8>),
+    /// The file was outlined, its data is found at the reference.
+    External {
+        /// The link name of the header, usually a URL relative to the document.
+        reference: String,
+        realsize: u64,
+    },
+    /// A device node, which has no data.
+    Device {
+        kind: DeviceKind,
+        major: u16,
+        minor: u16,
+    },
+    /// A directory, which has no data.
+    Directory,
+    /// A symbolic link, which has no data.
+    Symlink {
+        target: String,
+    },
     Nothing,
 }
 
+/// An entry found by scanning the tar structure, see [`TarDecompiler::list`].
+pub struct ListedEntry {
+    pub header: TarHeader,
+    /// The full name, from a long name entry or the header.
+    pub path: Option<String>,
+    /// The byte range of the encoded data in the document.
+    pub data: Range<usize>,
+    /// The length of the data once decoded.
+    pub size: u64,
+}
+
 pub enum TarError {
     NameNotAscii,
     NameHasHtmlEscapes,
+    LongNameHasMarkup,
     NotAStart,
     Num(core::num::ParseIntError),
     NotEnoughData,
     NotAnExpectedEscape,
+    NumTooLarge,
+    PatchOutOfBounds,
+    InvalidDoctype,
+    BadChecksum { expected: u64, found: u64 },
+    Base64(base64::DecodeError),
+    Inflate(std::io::ErrorKind),
 }
 
 impl core::fmt::Debug for TarError {
@@ -352,10 +879,29 @@ impl core::fmt::Debug for TarError {
                 f,
                 "file names must not contain characters that can go unescaped in HTML attributes"
             ),
+            TarError::LongNameHasMarkup => write!(
+                f,
+                "file names longer than {} bytes, or not split at a `/` after at most {} bytes, \
+                must not contain `<`, `&` or nul as they are written as HTML text",
+                TarEngine::MAX_NAME_LEN,
+                TarEngine::MAX_PREFIX_LEN,
+            ),
             TarError::NotAStart => write!(f, "this does not look like a tar+html header"),
             TarError::Num(e) => write!(f, "could not parse number in the tar header: {e}"),
             TarError::NotEnoughData => write!(f, "not enough data to iterate tar structure"),
             TarError::NotAnExpectedEscape => write!(f, "the escape ends in an unexpected way"),
+            TarError::NumTooLarge => write!(f, "number does not fit its field in the tar header"),
+            TarError::PatchOutOfBounds => write!(f, "patch starts beyond the end of its file"),
+            TarError::InvalidDoctype => {
+                write!(f, "a doctype must start with `<!` and end with `>`")
+            }
+            TarError::BadChecksum { expected, found } => write!(
+                f,
+                "a tar header has checksum {found:o} but its bytes sum to {expected:o}, was the \
+                document modified?"
+            ),
+            TarError::Base64(e) => write!(f, "the data of a file is not valid base64: {e}"),
+            TarError::Inflate(e) => write!(f, "the data of a file can not be inflated: {e}"),
         }
     }
 }
@@ -368,22 +914,144 @@ impl core::fmt::Display for TarError {
 
 impl std::error::Error for TarError {}
 
+/// Engine for writing the tar structure into an HTML document.
+///
+/// Layout cost: every entry is a pair of 512-byte headers followed by its base64 data, and the
+/// next pair must start at a 512-byte boundary again. There is no denser variant, tar readers
+/// locate headers only on that alignment and the HTML escapes live inside those headers. A file
+/// of a few bytes hence costs 1.5KiB. Documents with many tiny files should bundle them into one
+/// archive file of their own that the guest unpacks, see [`TarEngine::base64_entry_size`] for
+/// estimating the tradeoff.
 #[derive(Default)]
 pub struct TarEngine {
     len: u64,
     is_escaped: bool,
+    doctype: Option<Vec<u8>>,
+    line_width: Option<core::num::NonZeroUsize>,
+    /// A long name entry was written, the next entry may have a name that does not fit.
+    long_name_next: bool,
 }
 
 impl TarEngine {
+    /// Continue a document written by us, to append entries in place of its end.
+    ///
+    /// The engine is positioned at the start of [`TarDecompiler::end_of_archive`]. Keep the
+    /// document up to [`TarEngine::offset`], write the new entries, end them again with
+    /// [`TarEngine::escaped_eof`] or [`TarEngine::escaped_open_end`], and follow with the document
+    /// after the old end. The doctype and line width are not recovered, set them as for a new
+    /// document.
+    pub fn resume_from(data: &[u8]) -> Result<TarEngine, TarError> {
+        let (end, is_escaped) = TarDecompiler::find_end(data)?;
+
+        Ok(TarEngine {
+            len: end.start as u64,
+            is_escaped,
+            ..TarEngine::default()
+        })
+    }
+
+    /// The number of bytes of the document written so far.
+    pub fn offset(&self) -> u64 {
+        self.len
+    }
+
+    /// Use this doctype for documents which have none, instead of `<!DOCTYPE html>`.
+    pub fn set_doctype(&mut self, doctype: &str) -> Result<(), TarError> {
+        Self::validate_doctype(doctype)?;
+        self.doctype = Some(doctype.as_bytes().to_vec());
+        Ok(())
+    }
+
+    /// Break the base64 data into lines of this width, like MIME does at 76.
+    ///
+    /// A browser saving the page may wrap long text by itself. Lines of our own which are short
+    /// enough leave it nothing to wrap. The width is recorded in the header, see
+    /// [`TarHeader::parse_base64_len`], and decoders skip the line breaks.
+    pub fn set_line_width(&mut self, width: Option<core::num::NonZeroUsize>) {
+        self.line_width = width;
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let encoded = STANDARD.encode(data).into_bytes();
+
+        let Some(width) = self.line_width else {
+            return encoded;
+        };
+
+        encoded.chunks(width.get()).collect::<Vec<_>>().join(&b'\n')
+    }
+
+    /// Check a doctype for [`TarEngine::set_doctype`].
+    pub fn validate_doctype(doctype: &str) -> Result<(), TarError> {
+        let inner = doctype
+            .strip_prefix("<!")
+            .and_then(|rest| rest.strip_suffix('>'))
+            .ok_or(TarError::InvalidDoctype)?;
+
+        // It must stay a single tag, the rest of the head follows it.
+        if inner.contains(['<', '>']) {
+            return Err(TarError::InvalidDoctype);
+        }
+
+        Ok(())
+    }
+
+    /// The bytes an inline entry of `data_len` raw bytes occupies, including headers and padding.
+    pub const fn base64_entry_size(data_len: u64) -> u64 {
+        let encoded = data_len.div_ceil(3) * 4;
+        2 * core::mem::size_of::<TarHeader>() as u64 + encoded.next_multiple_of(512)
+    }
+
+    /// The bytes an outlined entry occupies, only its headers referencing the data elsewhere.
+    pub const fn external_entry_size() -> u64 {
+        2 * core::mem::size_of::<TarHeader>() as u64
+    }
+
+    /// The longest head, up to and including the `<html>` tag, that fits into the first header.
+    ///
+    /// See [`TarEngine::head_len`] for how the head is measured.
+    pub const MAX_HEAD_LEN: usize = 100 - Self::DATA_ESCAPE.len() - 1;
+
+    const DATA_ESCAPE: &[u8] = b" data-a=\"";
+
+    /// The longest name in the `name` field, it also closes the HTML attribute holding the name.
+    ///
+    /// Longer names are split at a `/` and continue in the `prefix` field, as in ustar.
+    pub const MAX_NAME_LEN: usize = 100 - Self::ID_END_CONT.len() - 1;
+
+    /// The longest part of a name in the `prefix` field, the rest of it holds our numeric fields.
+    ///
+    /// Names longer than `MAX_PREFIX_LEN + 1 + MAX_NAME_LEN` bytes are preceded by a GNU long name
+    /// entry, see [`TarEngine::escaped_long_name`].
+    pub const MAX_PREFIX_LEN: usize = 23;
+
+    /// The longest target of a hard link, it must end within `linkname`.
+    pub const MAX_LINK_LEN: usize = 99;
+
+    const ID_END_CONT: &[u8] = b"\" data-b=\"";
+    /// Closes the start tag of a file element at the end of the `prefix` field, its data follows.
+    const DATA_START: &[u8] = b"\">";
+
+    /// The length of the document head in the first header, with our doctype and without runs of
+    /// whitespace. It must not exceed [`TarEngine::MAX_HEAD_LEN`].
+    pub fn head_len(&self, html_head: &[u8]) -> usize {
+        Self::collapse_whitespace(&self.doctype_safe_head(html_head)).len()
+    }
+
     /// Mangle the HTML prefix such that we can interpret it as a tar header.
     ///
     /// Must not modify HTML semantics.
     pub fn start_of_file(&mut self, html_head: &[u8], entry_offset: usize) -> InitialEscape {
         let consumed = html_head.len();
-        let html_head = Self::doctype_safe_head(html_head);
-
-        const DATA_ESCAPE: &[u8] = b" data-a=\"";
-        assert!(html_head.len() < 100 - DATA_ESCAPE.len());
+        let html_head = self.doctype_safe_head(html_head);
+        let html_head = Self::collapse_whitespace(&html_head);
+
+        const DATA_ESCAPE: &[u8] = TarEngine::DATA_ESCAPE;
+        assert!(
+            html_head.len() <= Self::MAX_HEAD_LEN,
+            "the document head up to the `<html>` tag must fit into a tar name field, have {} bytes",
+            html_head.len()
+        );
         assert_eq!(html_head.last().copied(), Some(b'>'));
 
         let all_except_close = html_head.len() - 1;
@@ -403,7 +1071,7 @@ impl TarEngine {
             comment_introducer.len() + tail_len
         );
 
-        this.assign_size(extra.len() + tail_len);
+        this.assign_size((extra.len() + tail_len) as u64);
         this.assign_permission_encoding_meta();
         this.assign_checksum();
 
@@ -422,7 +1090,7 @@ impl TarEngine {
     // Our parser, and probably a few others, will only reliably recognize an actual document if
     // there is a doctype annotation before any other element. Since we add a nul-byte in front of
     // the actual data we will ensure that we are as explicit as possible.
-    fn doctype_safe_head(head: &[u8]) -> std::borrow::Cow<'_, [u8]> {
+    fn doctype_safe_head<'head>(&self, head: &'head [u8]) -> std::borrow::Cow<'head, [u8]> {
         let has_doctype = String::from_utf8_lossy(head)
             .to_ascii_lowercase()
             .contains("<!doctype");
@@ -430,25 +1098,395 @@ impl TarEngine {
         if has_doctype {
             std::borrow::Cow::Borrowed(head)
         } else {
-            let mut owned = Vec::with_capacity(9 + head.len());
-            owned.extend_from_slice(b"<!DOCTYPE html>");
+            let doctype = self.doctype.as_deref().unwrap_or(b"<!DOCTYPE html>");
+            let mut owned = Vec::with_capacity(doctype.len() + head.len());
+            owned.extend_from_slice(doctype);
             owned.extend_from_slice(head);
             std::borrow::Cow::Owned(owned)
         }
     }
 
-    pub fn escaped_base64(
-        &mut self,
-        Entry {
-            name,
-            data,
-            attributes: extras,
-        }: Entry,
+    // Formatted templates may split the attributes of `<html>` over several lines. Outside of
+    // attribute values any run of whitespace in a tag means the same as a single space, or nothing
+    // before its closing `>`, so we save the room in the tar name field.
+    fn collapse_whitespace(head: &[u8]) -> Vec<u8> {
+        let mut collapsed = Vec::with_capacity(head.len());
+        let mut quote = None;
+
+        for &byte in head {
+            let after_space = collapsed.last() == Some(&b' ');
+
+            match quote {
+                Some(q) if byte == q => quote = None,
+                Some(_) => {}
+                None if byte == b'"' || byte == b'\'' => quote = Some(byte),
+                None if byte.is_ascii_whitespace() => {
+                    if !after_space {
+                        collapsed.push(b' ');
+                    }
+
+                    continue;
+                }
+                None if byte == b'>' && after_space => {
+                    collapsed.pop();
+                }
+                None => {}
+            }
+
+            collapsed.push(byte);
+        }
+
+        collapsed
+    }
+
+    /// Insert a GNU long name entry if `name` does not fit into a header.
+    ///
+    /// It must directly precede the entry of that name, as `tar` writes it. Its payload is the
+    /// full name, as text of an element of its own, while the header of the entry holds a
+    /// truncated one. Every entry with such a name panics if not announced this way, except in
+    /// [`TarEngine::escaped_base64_parts`] which inserts them itself.
+    pub fn escaped_long_name(&mut self, name: HtmlAttributeSafeName) -> Option<EscapedData> {
+        name.split().is_none().then(|| {
+            let mut data = name.0.as_bytes().to_vec();
+            data.push(b'\0');
+
+            let escaped =
+                self.continue_qualified(HtmlAttributeSafeName(Self::LONG_LINK), data, |_, file| {
+                    // The name is written as is, not as base64 lines.
+                    file.prefix[TarHeader::LINE_WIDTH].fill(0);
+                    file.typeflag = b'L';
+                });
+
+            self.long_name_next = true;
+            escaped
+        })
+    }
+
+    const LONG_LINK: &str = "././@LongLink";
+
+    pub fn escaped_base64(
+        &mut self,
+        Entry {
+            name,
+            data,
+            attributes: extras,
+            compression,
+        }: Entry,
     ) -> EscapedData {
-        let data = STANDARD.encode(data).into_bytes();
+        self.escaped_compressed(name, data, compression, |file| {
+            file.assign_attributes(&extras);
+        })
+    }
+
+    /// Insert a file encoded ahead of time, the same entry as [`TarEngine::escaped_base64`].
+    ///
+    /// The entry must have been encoded with the line width of this engine.
+    pub fn escaped_encoded(&mut self, entry: EncodedEntry) -> EscapedData {
+        assert_eq!(
+            entry.line_width, self.line_width,
+            "encoded for another engine"
+        );
+
+        let EncodedEntry {
+            name,
+            attributes: extras,
+            realsize,
+            compression,
+            encoded,
+            ..
+        } = entry;
+
+        self.place_compressed(name, encoded, compression, realsize, |file| {
+            file.assign_attributes(&extras);
+        })
+    }
+
+    /// Encode data, compressed if that makes it smaller, and record the compression.
+    fn escaped_compressed(
+        &mut self,
+        name: HtmlAttributeSafeName,
+        data: &[u8],
+        compression: Option<Compression>,
+        hook: impl FnOnce(&mut TarHeader),
+    ) -> EscapedData {
+        let (encoded, compression) = self.encode_compressed(data, compression);
+        self.place_compressed(name, encoded, compression, data.len() as u64, hook)
+    }
+
+    /// The encoded data, compressed if that makes it smaller, and the compression it got.
+    fn encode_compressed(
+        &self,
+        data: &[u8],
+        compression: Option<Compression>,
+    ) -> (Vec<u8>, Option<Compression>) {
+        match compression.and_then(|compression| Some((compression, compression.compress(data)?))) {
+            Some((compression, compressed)) => (self.encode(&compressed), Some(compression)),
+            None => (self.encode(data), None),
+        }
+    }
+
+    /// Place encoded data with headers recording its compression and real size.
+    fn place_compressed(
+        &mut self,
+        name: HtmlAttributeSafeName,
+        encoded: Vec<u8>,
+        compression: Option<Compression>,
+        realsize: u64,
+        hook: impl FnOnce(&mut TarHeader),
+    ) -> EscapedData {
+        self.continue_qualified(name, encoded, |_, file| {
+            hook(file);
+
+            if let Some(compression) = compression {
+                file.assign_compression(compression);
+                write_octal(&mut file.prefix[TarHeader::REALSIZE], realsize)
+                    .expect("file too large for the header");
+            }
+        })
+    }
+
+    /// Encode new data for a file written before, to replace its old data in place.
+    ///
+    /// The data is compressed and wrapped into lines as recorded in the header of the old data.
+    /// Compression is dropped when it no longer makes the data smaller. The size, real size and
+    /// checksum of the header are updated, the padding to the next block is left to the caller.
+    pub fn reencode_base64(header: &mut TarHeader, data: &[u8]) -> Vec<u8> {
+        let engine = TarEngine {
+            line_width: header
+                .parse_line_width()
+                .and_then(|width| core::num::NonZeroUsize::new(width as usize)),
+            ..TarEngine::default()
+        };
+
+        let compressed = header
+            .parse_compression()
+            .and_then(|compression| compression.compress(data));
+
+        let encoded = match &compressed {
+            Some(compressed) => {
+                write_octal(&mut header.prefix[TarHeader::REALSIZE], data.len() as u64)
+                    .expect("file too large for the header");
+                engine.encode(compressed)
+            }
+            None => {
+                header.prefix[TarHeader::COMPRESSION].fill(0);
+                header.prefix[TarHeader::REALSIZE].fill(0);
+                engine.encode(data)
+            }
+        };
+
+        header.assign_size(encoded.len() as u64);
+        header.assign_checksum();
+        encoded
+    }
+
+    /// Insert a file of `len` bytes read from `reader` as base64, writing the entry to `out`.
+    ///
+    /// The same entry as [`TarEngine::escaped_base64`] but encoded a chunk at a time, the file is
+    /// never held in memory as a whole. The padding and both headers are written first, with the
+    /// encoded length computed from `len` up front. A reader ending before `len` bytes is an
+    /// error, the document written so far is then unusable. A long name must be announced before
+    /// as for any other entry.
+    pub fn escaped_base64_reader(
+        &mut self,
+        name: HtmlAttributeSafeName,
+        attributes: EntryAttributes,
+        mut reader: impl Read,
+        len: u64,
+        out: &mut impl Write,
+    ) -> std::io::Result<()> {
+        // A multiple of 3 bytes, so that no chunk but the last is padded.
+        const CHUNK: usize = 3 * 16 * 1024;
+
+        let data_len = self.encoded_len(len);
+        let (padding, header, file) = self.qualified_headers(name, data_len, |_, file| {
+            file.assign_attributes(&attributes);
+        });
+
+        out.write_all(padding)?;
+        out.write_all(header.as_bytes())?;
+        out.write_all(file.as_bytes())?;
+
+        let mut chunk = vec![0; CHUNK];
+        let mut encoded = String::new();
+        let mut remaining = len;
+        // Bytes of the current line, breaks continue across chunks.
+        let mut column = 0;
+
+        while remaining > 0 {
+            let chunk = &mut chunk[..remaining.min(CHUNK as u64) as usize];
+            reader.read_exact(chunk)?;
+            remaining -= chunk.len() as u64;
+
+            encoded.clear();
+            STANDARD.encode_string(chunk, &mut encoded);
+
+            let Some(width) = self.line_width else {
+                out.write_all(encoded.as_bytes())?;
+                continue;
+            };
+
+            let mut rest = encoded.as_bytes();
+            while !rest.is_empty() {
+                if column == width.get() {
+                    out.write_all(b"\n")?;
+                    column = 0;
+                }
+
+                let (line, after) = rest.split_at(rest.len().min(width.get() - column));
+                out.write_all(line)?;
+                column += line.len();
+                rest = after;
+            }
+        }
+
+        self.len += data_len;
+        Ok(())
+    }
+
+    /// The length of `len` bytes as encoded by [`TarEngine::encode`], with line breaks.
+    fn encoded_len(&self, len: u64) -> u64 {
+        let encoded = len.div_ceil(3) * 4;
+
+        match self.line_width {
+            Some(width) if encoded > 0 => encoded + (encoded - 1) / width.get() as u64,
+            _ => encoded,
+        }
+    }
+
+    /// Insert a file as base64, split into parts of at most `part_size` bytes of data.
+    ///
+    /// Browsers limit the length of a single text node, so a very large file is better spread over
+    /// several elements. Every part is compressed and encoded on its own, the ones after the first
+    /// are marked as continuations with their offset into the file. Extraction concatenates them
+    /// in order. A long name is announced before every part.
+    pub fn escaped_base64_parts(&mut self, entry: Entry, part_size: usize) -> Vec<EscapedData> {
+        assert!(part_size > 0, "parts must contain data");
+
+        if entry.data.len() <= part_size {
+            let long_name = self.escaped_long_name(entry.name);
+            return long_name
+                .into_iter()
+                .chain([self.escaped_base64(entry)])
+                .collect();
+        }
+
+        let Entry {
+            name,
+            data,
+            attributes: extras,
+            compression,
+        } = entry;
+
+        data.chunks(part_size)
+            .enumerate()
+            .flat_map(|(idx, part)| {
+                let offset = (idx * part_size) as u64;
+                let long_name = self.escaped_long_name(name);
+
+                let part = self.escaped_compressed(name, part, compression, |file| {
+                    file.assign_attributes(&extras);
+
+                    if offset > 0 {
+                        file.typeflag = b'M';
+                        write_octal(&mut file.prefix[TarHeader::OFFSET], offset)
+                            .expect("part offset too large for the header");
+                    }
+                });
+
+                long_name.into_iter().chain([part])
+            })
+            .collect()
+    }
+
+    /// Insert a patch to a file packed earlier, as base64.
+    pub fn escaped_patch(&mut self, Patch { name, offset, data }: Patch) -> EscapedData {
+        let data = self.encode(data);
 
         self.continue_qualified(name, data, |_, file| {
+            file.typeflag = b'P';
+            write_octal(&mut file.prefix[TarHeader::OFFSET], offset)
+                .expect("patch offset too large for the header");
+        })
+    }
+
+    /// Insert a device node.
+    pub fn escaped_device(
+        &mut self,
+        Device {
+            name,
+            kind,
+            attributes: extras,
+        }: Device,
+    ) -> EscapedData {
+        self.continue_qualified(name, Vec::new(), |_, file| {
             file.assign_attributes(&extras);
+            file.typeflag = kind.typeflag();
+        })
+    }
+
+    /// Insert a directory.
+    pub fn escaped_directory(
+        &mut self,
+        Directory {
+            name,
+            attributes: extras,
+        }: Directory,
+    ) -> EscapedData {
+        self.continue_qualified(name, Vec::new(), |_, file| {
+            write_octal(&mut file.mode, 0o755).unwrap();
+            file.assign_attributes(&extras);
+            file.typeflag = b'5';
+        })
+    }
+
+    /// Insert a hard link to a file from earlier in the document.
+    ///
+    /// The target must fit [`TarEngine::MAX_LINK_LEN`], unlike names there is no prefix or long
+    /// name entry for it.
+    pub fn escaped_link(
+        &mut self,
+        Link {
+            name,
+            target,
+            attributes: extras,
+        }: Link,
+    ) -> EscapedData {
+        self.continue_qualified(name, Vec::new(), |_, file| {
+            let HtmlAttributeSafeName(target) = target;
+            assert!(
+                target.len() <= Self::MAX_LINK_LEN,
+                "the link target {target:?} does not fit a header"
+            );
+
+            file.assign_attributes(&extras);
+            file.linkname[..target.len()].copy_from_slice(target.as_bytes());
+            file.typeflag = b'1';
+        })
+    }
+
+    /// Insert a symbolic link.
+    ///
+    /// The target must fit [`TarEngine::MAX_LINK_LEN`] like the one of a hard link.
+    pub fn escaped_symlink(
+        &mut self,
+        Symlink {
+            name,
+            target,
+            attributes: extras,
+        }: Symlink,
+    ) -> EscapedData {
+        self.continue_qualified(name, Vec::new(), |_, file| {
+            let HtmlAttributeSafeName(target) = target;
+            assert!(
+                target.len() <= Self::MAX_LINK_LEN,
+                "the symlink target {target:?} does not fit a header"
+            );
+
+            write_octal(&mut file.mode, 0o777).unwrap();
+            file.assign_attributes(&extras);
+            file.linkname[..target.len()].copy_from_slice(target.as_bytes());
+            file.typeflag = b'2';
         })
     }
 
@@ -464,24 +1502,43 @@ impl TarEngine {
     ) -> EscapedData {
         self.continue_qualified(name, Vec::new(), |_, file| {
             let HtmlAttributeSafeName(qualref) = reference;
-            let realsize_off = 452 - 345;
 
             // This does not assign any of the below fields but anyways.
             file.assign_attributes(&extras);
 
             file.linkname[0..][..qualref.len()].copy_from_slice(qualref.as_bytes());
             file.typeflag = b'S';
-            file.prefix[realsize_off..][..11]
-                .copy_from_slice(format!("{realsize:011o}").as_bytes());
+            write_octal(&mut file.prefix[TarHeader::REALSIZE], realsize)
+                .expect("realsize too large for the header");
         })
     }
 
     fn continue_qualified(
         &mut self,
-        HtmlAttributeSafeName(qualname): HtmlAttributeSafeName,
+        qualname: HtmlAttributeSafeName,
         data: Vec<u8>,
         hook: impl FnOnce(&mut TarHeader, &mut TarHeader),
     ) -> EscapedData {
+        let (padding, header, file) = self.qualified_headers(qualname, data.len() as u64, hook);
+
+        // Followed by the data.
+        self.len += data.len() as u64;
+
+        EscapedData {
+            padding,
+            header,
+            file,
+            data,
+        }
+    }
+
+    /// The headers of an entry with `data_len` bytes of data, which the caller writes after them.
+    fn qualified_headers(
+        &mut self,
+        qualname: HtmlAttributeSafeName,
+        data_len: u64,
+        hook: impl FnOnce(&mut TarHeader, &mut TarHeader),
+    ) -> (&'static [u8], TarHeader, TarHeader) {
         let padding = self.pad_to_fit();
 
         // How to start our extension header for a new escape.
@@ -491,8 +1548,8 @@ impl TarEngine {
             b"\0</noscript><noscript type=none class=\"wah_polyglot_data\" data-a=\"";
 
         const ID: &[u8] = b"\" data-wahtml_id=\"";
-        const ID_END_CONT: &[u8] = b"\" data-b=\"";
-        const DATA_START: &[u8] = b"\">";
+        const ID_END_CONT: &[u8] = TarEngine::ID_END_CONT;
+        const DATA_START: &[u8] = TarEngine::DATA_START;
 
         let start = if self.is_escaped {
             CONT_NAME
@@ -510,9 +1567,22 @@ impl TarEngine {
         this.prefix[end_start..].copy_from_slice(ID);
         self.len += core::mem::size_of::<TarHeader>() as u64;
 
+        let announced = core::mem::take(&mut self.long_name_next);
+        let (prefix, qualname) = qualname.split().unwrap_or_else(|| {
+            assert!(
+                announced,
+                "the name {:?} does not fit a header, see `TarEngine::escaped_long_name`",
+                qualname.0
+            );
+
+            // Truncated, like `tar` does. Readers take the name from the long name entry.
+            ("", &qualname.0[..Self::MAX_NAME_LEN])
+        });
+
         let mut file = TarHeader::EMPTY;
         let end_start = file.prefix.len() - DATA_START.len();
         file.name[..qualname.len()].copy_from_slice(qualname.as_bytes());
+        file.prefix[..prefix.len()].copy_from_slice(prefix.as_bytes());
 
         // We place the closing quotation for the HTML attribute covering the file name at the end
         // of this field. This does not influence the Tar interpretation (nul-byte is already
@@ -523,43 +1593,45 @@ impl TarEngine {
         cont_place[cont_idx..].copy_from_slice(ID_END_CONT);
         file.prefix[end_start..].copy_from_slice(DATA_START);
 
-        file.assign_size(data.len());
+        file.assign_size(data_len);
         file.assign_permission_encoding_meta();
 
+        if let Some(width) = self.line_width.filter(|_| data_len > 0) {
+            write_octal(&mut file.prefix[TarHeader::LINE_WIDTH], width.get() as u64)
+                .expect("line width too large for the header");
+        }
+
         hook(&mut this, &mut file);
 
         this.assign_checksum();
         file.assign_checksum();
         self.len += core::mem::size_of::<TarHeader>() as u64;
 
-        // Followed by the data.
-        self.len += data.len() as u64;
-
-        EscapedData {
-            padding,
-            header: this,
-            file,
-            data,
-        }
+        (padding, this, file)
     }
 
     /// End a sequence of escaped data, with a particular skip of raw HTML bytes to follow until
     /// the next blocks of such data (again starting as `escaped_insert_base64`).
+    ///
+    /// The sentinel is an element of its own, marked by the class `wah_polyglot_skip` so that
+    /// the data can be cleaned from the document again. The caller writes exactly `skip` bytes
+    /// after it, the next entry pads them to a block.
     pub fn escaped_end(&mut self, skip: usize) -> EscapedSentinel {
         assert!(self.is_escaped);
         let padding = self.pad_to_fit();
 
-        const START: &[u8] = b"\0</noscript><noscript type=none>";
+        const START: &[u8] = b"\0</noscript><noscript type=none class=wah_polyglot_skip>";
         const END: &[u8] = b"\0</noscript>";
 
         let mut this = TarHeader::EMPTY;
         this.name[..START.len()].copy_from_slice(START);
-        this.assign_size(skip);
+        this.assign_size(skip as u64);
         this.prefix[155 - END.len()..].copy_from_slice(END);
         this.assign_permission_encoding_meta();
         this.assign_checksum();
 
         self.is_escaped = false;
+        self.len += core::mem::size_of::<TarHeader>() as u64 + skip as u64;
 
         EscapedSentinel {
             padding,
@@ -567,38 +1639,54 @@ impl TarEngine {
         }
     }
 
-    /// End a sequence of escaped data with a tar EOF.
+    /// End the archive with a tar EOF, two zeroed blocks.
+    ///
+    /// Within a sequence of escaped data the `</noscript>` closing it follows the EOF, outside of
+    /// one, as in a document without any entries, nothing does.
     pub fn escaped_eof(&mut self) -> EscapedData {
-        if self.is_escaped {
-            self.inner_escaped_eof()
-        } else {
-            self.inner_insert_eof()
-        }
-    }
+        let padding = self.pad_to_fit();
+        self.len += 2 * core::mem::size_of::<TarHeader>() as u64;
 
-    fn inner_escaped_eof(&mut self) -> EscapedData {
         EscapedData {
-            padding: self.pad_to_fit(),
+            padding,
             header: TarHeader::EMPTY,
             file: TarHeader::EMPTY,
-            data: b"</noscript>".to_vec(),
+            data: self.close_escape().to_vec(),
         }
     }
 
-    pub fn insert_eof(&mut self) -> EscapedData {
-        self.escaped_eof()
-    }
+    /// End a sequence of escaped data without a tar EOF, leaving the archive open for entries to
+    /// be appended later.
+    ///
+    /// Only the `</noscript>` terminator follows the padding, the HTML of the document after it
+    /// is not a tar header. [`TarDecompiler`] stops at the terminator but plain tar tools will
+    /// complain about garbage at the end of the archive, or refuse it outright. Appending entries
+    /// means replacing the terminator, which sits at a block boundary, and writing it again after
+    /// the new data.
+    pub fn escaped_open_end(&mut self) -> EscapedTerminator {
+        if !self.is_escaped {
+            return EscapedTerminator {
+                padding: &[],
+                data: &[],
+            };
+        }
 
-    /// Outside a data escape block, insert a tar EOF marker.
-    fn inner_insert_eof(&mut self) -> EscapedData {
-        EscapedData {
+        EscapedTerminator {
             padding: self.pad_to_fit(),
-            header: TarHeader::EMPTY,
-            file: TarHeader::EMPTY,
-            data: vec![],
+            data: self.close_escape(),
         }
     }
 
+    /// Leave the sequence of escaped data, returning the terminator closing it if one is open.
+    fn close_escape(&mut self) -> &'static [u8] {
+        if !core::mem::take(&mut self.is_escaped) {
+            return &[];
+        }
+
+        self.len += ESCAPE_TERMINATOR.len() as u64;
+        ESCAPE_TERMINATOR
+    }
+
     fn pad_to_fit(&mut self) -> &'static [u8] {
         static POTENTIAL_PADDING: [u8; 512] = [0; 512];
         let pad = self.len.next_multiple_of(512) - self.len;
@@ -607,19 +1695,475 @@ impl TarEngine {
     }
 }
 
+/// Read a standard tar archive and escape its entries, keeping their names and attributes.
+///
+/// Regular files, directories, hard and symbolic links and device nodes are converted. Extension
+/// records only name the entries after them, entries with names or link targets that are not safe
+/// in an HTML attribute are skipped. See [`read_tar_stream`] for the names.
+///
+/// The iterator ends at the end of the archive, and also at a read error of the reader or at a
+/// header with a wrong checksum. Either looks like a truncated archive to the caller.
+pub fn pack_tar_stream<'e, R: Read + 'e>(
+    engine: &'e mut TarEngine,
+    reader: R,
+) -> impl Iterator<Item = EscapedData> + 'e {
+    read_tar_stream(reader)
+        .map_while(Result::ok)
+        .filter_map(move |entry| escape_standard_entry(engine, &entry))
+        .flatten()
+}
+
+/// An entry of a standard tar archive, see [`read_tar_stream`].
+pub struct StandardEntry {
+    /// The full path, from a PAX or GNU long name record or the header. Without a leading `./`
+    /// and for directories without the trailing `/`.
+    pub name: String,
+    pub header: TarHeader,
+    /// The link target of a PAX or GNU long link record, in place of the header's `linkname`.
+    pub link_name: Option<String>,
+    /// The data following the header, empty for anything but regular files.
+    pub data: Vec<u8>,
+}
+
+impl StandardEntry {
+    /// The metadata of the header. Owner names that are not safe in HTML are left out.
+    pub fn attributes(&self) -> EntryAttributes<'_> {
+        let mut attributes = EntryAttributes::from_header(&self.header);
+        attributes.uname = attributes
+            .uname
+            .and_then(|uname| HtmlAttributeSafeName::new(uname.0).ok());
+        attributes.gname = attributes
+            .gname
+            .and_then(|gname| HtmlAttributeSafeName::new(gname.0).ok());
+        attributes
+    }
+
+    /// For a hard link, the path of its target, without a leading `./` like the names.
+    pub fn link_target(&self) -> Option<&str> {
+        if self.header.typeflag != b'1' {
+            return None;
+        }
+
+        let target = self.linkname()?;
+        Some(target.strip_prefix("./").unwrap_or(target))
+    }
+
+    /// For a symbolic link, the path it points to. Unlike a hard link it is kept as written.
+    pub fn symlink_target(&self) -> Option<&str> {
+        if self.header.typeflag != b'2' {
+            return None;
+        }
+
+        self.linkname()
+    }
+
+    fn linkname(&self) -> Option<&str> {
+        if let Some(link_name) = &self.link_name {
+            return Some(link_name);
+        }
+
+        let target = CStr::from_bytes_until_nul(&self.header.linkname).ok()?;
+        target.to_str().ok()
+    }
+}
+
+/// The names a PAX record gives, see [`read_tar_stream`].
+#[derive(Clone, Default)]
+struct PaxNames {
+    path: Option<String>,
+    linkpath: Option<String>,
+}
+
+impl PaxNames {
+    /// Take over the `path` and `linkpath` of the records in `data`, other keys are ignored. An
+    /// empty value unsets the key.
+    fn read(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let malformed =
+            || std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed PAX record");
+        let mut rest = data;
+
+        while !rest.is_empty() {
+            // `<length> <key>=<value>\n`, the length counts the whole record.
+            let space = rest.iter().position(|&b| b == b' ').ok_or_else(malformed)?;
+            let len = core::str::from_utf8(&rest[..space])
+                .ok()
+                .and_then(|len| len.parse::<usize>().ok())
+                .filter(|&len| len > space && len <= rest.len())
+                .ok_or_else(malformed)?;
+
+            let (record, tail) = rest.split_at(len);
+            rest = tail;
+
+            let record = record[space + 1..]
+                .strip_suffix(b"\n")
+                .ok_or_else(malformed)?;
+            let eq = record
+                .iter()
+                .position(|&b| b == b'=')
+                .ok_or_else(malformed)?;
+            let value = &record[eq + 1..];
+            let value = (!value.is_empty()).then(|| String::from_utf8_lossy(value).into_owned());
+
+            match &record[..eq] {
+                b"path" => self.path = value,
+                b"linkpath" => self.linkpath = value,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the entries of a standard tar archive, one at a time.
+///
+/// The `path` and `linkpath` of a PAX record, and GNU long name and long link records, name the
+/// entry after them. Those of a global PAX record name all later entries, unless the entry has
+/// its own. Other keys of PAX records are ignored. A leading `./` of names is dropped, as is the
+/// entry of the root directory itself. Names that are not UTF-8 have their invalid bytes replaced.
+///
+/// The iterator ends at the end of the archive, or at an archive ending between entries without
+/// its zero blocks. A read error of the reader, a truncated entry or a header with a wrong
+/// checksum is an error, after which the iterator ends as well.
+pub fn read_tar_stream<R: Read>(
+    mut reader: R,
+) -> impl Iterator<Item = std::io::Result<StandardEntry>> {
+    let mut ended = false;
+    let (mut long_name, mut long_link) = (None, None);
+    let (mut pax, mut global) = (PaxNames::default(), PaxNames::default());
+
+    core::iter::from_fn(move || loop {
+        if ended {
+            return None;
+        }
+
+        let (header, data) = match read_standard_entry(&mut reader) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                ended = true;
+                continue;
+            }
+            Err(err) => {
+                ended = true;
+                return Some(Err(err));
+            }
+        };
+
+        let c_str = |field: &[u8]| {
+            let end = field
+                .iter()
+                .position(|&b| b == b'\0')
+                .unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+
+        match header.typeflag {
+            b'L' => {
+                long_name = Some(c_str(&data));
+                continue;
+            }
+            b'K' => {
+                long_link = Some(c_str(&data));
+                continue;
+            }
+            b'x' | b'g' => {
+                let names = if header.typeflag == b'x' {
+                    &mut pax
+                } else {
+                    &mut global
+                };
+
+                if let Err(err) = names.read(&data) {
+                    ended = true;
+                    return Some(Err(err));
+                }
+
+                continue;
+            }
+            _ => {}
+        }
+
+        let PaxNames { path, linkpath } = core::mem::take(&mut pax);
+        let (long_name, long_link) = (long_name.take(), long_link.take());
+        let link_name = linkpath.or(long_link).or_else(|| global.linkpath.clone());
+
+        let name = match path.or(long_name).or_else(|| global.path.clone()) {
+            Some(name) => name,
+            None => {
+                let mut name = c_str(&header.name);
+                let prefix = c_str(&header.prefix);
+
+                if header.magic.starts_with(b"ustar") && !prefix.is_empty() {
+                    name = format!("{prefix}/{name}");
+                }
+
+                name
+            }
+        };
+
+        let name = name.strip_prefix("./").unwrap_or(&name);
+        let name = match header.typeflag {
+            b'5' => name.trim_end_matches('/'),
+            _ => name,
+        };
+
+        // The root directory of the archive.
+        if name.is_empty() {
+            continue;
+        }
+
+        return Some(Ok(StandardEntry {
+            name: name.to_owned(),
+            header,
+            link_name,
+            data,
+        }));
+    })
+}
+
+/// The next header of a standard tar archive and the data following it, until the end marker.
+fn read_standard_entry(reader: &mut impl Read) -> std::io::Result<Option<(TarHeader, Vec<u8>)>> {
+    use std::io::{Error, ErrorKind};
+
+    let mut block = [0u8; 512];
+    let mut filled = 0;
+
+    while filled < block.len() {
+        match reader.read(&mut block[filled..]) {
+            // Ending without the zero blocks, as some writers do.
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut header = TarHeader::EMPTY;
+    header.assign_from_bytes(&block);
+
+    if header.is_empty() {
+        return Ok(None);
+    }
+
+    header
+        .verify_checksum()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+    let size = header
+        .parse_size()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    let mut data = vec![];
+    reader
+        .take(size.next_multiple_of(512))
+        .read_to_end(&mut data)?;
+
+    if (data.len() as u64) < size {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+
+    data.truncate(size as usize);
+    Ok(Some((header, data)))
+}
+
+fn escape_standard_entry(
+    engine: &mut TarEngine,
+    entry: &StandardEntry,
+) -> Option<Vec<EscapedData>> {
+    let StandardEntry {
+        name, header, data, ..
+    } = entry;
+    let name = HtmlAttributeSafeName::new(name).ok()?;
+    let attributes = entry.attributes();
+
+    // Everything that can fail is checked before announcing a long name for the entry.
+    let target = match header.typeflag {
+        b'1' => Some(HtmlAttributeSafeName::new(entry.link_target()?).ok()?),
+        _ => None,
+    };
+
+    let symlink = match header.typeflag {
+        b'2' => Some(
+            HtmlAttributeSafeName::new(entry.symlink_target()?)
+                .ok()
+                .filter(|target| target.0.len() <= TarEngine::MAX_LINK_LEN)?,
+        ),
+        _ => None,
+    };
+
+    let kind = match header.typeflag {
+        b'0' | b'\0' | b'7' | b'1' | b'2' | b'5' => None,
+        typeflag => Some(DeviceKind::from_typeflag(typeflag)?),
+    };
+
+    let mut escaped: Vec<_> = engine.escaped_long_name(name).into_iter().collect();
+
+    escaped.push(if let Some(target) = target {
+        engine.escaped_link(Link {
+            name,
+            target,
+            attributes,
+        })
+    } else if let Some(target) = symlink {
+        engine.escaped_symlink(Symlink {
+            name,
+            target,
+            attributes,
+        })
+    } else if let Some(kind) = kind {
+        engine.escaped_device(Device {
+            name,
+            kind,
+            attributes,
+        })
+    } else if header.typeflag == b'5' {
+        engine.escaped_directory(Directory { name, attributes })
+    } else {
+        engine.escaped_base64(Entry {
+            name,
+            data,
+            attributes,
+            compression: None,
+        })
+    });
+
+    Some(escaped)
+}
+
 /// Engine for turning a tar archive written by us into its constituent parts.
 #[derive(Default)]
 pub struct TarDecompiler {
     len: u64,
 }
 
+/// The escapes of a document, see [`TarDecompiler::entries`].
+pub struct Entries<'d, 'a> {
+    decompiler: &'d mut TarDecompiler,
+    data: &'a [u8],
+    is_in_escape: bool,
+    done: bool,
+}
+
+impl Iterator for Entries<'_, '_> {
+    type Item = Result<ParsedEscape, TarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let parsed = if self.is_in_escape {
+            self.decompiler.continue_escape(self.data)
+        } else {
+            self.decompiler.next_escape(self.data)
+        };
+
+        match &parsed {
+            Ok(ParsedEscape::Entry(..)) => self.is_in_escape = true,
+            Ok(ParsedEscape::EndOfEscapes { .. }) => self.is_in_escape = false,
+            Ok(ParsedEscape::Eof { .. }) | Err(_) => self.done = true,
+        }
+
+        Some(parsed)
+    }
+}
+
+impl core::iter::FusedIterator for Entries<'_, '_> {}
+
 impl TarDecompiler {
+    /// Scan the tar structure of a document for its entries, without parsing any HTML.
+    ///
+    /// Much cheaper than going through the DOM but this relies on the document being byte-exact
+    /// as we wrote it. Any mangling of the headers by a browser breaks this path.
+    pub fn list(data: &[u8]) -> Result<Vec<ListedEntry>, TarError> {
+        let mut decompiler = TarDecompiler::default();
+        decompiler.start_of_file(data)?;
+
+        let mut entries = vec![];
+
+        for parsed in decompiler.entries(data) {
+            match parsed? {
+                ParsedEscape::Entry(header, range, long_name) => {
+                    let encoded = data.get(range.clone()).ok_or(TarError::NotEnoughData)?;
+
+                    let size = if header.typeflag == b'S' {
+                        0
+                    } else if let Some(realsize) = header.parse_realsize() {
+                        realsize
+                    } else {
+                        let padding = encoded.iter().rev().take_while(|&&b| b == b'=').count();
+                        let base64_len = header.parse_base64_len().map_err(TarError::Num)?;
+                        (base64_len as usize / 4 * 3).saturating_sub(padding) as u64
+                    };
+
+                    entries.push(ListedEntry {
+                        path: long_name.or_else(|| header.parse_path()),
+                        header,
+                        data: range,
+                        size,
+                    });
+                }
+                ParsedEscape::EndOfEscapes { .. } | ParsedEscape::Eof { .. } => {}
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Where the end of the archive is, the EOF blocks and the `</noscript>` closing the last data.
+    ///
+    /// For an open-ended archive only the `</noscript>`. Appending replaces this range, see
+    /// [`TarEngine::resume_from`].
+    pub fn end_of_archive(data: &[u8]) -> Result<Range<usize>, TarError> {
+        Self::find_end(data).map(|(end, _)| end)
+    }
+
+    fn find_end(data: &[u8]) -> Result<(Range<usize>, bool), TarError> {
+        let mut decompiler = TarDecompiler::default();
+        decompiler.start_of_file(data)?;
+
+        let mut entries = decompiler.entries(data);
+
+        loop {
+            let start = entries.decompiler.len.next_multiple_of(512) as usize;
+
+            match entries.next() {
+                Some(Ok(ParsedEscape::Eof { end })) => {
+                    return Ok((start..end, entries.is_in_escape))
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err),
+                None => unreachable!("the entries end with an EOF or an error"),
+            }
+        }
+    }
+
+    /// Iterate the escapes of a document after its start, up to and including its EOF.
+    ///
+    /// Call [`TarDecompiler::start_of_file`] first. Whether the next escape continues a sequence
+    /// of escaped data, see [`TarDecompiler::continue_escape`], is tracked for the caller. The
+    /// iteration ends after the EOF or the first error.
+    pub fn entries<'d, 'a>(&'d mut self, data: &'a [u8]) -> Entries<'d, 'a> {
+        Entries {
+            decompiler: self,
+            data,
+            is_in_escape: false,
+            done: false,
+        }
+    }
+
     pub fn start_of_file(&mut self, data: &[u8]) -> Result<ParsedInitial, TarError> {
-        assert!(data.len() >= core::mem::size_of::<TarHeader>());
+        let Some(head) = data.first_chunk::<512>() else {
+            return Err(TarError::NotEnoughData);
+        };
 
         let mut this = TarHeader::EMPTY;
-        this.assign_from_bytes(data[..512].try_into().unwrap());
-        assert_eq!(this.typeflag, b'x');
+        this.assign_from_bytes(head);
+        if this.typeflag != b'x' {
+            return Err(TarError::NotAStart);
+        }
+
+        this.verify_checksum()?;
 
         let size = this.parse_size().map_err(TarError::Num)?;
         self.len += core::mem::size_of::<TarHeader>() as u64;
@@ -654,9 +2198,9 @@ impl TarDecompiler {
         escape: &ParsedEscape,
     ) -> Result<ParsedFileData, TarError> {
         match escape {
-            ParsedEscape::Entry(header, range) => {
+            ParsedEscape::Entry(header, range, _) => {
                 let data = data.get(range.clone()).ok_or(TarError::NotEnoughData)?;
-                Ok(Self::file_data(header, data))
+                Self::file_data(header, data)
             }
             ParsedEscape::EndOfEscapes { .. } | ParsedEscape::Eof { .. } => {
                 Ok(ParsedFileData::Nothing)
@@ -664,42 +2208,117 @@ impl TarDecompiler {
         }
     }
 
-    pub fn file_data(header: &TarHeader, data: &[u8]) -> ParsedFileData {
+    /// Decode the data of an entry, which fails if a browser mangled its base64.
+    pub fn file_data(header: &TarHeader, data: &[u8]) -> Result<ParsedFileData, TarError> {
         if header.typeflag == b'x' {
             // This isn't a file, this is a header!
-            return ParsedFileData::Nothing;
+            return Ok(ParsedFileData::Nothing);
         }
 
         if header.typeflag == b'S' {
-            // FIXME: this file was outlined from the document. Return the URL reference
-            // and checksum for it instead.
-            return ParsedFileData::Nothing;
+            return Ok(ParsedFileData::External {
+                reference: header
+                    .parse_link()
+                    .map(|reference| reference.0.to_owned())
+                    .unwrap_or_default(),
+                realsize: header.parse_realsize().unwrap_or(0),
+            });
+        }
+
+        if header.typeflag == b'5' {
+            return Ok(ParsedFileData::Directory);
+        }
+
+        if let Some(target) = header.parse_symlink() {
+            return Ok(ParsedFileData::Symlink {
+                target: target.0.to_owned(),
+            });
         }
 
-        ParsedFileData::Data(STANDARD.decode(data).unwrap())
+        if let Some(kind) = DeviceKind::from_typeflag(header.typeflag) {
+            let attributes = EntryAttributes::from_header(header);
+
+            return Ok(ParsedFileData::Device {
+                kind,
+                major: attributes.devmajor,
+                minor: attributes.devminor,
+            });
+        }
+
+        let decoded = if header.parse_line_width().is_some() {
+            let unwrapped: Vec<u8> = data.iter().copied().filter(|&b| b != b'\n').collect();
+            STANDARD.decode(unwrapped)
+        } else {
+            STANDARD.decode(data)
+        };
+
+        let decoded = decoded.map_err(TarError::Base64)?;
+
+        let data = match header.parse_compression() {
+            Some(compression) => compression
+                .decompress(&decoded)
+                .map_err(|err| TarError::Inflate(err.kind()))?,
+            None => decoded,
+        };
+
+        Ok(ParsedFileData::Data(data))
     }
 
     pub fn next_escape(&mut self, data: &[u8]) -> Result<ParsedEscape, TarError> {
-        self.next_double_header(data)
+        let esc = self.next_double_header(data)?;
+        self.with_long_name(data, esc)
     }
 
     pub fn continue_escape(&mut self, data: &[u8]) -> Result<ParsedEscape, TarError> {
-        let mut esc = self.next_double_header(data)?;
+        let esc = self.continue_double_header(data)?;
+        self.with_long_name(data, esc)
+    }
 
-        if let ParsedEscape::Eof { end } = &mut esc {
-            const TERMINATOR: &[u8] = b"</noscript>";
+    /// Read the name of a GNU long name entry and attach it to the entry that must follow.
+    fn with_long_name(&mut self, data: &[u8], esc: ParsedEscape) -> Result<ParsedEscape, TarError> {
+        let ParsedEscape::Entry(header, range, None) = &esc else {
+            return Ok(esc);
+        };
 
-            if data[*end..][..TERMINATOR.len()] != *TERMINATOR {
-                return Err(TarError::NotAnExpectedEscape);
+        if header.typeflag != b'L' {
+            return Ok(esc);
+        }
+
+        let name = data.get(range.clone()).ok_or(TarError::NotEnoughData)?;
+        let name = name.split(|&b| b == b'\0').next().unwrap_or_default();
+        let name = core::str::from_utf8(name).map_err(|_| TarError::NameNotAscii)?;
+
+        match self.continue_double_header(data)? {
+            ParsedEscape::Entry(file, range, None) if file.typeflag != b'L' => {
+                Ok(ParsedEscape::Entry(file, range, Some(name.to_owned())))
             }
+            _ => Err(TarError::NotAnExpectedEscape),
+        }
+    }
 
-            // We have added the `</template>` outside any header so we need to also skip it here.
-            *end += TERMINATOR.len();
+    fn continue_double_header(&mut self, data: &[u8]) -> Result<ParsedEscape, TarError> {
+        // An open-ended archive has no EOF, the terminator directly follows the padding.
+        self.pad_to_fit();
+        if let Some(end) = Self::after_terminator(data, self.len as usize) {
+            return Ok(ParsedEscape::Eof { end });
+        }
+
+        let mut esc = self.next_double_header(data)?;
+
+        // Otherwise it follows the EOF, outside any header, and is part of the end as well.
+        if let ParsedEscape::Eof { end } = &mut esc {
+            *end = Self::after_terminator(data, *end).ok_or(TarError::NotAnExpectedEscape)?;
         }
 
         Ok(esc)
     }
 
+    /// The end of the terminator of escaped data, if one starts at `at`.
+    fn after_terminator(data: &[u8], at: usize) -> Option<usize> {
+        let end = at + ESCAPE_TERMINATOR.len();
+        (data.get(at..end)? == ESCAPE_TERMINATOR).then_some(end)
+    }
+
     fn next_double_header(&mut self, data: &[u8]) -> Result<ParsedEscape, TarError> {
         self.pad_to_fit();
 
@@ -712,7 +2331,9 @@ impl TarDecompiler {
         extension.assign_from_bytes(header.try_into().unwrap());
 
         if extension.prefix.ends_with(b"</noscript>") {
-            let size = extension.parse_size().unwrap();
+            extension.verify_checksum()?;
+
+            let size = extension.parse_size().map_err(TarError::Num)?;
             self.len += core::mem::size_of::<TarHeader>() as u64;
             let start_of_data = self.len as usize;
             self.len += size;
@@ -726,12 +2347,14 @@ impl TarDecompiler {
 
         let mut file = TarHeader::EMPTY;
         file.assign_from_bytes(file_raw.try_into().unwrap());
-        let size = file.parse_size().unwrap();
 
-        // Now check what we are dealing with.
-        if extension.as_bytes() == TarHeader::EMPTY.as_bytes()
-            && file.as_bytes() == TarHeader::EMPTY.as_bytes()
-        {
+        // Now check what we are dealing with. An EOF is two zeroed blocks, a single one is not
+        // written by anyone and more likely a document cut short.
+        if extension.is_empty() {
+            if !file.is_empty() {
+                return Err(TarError::NotAnExpectedEscape);
+            }
+
             self.len += core::mem::size_of::<TarHeader>() as u64 * 2;
 
             return Ok(ParsedEscape::Eof {
@@ -739,6 +2362,10 @@ impl TarDecompiler {
             });
         }
 
+        extension.verify_checksum()?;
+        file.verify_checksum()?;
+        let size = file.parse_size().map_err(TarError::Num)?;
+
         self.len += core::mem::size_of::<TarHeader>() as u64 * 2;
         let file_start = self.len as usize;
         // Followed by the data.
@@ -753,7 +2380,7 @@ impl TarDecompiler {
             return Err(TarError::NotAnExpectedEscape);
         }
 
-        Ok(ParsedEscape::Entry(file, file_start..file_end))
+        Ok(ParsedEscape::Entry(file, file_start..file_end, None))
     }
 
     fn pad_to_fit(&mut self) {
@@ -764,6 +2391,9 @@ impl TarDecompiler {
 #[test]
 fn test_tar_header() {
     let attributes = EntryAttributes {
+        mode: Some(0o755),
+        uid: Some(1000),
+        gid: Some(100),
         mtime: Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1234)),
         uname: Some(HtmlAttributeSafeName("alice")),
         gname: Some(HtmlAttributeSafeName("bob")),
@@ -776,9 +2406,913 @@ fn test_tar_header() {
     header.assign_checksum();
 
     let after = EntryAttributes::from_header(&header);
+    assert_eq!(after.mode, attributes.mode);
+    assert_eq!(after.uid, attributes.uid);
+    assert_eq!(after.gid, attributes.gid);
     assert_eq!(after.mtime, attributes.mtime);
     assert_eq!(after.uname, attributes.uname);
     assert_eq!(after.gname, attributes.gname);
     assert_eq!(after.devmajor, attributes.devmajor);
     assert_eq!(after.devminor, attributes.devminor);
 }
+
+#[test]
+fn test_base64_entry_size() {
+    let mut engine = TarEngine::default();
+
+    for len in [0, 1, 383, 384, 385, 4096] {
+        let before = engine.len;
+        let data = vec![0; len];
+
+        engine.escaped_base64(Entry {
+            name: HtmlAttributeSafeName("file"),
+            data: &data,
+            attributes: Default::default(),
+            compression: None,
+        });
+
+        engine.pad_to_fit();
+        assert_eq!(
+            engine.len - before,
+            TarEngine::base64_entry_size(len as u64)
+        );
+    }
+}
+
+#[test]
+fn test_escaped_base64_reader() {
+    let data: Vec<u8> = (0..3_000_001u32).map(|i| (i * 7 % 251) as u8).collect();
+
+    for width in [None, core::num::NonZeroUsize::new(76)] {
+        let mut engine = TarEngine::default();
+        engine.set_line_width(width);
+        // Start off a block boundary, so the padding is part of the comparison.
+        engine.len = 100;
+
+        let mut streaming = TarEngine::default();
+        streaming.set_line_width(width);
+        streaming.len = 100;
+
+        let attributes = EntryAttributes {
+            mode: Some(0o640),
+            ..Default::default()
+        };
+
+        let escaped = engine.escaped_base64(Entry {
+            name: HtmlAttributeSafeName("large"),
+            data: &data,
+            attributes,
+            compression: None,
+        });
+
+        let mut expected = escaped.padding.to_vec();
+        expected.extend_from_slice(escaped.header.as_bytes());
+        expected.extend_from_slice(escaped.file.as_bytes());
+        expected.extend_from_slice(&escaped.data);
+
+        let mut written = vec![];
+        streaming
+            .escaped_base64_reader(
+                HtmlAttributeSafeName("large"),
+                attributes,
+                &data[..],
+                data.len() as u64,
+                &mut written,
+            )
+            .unwrap();
+
+        assert!(written == expected, "streamed entry differs");
+        assert_eq!(streaming.len, engine.len);
+        assert_eq!(
+            streaming.escaped_eof().padding,
+            engine.escaped_eof().padding
+        );
+    }
+
+    let mut engine = TarEngine::default();
+    let short = engine.escaped_base64_reader(
+        HtmlAttributeSafeName("short"),
+        Default::default(),
+        &data[..10],
+        11,
+        &mut vec![],
+    );
+    assert!(short.is_err());
+}
+
+#[test]
+fn test_octal_fields() {
+    let mut field = [0xff; 12];
+
+    write_octal(&mut field, 0).unwrap();
+    assert_eq!(&field, b"00000000000\0");
+    assert_eq!(parse_octal(&field).unwrap(), 0);
+
+    write_octal(&mut field, 0o77777777777).unwrap();
+    assert_eq!(&field, b"77777777777\0");
+    assert_eq!(parse_octal(&field).unwrap(), 0o77777777777);
+
+    assert!(write_octal(&mut field, 0o100000000000).is_err());
+    assert!(write_octal(&mut [0; 1], 1).is_err());
+    assert!(write_octal(&mut [], 0).is_err());
+    write_octal(&mut [0; 23], u64::MAX).unwrap();
+
+    assert_eq!(parse_octal(b"    644 \0").unwrap(), 0o644);
+    assert_eq!(parse_octal(b"0000644").unwrap(), 0o644);
+    assert!(parse_octal(b"\0\0\0").is_err());
+    assert!(parse_octal(b"0000648\0").is_err());
+    assert!(parse_octal(b"7777777777777777777777777\0").is_err());
+
+    let mut field = [0xff; 8];
+    write_octal_wide(&mut field, 0o644).unwrap();
+    assert_eq!(&field, b"0000644\0");
+    write_octal_wide(&mut field, 0o77777777).unwrap();
+    assert_eq!(&field, b"77777777");
+    assert_eq!(parse_octal(&field).unwrap(), 0o77777777);
+    assert!(write_octal_wide(&mut field, 0o100000000).is_err());
+}
+
+#[test]
+fn test_large_ids() {
+    let attributes = EntryAttributes {
+        uid: Some(3_000_000),
+        gid: Some(TarHeader::MAX_ID),
+        mtime: Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(TarHeader::MAX_MTIME)),
+        ..Default::default()
+    };
+    attributes.check().unwrap();
+
+    let mut header = TarHeader::EMPTY;
+    header.assign_attributes(&attributes);
+    assert_eq!(&header.uid, b"13343300");
+
+    let read = EntryAttributes::from_header(&header);
+    assert_eq!(read.uid, Some(3_000_000));
+    assert_eq!(read.gid, Some(TarHeader::MAX_ID));
+    assert_eq!(read.mtime, attributes.mtime);
+
+    let too_large = EntryAttributes {
+        uid: Some(TarHeader::MAX_ID + 1),
+        ..Default::default()
+    };
+    assert!(matches!(too_large.check(), Err(TarError::NumTooLarge)));
+}
+
+#[test]
+fn test_multi_line_html_tag() {
+    let head = b"<!DOCTYPE html>\n<html\n    lang=\"en\"\n    class=\"a  b\"\n>";
+    let tail = b"<head></head>";
+
+    let mut engine = TarEngine::default();
+    let init = engine.start_of_file(head, head.len() + tail.len());
+    assert_eq!(init.consumed, head.len());
+
+    let expected = b"\0<!DOCTYPE html> <html lang=\"en\" class=\"a  b\" data-a=\"";
+    assert_eq!(&init.header.name[..expected.len()], expected);
+}
+
+#[test]
+fn test_escaped_parts() {
+    let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+    let mut engine = TarEngine::default();
+
+    let parts = engine.escaped_base64_parts(
+        Entry {
+            name: HtmlAttributeSafeName("large"),
+            data: &data,
+            attributes: Default::default(),
+            compression: None,
+        },
+        300,
+    );
+
+    assert_eq!(parts.len(), 4);
+    assert_eq!(parts[0].file.parse_part_offset(), None);
+
+    let mut joined = vec![];
+    for (idx, part) in parts.iter().enumerate() {
+        if idx > 0 {
+            assert_eq!(part.file.parse_part_offset(), Some(joined.len() as u64));
+        }
+
+        assert_eq!(part.file.parse_name(), Some(HtmlAttributeSafeName("large")));
+        assert_eq!(part.file.parse_size(), Ok(part.data.len() as u64));
+        joined.extend(STANDARD.decode(&part.data).unwrap());
+    }
+
+    assert_eq!(joined, data);
+}
+
+#[test]
+fn test_patch() {
+    let mut engine = TarEngine::default();
+    let patch = engine.escaped_patch(Patch {
+        name: HtmlAttributeSafeName("etc/motd"),
+        offset: 7,
+        data: b"there!\n",
+    });
+
+    assert_eq!(patch.file.parse_patch_offset(), Some(7));
+    assert_eq!(patch.file.parse_part_offset(), None);
+    let data = STANDARD.decode(&patch.data).unwrap();
+
+    let mut file = b"Hello, world\n".to_vec();
+    apply_patch(&mut file, 7, &data).unwrap();
+    assert_eq!(file, b"Hello, there!\n");
+
+    apply_patch(&mut file, 0, b"J").unwrap();
+    assert_eq!(file, b"Jello, there!\n");
+
+    assert!(apply_patch(&mut file, 16, b"!").is_err());
+}
+
+#[test]
+fn test_eof_ignores_padding() {
+    let mut data = [0u8; 1024];
+    data[500..512].fill(b'x');
+    data[1012..1024].fill(b'y');
+
+    let mut decompiler = TarDecompiler::default();
+    let escape = decompiler.next_escape(&data).unwrap();
+    assert!(matches!(escape, ParsedEscape::Eof { end: 1024 }));
+
+    // Anything in a field is not the end, though.
+    data[156] = b'0';
+    let mut decompiler = TarDecompiler::default();
+    assert!(!matches!(
+        decompiler.next_escape(&data),
+        Ok(ParsedEscape::Eof { .. })
+    ));
+}
+
+/// The start of a test document, `<html lang=en><body>` escaped as the first header.
+#[cfg(test)]
+fn test_document(engine: &mut TarEngine) -> Vec<u8> {
+    const HTML: &[u8] = b"<html lang=en><body>";
+    let start = engine.start_of_file(&HTML[..14], HTML.len());
+
+    let mut data = start.header.as_bytes().to_vec();
+    data.extend_from_slice(&start.extra);
+    data.extend_from_slice(&HTML[start.consumed..]);
+    data
+}
+
+/// A file with the default attributes.
+#[cfg(test)]
+fn test_entry<'a>(name: &'a str, data: &'a [u8]) -> Entry<'a> {
+    Entry {
+        name: HtmlAttributeSafeName(name),
+        data,
+        attributes: Default::default(),
+        compression: None,
+    }
+}
+
+/// Append escaped data to a test document.
+#[cfg(test)]
+fn push_escaped(data: &mut Vec<u8>, entry: EscapedData) {
+    for part in [
+        entry.padding,
+        entry.header.as_bytes(),
+        entry.file.as_bytes(),
+    ] {
+        data.extend_from_slice(part);
+    }
+    data.extend_from_slice(&entry.data);
+}
+
+#[test]
+fn test_corrupt_size_is_a_checksum_error() {
+    let mut engine = TarEngine::default();
+    let mut data = test_document(&mut engine);
+    let file_at = data.len().next_multiple_of(512) + 512;
+    push_escaped(
+        &mut data,
+        engine.escaped_base64(test_entry("hello", b"Hello, world")),
+    );
+    push_escaped(&mut data, engine.escaped_eof());
+    assert!(TarDecompiler::list(&data).is_ok());
+
+    // Not an octal digit in the size of the file.
+    let size = core::mem::offset_of!(TarHeader, size);
+    data[file_at + size] = b'9';
+    assert!(matches!(
+        TarDecompiler::list(&data),
+        Err(TarError::BadChecksum { .. })
+    ));
+}
+
+#[test]
+fn test_open_end() {
+    let mut engine = TarEngine::default();
+    let mut data = test_document(&mut engine);
+    push_escaped(
+        &mut data,
+        engine.escaped_base64(test_entry("hello", b"Hello, world")),
+    );
+
+    let end = engine.escaped_open_end();
+    data.extend_from_slice(end.padding);
+    data.extend_from_slice(end.data);
+    let html_end = data.len();
+    assert_eq!(engine.offset(), html_end as u64);
+    data.extend_from_slice(b"</body></html>");
+
+    let entries = TarDecompiler::list(&data).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].size, 12);
+
+    let mut decompiler = TarDecompiler::default();
+    decompiler.start_of_file(&data).unwrap();
+    assert!(matches!(
+        decompiler.next_escape(&data),
+        Ok(ParsedEscape::Entry(..))
+    ));
+    assert!(matches!(
+        decompiler.continue_escape(&data),
+        Ok(ParsedEscape::Eof { end }) if end == html_end
+    ));
+}
+
+/// An entry of a standard tar archive, its header and padded data.
+#[cfg(test)]
+fn standard(name: &str, typeflag: u8, linkname: &str, data: &[u8]) -> Vec<u8> {
+    let mut header = TarHeader::EMPTY;
+    header.name[..name.len()].copy_from_slice(name.as_bytes());
+    header.linkname[..linkname.len()].copy_from_slice(linkname.as_bytes());
+    header.typeflag = typeflag;
+    header.magic = *b"ustar\0";
+    header.version = *b"00";
+    write_octal(&mut header.mode, 0o640).unwrap();
+    write_octal(&mut header.size, data.len() as u64).unwrap();
+    write_octal(&mut header.devmajor, 1).unwrap();
+    write_octal(&mut header.devminor, 3).unwrap();
+    header.assign_checksum();
+
+    let mut block = header.as_bytes().to_vec();
+    block.extend_from_slice(data);
+    block.resize(block.len().next_multiple_of(512), 0);
+    block
+}
+
+#[test]
+fn test_pack_tar_stream() {
+    let mut archive = vec![];
+    archive.extend(standard("./", b'5', "", b""));
+    archive.extend(standard("./etc/motd", b'0', "", b"Hello, world\n"));
+    archive.extend(standard("./etc/issue", b'1', "./etc/motd", b""));
+    archive.extend(standard("./bin/sh", b'2', "busybox", b""));
+    archive.extend(standard("./dev/null", b'3', "", b""));
+    archive.extend(standard("./tmp/", b'5', "", b""));
+    let long = format!("./usr/share/{}", "b".repeat(200));
+    archive.extend(standard(
+        "././@LongLink",
+        b'L',
+        "",
+        format!("{long}\0").as_bytes(),
+    ));
+    archive.extend(standard(&long[..100], b'0', "", b"Hi\n"));
+    archive.extend([0; 1024]);
+
+    let mut engine = TarEngine::default();
+    engine.start_of_file(b"<html lang=en>", 20);
+    let escaped: Vec<_> = pack_tar_stream(&mut engine, archive.as_slice()).collect();
+
+    let names: Vec<_> = escaped
+        .iter()
+        .map(|entry| (entry.file.parse_name().unwrap().0, entry.file.typeflag))
+        .collect();
+    assert_eq!(
+        names,
+        [
+            ("etc/motd", b'\0'),
+            ("etc/issue", b'1'),
+            ("bin/sh", b'2'),
+            ("dev/null", b'3'),
+            ("tmp", b'5'),
+            ("././@LongLink", b'L'),
+            (&long[2..][..TarEngine::MAX_NAME_LEN], b'\0'),
+        ]
+    );
+
+    assert_eq!(
+        STANDARD.decode(&escaped[0].data).unwrap(),
+        b"Hello, world\n"
+    );
+    assert_eq!(
+        EntryAttributes::from_header(&escaped[0].file).mode,
+        Some(0o640)
+    );
+    assert_eq!(escaped[1].file.parse_hard_link().unwrap().0, "etc/motd");
+    assert!(matches!(
+        TarDecompiler::file_data(&escaped[2].file, b"").unwrap(),
+        ParsedFileData::Symlink { target } if target == "busybox"
+    ));
+    assert_eq!(EntryAttributes::from_header(&escaped[3].file).devminor, 3);
+    assert!(matches!(
+        TarDecompiler::file_data(&escaped[4].file, b"").unwrap(),
+        ParsedFileData::Directory
+    ));
+    assert_eq!(escaped[5].data, format!("{}\0", &long[2..]).as_bytes());
+
+    // A corrupted header ends the stream.
+    archive[512 + 10] ^= 1;
+    let mut engine = TarEngine::default();
+    engine.start_of_file(b"<html lang=en>", 20);
+    assert_eq!(pack_tar_stream(&mut engine, archive.as_slice()).count(), 0);
+
+    // Reading on its own reports it.
+    let mut read = read_tar_stream(archive.as_slice());
+    let err = read.next().unwrap().err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(read.next().is_none());
+}
+
+#[test]
+fn test_read_pax_names() {
+    // A record, whose length counts its own digits.
+    fn record(key: &str, value: &str) -> String {
+        let rest = format!(" {key}={value}\n");
+        let mut len = rest.len();
+        while len != rest.len() + len.to_string().len() {
+            len = rest.len() + len.to_string().len();
+        }
+        format!("{len}{rest}")
+    }
+
+    // As `tar --format=pax` writes names longer than a header, cut to 100 bytes in the header.
+    let dir = format!("d/{}", "x".repeat(148));
+    let file = format!("{dir}/f");
+    let target = format!("{dir}/{}", "t".repeat(100));
+
+    let mut archive = vec![];
+    archive.extend(standard(
+        "pax_global_header",
+        b'g',
+        "",
+        record("comment", "docker").as_bytes(),
+    ));
+    archive.extend(standard(
+        "PaxHeaders/d",
+        b'x',
+        "",
+        record("path", &format!("{dir}/")).as_bytes(),
+    ));
+    archive.extend(standard(&dir[..100], b'5', "", b""));
+    archive.extend(standard(
+        "PaxHeaders/f",
+        b'x',
+        "",
+        record("path", &file).as_bytes(),
+    ));
+    archive.extend(standard(&file[..100], b'0', "", b"Hi\n"));
+
+    let mut records = record("path", "d/link");
+    records.push_str(&record("linkpath", &target));
+    archive.extend(standard("PaxHeaders/link", b'x', "", records.as_bytes()));
+    archive.extend(standard("d/link", b'2', &target[..100], b""));
+    archive.extend(standard("d/plain", b'0', "", b""));
+    archive.extend([0; 1024]);
+
+    let entries: Vec<_> = read_tar_stream(archive.as_slice())
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, [&*dir, &*file, "d/link", "d/plain"]);
+    assert_eq!(entries[1].data, b"Hi\n");
+    assert_eq!(entries[2].symlink_target(), Some(&*target));
+    // A record names only the entry after it.
+    assert_eq!(entries[3].link_name, None);
+
+    // A global record names all later entries without their own.
+    let mut archive = vec![];
+    archive.extend(standard(
+        "pax_global_header",
+        b'g',
+        "",
+        record("path", "same").as_bytes(),
+    ));
+    archive.extend(standard(
+        "PaxHeaders/own",
+        b'x',
+        "",
+        record("path", "own").as_bytes(),
+    ));
+    archive.extend(standard("a", b'0', "", b""));
+    archive.extend(standard("b", b'0', "", b""));
+    let names: Vec<_> = read_tar_stream(archive.as_slice())
+        .map(|entry| entry.unwrap().name)
+        .collect();
+    assert_eq!(names, ["own", "same"]);
+
+    let archive = standard("PaxHeaders/bad", b'x', "", b"99 path=x\n");
+    let err = read_tar_stream(archive.as_slice())
+        .next()
+        .unwrap()
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_list_plain_html() {
+    let short = b"<!DOCTYPE html><html><body><p>Hello</p></body></html>";
+    assert!(matches!(
+        TarDecompiler::list(short),
+        Err(TarError::NotEnoughData)
+    ));
+
+    let long = format!(
+        "<!DOCTYPE html><html><body>{}</body></html>",
+        "<p>Hello</p>".repeat(100)
+    );
+    assert!(matches!(
+        TarDecompiler::list(long.as_bytes()),
+        Err(TarError::NotAStart)
+    ));
+}
+
+#[test]
+fn test_external_file_data() {
+    let mut engine = TarEngine::default();
+    let escaped = engine.escaped_external(External {
+        name: HtmlAttributeSafeName("boot/wah-init.wasm"),
+        realsize: 0o1234567,
+        reference: HtmlAttributeSafeName("./assets/wah-init.wasm"),
+        attributes: Default::default(),
+    });
+
+    let ParsedFileData::External {
+        reference,
+        realsize,
+    } = TarDecompiler::file_data(&escaped.file, &escaped.data).unwrap()
+    else {
+        panic!("an outlined file has no reference");
+    };
+
+    assert_eq!(reference, "./assets/wah-init.wasm");
+    assert_eq!(realsize, 0o1234567);
+}
+
+#[test]
+fn test_compressed_entries() {
+    let text = "Hello, world!\n".repeat(1000);
+    let mut engine = TarEngine::default();
+    let escaped = engine.escaped_base64(Entry {
+        name: HtmlAttributeSafeName("hello"),
+        data: text.as_bytes(),
+        attributes: Default::default(),
+        compression: Some(Compression::Gzip),
+    });
+
+    assert_eq!(escaped.file.parse_compression(), Some(Compression::Gzip));
+    assert_eq!(escaped.file.parse_realsize(), Some(text.len() as u64));
+    assert!(escaped.data.len() < text.len() / 10);
+
+    let ParsedFileData::Data(data) =
+        TarDecompiler::file_data(&escaped.file, &escaped.data).unwrap()
+    else {
+        panic!("a compressed file has data");
+    };
+    assert_eq!(data, text.as_bytes());
+
+    // Bytes that do not get smaller are stored as they are.
+    let noise: Vec<u8> = (0..=255).collect();
+    let escaped = engine.escaped_base64(Entry {
+        name: HtmlAttributeSafeName("noise"),
+        data: &noise,
+        attributes: Default::default(),
+        compression: Some(Compression::Gzip),
+    });
+
+    assert_eq!(escaped.file.parse_compression(), None);
+    assert_eq!(escaped.file.parse_realsize(), None);
+    assert_eq!(escaped.data, STANDARD.encode(&noise).as_bytes());
+
+    let mut truncated = TarHeader::EMPTY;
+    truncated.assign_compression(Compression::Gzip);
+    assert!(matches!(
+        TarDecompiler::file_data(&truncated, b"H4sIAAAA"),
+        Err(TarError::Inflate(_))
+    ));
+}
+
+#[test]
+fn test_file_data_reports_bad_base64() {
+    let mut engine = TarEngine::default();
+    let escaped = engine.escaped_base64(Entry {
+        name: HtmlAttributeSafeName("hello"),
+        data: b"Hello, world",
+        attributes: Default::default(),
+        compression: None,
+    });
+
+    let mut mangled = escaped.data.clone();
+    mangled[3] = b'!';
+
+    assert!(matches!(
+        TarDecompiler::file_data(&escaped.file, &mangled),
+        Err(TarError::Base64(_))
+    ));
+}
+
+#[test]
+fn test_compute_checksum() {
+    let mut header = TarHeader::EMPTY;
+    header.name[..5].copy_from_slice(b"hello");
+    header.assign_size(12);
+    assert!(header.verify_checksum().is_err());
+
+    let before = header.compute_checksum();
+    header.assign_checksum();
+    assert_eq!(parse_octal(&header.chksum).unwrap(), u64::from(before));
+    assert_eq!(header.compute_checksum(), before);
+    assert!(header.verify_checksum().is_ok());
+
+    header.name[0] = b'j';
+    assert!(matches!(
+        header.verify_checksum(),
+        Err(TarError::BadChecksum { found, expected }) if expected == found + 2
+    ));
+
+    // Writers disagree on how to end the digits.
+    header.assign_checksum();
+    header.chksum.swap(6, 7);
+    assert!(header.verify_checksum().is_ok());
+}
+
+#[test]
+fn test_list_reports_bad_checksums() {
+    let mut engine = TarEngine::default();
+    let mut data = test_document(&mut engine);
+    push_escaped(
+        &mut data,
+        engine.escaped_base64(test_entry("hello", b"Hello, world")),
+    );
+    push_escaped(&mut data, engine.escaped_eof());
+
+    assert_eq!(TarDecompiler::list(&data).unwrap().len(), 1);
+
+    // As if something rewrote the mode of the file.
+    let mode = data
+        .windows(8)
+        .rposition(|field| field == b"0000644\0")
+        .unwrap();
+    data[mode + 4] = b'7';
+
+    assert!(matches!(
+        TarDecompiler::list(&data),
+        Err(TarError::BadChecksum { .. })
+    ));
+}
+
+#[test]
+fn test_long_names_use_the_prefix() {
+    let file_name = "a".repeat(TarEngine::MAX_NAME_LEN);
+    let long = format!("share/doc/wasi-document/{file_name}");
+    assert_eq!(long.len(), 113);
+
+    let short = "x".repeat(TarEngine::MAX_NAME_LEN);
+    assert!(HtmlAttributeSafeName::new(&short).is_ok());
+    // No `/` at which the name would fit, these need a long name entry.
+    assert!(HtmlAttributeSafeName::new(&format!("{short}x")).is_ok());
+    assert!(HtmlAttributeSafeName::new(&format!("{long}b")).is_ok());
+    assert!(matches!(
+        HtmlAttributeSafeName::new(&format!("{long}&amp;")),
+        Err(TarError::LongNameHasMarkup)
+    ));
+
+    let mut engine = TarEngine::default();
+    assert!(engine
+        .escaped_long_name(HtmlAttributeSafeName::new(&long).unwrap())
+        .is_none());
+    let escaped = engine.escaped_base64(Entry {
+        name: HtmlAttributeSafeName::new(&long).unwrap(),
+        data: b"Hello",
+        attributes: Default::default(),
+        compression: None,
+    });
+
+    assert_eq!(escaped.file.parse_name().unwrap().0, file_name);
+    assert_eq!(&escaped.file.prefix[..24], b"share/doc/wasi-document\0");
+    assert_eq!(escaped.file.parse_path().unwrap(), long);
+    assert!(escaped.file.verify_checksum().is_ok());
+
+    // The numbers in the rest of `prefix` are untouched.
+    assert_eq!(escaped.file.parse_base64_len().unwrap(), 8);
+    assert!(escaped
+        .file
+        .as_bytes()
+        .ends_with(b"\">\0\0\0\0\0\0\0\0\0\0\0\0"));
+}
+
+#[test]
+fn test_long_name_entries() {
+    let long = format!("usr/share/{}motd", "a/".repeat(143));
+    assert_eq!(long.len(), 300);
+
+    let mut engine = TarEngine::default();
+    let mut data = test_document(&mut engine);
+
+    let mut escaped = engine.escaped_base64_parts(test_entry(&long, b"Hello, world"), 8);
+    escaped.push(engine.escaped_eof());
+    assert_eq!(escaped.len(), 5);
+    assert_eq!(escaped[0].file.typeflag, b'L');
+    assert_eq!(escaped[1].file.parse_name().unwrap().0, &long[..89]);
+
+    for escaped in escaped {
+        push_escaped(&mut data, escaped);
+    }
+
+    let entries = TarDecompiler::list(&data).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert!(entries
+        .iter()
+        .all(|entry| entry.path.as_deref() == Some(long.as_str())));
+    assert_eq!(entries[1].header.parse_part_offset(), Some(8));
+}
+
+#[test]
+fn test_entries() {
+    let mut engine = TarEngine::default();
+    let mut data = test_document(&mut engine);
+
+    for name in ["hello", "bye"] {
+        push_escaped(
+            &mut data,
+            engine.escaped_base64(test_entry(name, name.as_bytes())),
+        );
+    }
+
+    push_escaped(&mut data, engine.escaped_eof());
+    let end = data.len();
+    data.extend_from_slice(b"</body></html>");
+
+    let mut decompiler = TarDecompiler::default();
+    decompiler.start_of_file(&data).unwrap();
+    let mut entries = decompiler.entries(&data);
+
+    for name in ["hello", "bye"] {
+        let Some(Ok(ParsedEscape::Entry(file, range, None))) = entries.next() else {
+            panic!("expected the entry {name}");
+        };
+
+        assert_eq!(file.parse_name().unwrap().0, name);
+        let ParsedFileData::Data(decoded) = TarDecompiler::file_data(&file, &data[range]).unwrap()
+        else {
+            panic!("the entry {name} has data");
+        };
+        assert_eq!(decoded, name.as_bytes());
+    }
+
+    assert!(matches!(entries.next(), Some(Ok(ParsedEscape::Eof { end: at })) if at == end));
+    assert!(entries.next().is_none());
+
+    // The iteration also ends at the first error.
+    let cut = &data[..end - 600];
+    let mut decompiler = TarDecompiler::default();
+    decompiler.start_of_file(cut).unwrap();
+    let results: Vec<_> = decompiler.entries(cut).collect();
+    assert_eq!(results.len(), 3);
+    assert!(matches!(results[2], Err(TarError::NotEnoughData)));
+}
+
+#[test]
+fn test_escaped_end_skips_html() {
+    const BETWEEN: &[u8] = b"<p>Below the fold</p>";
+
+    let mut engine = TarEngine::default();
+    let mut data = test_document(&mut engine);
+
+    for name in ["hello", "bye"] {
+        push_escaped(
+            &mut data,
+            engine.escaped_base64(test_entry(name, name.as_bytes())),
+        );
+
+        if name == "hello" {
+            let sentinel = engine.escaped_end(BETWEEN.len());
+            data.extend_from_slice(sentinel.padding);
+            data.extend_from_slice(sentinel.header.as_bytes());
+            data.extend_from_slice(BETWEEN);
+        }
+    }
+
+    push_escaped(&mut data, engine.escaped_eof());
+    assert_eq!(engine.offset(), data.len() as u64);
+    data.extend_from_slice(b"</body></html>");
+
+    let listed = TarDecompiler::list(&data).unwrap();
+    let names: Vec<_> = listed.iter().map(|entry| entry.path.as_deref()).collect();
+    assert_eq!(names, [Some("hello"), Some("bye")]);
+
+    let mut decompiler = TarDecompiler::default();
+    decompiler.start_of_file(&data).unwrap();
+    let skipped = decompiler.entries(&data).find_map(|escape| match escape {
+        Ok(ParsedEscape::EndOfEscapes { html_data }) => Some(html_data),
+        _ => None,
+    });
+    assert_eq!(&data[skipped.unwrap()], BETWEEN);
+}
+
+#[test]
+fn test_eof_without_entries() {
+    const TAIL: &[u8] = b"</body></html>";
+
+    let mut engine = TarEngine::default();
+    let mut data = test_document(&mut engine);
+
+    // Nothing was escaped, so there is no `</noscript>` to close either.
+    let eof = engine.escaped_eof();
+    assert!(eof.data.is_empty());
+    push_escaped(&mut data, eof);
+    assert_eq!(engine.offset(), data.len() as u64);
+    data.extend_from_slice(TAIL);
+
+    assert!(TarDecompiler::list(&data).unwrap().is_empty());
+    let end = TarDecompiler::end_of_archive(&data).unwrap();
+    assert_eq!(end.len(), 1024);
+    assert_eq!(&data[end.end..], TAIL);
+
+    // A single zeroed block is not an EOF.
+    let mut cut = data[..end.start + 512].to_vec();
+    cut.extend_from_slice(&[b'7'; 512]);
+    assert!(matches!(
+        TarDecompiler::list(&cut),
+        Err(TarError::NotAnExpectedEscape)
+    ));
+}
+
+#[test]
+fn test_resume_from() {
+    const TAIL: &[u8] = b"</body></html>";
+
+    for open_ended in [false, true] {
+        let mut engine = TarEngine::default();
+        let mut data = test_document(&mut engine);
+        push_escaped(
+            &mut data,
+            engine.escaped_base64(test_entry("hello", b"Hello, world")),
+        );
+
+        if open_ended {
+            let end = engine.escaped_open_end();
+            data.extend_from_slice(end.padding);
+            data.extend_from_slice(end.data);
+        } else {
+            push_escaped(&mut data, engine.escaped_eof());
+        }
+        data.extend_from_slice(TAIL);
+
+        let end = TarDecompiler::end_of_archive(&data).unwrap();
+        assert_eq!(&data[end.end..], TAIL);
+
+        let mut engine = TarEngine::resume_from(&data).unwrap();
+        assert_eq!(engine.offset(), end.start as u64);
+
+        let mut appended = data[..end.start].to_vec();
+        push_escaped(
+            &mut appended,
+            engine.escaped_base64(test_entry("bye", b"Goodbye")),
+        );
+        push_escaped(&mut appended, engine.escaped_eof());
+        appended.extend_from_slice(&data[end.end..]);
+
+        let entries = TarDecompiler::list(&appended).unwrap();
+        let names: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.header.parse_name().unwrap().0, entry.size))
+            .collect();
+        assert_eq!(names, [("hello", 12), ("bye", 7)]);
+
+        let decoded = STANDARD.decode(&appended[entries[1].data.clone()]).unwrap();
+        assert_eq!(decoded, b"Goodbye");
+    }
+}
+
+#[test]
+fn test_line_width() {
+    let data: Vec<u8> = (0..=255).collect();
+    let mut engine = TarEngine::default();
+    engine.set_line_width(core::num::NonZeroUsize::new(76));
+
+    let escaped = engine.escaped_base64(Entry {
+        name: HtmlAttributeSafeName("wrapped"),
+        data: &data,
+        attributes: Default::default(),
+        compression: None,
+    });
+
+    let lines: Vec<_> = escaped.data.split(|&b| b == b'\n').collect();
+    assert!(lines[..lines.len() - 1].iter().all(|line| line.len() == 76));
+    assert_eq!(escaped.file.parse_line_width(), Some(76));
+    assert_eq!(escaped.file.parse_size(), Ok(escaped.data.len() as u64));
+
+    let unwrapped = STANDARD.encode(&data);
+    assert_eq!(escaped.file.parse_base64_len(), Ok(unwrapped.len() as u64));
+
+    match TarDecompiler::file_data(&escaped.file, &escaped.data).unwrap() {
+        ParsedFileData::Data(decoded) => assert_eq!(decoded, data),
+        _ => panic!("not decoded as data"),
+    }
+}
@@ -0,0 +1,32 @@
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+
+/// A script in the size range of a bundled loader, made of many small independent functions.
+fn large_script() -> String {
+    (0..2000)
+        .map(|idx| {
+            format!(
+                "function handler{idx}(event, options) {{\n  \
+                     const target = event.target || options.fallback;\n  \
+                     if (target && target.dataset.index == {idx}) {{\n    \
+                         return target.textContent.trim().split(\" \").length;\n  \
+                     }}\n  \
+                     return undefined;\n\
+                 }}\n"
+            )
+        })
+        .collect()
+}
+
+fn bench_minify(c: &mut Criterion) {
+    let script = large_script();
+
+    let mut group = c.benchmark_group("minify");
+    group.throughput(Throughput::Bytes(script.len() as u64));
+    group.bench_function("js", |b| {
+        b.iter(|| wasi_document_minify_js::minify_js(script.as_bytes()).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_minify);
+criterion_main!(benches);
@@ -1,38 +1,891 @@
-pub fn minify_js(js: &[u8]) -> Vec<u8> {
-    minify(oxc_span::SourceType::jsx(), js)
+pub use oxc_syntax::es_target::ESTarget;
+
+pub fn minify_js(js: &[u8]) -> Result<Vec<u8>, MinifyError> {
+    minify_js_with(js, &MinifyOptions::default())
+}
+
+pub fn minify_mjs(mjs: &[u8]) -> Result<Vec<u8>, MinifyError> {
+    minify_mjs_with(mjs, &MinifyOptions::default())
+}
+
+pub fn minify_js_with(js: &[u8], options: &MinifyOptions) -> Result<Vec<u8>, MinifyError> {
+    minify(oxc_span::SourceType::jsx(), js, options)
+}
+
+pub fn minify_mjs_with(mjs: &[u8], options: &MinifyOptions) -> Result<Vec<u8>, MinifyError> {
+    minify(oxc_span::SourceType::mjs(), mjs, options)
+}
+
+/// Minify TypeScript by erasing its types, see [`MinifyError::NotErasable`] for what that excludes.
+///
+/// Imports used only as types must be marked `type`, as with `verbatimModuleSyntax`, the others
+/// are kept.
+pub fn minify_ts(ts: &[u8]) -> Result<Vec<u8>, MinifyError> {
+    minify_ts_with(ts, &MinifyOptions::default())
+}
+
+/// Minify TypeScript with JSX, like [`minify_ts`].
+pub fn minify_tsx(tsx: &[u8]) -> Result<Vec<u8>, MinifyError> {
+    minify_tsx_with(tsx, &MinifyOptions::default())
 }
 
-pub fn minify_mjs(mjs: &[u8]) -> Vec<u8> {
-    minify(oxc_span::SourceType::mjs(), mjs)
+pub fn minify_ts_with(ts: &[u8], options: &MinifyOptions) -> Result<Vec<u8>, MinifyError> {
+    minify(oxc_span::SourceType::ts(), ts, options)
+}
+
+pub fn minify_tsx_with(tsx: &[u8], options: &MinifyOptions) -> Result<Vec<u8>, MinifyError> {
+    minify(oxc_span::SourceType::tsx(), tsx, options)
+}
+
+/// What to keep of a script besides its code, nothing by default.
+#[derive(Clone, Copy, Default)]
+pub struct MinifyOptions {
+    /// Keep license banners, comments starting with `/*!` or `//!` or containing `@license` or
+    /// `@preserve`. Like `comments: "some"` of terser, bundled dependencies may require them.
+    pub preserve_legal_comments: bool,
+    /// The ECMAScript version the output must run on, `ESNext` by default.
+    ///
+    /// The minifier then only rewrites to syntax of that version. Nothing is down-leveled, a
+    /// script already using newer syntax is refused with [`MinifyError::Unsupported`]. Only
+    /// syntax is checked, not regular expression flags or built-ins.
+    pub target: ESTarget,
+}
+
+/// A script that can not be minified. The caller may well use it unminified instead.
+#[derive(Debug)]
+pub enum MinifyError {
+    /// Scripts are read as UTF-8, a Latin-1 comment for instance is not.
+    Utf8(std::str::Utf8Error),
+    /// The diagnostics of the parser, minifying a script with syntax errors would break it.
+    Parse(Vec<oxc_diagnostics::OxcDiagnostic>),
+    /// The script uses syntax newer than the [target](MinifyOptions::target).
+    Unsupported {
+        syntax: &'static str,
+        target: ESTarget,
+    },
+    /// TypeScript that means more than its types, enums, namespaces, parameter properties and
+    /// `import =` or `export =`. Like `erasableSyntaxOnly` of tsc, we only remove types.
+    NotErasable(&'static str),
+}
+
+impl core::fmt::Display for MinifyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MinifyError::Utf8(err) => write!(f, "the script is not UTF-8: {err}"),
+            MinifyError::Parse(errors) => {
+                write!(f, "the script does not parse")?;
+
+                for error in errors {
+                    write!(f, "\n{error}")?;
+                }
+
+                Ok(())
+            }
+            MinifyError::Unsupported { syntax, target } => {
+                write!(f, "the script uses {syntax}, which {target} does not have")
+            }
+            MinifyError::NotErasable(syntax) => {
+                write!(
+                    f,
+                    "the script uses {syntax}, which is more than types to erase"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for MinifyError {}
+
+/// Check that minified script refers to no global the original did not.
+///
+/// This is a guardrail against a minifier bug, not a proof of equivalence. Renaming is where
+/// minifiers go wrong most visibly: a local that is renamed inconsistently, or shadowed by the
+/// new name, turns into a reference to some global. Removing dead code may drop globals, so
+/// fewer is fine.
+///
+/// Only the unresolved references of the two scripts are compared, not their syntax trees, which
+/// minification changes by design. A minifier that folds an expression wrongly or reorders
+/// statements with side effects passes this check.
+pub fn check_minified_js(original: &[u8], minified: &[u8]) -> Result<(), String> {
+    check_minified(oxc_span::SourceType::jsx(), original, minified)
+}
+
+fn check_minified(
+    source_type: oxc_span::SourceType,
+    original: &[u8],
+    minified: &[u8],
+) -> Result<(), String> {
+    let original = unresolved_references(source_type, original)?;
+    let minified = unresolved_references(source_type, minified)
+        .map_err(|err| format!("the minified script does not parse: {err}"))?;
+
+    let introduced: Vec<_> = minified.difference(&original).cloned().collect();
+
+    if !introduced.is_empty() {
+        return Err(format!(
+            "the minified script refers to globals the original does not: {}",
+            introduced.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// The names a script refers to without declaring them.
+fn unresolved_references(
+    source_type: oxc_span::SourceType,
+    code: &[u8],
+) -> Result<std::collections::BTreeSet<String>, String> {
+    use oxc_allocator::Allocator;
+    use oxc_parser::Parser;
+    use oxc_semantic::SemanticBuilder;
+
+    let code = std::str::from_utf8(code).map_err(|err| err.to_string())?;
+    let allocator = Allocator::default();
+    let parsed = Parser::new(&allocator, code, source_type).parse();
+
+    if !parsed.errors.is_empty() {
+        return Err(format!("{:?}", parsed.errors));
+    }
+
+    let semantic = SemanticBuilder::new().build(&parsed.program).semantic;
+    let names = semantic
+        .scoping()
+        .root_unresolved_references()
+        .keys()
+        .map(|name| name.to_string())
+        .collect();
+
+    Ok(names)
 }
 
-fn minify(source_type: oxc_span::SourceType, code: &[u8]) -> Vec<u8> {
+fn minify(
+    source_type: oxc_span::SourceType,
+    code: &[u8],
+    options: &MinifyOptions,
+) -> Result<Vec<u8>, MinifyError> {
     use oxc_allocator::Allocator;
-    use oxc_codegen::{Codegen, CodegenOptions, CommentOptions};
-    use oxc_minifier::{Minifier, MinifierOptions};
+    use oxc_codegen::{Codegen, CodegenOptions, CommentOptions, LegalComment};
+    use oxc_compat::EngineTargets;
+    use oxc_minifier::{CompressOptions, Minifier, MinifierOptions};
     use oxc_parser::Parser;
 
-    let code = std::str::from_utf8(code).expect("Invalid UTF-8");
+    let code = std::str::from_utf8(code).map_err(MinifyError::Utf8)?;
     let allocator = Allocator::default();
     let mut parsed = Parser::new(&allocator, code, source_type).parse();
 
     if !parsed.errors.is_empty() {
-        panic!("Parsing errors: {:#?}", parsed.errors);
+        return Err(MinifyError::Parse(parsed.errors));
+    }
+
+    if source_type.is_typescript()
+        && let Some(syntax) = erase_types(&allocator, &mut parsed.program)
+    {
+        return Err(MinifyError::NotErasable(syntax));
+    }
+
+    let targets = EngineTargets::from_target(&options.target.to_string())
+        .expect("every ECMAScript version is an engine target");
+
+    if let Some(syntax) = newer_syntax(&parsed.program, &targets) {
+        return Err(MinifyError::Unsupported {
+            syntax,
+            target: options.target,
+        });
     }
 
-    let options = MinifierOptions::default();
-    let minifier = Minifier::new(options);
+    let minifier = Minifier::new(MinifierOptions {
+        compress: Some(CompressOptions {
+            target: targets,
+            ..CompressOptions::default()
+        }),
+        ..MinifierOptions::default()
+    });
     let minified = minifier.minify(&allocator, &mut parsed.program);
 
     let codegen = Codegen::new()
         .with_options(CodegenOptions {
             source_map_path: None,
             minify: true,
-            comments: CommentOptions::disabled(),
+            comments: if options.preserve_legal_comments {
+                CommentOptions {
+                    normal: false,
+                    jsdoc: false,
+                    annotation: false,
+                    legal: LegalComment::Inline,
+                }
+            } else {
+                CommentOptions::disabled()
+            },
             ..CodegenOptions::default()
         })
         .with_scoping(minified.scoping)
         .build(&parsed.program);
 
-    codegen.code.into_bytes()
+    Ok(codegen.code.into_bytes())
+}
+
+/// Remove the types of a TypeScript program, leaving JavaScript. Returns the first syntax that
+/// can not be removed without changing what the program does.
+fn erase_types<'a>(
+    allocator: &'a oxc_allocator::Allocator,
+    program: &mut oxc_ast::ast::Program<'a>,
+) -> Option<&'static str> {
+    use oxc_allocator::{Allocator, TakeIn, Vec};
+    use oxc_ast::ast::*;
+    use oxc_ast::match_declaration;
+    use oxc_ast_visit::{VisitMut, walk_mut};
+    use oxc_syntax::scope::ScopeFlags;
+
+    struct Erase<'a> {
+        allocator: &'a Allocator,
+        refused: Option<&'static str>,
+    }
+
+    fn refuse(refused: &mut Option<&'static str>, syntax: &'static str) -> bool {
+        refused.get_or_insert(syntax);
+        false
+    }
+
+    /// Whether the declaration is only there for the type checker.
+    fn is_erased(declaration: &Declaration<'_>, refused: &mut Option<&'static str>) -> bool {
+        match declaration {
+            Declaration::VariableDeclaration(variable) => variable.declare,
+            Declaration::FunctionDeclaration(function) => {
+                function.declare || function.body.is_none()
+            }
+            Declaration::ClassDeclaration(class) => class.declare,
+            Declaration::TSTypeAliasDeclaration(_)
+            | Declaration::TSInterfaceDeclaration(_)
+            | Declaration::TSGlobalDeclaration(_) => true,
+            Declaration::TSEnumDeclaration(enumeration) => {
+                enumeration.declare || refuse(refused, "an enum")
+            }
+            Declaration::TSModuleDeclaration(module) => {
+                module.declare || refuse(refused, "a namespace")
+            }
+            Declaration::TSImportEqualsDeclaration(import) => {
+                import.import_kind.is_type() || refuse(refused, "`import =`")
+            }
+        }
+    }
+
+    /// Whether to remove the statement, after removing its type-only specifiers.
+    fn erase(statement: &mut Statement<'_>, refused: &mut Option<&'static str>) -> bool {
+        match statement {
+            Statement::ImportDeclaration(import) => {
+                if import.import_kind.is_type() {
+                    return true;
+                }
+
+                // Keep imports for their side effects, `import {} from` included.
+                let Some(specifiers) = import.specifiers.as_mut().filter(|s| !s.is_empty()) else {
+                    return false;
+                };
+
+                specifiers.retain(|specifier| {
+                    !matches!(specifier, ImportDeclarationSpecifier::ImportSpecifier(specifier)
+                        if specifier.import_kind.is_type())
+                });
+
+                specifiers.is_empty()
+            }
+            Statement::ExportNamedDeclaration(export) => {
+                if export.export_kind.is_type() {
+                    return true;
+                }
+
+                if let Some(declaration) = &export.declaration {
+                    return is_erased(declaration, refused);
+                }
+
+                let before = export.specifiers.len();
+                export
+                    .specifiers
+                    .retain(|specifier| !specifier.export_kind.is_type());
+
+                before > 0 && export.specifiers.is_empty()
+            }
+            Statement::ExportAllDeclaration(export) => export.export_kind.is_type(),
+            Statement::ExportDefaultDeclaration(export) => match &export.declaration {
+                ExportDefaultDeclarationKind::TSInterfaceDeclaration(_) => true,
+                ExportDefaultDeclarationKind::FunctionDeclaration(function) => {
+                    function.body.is_none()
+                }
+                _ => false,
+            },
+            Statement::TSExportAssignment(_) => refuse(refused, "`export =`"),
+            Statement::TSNamespaceExportDeclaration(_) => true,
+            match_declaration!(Statement) => is_erased(statement.to_declaration(), refused),
+            _ => false,
+        }
+    }
+
+    impl<'a> VisitMut<'a> for Erase<'a> {
+        fn visit_statements(&mut self, it: &mut Vec<'a, Statement<'a>>) {
+            it.retain_mut(|statement| !erase(statement, &mut self.refused));
+            walk_mut::walk_statements(self, it);
+        }
+
+        fn visit_expression(&mut self, it: &mut Expression<'a>) {
+            loop {
+                let inner = match it {
+                    Expression::TSAsExpression(e) => e.expression.take_in(self.allocator),
+                    Expression::TSSatisfiesExpression(e) => e.expression.take_in(self.allocator),
+                    Expression::TSNonNullExpression(e) => e.expression.take_in(self.allocator),
+                    Expression::TSTypeAssertion(e) => e.expression.take_in(self.allocator),
+                    Expression::TSInstantiationExpression(e) => {
+                        e.expression.take_in(self.allocator)
+                    }
+                    _ => break,
+                };
+
+                *it = inner;
+            }
+
+            walk_mut::walk_expression(self, it);
+        }
+
+        fn visit_simple_assignment_target(&mut self, it: &mut SimpleAssignmentTarget<'a>) {
+            let Some(expression) = it.get_expression_mut() else {
+                return walk_mut::walk_simple_assignment_target(self, it);
+            };
+
+            let mut inner = expression.take_in(self.allocator);
+            self.visit_expression(&mut inner);
+
+            match inner {
+                Expression::Identifier(identifier) => {
+                    *it = SimpleAssignmentTarget::AssignmentTargetIdentifier(identifier);
+                }
+                inner if inner.is_member_expression() => {
+                    *it = SimpleAssignmentTarget::from(inner.into_member_expression());
+                }
+                _ => {
+                    refuse(
+                        &mut self.refused,
+                        "a type assertion around this assignment target",
+                    );
+                }
+            }
+        }
+
+        fn visit_class(&mut self, it: &mut Class<'a>) {
+            it.type_parameters = None;
+            it.super_type_arguments = None;
+            it.implements.clear();
+            it.r#abstract = false;
+            it.body.body.retain(|element| {
+                !element.is_typescript_syntax()
+                    && !matches!(element, ClassElement::PropertyDefinition(p) if p.declare)
+            });
+
+            walk_mut::walk_class(self, it);
+        }
+
+        fn visit_property_definition(&mut self, it: &mut PropertyDefinition<'a>) {
+            it.type_annotation = None;
+            it.accessibility = None;
+            it.optional = false;
+            it.definite = false;
+            it.readonly = false;
+            it.r#override = false;
+            walk_mut::walk_property_definition(self, it);
+        }
+
+        fn visit_accessor_property(&mut self, it: &mut AccessorProperty<'a>) {
+            it.type_annotation = None;
+            it.accessibility = None;
+            it.definite = false;
+            it.r#override = false;
+            walk_mut::walk_accessor_property(self, it);
+        }
+
+        fn visit_method_definition(&mut self, it: &mut MethodDefinition<'a>) {
+            it.accessibility = None;
+            it.optional = false;
+            it.r#override = false;
+            walk_mut::walk_method_definition(self, it);
+        }
+
+        fn visit_function(&mut self, it: &mut Function<'a>, flags: ScopeFlags) {
+            it.type_parameters = None;
+            it.this_param = None;
+            it.return_type = None;
+            walk_mut::walk_function(self, it, flags);
+        }
+
+        fn visit_arrow_function_expression(&mut self, it: &mut ArrowFunctionExpression<'a>) {
+            it.type_parameters = None;
+            it.return_type = None;
+            walk_mut::walk_arrow_function_expression(self, it);
+        }
+
+        fn visit_formal_parameter(&mut self, it: &mut FormalParameter<'a>) {
+            if it.accessibility.is_some() || it.readonly || it.r#override {
+                refuse(&mut self.refused, "a parameter property");
+            }
+
+            it.type_annotation = None;
+            it.optional = false;
+            walk_mut::walk_formal_parameter(self, it);
+        }
+
+        fn visit_formal_parameter_rest(&mut self, it: &mut FormalParameterRest<'a>) {
+            it.type_annotation = None;
+            walk_mut::walk_formal_parameter_rest(self, it);
+        }
+
+        fn visit_variable_declarator(&mut self, it: &mut VariableDeclarator<'a>) {
+            it.type_annotation = None;
+            it.definite = false;
+            walk_mut::walk_variable_declarator(self, it);
+        }
+
+        fn visit_catch_parameter(&mut self, it: &mut CatchParameter<'a>) {
+            it.type_annotation = None;
+            walk_mut::walk_catch_parameter(self, it);
+        }
+
+        fn visit_call_expression(&mut self, it: &mut CallExpression<'a>) {
+            it.type_arguments = None;
+            walk_mut::walk_call_expression(self, it);
+        }
+
+        fn visit_new_expression(&mut self, it: &mut NewExpression<'a>) {
+            it.type_arguments = None;
+            walk_mut::walk_new_expression(self, it);
+        }
+
+        fn visit_tagged_template_expression(&mut self, it: &mut TaggedTemplateExpression<'a>) {
+            it.type_arguments = None;
+            walk_mut::walk_tagged_template_expression(self, it);
+        }
+
+        fn visit_jsx_opening_element(&mut self, it: &mut JSXOpeningElement<'a>) {
+            it.type_arguments = None;
+            walk_mut::walk_jsx_opening_element(self, it);
+        }
+    }
+
+    let mut erase = Erase {
+        allocator,
+        refused: None,
+    };
+
+    erase.visit_program(program);
+    erase.refused
+}
+
+/// The first syntax of the program the targets do not support, by its name in errors.
+fn newer_syntax(
+    program: &oxc_ast::ast::Program<'_>,
+    targets: &oxc_compat::EngineTargets,
+) -> Option<&'static str> {
+    use oxc_ast::ast::*;
+    use oxc_ast_visit::{Visit, walk};
+    use oxc_compat::{ESFeature, EngineTargets};
+    use oxc_syntax::operator::{AssignmentOperator, BinaryOperator, LogicalOperator};
+    use oxc_syntax::scope::ScopeFlags;
+
+    struct Syntax<'t> {
+        targets: &'t EngineTargets,
+        found: Option<&'static str>,
+        function_depth: usize,
+    }
+
+    impl Syntax<'_> {
+        fn uses(&mut self, feature: ESFeature, syntax: &'static str) {
+            if self.found.is_none() && self.targets.has_feature(feature) {
+                self.found = Some(syntax);
+            }
+        }
+    }
+
+    impl<'a> Visit<'a> for Syntax<'_> {
+        fn visit_function(&mut self, it: &Function<'a>, flags: ScopeFlags) {
+            match (it.r#async, it.generator) {
+                (true, true) => {
+                    self.uses(ESFeature::ES2018AsyncGeneratorFunctions, "async generators")
+                }
+                (true, false) => self.uses(ESFeature::ES2017AsyncToGenerator, "async functions"),
+                _ => {}
+            }
+
+            self.function_depth += 1;
+            walk::walk_function(self, it, flags);
+            self.function_depth -= 1;
+        }
+
+        fn visit_arrow_function_expression(&mut self, it: &ArrowFunctionExpression<'a>) {
+            if it.r#async {
+                self.uses(ESFeature::ES2017AsyncToGenerator, "async functions");
+            }
+
+            self.function_depth += 1;
+            walk::walk_arrow_function_expression(self, it);
+            self.function_depth -= 1;
+        }
+
+        fn visit_await_expression(&mut self, it: &AwaitExpression<'a>) {
+            if self.function_depth == 0 {
+                self.uses(ESFeature::ES2022TopLevelAwait, "top-level await");
+            }
+
+            walk::walk_await_expression(self, it);
+        }
+
+        fn visit_for_of_statement(&mut self, it: &ForOfStatement<'a>) {
+            if it.r#await {
+                self.uses(ESFeature::ES2018AsyncGeneratorFunctions, "`for await`");
+
+                if self.function_depth == 0 {
+                    self.uses(ESFeature::ES2022TopLevelAwait, "top-level await");
+                }
+            }
+
+            walk::walk_for_of_statement(self, it);
+        }
+
+        fn visit_binary_expression(&mut self, it: &BinaryExpression<'a>) {
+            if it.operator == BinaryOperator::Exponential {
+                self.uses(ESFeature::ES2016ExponentiationOperator, "`**`");
+            }
+
+            walk::walk_binary_expression(self, it);
+        }
+
+        fn visit_logical_expression(&mut self, it: &LogicalExpression<'a>) {
+            if it.operator == LogicalOperator::Coalesce {
+                self.uses(ESFeature::ES2020NullishCoalescingOperator, "`??`");
+            }
+
+            walk::walk_logical_expression(self, it);
+        }
+
+        fn visit_assignment_expression(&mut self, it: &AssignmentExpression<'a>) {
+            match it.operator {
+                AssignmentOperator::Exponential => {
+                    self.uses(ESFeature::ES2016ExponentiationOperator, "`**=`")
+                }
+                AssignmentOperator::LogicalAnd
+                | AssignmentOperator::LogicalOr
+                | AssignmentOperator::LogicalNullish => self.uses(
+                    ESFeature::ES2021LogicalAssignmentOperators,
+                    "logical assignment",
+                ),
+                _ => {}
+            }
+
+            walk::walk_assignment_expression(self, it);
+        }
+
+        fn visit_object_expression(&mut self, it: &ObjectExpression<'a>) {
+            if it
+                .properties
+                .iter()
+                .any(|property| matches!(property, ObjectPropertyKind::SpreadProperty(_)))
+            {
+                self.uses(ESFeature::ES2018ObjectRestSpread, "object spread");
+            }
+
+            walk::walk_object_expression(self, it);
+        }
+
+        fn visit_object_pattern(&mut self, it: &ObjectPattern<'a>) {
+            if it.rest.is_some() {
+                self.uses(ESFeature::ES2018ObjectRestSpread, "object rest");
+            }
+
+            walk::walk_object_pattern(self, it);
+        }
+
+        fn visit_catch_clause(&mut self, it: &CatchClause<'a>) {
+            if it.param.is_none() {
+                self.uses(
+                    ESFeature::ES2019OptionalCatchBinding,
+                    "optional catch binding",
+                );
+            }
+
+            walk::walk_catch_clause(self, it);
+        }
+
+        fn visit_chain_expression(&mut self, it: &ChainExpression<'a>) {
+            self.uses(ESFeature::ES2020OptionalChaining, "optional chaining");
+            walk::walk_chain_expression(self, it);
+        }
+
+        fn visit_big_int_literal(&mut self, it: &BigIntLiteral<'a>) {
+            self.uses(ESFeature::ES2020BigInt, "BigInt literals");
+            walk::walk_big_int_literal(self, it);
+        }
+
+        fn visit_property_definition(&mut self, it: &PropertyDefinition<'a>) {
+            self.uses(ESFeature::ES2022ClassProperties, "class fields");
+            walk::walk_property_definition(self, it);
+        }
+
+        fn visit_static_block(&mut self, it: &StaticBlock<'a>) {
+            self.uses(ESFeature::ES2022ClassStaticBlock, "class static blocks");
+            walk::walk_static_block(self, it);
+        }
+
+        fn visit_private_in_expression(&mut self, it: &PrivateInExpression<'a>) {
+            self.uses(ESFeature::ES2022PrivatePropertyInObject, "`#field in`");
+            walk::walk_private_in_expression(self, it);
+        }
+    }
+
+    let mut syntax = Syntax {
+        targets,
+        found: None,
+        function_depth: 0,
+    };
+
+    syntax.visit_program(program);
+    syntax.found
+}
+
+#[test]
+fn check_finds_introduced_globals() {
+    let original =
+        b"function f(value) { const local = value + 1; return local * 2; }\nf(globalThis.x);";
+    let minified = minify_js(original).unwrap();
+    assert_eq!(check_minified_js(original, &minified), Ok(()));
+
+    // A renamed local that escaped its declaration.
+    let broken = b"function f(a) { const b = a + 1; return c * 2; }\nf(globalThis.x);";
+    let err = check_minified_js(original, broken).unwrap_err();
+    assert_eq!(
+        err,
+        "the minified script refers to globals the original does not: c"
+    );
+
+    // All of them, while the globals of the original are fine to keep or drop.
+    let broken = b"function f(a) { return b(c, globalThis); }\nf(d);";
+    let err = check_minified_js(original, broken).unwrap_err();
+    assert_eq!(
+        err,
+        "the minified script refers to globals the original does not: b, c, d"
+    );
+}
+
+#[test]
+fn broken_scripts_are_errors() {
+    let minified = minify_js(b"const greeting = 'Hello';\nconsole.log(greeting);").unwrap();
+    assert!(
+        std::str::from_utf8(&minified)
+            .unwrap()
+            .contains("console.log")
+    );
+
+    let err = minify_js(b"function f( { return 1; }").unwrap_err();
+    assert!(matches!(&err, MinifyError::Parse(errors) if !errors.is_empty()));
+    assert!(
+        err.to_string().starts_with("the script does not parse"),
+        "{err}"
+    );
+
+    // A Latin-1 `é` in a comment.
+    let err = minify_mjs(b"// caf\xe9\nexport const a = 1;").unwrap_err();
+    assert!(matches!(err, MinifyError::Utf8(_)));
+}
+
+#[test]
+fn legal_comments_are_kept_on_request() {
+    let script = b"/*! (c) foo */\n/* an explanation */\nexport const answer = 42;";
+    let keep = MinifyOptions {
+        preserve_legal_comments: true,
+        ..MinifyOptions::default()
+    };
+
+    let minified = String::from_utf8(minify_mjs_with(script, &keep).unwrap()).unwrap();
+    assert!(minified.contains("/*! (c) foo */"), "{minified}");
+    assert!(!minified.contains("explanation"), "{minified}");
+
+    let minified = String::from_utf8(minify_mjs(script).unwrap()).unwrap();
+    assert!(!minified.contains("(c) foo"), "{minified}");
+}
+
+#[test]
+fn newer_syntax_than_the_target_is_refused() {
+    let es2017 = MinifyOptions {
+        target: ESTarget::ES2017,
+        ..MinifyOptions::default()
+    };
+
+    let script = b"const f = async (a) => { try { return await a.b; } catch (e) { return a; } };";
+    assert!(minify_js_with(script, &es2017).is_ok());
+
+    let script = b"const f = (a) => a?.b ?? a;";
+    let err = minify_js_with(script, &es2017).unwrap_err();
+    assert!(
+        matches!(
+            err,
+            MinifyError::Unsupported {
+                target: ESTarget::ES2017,
+                ..
+            }
+        ),
+        "{err}"
+    );
+    assert!(
+        err.to_string().ends_with("which es2017 does not have"),
+        "{err}"
+    );
+    assert!(minify_js(script).is_ok());
+
+    let script = b"class A { static { this.x = 1; } }\nexport { A };";
+    let err = minify_mjs_with(script, &es2017).unwrap_err();
+    assert!(err.to_string().contains("class static blocks"), "{err}");
+}
+
+#[test]
+fn typescript_types_are_erased() {
+    let script = b"import type { Config } from './config';
+import { type Entry, load } from './entries';
+interface Named { name: string }
+type Id = number;
+declare const DEBUG: boolean;
+export function first<T extends Named>(items: T[], fallback?: T): T | undefined {
+    const [head] = items as T[];
+    return head ?? fallback!;
+}
+export abstract class Store<T> implements Named {
+    name: string = 'store';
+    declare size: number;
+    private items?: T[];
+    abstract open(config: Config): void;
+    get(this: Store<T>, id: Id): T | undefined { return this.items![id]; }
+}
+export const entries = load<Entry>() satisfies unknown;";
+
+    let minified = String::from_utf8(minify_ts(script).unwrap()).unwrap();
+    for typed in [
+        "interface",
+        "Named",
+        "Config",
+        "Entry",
+        "Id",
+        "DEBUG",
+        "size",
+        "abstract",
+        "implements",
+        "private",
+        "satisfies",
+        " as ",
+        "<T",
+        ": ",
+    ] {
+        assert!(!minified.contains(typed), "{typed} in {minified}");
+    }
+    assert!(minified.contains("./entries"), "{minified}");
+    assert!(!minified.contains("./config"), "{minified}");
+    assert!(minified.contains("class Store"), "{minified}");
+
+    let script = b"const view = <T,>(props: { items: T[] }) => <ul>{props.items.length}</ul>;
+export default view;";
+    let minified = String::from_utf8(minify_tsx(script).unwrap()).unwrap();
+    assert!(minified.contains("<ul>"), "{minified}");
+    assert!(!minified.contains("items:"), "{minified}");
+
+    let err = minify_ts(b"enum Color { Red }\nexport const red = Color.Red;").unwrap_err();
+    assert!(matches!(err, MinifyError::NotErasable("an enum")), "{err}");
+    let err = minify_ts(b"export class A { constructor(private a: number) {} }").unwrap_err();
+    assert!(
+        matches!(err, MinifyError::NotErasable("a parameter property")),
+        "{err}"
+    );
+}
+
+/// Erase the types of `ts` and compare with `js`, both printed the same way.
+#[cfg(test)]
+fn assert_erases(ts: &str, js: &str) {
+    use oxc_allocator::Allocator;
+    use oxc_codegen::Codegen;
+    use oxc_parser::Parser;
+    use oxc_span::SourceType;
+
+    let allocator = Allocator::default();
+    let mut parsed = Parser::new(&allocator, ts, SourceType::ts()).parse();
+    assert!(parsed.errors.is_empty(), "{:?}", parsed.errors);
+    assert_eq!(erase_types(&allocator, &mut parsed.program), None, "{ts}");
+    let erased = Codegen::new().build(&parsed.program).code;
+
+    let expected = Parser::new(&allocator, js, SourceType::mjs()).parse();
+    assert!(expected.errors.is_empty(), "{:?}", expected.errors);
+    let expected = Codegen::new().build(&expected.program).code;
+
+    assert_eq!(erased, expected, "erasing {ts}");
+}
+
+#[test]
+fn typescript_constructs_erase_to_javascript() {
+    // Generics of functions, classes, calls, instantiations and templates.
+    assert_erases(
+        "function id<T extends object = {}>(x: T): T { return x; }
+const a = id<number>(1);
+class Box<T> extends Base<T> implements Named<T> {}
+const m = new Map<string, number>();
+const make = id<string>;
+const tagged = tag<number>`a${1}`;
+const fn = async <T,>(x: T): Promise<T> => x;",
+        "function id(x) { return x; }
+const a = id(1);
+class Box extends Base {}
+const m = new Map();
+const make = id;
+const tagged = tag`a${1}`;
+const fn = async (x) => x;",
+    );
+
+    // Assertions, also as assignment targets and inside optional chains.
+    assert_erases(
+        "const n = (value as unknown as number) + 1;
+(el as any).x = 2;
+(count as number) += 1;
+[(first as any), second] = pair;
+const b = <string>other;
+const c = maybe!.field!;
+const d = a?.b!.c;",
+        "const n = (value) + 1;
+(el).x = 2;
+count += 1;
+[(first), second] = pair;
+const b = other;
+const c = maybe.field;
+const d = a?.b.c;",
+    );
+
+    // Enums only declared for the checker, others are refused.
+    assert_erases(
+        "declare enum Mode { A, B }
+declare const enum Flag { X = 1 }
+export declare enum Shape { Round }
+const mode = 1;",
+        "const mode = 1;",
+    );
+
+    assert_erases(
+        "export const config = { port: 80 } satisfies Config;
+const list = [1, 2] satisfies number[] as readonly number[];",
+        "export const config = { port: 80 };
+const list = [1, 2];",
+    );
+
+    // Optional and defaulted parameters, optional members and overloads.
+    assert_erases(
+        "function f(a?: number, b: string = 'x', ...rest: any[]): void {}
+function g({ a }: { a?: number } = {}): void;
+function g({ a }: { a?: number } = {}) { return a; }
+const h = (a?: number): number => a ?? 0;
+class C { a?: number; b!: string; m?(): void; n(x?: C): C | undefined { return x; } }
+try {} catch (e: unknown) {}",
+        "function f(a, b = 'x', ...rest) {}
+function g({ a } = {}) { return a; }
+const h = (a) => a ?? 0;
+class C { a; b; n(x) { return x; } }
+try {} catch (e) {}",
+    );
 }
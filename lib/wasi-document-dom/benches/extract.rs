@@ -0,0 +1,109 @@
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use html_and_tar::{Entry, HtmlAttributeSafeName, TarEngine};
+use wasi_document_dom::SourceDocument;
+
+const TEMPLATE: &str = "<!DOCTYPE html>\n<html lang=\"en\">\n<head><title>Bench</title></head>\n<body><p>Hello</p></body>\n</html>\n";
+
+/// Pack a document the way `wasi-document build` lays it out, keeping the original stage0.
+fn packed_document(files: &[(String, Vec<u8>)]) -> String {
+    let mut source = SourceDocument::new(TEMPLATE);
+    let structure = source.prepare_tar_structure().unwrap();
+
+    let mut head = source.span(structure.html_tag);
+    head.end = head.start + structure.html_insertion_point;
+    head.start = 0;
+    let insert = source.span(structure.insertion_tag);
+
+    let mut engine = TarEngine::default();
+    let init = engine.start_of_file(source[head].as_bytes(), insert.start);
+
+    let mut doc: Vec<u8> = vec![];
+    doc.extend_from_slice(init.header.as_bytes());
+    doc.extend_from_slice(&init.extra);
+    doc.extend_from_slice(source[init.consumed..insert.start].as_bytes());
+
+    let mut entries = vec![];
+    for (name, data) in files {
+        entries.push(engine.escaped_base64(Entry {
+            name: HtmlAttributeSafeName::new(name).unwrap(),
+            data,
+            attributes: Default::default(),
+            compression: None,
+        }));
+    }
+
+    entries.push(engine.escaped_eof());
+
+    for entry in &entries {
+        doc.extend_from_slice(entry.padding);
+        doc.extend_from_slice(entry.header.as_bytes());
+        doc.extend_from_slice(entry.file.as_bytes());
+        doc.extend_from_slice(&entry.data);
+    }
+
+    doc.extend_from_slice(source[insert.end..].as_bytes());
+    String::from_utf8(doc).unwrap()
+}
+
+/// `count` files of `size` bytes each.
+fn files(count: usize, size: usize) -> Vec<(String, Vec<u8>)> {
+    (0..count)
+        .map(|idx| {
+            let data = (0..size)
+                .map(|byte: usize| (byte * 7 + idx) as u8)
+                .collect();
+            (format!("root/file{idx}"), data)
+        })
+        .collect()
+}
+
+fn bench_extract(c: &mut Criterion) {
+    let document = packed_document(&files(128, 4096));
+
+    let mut group = c.benchmark_group("extract");
+    group.throughput(Throughput::Bytes(document.len() as u64));
+    group.bench_function("split_tar_contents", |b| {
+        b.iter_batched(
+            || SourceDocument::new(&document),
+            |mut source| source.split_tar_contents().unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Documents of several megabytes, where each reparse serializes all of the text again.
+fn bench_reparse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reparse");
+    group.sample_size(10);
+
+    for megabytes in [4, 16] {
+        let document = packed_document(&files(megabytes * 4, 192 << 10));
+        group.throughput(Throughput::Bytes(document.len() as u64));
+
+        group.bench_function(format!("split_tar_contents/{megabytes}MiB"), |b| {
+            b.iter_batched(
+                || SourceDocument::new(&document),
+                |mut source| source.split_tar_contents().unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+
+        // A template of the same size, with its text in one long run instead of files.
+        let prose = "All work and no play makes Jack a dull boy.\n".repeat(document.len() / 44);
+        let template = TEMPLATE.replace("<p>Hello</p>", &format!("<pre>{prose}</pre>"));
+
+        group.bench_function(format!("prepare_tar_structure/{megabytes}MiB"), |b| {
+            b.iter_batched(
+                || SourceDocument::new(&template),
+                |mut source| source.prepare_tar_structure().unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_extract, bench_reparse);
+criterion_main!(benches);
@@ -1,9 +1,11 @@
 use core::{error::Error, ops};
 use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
 
 use html_and_tar::{
-    Entry, EntryAttributes, External, HtmlAttributeSafeName, ParsedFileData, TarDecompiler,
-    TarHeader,
+    Device, DeviceKind, Directory, Entry, EntryAttributes, External, HtmlAttributeSafeName,
+    ParsedFileData, Symlink, TarDecompiler, TarEngine, TarHeader,
 };
 use lithtml::{Dom, Element, Node};
 
@@ -11,6 +13,9 @@ pub struct Structure {
     pub html_tag: TagSpan,
     pub html_insertion_point: usize,
     pub insertion_tag: TagSpan,
+    /// Further insertion points in document order, by their name. Their id is the one of the
+    /// first with `_` and the name appended, `WAH_POLYGLOT_HTML_PLUS_TAR_CONTENT_below` say.
+    pub continued_insertions: Vec<(String, TagSpan)>,
     pub stage0: TagSpan,
 }
 
@@ -42,19 +47,45 @@ pub struct TarEntryOwned {
 #[derive(Clone)]
 enum OwnedContent {
     Data(Vec<u8>),
-    Reference { opaque: Vec<u8>, realsize: u64 },
+    Reference {
+        opaque: Vec<u8>,
+        realsize: u64,
+    },
+    /// The numbers are kept in the header.
+    Device(DeviceKind),
+    Directory,
+    /// The target is kept in the header.
+    Symlink,
 }
 
 impl TarEntryOwned {
     pub fn from_entry(entry: Entry<'_>) -> Self {
+        Self::from_parts(
+            entry.name,
+            &entry.attributes,
+            entry.compression,
+            entry.data.to_vec(),
+        )
+    }
+
+    /// Like [`Self::from_entry`] but taking over the data, for a file read only to be packed.
+    pub fn from_parts(
+        name: HtmlAttributeSafeName<'_>,
+        attributes: &EntryAttributes<'_>,
+        compression: Option<html_and_tar::Compression>,
+        data: Vec<u8>,
+    ) -> Self {
         TarEntryOwned {
             header: {
                 let mut empty = TarHeader::EMPTY;
-                empty.assign_attributes(&entry.attributes);
+                empty.assign_attributes(attributes);
+                if let Some(compression) = compression {
+                    empty.assign_compression(compression);
+                }
                 empty
             },
-            name: entry.name.0.to_string(),
-            content: OwnedContent::Data(entry.data.to_vec()),
+            name: name.0.to_string(),
+            content: OwnedContent::Data(data),
             reference: None,
         }
     }
@@ -80,6 +111,7 @@ impl TarEntryOwned {
             name,
             data,
             attributes,
+            compression: self.header.parse_compression(),
         })
     }
 
@@ -88,6 +120,7 @@ impl TarEntryOwned {
         (match &self.content {
             OwnedContent::Data(data) => data.len(),
             OwnedContent::Reference { opaque, .. } => opaque.len(),
+            OwnedContent::Device(_) | OwnedContent::Directory | OwnedContent::Symlink => 0,
         }) as u64
     }
 
@@ -95,6 +128,7 @@ impl TarEntryOwned {
         match &self.content {
             OwnedContent::Data(data) => data.len() as u64,
             OwnedContent::Reference { realsize, .. } => *realsize,
+            OwnedContent::Device(_) | OwnedContent::Directory | OwnedContent::Symlink => 0,
         }
     }
 
@@ -115,26 +149,184 @@ impl TarEntryOwned {
         })
     }
 
+    pub fn as_html_and_tar_device(&self) -> Option<Device<'_>> {
+        let OwnedContent::Device(kind) = self.content else {
+            return None;
+        };
+
+        Some(Device {
+            name: HtmlAttributeSafeName::new(&self.name).ok()?,
+            kind,
+            attributes: self.attributes(),
+        })
+    }
+
+    pub fn as_html_and_tar_directory(&self) -> Option<Directory<'_>> {
+        let OwnedContent::Directory = self.content else {
+            return None;
+        };
+
+        Some(Directory {
+            name: HtmlAttributeSafeName::new(&self.name).ok()?,
+            attributes: self.attributes(),
+        })
+    }
+
+    pub fn as_html_and_tar_symlink(&self) -> Option<Symlink<'_>> {
+        let OwnedContent::Symlink = self.content else {
+            return None;
+        };
+
+        Some(Symlink {
+            name: HtmlAttributeSafeName::new(&self.name).ok()?,
+            target: self.header.parse_symlink()?,
+            attributes: self.attributes(),
+        })
+    }
+
     pub fn make_external(&mut self, reference: HtmlAttributeSafeName) {
         let HtmlAttributeSafeName(reference) = reference;
         self.reference = Some(reference.to_string());
     }
+
+    /// Write the file as an entry of a standard ustar archive, without any of our HTML.
+    ///
+    /// Hard links stay links, their target must be earlier in the archive. Symbolic links keep
+    /// their target as is. A name too long for `prefix` and `name` gets a GNU long name record
+    /// before the entry, as `tar` writes them. Outlined files have no data here and are an error.
+    pub fn write_plain_tar(&self, out: &mut dyn io::Write) -> io::Result<()> {
+        if self.reference.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("`{}` is outlined from the document", self.name),
+            ));
+        }
+
+        let mut header = TarHeader::EMPTY;
+        header.assign_permission_encoding_meta();
+        header.magic = *b"ustar\0";
+        header.version = *b"00";
+        header.assign_attributes(&self.attributes());
+
+        let (typeflag, data): (u8, &[u8]) = match &self.content {
+            _ if self.header.parse_hard_link().is_some() => (b'1', &[]),
+            OwnedContent::Data(data) => (b'0', data),
+            OwnedContent::Device(DeviceKind::Character) => (b'3', &[]),
+            OwnedContent::Device(DeviceKind::Block) => (b'4', &[]),
+            OwnedContent::Directory => (b'5', &[]),
+            OwnedContent::Symlink => (b'2', &[]),
+            OwnedContent::Reference { .. } => unreachable!("outlined files have a reference"),
+        };
+
+        let mut name = Cow::Borrowed(self.name.as_str());
+        if typeflag == b'5' && !name.ends_with('/') {
+            name.to_mut().push('/');
+        }
+
+        match split_plain_name(&name) {
+            Some((prefix, name)) => {
+                header.prefix[..prefix.len()].copy_from_slice(prefix.as_bytes());
+                header.name[..name.len()].copy_from_slice(name.as_bytes());
+            }
+            None => {
+                let mut long_name = header;
+                long_name.typeflag = b'L';
+                long_name.name[..13].copy_from_slice(b"././@LongLink");
+                write_plain_entry(out, long_name, format!("{name}\0").as_bytes())?;
+
+                // Truncated, readers take the name from the record.
+                header.name.copy_from_slice(&name.as_bytes()[..100]);
+            }
+        }
+
+        let target = self
+            .header
+            .parse_hard_link()
+            .or_else(|| self.header.parse_symlink());
+        if let Some(target) = target {
+            let target = target.0.as_bytes();
+            if target.len() > header.linkname.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("the link target of `{}` is too long", self.name),
+                ));
+            }
+
+            header.linkname[..target.len()].copy_from_slice(target);
+        }
+
+        header.typeflag = typeflag;
+        write_plain_entry(out, header, data)
+    }
+}
+
+/// Split a path into the ustar `prefix` and `name`, at a `/` if it does not fit `name` alone.
+fn split_plain_name(path: &str) -> Option<(&str, &str)> {
+    const NAME_LEN: usize = 100;
+    const PREFIX_LEN: usize = 155;
+
+    if path.len() <= NAME_LEN {
+        return Some(("", path));
+    }
+
+    path.match_indices('/')
+        .map(|(idx, _)| (&path[..idx], &path[idx + 1..]))
+        .find(|(prefix, name)| {
+            prefix.len() <= PREFIX_LEN && !name.is_empty() && name.len() <= NAME_LEN
+        })
+}
+
+/// Write a header with the size of its data, then the data padded to the block size.
+fn write_plain_entry(
+    out: &mut dyn io::Write,
+    mut header: TarHeader,
+    data: &[u8],
+) -> io::Result<()> {
+    html_and_tar::write_octal(&mut header.size, data.len() as u64)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+    header.assign_checksum();
+
+    out.write_all(header.as_bytes())?;
+    out.write_all(data)?;
+    out.write_all(&[0; 512][..data.len().next_multiple_of(512) - data.len()])
+}
+
+/// The metadata of a file element, see [`SourceDocument::list_entries`].
+pub struct EntryManifest {
+    /// The full name, from a long name entry or the header.
+    pub name: String,
+    /// The bytes of encoded data in the element, as recorded by its header.
+    ///
+    /// The file itself is about three quarters of it, or [`TarHeader::parse_realsize`] for a
+    /// compressed file.
+    pub size: u64,
+    pub typeflag: u8,
+    pub header: TarHeader,
+}
+
+impl EntryManifest {
+    pub fn attributes(&self) -> EntryAttributes<'_> {
+        EntryAttributes::from_header(&self.header)
+    }
 }
 
 pub struct SourceDocument<'text> {
     text: Cow<'text, str>,
     by_line: Vec<usize>,
+    /// The buffer of the text before the last reparse, the next one serializes into it.
+    spare: String,
 }
 
-fn parse_tar_tags(source: &mut SourceDocument) -> Result<Structure, Box<dyn Error>> {
-    const ID_TAR_CONTENT: &str = "WAH_POLYGLOT_HTML_PLUS_TAR_CONTENT";
-    const ID_TAR_STAGE0: &str = "WAH_POLYGLOT_HTML_PLUS_TAR_STAGE0";
+const ID_TAR_CONTENT: &str = "WAH_POLYGLOT_HTML_PLUS_TAR_CONTENT";
+const ID_TAR_STAGE0: &str = "WAH_POLYGLOT_HTML_PLUS_TAR_STAGE0";
+const CSP: &str = "Content-Security-Policy";
 
+fn parse_tar_tags(source: &mut SourceDocument) -> Result<Structure, Box<dyn Error>> {
     let (mut dom, html, insertion, stage0);
     let mut is_original = true;
 
     loop {
-        let text = source.text.trim_matches('\0');
+        let text = trim_document(&source.text);
         dom = Dom::parse(text)?;
         clean_start_of_file(&mut dom);
 
@@ -144,17 +336,14 @@ fn parse_tar_tags(source: &mut SourceDocument) -> Result<Structure, Box<dyn Erro
         .ok_or_else(|| no_node("begin of Tar file", "starting `<html>` tag"))?;
 
         let pre_insertion = find_element(&dom, |node| {
-            node.element().filter(|el| {
-                el.attributes.get("id").and_then(Option::as_deref) == Some(ID_TAR_CONTENT)
-            })
+            node.element()
+                .filter(|el| attribute(el, "id") == Some(ID_TAR_CONTENT))
         });
 
         let pre_stage0 = find_element(&dom, |node| {
             node.element()
                 .filter(|el| el.name.to_lowercase() == "script")
-                .filter(|el| {
-                    el.attributes.get("id").and_then(Option::as_deref) == Some(ID_TAR_STAGE0)
-                })
+                .filter(|el| attribute(el, "id") == Some(ID_TAR_STAGE0))
         });
 
         // If we haven't modified the dom, but we're missing an insertion point, let's try to
@@ -227,7 +416,10 @@ fn parse_tar_tags(source: &mut SourceDocument) -> Result<Structure, Box<dyn Erro
                     .insert(0, lithtml::Node::Element(synth_script));
             }
 
-            *source = SourceDocument::from_reparse(&mut dom);
+            // Synthesized elements are short, avoid growing the buffer while serializing.
+            let mut text = core::mem::take(&mut source.spare);
+            serialize_dom(&mut dom, &mut text, source.text.len() + 1024);
+            source.replace_text(text);
 
             is_original = false;
             continue;
@@ -254,6 +446,15 @@ fn parse_tar_tags(source: &mut SourceDocument) -> Result<Structure, Box<dyn Erro
 
     let html_insertion_point = source.element_end_of_start_tag(html);
 
+    let mut continued_insertions = vec![];
+    let _ = find_element(&dom, |node| {
+        let el = node.element()?;
+        let id = attribute(el, "id")?;
+        let name = id.strip_prefix(ID_TAR_CONTENT)?.strip_prefix('_')?;
+        continued_insertions.push((name.to_owned(), TagSpan::from(el)));
+        None::<()>
+    });
+
     #[derive(Debug)]
     struct MissingNodeError {
         content: String,
@@ -283,25 +484,45 @@ fn parse_tar_tags(source: &mut SourceDocument) -> Result<Structure, Box<dyn Erro
         html_tag: html.into(),
         html_insertion_point,
         insertion_tag: insertion.into(),
+        continued_insertions,
         stage0: stage0.into(),
     })
 }
 
+/// Look up an attribute by name. Names are case-insensitive in HTML, browsers saving a page
+/// write them in lowercase but other tools might not.
+fn attribute<'el>(el: &'el Element, name: &str) -> Option<&'el str> {
+    el.attributes
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, value)| value.as_deref())
+}
+
+/// Check for a class of an element. The parser only splits a lowercase `class` attribute into
+/// classes, any other spelling is left among the attributes.
+fn has_class(el: &Element, class: &str) -> bool {
+    el.classes.iter().any(|name| name == class)
+        || attribute(el, "class")
+            .is_some_and(|names| names.split_ascii_whitespace().any(|name| name == class))
+}
+
+/// A file element with its header, and its full name if a long name preceded it.
+type FileElement<'dom, 'a> = (TarHeader, Option<String>, &'dom Element<'a>);
+
 fn parse_file_elements<'dom: 'a, 'a>(
     dom: &'dom Dom<'a>,
-) -> Result<Vec<(TarHeader, &'dom Element<'a>)>, Box<dyn Error>> {
+) -> Result<Vec<FileElement<'dom, 'a>>, Box<dyn Error>> {
     let mut nodes = vec![];
+    let mut long_name = None;
 
     // This is a visitor and short-circuits for elements we find uninteresting. Just never return
     // anything so the iteration visits every node.
-    let _ = find_element(&dom, |node| {
+    let _ = find_element(dom, |node| {
         let el = node
             .element()
-            .filter(|el| el.classes.contains(&Cow::Borrowed("wah_polyglot_data")))?;
+            .filter(|el| has_class(el, "wah_polyglot_data"))?;
 
-        // FIXME: this is extra brittle because it is case-sensitive!!
-        let given_name = el.attributes.get("data-wahtml_id")?;
-        let given_name = given_name.as_deref()?;
+        let given_name = attribute(el, "data-wahtml_id")?;
 
         // Cleanup  replacement characters if they exist.
         let given_name = given_name
@@ -310,19 +531,18 @@ fn parse_file_elements<'dom: 'a, 'a>(
         let given_name = given_name.trim_matches('\0');
 
         if given_name.len() > 100 {
-            eprintln!("Warning: file element has too long name, file ignored");
+            log::warn!("file element has too long name, file ignored");
             return None;
         }
 
-        let header = el.attributes.get("data-b")?;
-        let header = header.as_deref()?;
+        let header = attribute(el, "data-b")?;
 
         // Same treatment as the name attribute.
         let header = header.replace('\u{fffd}', "\0").replace("&#65533;", "\0");
         let header = header.as_bytes();
 
         if header.len() > 412 {
-            eprintln!("Warning: file element has too long header, file ignored");
+            log::warn!("file element has too long header, file ignored");
             return None;
         }
 
@@ -333,7 +553,16 @@ fn parse_file_elements<'dom: 'a, 'a>(
         header.assign_from_bytes(&bytes);
         header.name[..given_name.len()].copy_from_slice(given_name.as_bytes());
 
-        nodes.push((header, el));
+        // A GNU long name, its text is the name of the element that follows.
+        if header.typeflag == b'L' {
+            let text = el.children.iter().find_map(|child| child.text());
+            let text = text.unwrap_or_default();
+            let text = text.replace('\u{fffd}', "\0").replace("&#65533;", "\0");
+            long_name = Some(text.trim_matches('\0').to_owned());
+            return None;
+        }
+
+        nodes.push((header, long_name.take(), el));
 
         None::<()>
     });
@@ -341,6 +570,358 @@ fn parse_file_elements<'dom: 'a, 'a>(
     Ok(nodes)
 }
 
+/// Decode the data of one file element, `None` if it is not a file after all.
+fn decode_file_element(
+    header: TarHeader,
+    long_name: Option<String>,
+    element: &Element,
+) -> Result<Option<TarEntryOwned>, Box<dyn Error>> {
+    if element.children.len() > 1 {
+        log::warn!("file element has too many children, but we will ignore them");
+    }
+
+    let text = element
+        .children
+        .iter()
+        .find_map(|child| child.text())
+        .expect("<template> file element has no text child?");
+
+    let text = payload_text(text);
+    let bytes = text.as_bytes();
+
+    if header.parse_base64_len().ok() != Some(bytes.len() as u64) {
+        log::warn!(
+            "file element {:?} has {} bytes of data, its header records {:?}",
+            header.parse_path(),
+            bytes.len(),
+            header.parse_base64_len().ok(),
+        );
+    }
+
+    let name = match long_name.or_else(|| header.parse_path()) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    let data = TarDecompiler::file_data(&header, bytes)
+        .map_err(|err| format!("File `{name}` can not be decoded: {err}"))?;
+
+    let (reference, content) = match data {
+        ParsedFileData::Data(filedata) => (None, OwnedContent::Data(filedata)),
+        ParsedFileData::External {
+            reference,
+            realsize,
+        } => {
+            // FIXME: this should validate instead.. Or we should change this to never
+            // validate anything with an option to represent invalid entries..
+            let content = OwnedContent::Reference {
+                opaque: vec![],
+                realsize,
+            };
+
+            (Some(reference), content)
+        }
+        ParsedFileData::Device { kind, .. } => (None, OwnedContent::Device(kind)),
+        ParsedFileData::Directory => (None, OwnedContent::Directory),
+        ParsedFileData::Symlink { .. } => (None, OwnedContent::Symlink),
+        // In fact not a file element.
+        ParsedFileData::Nothing => return Ok(None),
+    };
+
+    Ok(Some(TarEntryOwned {
+        header,
+        name,
+        content,
+        reference,
+    }))
+}
+
+/// The files of [`SourceDocument::for_each_file`] that later elements still refer to.
+#[derive(Default)]
+struct FileStream {
+    /// The targets of links, with the latest of their files passed on so far.
+    linked: BTreeMap<String, Option<TarEntryOwned>>,
+    /// The index of the element with the last patch of each patched file.
+    last_patch: BTreeMap<String, usize>,
+    /// Files that are patched by a later element.
+    patched: BTreeMap<String, TarEntryOwned>,
+    /// Links to patched files, by their target, with the index of their element.
+    waiting: BTreeMap<String, Vec<(usize, TarEntryOwned)>>,
+}
+
+type FileSink<'f> = dyn FnMut(TarEntryOwned) -> Result<(), Box<dyn Error>> + 'f;
+
+impl FileStream {
+    /// A file is complete up to the element at `idx`, pass it on unless it is patched later.
+    fn complete(
+        &mut self,
+        file: TarEntryOwned,
+        idx: usize,
+        f: &mut FileSink<'_>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self
+            .last_patch
+            .get(&file.name)
+            .is_some_and(|&last| last > idx)
+        {
+            // A patch applies to the latest file of its name, an earlier one is done.
+            if let Some(earlier) = self.patched.insert(file.name.clone(), file) {
+                self.pass_on(earlier, f)?;
+            }
+
+            return Ok(());
+        }
+
+        self.pass_on(file, f)
+    }
+
+    fn pass_on(&mut self, file: TarEntryOwned, f: &mut FileSink<'_>) -> Result<(), Box<dyn Error>> {
+        if let Some(latest) = self.linked.get_mut(&file.name) {
+            *latest = Some(file.clone());
+        }
+
+        let waiting = self.waiting.remove(&file.name).unwrap_or_default();
+        f(file)?;
+
+        for (idx, link) in waiting {
+            self.link(link, idx, f)?;
+        }
+
+        Ok(())
+    }
+
+    fn patch(
+        &mut self,
+        patch: TarEntryOwned,
+        idx: usize,
+        f: &mut FileSink<'_>,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(base) = self.patched.remove(&patch.name) else {
+            return Err(format!("Patch of `{}` has no file to apply to", patch.name).into());
+        };
+
+        let mut patched = apply_patches(vec![base, patch])?;
+        let file = patched.pop().expect("the patched file");
+        self.complete(file, idx, f)
+    }
+
+    fn link(
+        &mut self,
+        mut link: TarEntryOwned,
+        idx: usize,
+        f: &mut FileSink<'_>,
+    ) -> Result<(), Box<dyn Error>> {
+        let target = link
+            .header
+            .parse_hard_link()
+            .expect("only links are linked")
+            .0
+            .to_owned();
+
+        if self.patched.contains_key(&target) {
+            self.waiting.entry(target).or_default().push((idx, link));
+            return Ok(());
+        }
+
+        let Some(Some(base)) = self.linked.get(&target) else {
+            return Err(format!("Link `{}` has no file `{target}` to refer to", link.name).into());
+        };
+
+        link.content = base.content.clone();
+        link.reference = base.reference.clone();
+        self.complete(link, idx, f)
+    }
+}
+
+/// Append the continuation parts of split files to the entry they continue.
+fn join_parts(
+    files: impl Iterator<Item = TarEntryOwned>,
+) -> Result<Vec<TarEntryOwned>, Box<dyn Error>> {
+    let mut joined: Vec<TarEntryOwned> = vec![];
+
+    for file in files {
+        let Some(offset) = file.header.parse_part_offset() else {
+            joined.push(file);
+            continue;
+        };
+
+        let previous = joined.last_mut().filter(|prev| prev.name == file.name);
+
+        match (previous.map(|prev| &mut prev.content), file.content) {
+            (Some(OwnedContent::Data(data)), OwnedContent::Data(part))
+                if data.len() as u64 == offset =>
+            {
+                data.extend_from_slice(&part);
+            }
+            _ => {
+                return Err(format!(
+                    "Part of file `{}` at offset {offset} does not continue its previous part",
+                    file.name
+                )
+                .into());
+            }
+        }
+    }
+
+    Ok(joined)
+}
+
+/// Apply patches to the latest earlier entry of their file, dropping them from the list.
+fn apply_patches(files: Vec<TarEntryOwned>) -> Result<Vec<TarEntryOwned>, Box<dyn Error>> {
+    let mut patched: Vec<TarEntryOwned> = vec![];
+
+    for file in files {
+        let Some(offset) = file.header.parse_patch_offset() else {
+            patched.push(file);
+            continue;
+        };
+
+        let base = patched.iter_mut().rev().find(|base| base.name == file.name);
+
+        match (base.map(|base| &mut base.content), &file.content) {
+            (Some(OwnedContent::Data(data)), OwnedContent::Data(patch)) => {
+                html_and_tar::apply_patch(data, offset, patch)?;
+            }
+            _ => {
+                return Err(format!("Patch of `{}` has no file to apply to", file.name).into());
+            }
+        }
+    }
+
+    Ok(patched)
+}
+
+/// Give hard links the contents of the latest earlier entry of their target.
+fn resolve_links(mut files: Vec<TarEntryOwned>) -> Result<Vec<TarEntryOwned>, Box<dyn Error>> {
+    for idx in 0..files.len() {
+        let Some(target) = files[idx].header.parse_hard_link() else {
+            continue;
+        };
+
+        let Some(base) = files[..idx].iter().rev().find(|base| base.name == target.0) else {
+            return Err(format!(
+                "Link `{}` has no file `{}` to refer to",
+                files[idx].name, target.0
+            )
+            .into());
+        };
+
+        let (content, reference) = (base.content.clone(), base.reference.clone());
+        files[idx].content = content;
+        files[idx].reference = reference;
+    }
+
+    Ok(files)
+}
+
+/// Clean our data from a DOM, making it into the original document.
+///
+/// There may be comments and text between the doctype and the `<html>` tag.
+fn strip_file_elements(dom: &mut Dom) {
+    if let Some(html) = dom
+        .children
+        .iter_mut()
+        .filter_map(|node| {
+            if let lithtml::Node::Element(el) = node {
+                Some(el)
+            } else {
+                None
+            }
+        })
+        .next()
+    {
+        html.attributes
+            .retain(|key, _| !key.eq_ignore_ascii_case("data-a"));
+    };
+
+    // The padding between our elements goes as well, a rebuild would otherwise keep adding to it.
+    find_element_mut(dom, |node| {
+        if let lithtml::Node::Element(el) = node {
+            el.children.retain(|child| {
+                // The data and the sentinels before raw HTML between it, see `TarEngine::escaped_end`.
+                let is_data = child.element().is_some_and(|el| {
+                    has_class(el, "wah_polyglot_data") || has_class(el, "wah_polyglot_skip")
+                });
+                !is_data && !child.text().is_some_and(is_padding_text)
+            })
+        }
+
+        false
+    });
+}
+
+/// Text that only holds the nul padding of tar blocks, as we wrote it or as a browser saved it.
+fn is_padding_text(text: &str) -> bool {
+    let text = text.replace("&#65533;", "\0");
+    text.contains(['\0', '\u{fffd}'])
+        && text
+            .chars()
+            .all(|ch| ch == '\0' || ch == '\u{fffd}' || ch.is_whitespace())
+}
+
+/// Fix for <https://github.com/Roba1993/lithtml/issues/1>. Empty non-void elements are formatted
+/// as self-closing, but HTML does not permit that. We insert a fake empty string node in each one.
+fn keep_elements_open(dom: &mut Dom) {
+    find_element_mut(dom, |node| {
+        if let lithtml::Node::Element(el) = node
+            && el.variant == lithtml::ElementVariant::Normal
+            && el.children.is_empty()
+        {
+            el.children.push(lithtml::Node::Text(Cow::Borrowed("")));
+        }
+
+        false
+    });
+}
+
+/// Recover the encoded data from the text of a file element.
+///
+/// The size in the header counts the base64 bytes as we wrote them. See `html_and_tar`, the
+/// browser might have inserted line breaks by itself while saving and replaced the nul padding
+/// around the data. Base64 never contains whitespace so we can strip all of it. The end of the
+/// element delimits the data, the size in the header should match after this cleanup.
+fn payload_text(text: &str) -> String {
+    let text = text.replace('\u{fffd}', "\0").replace("&#65533;", "\0");
+    let text = text.trim_matches(|ch: char| ch == '\0' || ch.is_ascii_whitespace());
+    text.replace(|ch: char| ch.is_ascii_whitespace(), "")
+}
+
+/// The part of the document which we hand to the parser.
+///
+/// Our tar header places nul bytes before the doctype and the EOF places some at the end. The
+/// parser can not handle them, nor the replacement characters a browser substitutes for them.
+fn trim_document(mut text: &str) -> &str {
+    const ESCAPED_NUL: &str = "&#65533;";
+    let is_padding = |ch: char| ch == '\0' || ch == '\u{fffd}' || ch.is_whitespace();
+
+    loop {
+        let trimmed = text.trim_matches(is_padding);
+        let trimmed = trimmed.strip_prefix(ESCAPED_NUL).unwrap_or(trimmed);
+        let trimmed = trimmed.strip_suffix(ESCAPED_NUL).unwrap_or(trimmed);
+
+        if trimmed.len() == text.len() {
+            return text;
+        }
+
+        text = trimmed;
+    }
+}
+
+/// Offsets of the lines as the parser sees them, i.e. after [`trim_document`].
+fn line_starts(text: &str, by_line: &mut Vec<usize>) {
+    let trimmed = trim_document(text);
+    let offset = trimmed.as_ptr() as usize - text.as_ptr() as usize;
+
+    let starts = trimmed.split_inclusive('\n').scan(offset, |acc, val| {
+        let start = *acc;
+        *acc += val.len();
+        Some(start)
+    });
+
+    by_line.clear();
+    by_line.extend(starts);
+}
+
 // When Chromium saves a file it will leave comments between the doctype and the <html> tag.
 fn clean_start_of_file(dom: &mut Dom) {
     dom.children
@@ -392,66 +973,94 @@ fn find_element_mut<'a, 'src>(
     None
 }
 
+/// Serialize a DOM into a buffer, which has room for `capacity` bytes first.
+///
+/// Every DOM we modify is written through here, never by formatting it directly, so that the
+/// workarounds for the serializer apply to all of them.
+fn serialize_dom(dom: &mut lithtml::Dom, text: &mut String, capacity: usize) {
+    keep_elements_open(dom);
+
+    // The default single quotes turn a `'` in a value into `\'`, which HTML does not know as an
+    // escape. Values with single quotes are common, `'self'` in a policy for instance.
+    let options = lithtml::FormattingOptions {
+        double_quot: true,
+        ..lithtml::FormattingOptions::pretty()
+    };
+
+    text.clear();
+    text.reserve(capacity);
+    dom.fmt_opt(text, &options)
+        .expect("formatting into a string does not fail");
+}
+
 impl<'text> SourceDocument<'text> {
     pub fn new(text: &'text str) -> Self {
-        let by_line = text.split_inclusive('\n').scan(0usize, |acc, val| {
-            let start = *acc;
-            *acc += val.len();
-            Some(start)
-        });
+        let mut by_line = vec![];
+        line_starts(text, &mut by_line);
 
         SourceDocument {
             text: Cow::Borrowed(text),
-            by_line: Vec::from_iter(by_line),
+            by_line,
+            spare: String::new(),
+        }
+    }
+
+    /// An empty document whose buffer can hold `capacity` bytes, see [`Self::reparse_from`].
+    pub fn new_with_capacity(capacity: usize) -> Self {
+        SourceDocument {
+            text: Cow::Owned(String::with_capacity(capacity)),
+            by_line: vec![],
+            spare: String::new(),
         }
     }
 
     pub fn from_reparse(dom: &mut lithtml::Dom) -> Self {
-        // Fix for <https://github.com/Roba1993/lithtml/issues/1>. Empty non-void elements are
-        // formatted as self-closing, but HTML does not permit that. We insert a fake empty string
-        // node in each one.
-        find_element_mut(dom, |node| {
-            if let lithtml::Node::Element(el) = node {
-                if el.variant == lithtml::ElementVariant::Normal && el.children.is_empty() {
-                    el.children.push(lithtml::Node::Text(Cow::Borrowed("")));
-                }
-            }
+        let mut document = SourceDocument::new_with_capacity(0);
+        document.reparse_from(dom);
+        document
+    }
 
-            false
-        });
+    /// Replace the text by the serialization of a DOM, reusing this document's buffers.
+    ///
+    /// The DOM usually borrows the text it was parsed from, so this can not be the document that
+    /// the DOM came from. Our own edits serialize into the buffer of the text before the last
+    /// reparse instead, and swap it in.
+    pub fn reparse_from(&mut self, dom: &mut lithtml::Dom) {
+        let mut text = match core::mem::take(&mut self.text) {
+            Cow::Owned(text) => text,
+            Cow::Borrowed(_) => core::mem::take(&mut self.spare),
+        };
 
-        let text: String = dom.to_string();
+        serialize_dom(dom, &mut text, 0);
+        line_starts(&text, &mut self.by_line);
+        self.text = Cow::Owned(text);
+    }
 
-        let by_line = text.split_inclusive('\n').scan(0usize, |acc, val| {
-            let start = *acc;
-            *acc += val.len();
-            Some(start)
-        });
+    /// Swap in a new text, keeping the buffer of the old one for the next reparse.
+    fn replace_text(&mut self, text: String) {
+        line_starts(&text, &mut self.by_line);
 
-        SourceDocument {
-            by_line: Vec::from_iter(by_line),
-            text: Cow::Owned(text),
+        if let Cow::Owned(old) = core::mem::replace(&mut self.text, Cow::Owned(text)) {
+            self.spare = old;
         }
     }
 
     pub fn span(&self, span: TagSpan) -> ops::Range<usize> {
-        let bias = |loc: &SourceCharacter| {
-            if loc.line == 1 {
-                self.text.len() - self.text.trim_start_matches('\0').len()
-            } else {
-                0
-            }
-        };
+        self.offset(span.start)..self.offset(span.end)
+    }
 
-        // FIXME: unsure if the `column` attribute is by character or byte offset.
-        let start = self.by_line[span.start.line.checked_sub(1).unwrap()]
-            + bias(&span.start)
-            + span.start.column.checked_sub(1).unwrap();
-        let end = self.by_line[span.end.line.checked_sub(1).unwrap()]
-            + bias(&span.end)
-            + span.end.column.checked_sub(1).unwrap();
+    /// The byte offset of a position of the parser.
+    ///
+    /// The parser counts columns in characters, starting from one, not in bytes. The end of a
+    /// span can be one past the last character of the text.
+    fn offset(&self, at: SourceCharacter) -> usize {
+        let line = self.by_line[at.line.checked_sub(1).unwrap()];
+        let column = at.column.checked_sub(1).unwrap();
 
-        start..end
+        self.text[line..]
+            .char_indices()
+            .nth(column)
+            .map_or(self.text.len(), |(idx, _)| line + idx)
     }
 
     pub fn element_end_of_start_tag(&self, el: &lithtml::Element) -> usize {
@@ -482,92 +1091,442 @@ impl<'text> SourceDocument<'text> {
 
     pub fn split_tar_contents(&mut self) -> Result<Vec<TarEntryOwned>, Box<dyn Error>> {
         // FIXME: the parser can not handle this. Unfortunate.
-        let text = self.text.trim_matches('\0');
+        let text = trim_document(&self.text);
 
         let mut dom = Dom::parse(text)?;
         let elements = parse_file_elements(&dom)?;
 
-        let files = elements.into_iter().flat_map(|(header, element)| {
-            if element.children.len() > 1 {
-                eprintln!("Warning: file element has too many children, but we will ignore them",);
+        let files = elements
+            .into_iter()
+            .map(|(header, long_name, element)| decode_file_element(header, long_name, element))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let files = join_parts(files.into_iter().flatten())?;
+        let files = apply_patches(files)?;
+        let files = resolve_links(files)?;
+
+        strip_file_elements(&mut dom);
+
+        // Stripping our data usually shrinks the document, the original size is a good bound.
+        let mut text = core::mem::take(&mut self.spare);
+        serialize_dom(&mut dom, &mut text, self.text.len());
+        self.replace_text(text);
+
+        Ok(files)
+    }
+
+    /// Decode the files one at a time, for documents too large to hold all of them at once.
+    ///
+    /// Unlike [`Self::split_tar_contents`] this leaves the document as is. Files are passed on
+    /// once complete, so a file that is patched later waits for its last patch and links to it
+    /// wait with it. Only the targets of links are kept after they were passed on.
+    pub fn for_each_file(
+        &self,
+        mut f: impl FnMut(TarEntryOwned) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let text = trim_document(&self.text);
+
+        let dom = Dom::parse(text)?;
+        let elements = parse_file_elements(&dom)?;
+
+        // The headers tell which files are needed again, without decoding any data.
+        let mut stream = FileStream::default();
+        for (idx, (header, long_name, _)) in elements.iter().enumerate() {
+            if let Some(target) = header.parse_hard_link() {
+                stream.linked.insert(target.0.to_owned(), None);
             }
 
-            let text = element
-                .children
-                .iter()
-                .find_map(|child| child.text())
-                .expect("<template> file element has no text child?");
-
-            // See `html_and_tar`, the browser might have inserted line breaks by itself while
-            // saving. This cleans them up, there's no risk we have bad base64 data from this..
-            let text = text
-                .replace('\u{fffd}', "\0")
-                .replace("&#65533;", "\0")
-                .replace('\r', "")
-                .replace('\n', "");
-
-            let bytes = text.trim_matches('\0').trim().as_bytes();
-
-            let filedata = match TarDecompiler::file_data(&header, bytes) {
-                ParsedFileData::Data(filedata) => filedata,
-                // In fact not a file element.
-                ParsedFileData::Nothing => return None,
-            };
+            if header.parse_patch_offset().is_some()
+                && let Some(name) = long_name.clone().or_else(|| header.parse_path())
+            {
+                stream.last_patch.insert(name, idx);
+            }
+        }
 
-            let name = header.parse_name()?.0.to_string();
+        let mut pending: Option<(usize, TarEntryOwned)> = None;
 
-            let (reference, content);
+        for (idx, (header, long_name, element)) in elements.into_iter().enumerate() {
+            let Some(file) = decode_file_element(header, long_name, element)? else {
+                continue;
+            };
 
-            if header.typeflag == b'S' {
-                let hsn = header.parse_link()?;
-                // FIXME: this should validate instead.. Or we should change this to never validate
-                // anything with an option to represent invalid entries..
-                reference = Some(hsn.0.to_string());
+            // Parts follow the one before them directly, as our builder writes them.
+            if file.header.parse_part_offset().is_some() {
+                let previous = pending.take().map(|(_, previous)| previous);
+                let mut joined = join_parts(previous.into_iter().chain([file]))?;
+                pending = joined.pop().map(|joined| (idx, joined));
+                continue;
+            }
 
-                content = OwnedContent::Reference {
-                    opaque: filedata,
-                    // See [`External::realsize`], do we want this? So fake for now while
-                    // evaluating.
-                    realsize: 0,
-                };
+            if let Some((at, done)) = pending.take() {
+                stream.complete(done, at, &mut f)?;
+            }
+
+            if file.header.parse_patch_offset().is_some() {
+                stream.patch(file, idx, &mut f)?;
+            } else if file.header.parse_hard_link().is_some() {
+                stream.link(file, idx, &mut f)?;
             } else {
-                reference = None;
-                content = OwnedContent::Data(filedata);
+                pending = Some((idx, file));
             }
+        }
+
+        if let Some((at, done)) = pending {
+            stream.complete(done, at, &mut f)?;
+        }
+
+        Ok(())
+    }
+
+    /// List the file elements by their headers, without decoding any of their data.
+    ///
+    /// Like [`Self::split_tar_contents`] this goes through the DOM and works for documents saved
+    /// by a browser. Every element is listed as is, parts, patches and links are not resolved
+    /// and appear with their own typeflag.
+    pub fn list_entries(&self) -> Result<Vec<EntryManifest>, Box<dyn Error>> {
+        let text = trim_document(&self.text);
+
+        let dom = Dom::parse(text)?;
+        let elements = parse_file_elements(&dom)?;
 
-            Some(TarEntryOwned {
-                header,
-                name,
-                content,
-                reference,
+        let manifest = elements
+            .into_iter()
+            .filter_map(|(header, long_name, _)| {
+                let name = long_name.or_else(|| header.parse_path())?;
+                Some((name, header))
             })
-        });
+            .map(|(name, header)| {
+                Ok(EntryManifest {
+                    name,
+                    size: header.parse_size()?,
+                    typeflag: header.typeflag,
+                    header,
+                })
+            })
+            .collect::<Result<Vec<_>, core::num::ParseIntError>>()?;
+
+        Ok(manifest)
+    }
+
+    /// Decode every file element like [`Self::split_tar_contents`], stopping at the first one
+    /// that is not recoverable with its name and why. Returns the number of files.
+    ///
+    /// Beyond decoding, each header must still match its checksum and each element must hold as
+    /// much data as its header records, so a document that lost or gained bytes is caught.
+    pub fn verify_file_elements(&self) -> Result<usize, Box<dyn Error>> {
+        let text = trim_document(&self.text);
 
-        let files = files.collect();
-
-        // Now clean that data from our DOM, make it into an original document.. There may be
-        // comments and text between the doctype and the <html> tag.
-        if let Some(html) = dom
-            .children
-            .iter_mut()
-            .filter_map(|node| {
-                if let lithtml::Node::Element(el) = node {
-                    Some(el)
-                } else {
-                    None
+        let dom = Dom::parse(text)?;
+        let elements = parse_file_elements(&dom)?;
+
+        let mut files = vec![];
+
+        for (header, long_name, element) in elements {
+            let Some(name) = long_name.clone().or_else(|| header.parse_path()) else {
+                continue;
+            };
+
+            header
+                .verify_element_checksum()
+                .map_err(|err| format!("File `{name}` has a broken header: {err}"))?;
+
+            let text = element.children.iter().find_map(|child| child.text());
+            let found = payload_text(text.unwrap_or_default()).len() as u64;
+            let recorded = header.parse_base64_len()?;
+
+            if found != recorded {
+                return Err(format!(
+                    "File `{name}` has {found} bytes of data, its header records {recorded}"
+                )
+                .into());
+            }
+
+            files.extend(decode_file_element(header, long_name, element)?);
+        }
+
+        let files = join_parts(files.into_iter())?;
+        let files = apply_patches(files)?;
+        let files = resolve_links(files)?;
+
+        Ok(files.len())
+    }
+
+    /// Write the files whose names match as a standard tar archive, for tools that know nothing
+    /// of our HTML. See [`TarEntryOwned::write_plain_tar`].
+    ///
+    /// Outlined files are skipped. A hard link whose target is not written gets the data instead.
+    pub fn extract_tar(
+        &self,
+        out: &mut dyn io::Write,
+        is_match: &dyn Fn(&str) -> bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut written = BTreeSet::new();
+
+        self.for_each_file(|mut file| {
+            if !is_match(&file.name) {
+                return Ok(());
+            }
+
+            if file.reference.is_some() {
+                log::warn!("`{}` is outlined from the document, skipped", file.name);
+                return Ok(());
+            }
+
+            let dangling = file
+                .header
+                .parse_hard_link()
+                .is_some_and(|target| !written.contains(target.0));
+            if dangling {
+                file.header.typeflag = b'0';
+            }
+
+            file.write_plain_tar(out)?;
+            written.insert(file.name);
+            Ok(())
+        })?;
+
+        // The two zero blocks ending the archive.
+        out.write_all(&[0; 1024])?;
+        Ok(())
+    }
+
+    /// Replace the data of one file, leaving all other entries byte-identical.
+    ///
+    /// The new data is encoded like the old, see [`TarEngine::reencode_base64`], and only the
+    /// header and data of the file change. When the data needs more or fewer 512-byte blocks the
+    /// rest of the document moves along unchanged, the tar structure only counts from one header
+    /// to the next. This scans the tar structure as [`TarDecompiler::list`] does, a document saved
+    /// by a browser must be repacked instead. So must files split into parts or patched, their
+    /// data is spread over several entries.
+    pub fn replace_entry(&mut self, name: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let listed = TarDecompiler::list(self.text.as_bytes())?;
+        let mut matching = listed
+            .into_iter()
+            .filter(|entry| entry.path.as_deref() == Some(name));
+
+        let entry = matching
+            .next()
+            .ok_or_else(|| format!("The document has no file `{name}`"))?;
+
+        if matching.next().is_some() {
+            return Err(format!(
+                "The file `{name}` has several entries, split into parts or patched, repack the \
+                document to replace it"
+            )
+            .into());
+        }
+
+        if !matches!(entry.header.typeflag, b'0' | b'\0') {
+            return Err(format!(
+                "`{name}` is not a regular file in the document, it can not be replaced"
+            )
+            .into());
+        }
+
+        let mut header = entry.header;
+        let encoded = TarEngine::reencode_base64(&mut header, data);
+
+        let header_start = entry.data.start - core::mem::size_of::<TarHeader>();
+        let old_end = entry.data.start + entry.data.len().next_multiple_of(512);
+        let padding = encoded.len().next_multiple_of(512) - encoded.len();
+
+        let mut text = Vec::with_capacity(self.text.len() - entry.data.len() + encoded.len());
+        text.extend_from_slice(&self.text.as_bytes()[..header_start]);
+        text.extend_from_slice(header.as_bytes());
+        text.extend_from_slice(&encoded);
+        text.resize(text.len() + padding, 0);
+        text.extend_from_slice(&self.text.as_bytes()[old_end..]);
+
+        let text = String::from_utf8(text)?;
+        line_starts(&text, &mut self.by_line);
+        self.text = Cow::Owned(text);
+        Ok(())
+    }
+
+    /// Insert a `<meta http-equiv="Content-Security-Policy">` at the start of `<head>`.
+    ///
+    /// Fails if the document already has such a tag, a second policy would only restrict the
+    /// first further and that is hardly ever intended.
+    pub fn insert_csp(&mut self, policy: &str) -> Result<(), Box<dyn Error>> {
+        Self::validate_csp(policy)?;
+
+        let text = trim_document(&self.text);
+        let mut dom = Dom::parse(text)?;
+
+        let is_csp = |el: &Element| {
+            el.name.eq_ignore_ascii_case("meta")
+                && el.attributes.iter().any(|(key, value)| {
+                    key.eq_ignore_ascii_case("http-equiv")
+                        && value
+                            .as_deref()
+                            .is_some_and(|value| value.eq_ignore_ascii_case(CSP))
+                })
+        };
+
+        if find_element(&dom, |node| node.element().filter(|el| is_csp(el))).is_some() {
+            return Err("the document already has a Content-Security-Policy `<meta>` tag".into());
+        }
+
+        let head = find_element_mut(&mut dom, |node| {
+            node.element()
+                .filter(|el| el.name.eq_ignore_ascii_case("head"))
+                .is_some()
+        })
+        .and_then(|el| match el {
+            lithtml::Node::Element(el) => Some(el),
+            _ => None,
+        })
+        .ok_or("no `<head>` tag to put the Content-Security-Policy into")?;
+
+        let meta = lithtml::Element {
+            name: "meta".into(),
+            variant: lithtml::ElementVariant::Void,
+            attributes: [
+                (Cow::Borrowed("http-equiv"), Some(Cow::Borrowed(CSP))),
+                (
+                    Cow::Borrowed("content"),
+                    Some(Cow::Owned(policy.to_string())),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            classes: vec![],
+            children: vec![],
+            source_span: head.source_span.clone(),
+        };
+
+        head.children.insert(0, lithtml::Node::Element(meta));
+
+        let mut text = core::mem::take(&mut self.spare);
+        serialize_dom(&mut dom, &mut text, self.text.len() + policy.len() + 64);
+        self.replace_text(text);
+
+        Ok(())
+    }
+
+    /// Replace `src` and `href` attributes referring to one of the assets by its URI.
+    ///
+    /// References are compared as written in the attributes. Returns those that were found, the
+    /// document leaves the others unused.
+    pub fn inline_references(
+        &mut self,
+        uris: &BTreeMap<String, String>,
+    ) -> Result<BTreeSet<String>, Box<dyn Error>> {
+        let text = trim_document(&self.text);
+        let mut dom = Dom::parse(text)?;
+        let mut found = BTreeSet::new();
+
+        find_element_mut(&mut dom, |node| {
+            let lithtml::Node::Element(el) = node else {
+                return false;
+            };
+
+            for (key, value) in el.attributes.iter_mut() {
+                if !key.eq_ignore_ascii_case("src") && !key.eq_ignore_ascii_case("href") {
+                    continue;
                 }
-            })
-            .nth(0)
+
+                if let Some(reference) = value.as_deref()
+                    && let Some(uri) = uris.get(reference)
+                {
+                    found.insert(reference.to_owned());
+                    *value = Some(Cow::Owned(uri.clone()));
+                }
+            }
+
+            false
+        });
+
+        if found.is_empty() {
+            return Ok(found);
+        }
+
+        let inlined: usize = uris.values().map(String::len).sum();
+        let mut text = core::mem::take(&mut self.spare);
+        serialize_dom(&mut dom, &mut text, self.text.len() + inlined);
+        self.replace_text(text);
+
+        Ok(found)
+    }
+
+    /// Find features of a template that a browser's Save As is known to break, with advice.
+    ///
+    /// The length of the head is left to the builder, it depends on the doctype it inserts.
+    pub fn browser_compat_lints(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let text = trim_document(&self.text);
+        let dom = Dom::parse(text)?;
+        let mut lints = vec![];
+
+        let doctype = text
+            .get(..9)
+            .filter(|start| start.eq_ignore_ascii_case("<!doctype"))
+            .and_then(|_| text.split_once('>'))
+            .map(|(doctype, _)| {
+                doctype
+                    .split_ascii_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            });
+
+        if let Some(doctype) = doctype
+            && !doctype.eq_ignore_ascii_case("<!doctype html")
         {
-            html.attributes.remove("data-a");
-        };
+            lints.push(format!(
+                "the doctype `{doctype}>` is unusual, browsers may rewrite it when saving. Use \
+                `<!DOCTYPE html>` or leave it out."
+            ));
+        }
+
+        let mut templates = 0;
+        let _ = find_element(&dom, |node| {
+            let is_template = node.element().is_some_and(|el| {
+                el.name.eq_ignore_ascii_case("template")
+                    && !attribute(el, "id").is_some_and(|id| id.starts_with(ID_TAR_CONTENT))
+            });
+
+            templates += usize::from(is_template);
+            None::<()>
+        });
+
+        if templates > 0 {
+            lints.push(format!(
+                "the template has {templates} `<template>` elements, Chromium drops their content \
+                when saving the page. Use `<noscript>` for inert markup, or create it from a script."
+            ));
+        }
+
+        Ok(lints)
+    }
+
+    /// Check that a policy can be written as the value of an attribute.
+    pub fn validate_csp(policy: &str) -> Result<(), Box<dyn Error>> {
+        if policy
+            .bytes()
+            .any(|b| b == b'"' || !(b' '..=b'~').contains(&b))
+        {
+            return Err("expected printable ASCII without double quotes".into());
+        }
+
+        Ok(())
+    }
+
+    /// Remove the packed files and the stage0 script, leaving the original HTML.
+    ///
+    /// Unlike [`Self::split_tar_contents`] this does not decode any of the files, which is much
+    /// cheaper for large documents when only the template is needed.
+    pub fn strip_payload(&mut self) -> Result<(), Box<dyn Error>> {
+        let text = trim_document(&self.text);
+        let mut dom = Dom::parse(text)?;
+
+        strip_file_elements(&mut dom);
 
         find_element_mut(&mut dom, |node| {
             if let lithtml::Node::Element(el) = node {
                 el.children.retain(|child| {
                     child
                         .element()
-                        .filter(|el| el.classes.contains(&Cow::Borrowed("wah_polyglot_data")))
+                        .filter(|el| el.name.to_lowercase() == "script")
+                        .filter(|el| attribute(el, "id") == Some(ID_TAR_STAGE0))
                         .is_none()
                 })
             }
@@ -575,9 +1534,11 @@ impl<'text> SourceDocument<'text> {
             false
         });
 
-        *self = SourceDocument::from_reparse(&mut dom);
+        let mut text = core::mem::take(&mut self.spare);
+        serialize_dom(&mut dom, &mut text, self.text.len());
+        self.replace_text(text);
 
-        Ok(files)
+        Ok(())
     }
 }
 
@@ -619,3 +1580,93 @@ impl From<&'_ lithtml::Element<'_>> for TagSpan {
         }
     }
 }
+
+#[test]
+fn test_payload_text() {
+    let mangled = "\u{fffd}\u{fffd}\n  SGVsbG8s\r\nIHdvcmxk\nIQo=\n&#65533;&#65533;\0";
+    assert_eq!(payload_text(mangled), "SGVsbG8sIHdvcmxkIQo=");
+}
+
+#[test]
+fn test_reparse_keeps_empty_elements_open() {
+    const TEMPLATE: &str = "<!DOCTYPE html><html><head><title></title></head>\
+        <body><div id=app><span></span></div><textarea></textarea></body></html>";
+
+    let check = |doc: &SourceDocument| {
+        let text = &doc[..];
+        assert!(!text.contains("/>"), "self-closed element in {text}");
+        assert!(text.contains("<span></span>"), "{text}");
+    };
+
+    let mut doc = SourceDocument::new(TEMPLATE);
+    doc.prepare_tar_structure().unwrap();
+    check(&doc);
+
+    let mut doc = SourceDocument::new(TEMPLATE);
+    doc.strip_payload().unwrap();
+    check(&doc);
+
+    let mut doc = SourceDocument::new(TEMPLATE);
+    assert!(doc.split_tar_contents().unwrap().is_empty());
+    check(&doc);
+
+    let mut doc = SourceDocument::new(TEMPLATE);
+    doc.insert_csp("default-src 'self'").unwrap();
+    assert!(doc[..].contains("<span></span>"));
+}
+
+#[test]
+fn test_span_counts_characters() {
+    const TEMPLATE: &str = "<!DOCTYPE html><!-- déjà vu 🦀 --><html lang=\"en\"><head>\
+        <template id=\"WAH_POLYGLOT_HTML_PLUS_TAR_CONTENT\"></template></head>\
+        <body><p>ünïcödé</p><script id=\"WAH_POLYGLOT_HTML_PLUS_TAR_STAGE0\"></script></body></html>";
+
+    let mut doc = SourceDocument::new(TEMPLATE);
+    let structure = doc.prepare_tar_structure().unwrap();
+    // The document had all its elements, it was not rewritten.
+    assert_eq!(&doc[..], TEMPLATE);
+
+    let html = doc.span(structure.html_tag);
+    assert_eq!(html.start, TEMPLATE.find("<html").unwrap());
+    assert_eq!(html.end, TEMPLATE.len());
+    assert_eq!(
+        &doc[html.start..][..structure.html_insertion_point],
+        "<html lang=\"en\">"
+    );
+
+    let stage0 = &doc[doc.span(structure.stage0)];
+    assert!(stage0.starts_with("<script id="), "{stage0}");
+    assert!(stage0.ends_with("</script>"), "{stage0}");
+}
+
+#[test]
+fn test_inline_references() {
+    const TEMPLATE: &str = "<!DOCTYPE html><html><head><link rel=icon href=\"icon.svg\"></head>\
+        <body><img src=\"logo.png\"><img src=\"other.png\"><a href=\"logo.png\">logo</a></body></html>";
+
+    let uris: BTreeMap<_, _> = [
+        ("logo.png", "data:image/png;base64,iVBORw0KGgo="),
+        ("icon.svg", "data:image/svg+xml;base64,PHN2Zy8+"),
+        ("unused.css", "data:text/css;base64,"),
+    ]
+    .map(|(reference, uri)| (reference.to_owned(), uri.to_owned()))
+    .into_iter()
+    .collect();
+
+    let mut doc = SourceDocument::new(TEMPLATE);
+    let found = doc.inline_references(&uris).unwrap();
+    assert_eq!(
+        found,
+        BTreeSet::from(["icon.svg".to_owned(), "logo.png".to_owned()])
+    );
+
+    let text = &doc[..];
+    assert_eq!(
+        text.matches("\"data:image/png;base64,iVBORw0KGgo=\"")
+            .count(),
+        2
+    );
+    assert!(text.contains("href=\"data:image/svg+xml;base64,PHN2Zy8+\""));
+    assert!(text.contains("src=\"other.png\""));
+    assert!(!text.contains("logo.png"));
+}
@@ -267,7 +267,7 @@ impl<'st> StringSection<'st> {
         let pre_start: u32 = u32::try_from(byte_stream.len()).expect("Unhandled strings offset, too much data");
         let post_skip = pre_start + 12;
         let post = post_skip + self.offset;
-        let pad = 3 - (post + 3) & 0x3;
+        let pad = 3 - ((post + 3) & 0x3);
         
         byte_stream.extend_from_slice(bytemuck::cast_slice::<u32, u8>(&[INST_SKIP, 1, post+pad]));
         assert_eq!(byte_stream.len(), post_skip as usize);
@@ -285,3 +285,19 @@ impl<'st> StringSection<'st> {
         post_skip
     }
 }
+
+#[test]
+fn strings_are_padded_to_the_next_instruction() {
+    for len in 0..8 {
+        let mut strings = StringSection::default();
+        strings.push(vec![b'a'; len]);
+
+        let mut byte_stream = vec![0u8; 4];
+        strings.encode(&mut byte_stream);
+        assert_eq!(byte_stream.len() % 4, 0, "{len} bytes of strings");
+
+        // The skip continues right after the padding.
+        let skip = u32::from_ne_bytes(byte_stream[12..16].try_into().unwrap());
+        assert_eq!(skip as usize, byte_stream.len(), "{len} bytes of strings");
+    }
+}
@@ -2,6 +2,10 @@ import { WASI, File, OpenFile, Directory, PreopenDirectory } from "@bjorn3/brows
 // This include is synthesized by `build.js:wasiInterpreterPlugin`.
 import { load_config } from 'wasi-config:config.toml'
 
+// The name of the runtime configuration in the document, the build replaces
+// this literal with `Machine.config-name`.
+const CONFIG = 'boot/config.json';
+
 async function fallback_shell(configuration, error) {
   document.documentElement.innerHTML = `<p>Missing boot exec</p>`;
 
@@ -861,6 +865,27 @@ async function worker_mount({
     console.log(`Initialized towards stage3 in ${ops.length-256} steps`);
   }
 
+  // The runtime configuration of the document, `Document.runtime-config`.
+  // Its `args` replace the arguments of the kernel, its `env` table adds to
+  // the environment.
+  const runtime_config = wasi_root_fs?.find(item => item.header.name === CONFIG);
+
+  if (runtime_config) {
+    try {
+      const { args, env } = JSON.parse(new TextDecoder().decode(runtime_config.data));
+
+      if (Array.isArray(args)) {
+        configuration.args = args.map(arg => ''+arg);
+      }
+
+      for (const [key, value] of Object.entries(env ?? {})) {
+        configuration.env.push(`${key}=${value}`);
+      }
+    } catch (e) {
+      console.log('Invalid runtime configuration', e);
+    }
+  }
+
   let args = configuration.args;
   let env = configuration.env;
   let fds = configuration.fds;
@@ -868,7 +893,53 @@ async function worker_mount({
   configuration.WASI = WASI;
 
   if (wasi_root_fs) {
-    let wasi_root_files = new Map(wasi_root_fs.map(item => [item.header.name, item.data]));
+    const by_name = new Map(wasi_root_fs.map(item => [item.header.name, item]));
+
+    // Follow symbolic links, typeflag '2', from the directory of each link.
+    // Gives up after as many hops as Linux does, a loop resolves to nothing.
+    const resolve_symlink = (item) => {
+      for (let hops = 0; item?.header.typeflag === 50; hops++) {
+        if (hops === 40) {
+          return undefined;
+        }
+
+        const target = item.header.linkname;
+        const path = target.startsWith('/') ? [] : item.header.name.split('/').slice(0, -1);
+
+        for (const part of target.split('/')) {
+          if (part === '..') {
+            path.pop();
+          } else if (part !== '' && part !== '.') {
+            path.push(part);
+          }
+        }
+
+        item = by_name.get(path.join('/'));
+      }
+
+      return item;
+    };
+
+    // Directories, typeflag '5', are keyed with a trailing slash so that only
+    // the directories along their path are created. The shim has no links or
+    // devices: a symbolic link to a file becomes a copy of it, other links and
+    // device nodes, typeflags '3' and '4', are not created.
+    let wasi_root_files = new Map(wasi_root_fs.flatMap(item => {
+      switch (item.header.typeflag) {
+        case 53:
+          return [[`${item.header.name}/`, item.data]];
+        case 50: {
+          const target = resolve_symlink(item);
+          const is_file = target && ![51, 52, 53].includes(target.header.typeflag);
+          return is_file ? [[item.header.name, target.data]] : [];
+        }
+        case 51:
+        case 52:
+          return [];
+        default:
+          return [[item.header.name, item.data]];
+      }
+    }));
 
     // The given layer will be underlaid the inputs to the boot archive extractor.
     for (const [key, value] of wasi_root_files) {
@@ -895,6 +966,10 @@ async function worker_mount({
         basedir = reldir.fd_obj;
       }
 
+      if (file === '') {
+        continue;
+      }
+
       // Open read-write with creation flags.
       const maybefd = basedir.path_open(0, file, 1, 1);
 